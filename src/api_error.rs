@@ -0,0 +1,106 @@
+// Errors of the API as structured JSON, so the frontend can handle them by their code instead of matching on the message.
+
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::Serialize;
+
+/// An error response of the API. It's sent as `{"error": {"code": "...", "message": "...", "status": 400}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApiError {
+    /// A stable identifier of the error, like `missing_input`, for the frontend to act on.
+    pub code: &'static str,
+    /// A description of the error for humans; it may change at any time.
+    pub message: String,
+    /// The HTTP status code of the response.
+    pub status: u16,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            status: status.as_u16(),
+        }
+    }
+
+    /// Builds the response with the status code of the error and the error as its JSON body.
+    pub fn response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status).json(serde_json::json!({ "error": self }))
+    }
+}
+
+impl From<ApiError> for HttpResponse {
+    fn from(error: ApiError) -> Self {
+        error.response()
+    }
+}
+
+/// Shorthand for building the response of an error directly.
+pub fn error_response(
+    status: StatusCode,
+    code: &'static str,
+    message: impl Into<String>,
+) -> HttpResponse {
+    ApiError::new(status, code, message).response()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderMap;
+    use qstring::QString;
+
+    use super::*;
+    use crate::chatbot::stream_response::{chatbot_from_request, request_params_from_request};
+
+    async fn json_body(response: HttpResponse) -> serde_json::Value {
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .expect("The body can be read");
+        serde_json::from_slice(&body).expect("The body is JSON")
+    }
+
+    #[actix_web::test]
+    async fn test_errors_are_structured_json() {
+        crate::auth::AUTH_KEY.get_or_init(|| "test_key".to_string());
+        let headers = HeaderMap::new();
+
+        // A request without any credentials.
+        let response = crate::auth::authorize_or_fail_fn(&QString::from(""), &headers)
+            .await
+            .expect_err("The request isn't authorized");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response
+                .headers()
+                .get("content-type")
+                .map(|value| value.as_bytes()),
+            Some(&b"application/json"[..])
+        );
+        let body = json_body(response).await;
+        assert_eq!(body["error"]["code"], "unauthorized");
+        assert_eq!(body["error"]["status"], 401);
+        assert!(body["error"]["message"].is_string());
+
+        // The status codes of the query parsing stay the same.
+        let response = request_params_from_request(&QString::from("temperature=hot"), &headers)
+            .expect_err("The temperature is invalid");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(*response).await;
+        assert_eq!(body["error"]["code"], "invalid_parameter");
+        assert_eq!(body["error"]["status"], 400);
+
+        let response =
+            chatbot_from_request(&QString::from("chatbot=removed-model"), &headers, None)
+                .expect_err("The chatbot isn't available");
+        let body = json_body(*response).await;
+        assert_eq!(
+            body,
+            serde_json::json!({"error": {
+                "code": "chatbot_not_found",
+                "message": "Chatbot not found. Consult the /availablechatbots endpoint for available chatbots.",
+                "status": 422,
+            }})
+        );
+    }
+}
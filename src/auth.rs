@@ -6,7 +6,17 @@ pub static AUTH_KEY: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCe
 /// Same with whether or not guests should be allowed to access the streaming API.
 pub static ALLOW_GUESTS: once_cell::sync::OnceCell<bool> = once_cell::sync::OnceCell::new();
 
-use actix_web::{http::header::HeaderMap, HttpResponse};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::{header::HeaderMap, StatusCode},
+    HttpResponse,
+};
+use base64::Engine;
 use once_cell::sync::Lazy;
 use qstring::QString;
 use reqwest::Client;
@@ -15,6 +25,8 @@ use reqwest::Client;
 /// If a username was found in the token check, it will be returned.
 use tracing::{debug, error, trace, warn};
 
+use crate::api_error::error_response;
+
 pub static REQUIRE_AUTH_KEY: bool = false; // Whether or not the auth key needs to also be sent.
                                            // Note: if the auth key is not sent, an attacked might construct a request against any instance using a mock setup,
                                            // similar to how the testing suite does the fixture. This could lead to people using the backend, and specifically
@@ -33,6 +45,10 @@ pub static REQUIRE_AUTH_KEY: bool = false; // Whether or not the auth key needs
 /// Sending the auth_key is still recommended, as this switch will be turned on in the near future to improve security.
 ///
 /// If the Authentication header isn't valid UTF-8 or in the "Bearer " format, an UnprocessableEntity response is returned.
+///
+/// All errors are JSON objects like `{"error": {"code": "unauthorized", "message": "...", "status": 401}}`.
+/// The codes of the authentication are `unauthorized`, `invalid_token`, `invalid_authorization_header`, `missing_rest_url`,
+/// `auth_not_configured`, `upstream_timeout`, `upstream_unavailable`, `upstream_response_too_large` and `bad_upstream_response`.
 #[documented::docs_const]
 pub async fn authorize_or_fail_fn(
     qstring: &QString,
@@ -40,8 +56,11 @@ pub async fn authorize_or_fail_fn(
 ) -> Result<String, HttpResponse> {
     let Some(auth_key) = crate::auth::AUTH_KEY.get() else {
         error!("No key found in the environment. Sending 500.");
-        return Err(HttpResponse::InternalServerError()
-            .body("No auth key found in the environment; Authorization failed."));
+        return Err(error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "auth_not_configured",
+            "No auth key found in the environment; Authorization failed.",
+        ));
     };
 
     match (
@@ -61,8 +80,11 @@ pub async fn authorize_or_fail_fn(
                 Ok(header_val) => header_val.to_string(),
                 Err(e) => {
                     warn!("Authorization header is not a valid UTF-8 string: {}", e);
-                    return Err(HttpResponse::UnprocessableEntity()
-                        .body("Authorization header is not a valid UTF-8 string."));
+                    return Err(error_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "invalid_authorization_header",
+                        "Authorization header is not a valid UTF-8 string.",
+                    ));
                 }
             };
 
@@ -71,7 +93,9 @@ pub async fn authorize_or_fail_fn(
             // The Authentication header is a Bearer token, so we need to extract the token from it.
             let Some(token) = auth_string.strip_prefix("Bearer ") else {
                 warn!("Authorization header is not a Bearer token.");
-                return Err(HttpResponse::UnprocessableEntity().body(
+                return Err(error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_authorization_header",
                     "Authorization header is not a Bearer token. Please use the Bearer token format.",
                 ));
             };
@@ -94,8 +118,11 @@ pub async fn authorize_or_fail_fn(
             } else {
                 // If the rest URL is not present, we'll return a 400.
                 warn!("No rest URL found in headers, cannot check token.");
-                return Err(HttpResponse::BadRequest()
-                    .body("Authentication not successful; please use the nginx proxy. (rest)"));
+                return Err(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "missing_rest_url",
+                    "Authentication not successful; please use the nginx proxy. (rest)",
+                ));
             };
 
             // Depending on whether the token was valid or not, check the query string token.
@@ -106,15 +133,22 @@ pub async fn authorize_or_fail_fn(
                         if let Some(key) = maybe_key {
                             if key != auth_key {
                                 warn!("Auth key does not match. Sending 401.");
-                                Err(HttpResponse::Unauthorized().body("Auth key does not match."))
+                                Err(error_response(
+                                    StatusCode::UNAUTHORIZED,
+                                    "unauthorized",
+                                    "Auth key does not match.",
+                                ))
                             } else {
                                 debug!("Auth key matches, sending success.");
                                 Ok(username)
                             }
                         } else {
                             warn!("No auth key provided in the request. Sending 401.");
-                            Err(HttpResponse::Unauthorized()
-                                .body("No auth key provided in the request."))
+                            Err(error_response(
+                                StatusCode::UNAUTHORIZED,
+                                "unauthorized",
+                                "No auth key provided in the request.",
+                            ))
                         }
                     } else {
                         // No auth key required, just log it.
@@ -134,25 +168,172 @@ pub async fn authorize_or_fail_fn(
         }
         (Some(_), None) => {
             warn!("No Authorization header found. Sending 401.");
-            Err(HttpResponse::Unauthorized()
-                .body("No Authorization header found. Please use the Bearer token format."))
+            Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "No Authorization header found. Please use the Bearer token format.",
+            ))
         }
         (None, None) => {
             // If the key is not found, we'll return a 401.
             warn!("No key provided in the request.");
 
-            Err(HttpResponse::Unauthorized().body("Some necessary field weren't found in the request, please make sure to use the nginx proxy. If this is the first time logging in, check whether the nginx proxy and sets the right headers."))
+            Err(error_response(StatusCode::UNAUTHORIZED, "unauthorized", "Some necessary field weren't found in the request, please make sure to use the nginx proxy. If this is the first time logging in, check whether the nginx proxy and sets the right headers."))
+        }
+    }
+}
+
+/// The timeout for the requests to the token check and the vault, in milliseconds.
+/// Without it, a hung upstream would stall the authentication of every request indefinitely.
+/// Set via the environment variable `AUTH_HTTP_TIMEOUT_MS`; defaults to 10 seconds.
+static AUTH_HTTP_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_millis(
+        std::env::var("AUTH_HTTP_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(10_000),
+    )
+});
+
+/// The maximum size of a response from the token check or the vault; both only send a small JSON object.
+/// Set via the environment variable `AUTH_HTTP_MAX_RESPONSE_BYTES`; defaults to 1 MiB.
+static AUTH_HTTP_MAX_RESPONSE_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("AUTH_HTTP_MAX_RESPONSE_BYTES")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(1024 * 1024)
+});
+
+/// How long a successfully checked token is remembered, so that not every request of a user needs a round trip to the token check.
+/// Set via the environment variable `AUTH_CACHE_TTL_SECS`; defaults to 5 minutes. 0 disables the cache.
+static AUTH_CACHE_TTL: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("AUTH_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(300),
+    )
+});
+
+/// The usernames of the tokens that were checked successfully, keyed by token and rest URL, with the time they were checked.
+/// Failed checks are never stored, so a token that was rejected is checked again on the next request.
+static TOKEN_CACHE: Lazy<Mutex<HashMap<TokenCacheKey, (String, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The token and the rest URL it was checked against.
+type TokenCacheKey = (String, String);
+
+/// Returns the cached username of the token, if it was checked within the TTL.
+/// Expired entries are removed when they are encountered.
+fn cached_username(token: &str, rest_url: &str, ttl: Duration) -> Option<String> {
+    let mut cache = TOKEN_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let key = (token.to_string(), rest_url.to_string());
+    match cache.get(&key) {
+        Some((username, checked_at)) if checked_at.elapsed() < ttl => Some(username.clone()),
+        Some(_) => {
+            trace!("Cached token check expired, checking again.");
+            cache.remove(&key);
+            None
         }
+        None => None,
     }
 }
 
-static REQWEST_CLIENT: Lazy<Client> = Lazy::new(reqwest::Client::new);
+/// Remembers the username of a token that was checked successfully.
+fn cache_username(token: &str, rest_url: &str, username: &str) {
+    TOKEN_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(
+            (token.to_string(), rest_url.to_string()),
+            (username.to_string(), Instant::now()),
+        );
+}
+
+static REQWEST_CLIENT: Lazy<Client> = Lazy::new(|| build_http_client(*AUTH_HTTP_TIMEOUT));
+
+/// Builds the client for the upstream services; the timeout applies to connecting as well as to the entire request.
+fn build_http_client(timeout: Duration) -> Client {
+    Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()
+        .expect("Failed to create reqwest client")
+}
+
+/// Sends a request to an upstream service (named for the messages) and reads the response, at most max_bytes of it.
+/// Returns the status and the body, or the response for the client if the upstream timed out (504), couldn't be reached (503) or sent too much (502).
+async fn send_with_limits(
+    request: reqwest::RequestBuilder,
+    max_bytes: usize,
+    upstream: &str,
+) -> Result<(reqwest::StatusCode, String), HttpResponse> {
+    let failed = |e: reqwest::Error, action: &str| {
+        if e.is_timeout() {
+            error!("Timeout while {action} the {upstream}: {e}");
+            error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                "upstream_timeout",
+                format!("The {upstream} did not respond in time."),
+            )
+        } else {
+            error!("Error while {action} the {upstream}: {e}");
+            error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "upstream_unavailable",
+                format!("Error sending request to the {upstream}, is the URL correct?"),
+            )
+        }
+    };
+    let too_large = || {
+        error!("The response of the {upstream} is larger than {max_bytes} bytes.");
+        error_response(
+            StatusCode::BAD_GATEWAY,
+            "upstream_response_too_large",
+            format!("The response of the {upstream} is too large."),
+        )
+    };
+
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| failed(e, "sending a request to"))?;
+    trace!("Full response from the {}: {:?}", upstream, response);
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+
+    // The content length might not be set, so the body is read in chunks until it's complete or too large.
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| failed(e, "reading the response of"))?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((
+        response.status(),
+        String::from_utf8_lossy(&body).trim().to_owned(),
+    ))
+}
 
 /// Recives a token, checks it against the URL provided in the header and returns the username.
 async fn get_username_from_token(token: &str, rest_url: &str) -> Result<String, HttpResponse> {
     // debug!("Checking token: {}", token);
     debug!("Using rest URL: {}", rest_url);
 
+    if let Some(username) = cached_username(token, rest_url, *AUTH_CACHE_TTL) {
+        debug!("Token was already checked recently, username: {}", username);
+        return Ok(username);
+    }
+
     // If the URL is set, we'll send a GET request to it with the token in the header.
 
     // The entire url ending is "/api/freva-nextgen/auth/v2/systemuser",
@@ -169,41 +350,22 @@ async fn get_username_from_token(token: &str, rest_url: &str) -> Result<String,
 
     debug!("Using path: {}", path);
 
-    let response = REQWEST_CLIENT
+    let request = REQWEST_CLIENT
         .get(rest_url.to_string() + &path)
         .header("Authorization", format!("Bearer {token}"));
-    let response = response.send().await;
-
-    trace!("Full response for token check: {:?}", response);
-
-    let result = match response {
-        Ok(res) => {
-            if res.status().is_success() {
-                // If the response is successful, we'll return the username.
-                let content = res
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Empty JSON!".to_string())
-                    .trim()
-                    .to_owned(); // just signals that an error happened
-                debug!("Token check successful, content: {}", content);
-                content
-            } else {
-                // If the response is not successful, we'll return a 401.
-                warn!("Token check failed, status code: {}", res.status());
-                return Err(HttpResponse::Unauthorized()
-                    .body("Token check failed, the token is likely not valid (anymore)."));
-            }
-        }
-        Err(e) => {
-            // If there was an error sending the request, we'll return a 503.
-            error!("Error sending token check request: {}", e);
-            return Err(
-                HttpResponse::ServiceUnavailable()
-                    .body("Error sending token check request, is the URL correct?"), // This is technically about the vault, but 503 fits better than 401 here.
-            );
-        }
-    };
+    let (status, result) =
+        send_with_limits(request, *AUTH_HTTP_MAX_RESPONSE_BYTES, "token check").await?;
+
+    if !status.is_success() {
+        // If the response is not successful, we'll return a 401.
+        warn!("Token check failed, status code: {}", status);
+        return Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            "Token check failed, the token is likely not valid (anymore).",
+        ));
+    }
+    debug!("Token check successful, content: {}", result);
 
     // The result is a JSON object with the username and some other stuff, but we only care about the username.
     let username = match serde_json::from_str::<serde_json::Value>(&result) {
@@ -216,25 +378,34 @@ async fn get_username_from_token(token: &str, rest_url: &str) -> Result<String,
                 // If the token is invalid, the response will contain a "detail" field with an error message.
                 if let Some(detail) = json["detail"].as_str() {
                     error!("Token check failed, detail: {}", detail);
-                    return Err(
-                        HttpResponse::Unauthorized().body(format!("Token check failed: {detail}"))
-                    );
+                    return Err(error_response(
+                        StatusCode::UNAUTHORIZED,
+                        "invalid_token",
+                        format!("Token check failed: {detail}"),
+                    ));
                 } else {
                     // The response was malformed, that's a 502.
                     error!("Token check response is malformed, no username found.");
-                    return Err(HttpResponse::BadGateway()
-                        .body("Token check response is malformed, no username found."));
+                    return Err(error_response(
+                        StatusCode::BAD_GATEWAY,
+                        "bad_upstream_response",
+                        "Token check response is malformed, no username found.",
+                    ));
                 }
             }
         }
         Err(e) => {
             // If the JSON is not valid, we'll return a 502.
             error!("Error parsing token check response: {}", e);
-            return Err(HttpResponse::BadGateway()
-                .body("Token check response is malformed, not valid JSON."));
+            return Err(error_response(
+                StatusCode::BAD_GATEWAY,
+                "bad_upstream_response",
+                "Token check response is malformed, not valid JSON.",
+            ));
         }
     };
     debug!("Token check successful, username: {}", username);
+    cache_username(token, rest_url, &username);
     Ok(username)
 }
 
@@ -242,38 +413,22 @@ async fn get_username_from_token(token: &str, rest_url: &str) -> Result<String,
 pub async fn get_mongodb_uri(vault_url: &str) -> Result<String, HttpResponse> {
     // The vault URL will be contained in the answer to the request to the vault. (No endpoint or authentication needed.)
     // debug!("Getting MongoDB URL from vault: {}", vault_url);
-    let response = REQWEST_CLIENT.get(vault_url).send().await;
-
-    // Extract the result or fail
-    let result = match response {
-        Ok(res) => {
-            if res.status().is_success() {
-                // If the response is successful, we'll return the MongoDB URL.
-                let content = res.text().await;
-                // debug!("Response from vault: {:?}", content);
-                let content = match content {
-                    Ok(text) => text.trim().to_owned(),
-                    Err(e) => {
-                        error!("Error reading response text: {}", e);
-                        return Err(HttpResponse::BadGateway()
-                            .body("Error reading response text from vault."));
-                    }
-                };
-                // debug!("Vault response: {}", content);
-                content
-            } else {
-                // If the response is not successful, we'll return a 502.
-                warn!("Failed to get MongoDB URL, status code: {}", res.status());
-                return Err(HttpResponse::BadGateway()
-                    .body("Failed to get MongoDB URL. Is Nginx running correctly?"));
-            }
-        }
-        Err(e) => {
-            // If there was an error sending the request, we'll return a 503.
-            error!("Error sending request to vault: {}", e);
-            return Err(HttpResponse::ServiceUnavailable().body("Error sending request to vault."));
-        }
-    };
+    let (status, result) = send_with_limits(
+        REQWEST_CLIENT.get(vault_url),
+        *AUTH_HTTP_MAX_RESPONSE_BYTES,
+        "vault",
+    )
+    .await?;
+
+    if !status.is_success() {
+        // If the response is not successful, we'll return a 502.
+        warn!("Failed to get MongoDB URL, status code: {}", status);
+        return Err(error_response(
+            StatusCode::BAD_GATEWAY,
+            "bad_upstream_response",
+            "Failed to get MongoDB URL. Is Nginx running correctly?",
+        ));
+    }
 
     // The result is a JSON object containing a bunch of stuff, but we only care about the MongoDB URL ("mongodb.url").
     let mongodb_url = match serde_json::from_str::<serde_json::Value>(&result) {
@@ -287,15 +442,21 @@ pub async fn get_mongodb_uri(vault_url: &str) -> Result<String, HttpResponse> {
             } else {
                 // If the MongoDB URL is not found, we'll return a 502.
                 error!("MongoDB URL not found in vault response.");
-                return Err(
-                    HttpResponse::BadGateway().body("MongoDB URL not found in vault response.")
-                );
+                return Err(error_response(
+                    StatusCode::BAD_GATEWAY,
+                    "bad_upstream_response",
+                    "MongoDB URL not found in vault response.",
+                ));
             }
         }
         Err(e) => {
             // If the JSON is not valid, we'll return a 502.
             error!("Error parsing vault response: {}", e);
-            return Err(HttpResponse::BadGateway().body("Vault response was malformed."));
+            return Err(error_response(
+                StatusCode::BAD_GATEWAY,
+                "bad_upstream_response",
+                "Vault response was malformed.",
+            ));
         }
     };
     // debug!("MongoDB URL: {}", mongodb_url);
@@ -328,6 +489,11 @@ pub fn is_guest(username: &str) -> bool {
         warn!("ALLOW_GUESTS is not set, this should not happen! defaulting to false.");
     }
 
+    has_guest_username(username)
+}
+
+/// Whether the username is one of a guest, regardless of whether guests are allowed.
+pub fn has_guest_username(username: &str) -> bool {
     // Usernames are by default guests, unless they follow one of these patterns:
     // "kXXXXXX" (where X is a digit) or "bXXXXXX" (where X is a digit).
     // "testing" is also considered a non-guest
@@ -345,6 +511,50 @@ pub fn is_guest(username: &str) -> bool {
     true
 }
 
+/// The claim of the token that names the organization of the user, which decides the database for deployments with several tenants.
+/// Set via the environment variable `TENANT_CLAIM`; defaults to "organization".
+static TENANT_CLAIM: Lazy<String> = Lazy::new(|| {
+    std::env::var("TENANT_CLAIM")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| "organization".to_string())
+});
+
+/// Reads the tenant of the user from the claims of the token in the Authorization header, if it has one.
+/// The token was already checked against the rest endpoint by `authorize_or_fail_fn`, so its payload is only decoded here.
+pub fn get_tenant(headers: &HeaderMap) -> Option<String> {
+    let header = headers
+        .get("Authorization")
+        .or_else(|| headers.get("x-freva-user-token"))?;
+    let token = header.to_str().ok()?.strip_prefix("Bearer ")?;
+    tenant_from_token(token, &TENANT_CLAIM)
+}
+
+/// Decodes the payload of a JWT and returns the given claim, if it's a non-empty string.
+pub(crate) fn tenant_from_token(token: &str, claim: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let payload = match base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+    {
+        Ok(payload) => payload,
+        Err(e) => {
+            debug!(
+                "The payload of the token is not valid base64, no tenant: {:?}",
+                e
+            );
+            return None;
+        }
+    };
+    let claims = serde_json::from_slice::<serde_json::Value>(&payload).ok()?;
+    claims
+        .get(claim)?
+        .as_str()
+        .map(str::trim)
+        .filter(|tenant| !tenant.is_empty())
+        .map(str::to_string)
+}
+
 /// Given a qstring and headers, as well as a list of fields to check against,
 /// returns the first field from the qstring or headers that matches one of the fields in the list.
 /// If none is found, returns None.
@@ -374,3 +584,122 @@ pub fn get_first_matching_field<'a>(
         qstring_result.or(header_result)
     }
 }
+
+/// Connects to the database of the vault URL in the request, the one of the user's tenant if it has one.
+/// Only for endpoints that require Authentication: the vault URL comes from the client.
+pub(crate) async fn database_from_request(
+    qstring: &QString,
+    headers: &HeaderMap,
+) -> Result<mongodb::Database, HttpResponse> {
+    let Some(vault_url) = get_first_matching_field(
+        qstring,
+        headers,
+        &[
+            "x-freva-vault-url",
+            "x-vault-url",
+            "vault-url",
+            "vault_url",
+            "freva_vault_url",
+        ],
+        true,
+    ) else {
+        warn!("No vault URL provided, cannot connect to the database for threads.");
+        return Err(HttpResponse::UnprocessableEntity()
+            .body("Vault URL not found. Please provide a non-empty vault URL in the headers."));
+    };
+    crate::chatbot::mongodb::mongodb_storage::get_database(
+        vault_url,
+        get_tenant(headers).as_deref(),
+    )
+    .await
+    .inspect_err(|e| error!("Error initializing database connection: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_slow_upstream_times_out() {
+        // An upstream that accepts the connection, but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Binding a free port");
+        let address = listener.local_addr().expect("The listener has an address");
+        std::thread::spawn(move || {
+            let _connection = listener.accept();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        let client = build_http_client(Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        let result = send_with_limits(
+            client.get(format!("http://{address}/systemuser")),
+            1024,
+            "token check",
+        )
+        .await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            result.expect_err("The request should time out").status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_token_check_is_cached() {
+        use std::io::{Read, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // A token check that accepts the token "good" and counts how often it's asked.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Binding a free port");
+        let rest_url = format!(
+            "http://{}",
+            listener.local_addr().expect("The listener has an address")
+        );
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server_hits = Arc::clone(&hits);
+        std::thread::spawn(move || {
+            for mut connection in listener.incoming().flatten() {
+                server_hits.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 4096];
+                let length = connection.read(&mut request).unwrap_or(0);
+                let (status, body) =
+                    if String::from_utf8_lossy(&request[..length]).contains("Bearer good") {
+                        ("200 OK", r#"{"pw_name": "k123456"}"#)
+                    } else {
+                        ("401 Unauthorized", r#"{"detail": "Invalid token"}"#)
+                    };
+                let _ = write!(
+                    connection,
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+
+        // The second check within the TTL is answered from the cache.
+        for _ in 0..2 {
+            let username = get_username_from_token("good", &rest_url)
+                .await
+                .expect("The token is valid");
+            assert_eq!(username, "k123456");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // A failed check is not cached.
+        for _ in 0..2 {
+            assert!(get_username_from_token("bad", &rest_url).await.is_err());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // Expired entries are evicted.
+        assert!(cached_username("good", &rest_url, Duration::ZERO).is_none());
+        assert!(TOKEN_CACHE
+            .lock()
+            .expect("The cache isn't poisoned")
+            .get(&("good".to_string(), rest_url.clone()))
+            .is_none());
+    }
+}
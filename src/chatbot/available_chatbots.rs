@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+
+use async_openai::types::FinishReason;
 use once_cell::sync::Lazy;
 use tracing::{debug, error, info, trace, warn};
 
@@ -53,6 +56,396 @@ fn get_available_chatbots_from_litellm_file() -> Vec<AvailableChatbots> {
     chatbots
 }
 
+/// Goes through the settings of the models in the LiteLLM file, as `(model_name, key, value)` for every `key: value` line after a `model_name`.
+/// Like the model names, the settings are read line by line instead of as yaml; the quotes around a value are stripped.
+/// Settings before the first model are skipped.
+fn model_settings(file_content: &str) -> impl Iterator<Item = (&str, &str, &str)> {
+    let mut current_model = None;
+    file_content.lines().filter_map(move |line| {
+        let line = line.trim_matches(|c: char| c == '-' || c.is_whitespace());
+        let (key, value) = line.split_once(':')?;
+        let (key, value) = (key.trim(), value.trim());
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        if key == "model_name" {
+            current_model = Some(value);
+            return None;
+        }
+        Some((current_model?, key, value))
+    })
+}
+
+/// The markers a model uses to denote the start and end of a tool call inside its text content.
+/// Local models served through Ollama don't use the OpenAI tool call format when streaming,
+/// so we need to detect the tool calls ourselves. Which tokens they use depends on the fine-tune.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolCallMarkers {
+    pub start: String,
+    pub end: String,
+}
+
+impl Default for ToolCallMarkers {
+    fn default() -> Self {
+        ToolCallMarkers {
+            start: "<tool_call>".to_string(),
+            end: "</tool_call>".to_string(),
+        }
+    }
+}
+
+impl ToolCallMarkers {
+    /// Returns Some(true) if the delta starts a tool call, Some(false) if it ends one and None otherwise.
+    /// The markers are special tokens, so they are sent inside one delta, not split and with no other content.
+    pub fn classify(&self, delta: &str) -> Option<bool> {
+        if delta == self.start {
+            Some(true)
+        } else if delta == self.end {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// The tool call markers of all models that configured their own in the LiteLLM file.
+/// Models that aren't in here use the default markers.
+static TOOL_CALL_MARKERS: Lazy<HashMap<String, ToolCallMarkers>> = Lazy::new(|| {
+    let markers = parse_tool_call_markers(include_str!("../../litellm_config.yaml"));
+    debug!("Custom tool call markers: {:?}", markers);
+    markers
+});
+
+/// Reads the custom tool call markers from the LiteLLM file.
+/// They are set in the model_info of a model as `tool_call_start_marker: "..."` and `tool_call_end_marker: "..."`;
+/// if only one of them is set, the other one is the default.
+fn parse_tool_call_markers(file_content: &str) -> HashMap<String, ToolCallMarkers> {
+    let mut markers: HashMap<String, ToolCallMarkers> = HashMap::new();
+    for (model, key, value) in model_settings(file_content) {
+        match key {
+            "tool_call_start_marker" | "tool_call_end_marker" if value.is_empty() => {
+                warn!(
+                    "Found an empty tool call marker for model {}, skipping it.",
+                    model
+                );
+            }
+            "tool_call_start_marker" => {
+                markers.entry(model.to_string()).or_default().start = value.to_string();
+            }
+            "tool_call_end_marker" => {
+                markers.entry(model.to_string()).or_default().end = value.to_string();
+            }
+            _ => {}
+        }
+    }
+    markers
+}
+
+/// The max_tokens that is used for models that don't set their own limit.
+pub const DEFAULT_MAX_TOKENS: u32 = 16000;
+
+/// The completion token limits of all models that set one in the LiteLLM file.
+static MODEL_MAX_TOKENS: Lazy<HashMap<String, u32>> = Lazy::new(|| {
+    let limits = parse_token_limits(
+        include_str!("../../litellm_config.yaml"),
+        "max_output_tokens",
+    );
+    debug!("Completion token limits: {:?}", limits);
+    limits
+});
+
+/// An upper bound for the max_tokens of all models, for deployments that want to limit the costs.
+/// Set via the environment variable `MAX_TOKENS`; if it isn't set, only the limits of the models apply.
+static MAX_TOKENS_OVERRIDE: Lazy<Option<u32>> = Lazy::new(|| {
+    std::env::var("MAX_TOKENS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+});
+
+/// Reads token limits from the LiteLLM file.
+/// They are set in the model_info of a model under the names LiteLLM uses as well, like `max_output_tokens: 32768` or `max_input_tokens: 128000`.
+fn parse_token_limits(file_content: &str, limit_key: &str) -> HashMap<String, u32> {
+    let mut limits = HashMap::new();
+    for (model, _, value) in model_settings(file_content).filter(|(_, key, _)| *key == limit_key) {
+        match value.parse::<u32>() {
+            Ok(limit) => {
+                limits.insert(model.to_string(), limit);
+            }
+            Err(e) => warn!(
+                "Invalid {} {:?} for model {}, skipping it: {:?}",
+                limit_key, value, model, e
+            ),
+        }
+    }
+    limits
+}
+
+/// The context window that is assumed for models whose size isn't known.
+pub const DEFAULT_CONTEXT_WINDOW: u32 = 128_000;
+
+/// The context windows of all models that set one in the LiteLLM file.
+static MODEL_CONTEXT_WINDOWS: Lazy<HashMap<String, u32>> = Lazy::new(|| {
+    let windows = parse_token_limits(
+        include_str!("../../litellm_config.yaml"),
+        "max_input_tokens",
+    );
+    debug!("Context windows: {:?}", windows);
+    windows
+});
+
+/// Chooses the context window of a model: the one set in the LiteLLM file, or the one of its family.
+fn context_window_for(windows: &HashMap<String, u32>, model: &str) -> u32 {
+    windows.get(model).copied().unwrap_or_else(|| {
+        if model.starts_with("gpt-4.1") {
+            1_047_576
+        } else if model.starts_with("gpt-5") {
+            272_000
+        } else if model.starts_with("o3") || model.starts_with("o4") || model.starts_with("claude")
+        {
+            200_000
+        } else {
+            DEFAULT_CONTEXT_WINDOW
+        }
+    })
+}
+
+/// Chooses the max_tokens for a model: its own limit or the default, but never more than the override.
+fn max_tokens_for(limits: &HashMap<String, u32>, model: &str, max_override: Option<u32>) -> u32 {
+    let limit = limits.get(model).copied().unwrap_or(DEFAULT_MAX_TOKENS);
+    max_override.map_or(limit, |max_override| limit.min(max_override))
+}
+
+/// What the stream does when the LLM gives a reason to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopAction {
+    /// The stream ends with a StreamEnd.
+    End,
+    /// The accumulated tool call is run and the stream restarted with its result.
+    RunTools,
+    /// The stop is ignored and the stream is polled further, for providers that send content after it.
+    Continue,
+}
+
+impl StopAction {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "end" => Some(StopAction::End),
+            "run_tools" => Some(StopAction::RunTools),
+            "continue" => Some(StopAction::Continue),
+            _ => None,
+        }
+    }
+}
+
+/// The actions of all models that override what happens for some finish reasons in the LiteLLM file.
+static FINISH_REASON_ACTIONS: Lazy<HashMap<String, Vec<(FinishReason, StopAction)>>> =
+    Lazy::new(|| {
+        let actions = parse_finish_reason_actions(include_str!("../../litellm_config.yaml"));
+        debug!("Custom finish reason actions: {:?}", actions);
+        actions
+    });
+
+/// Reads the finish reason overrides from the LiteLLM file.
+/// They are set in the model_info of a model as a comma-separated list, like `finish_reason_actions: "stop=run_tools,length=continue"`.
+/// The finish reasons are named like in the API (stop, length, tool_calls, content_filter, function_call), the actions are end, run_tools and continue.
+fn parse_finish_reason_actions(
+    file_content: &str,
+) -> HashMap<String, Vec<(FinishReason, StopAction)>> {
+    let mut actions: HashMap<String, Vec<(FinishReason, StopAction)>> = HashMap::new();
+    for (model, _, value) in
+        model_settings(file_content).filter(|(_, key, _)| *key == "finish_reason_actions")
+    {
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let parsed = entry.split_once('=').and_then(|(reason, action)| {
+                let reason = serde_json::from_value::<FinishReason>(serde_json::Value::String(
+                    reason.trim().to_string(),
+                ))
+                .ok()?;
+                Some((reason, StopAction::parse(action)?))
+            });
+            match parsed {
+                Some(parsed) => actions.entry(model.to_string()).or_default().push(parsed),
+                None => warn!(
+                    "Invalid finish reason action {:?} for model {}, skipping it.",
+                    entry, model
+                ),
+            }
+        }
+    }
+    actions
+}
+
+/// Chooses the action for a finish reason: the override of the model, if it has one, or the default.
+/// By default, tool calls are run and everything else ends the stream.
+fn stop_action_for(
+    actions: &HashMap<String, Vec<(FinishReason, StopAction)>>,
+    model: &str,
+    reason: FinishReason,
+) -> StopAction {
+    let overridden = actions.get(model).and_then(|overrides| {
+        overrides
+            .iter()
+            .find(|(overridden_reason, _)| *overridden_reason == reason)
+            .map(|(_, action)| *action)
+    });
+    overridden.unwrap_or(match reason {
+        FinishReason::ToolCalls => StopAction::RunTools,
+        _ => StopAction::End,
+    })
+}
+
+/// Providers differ in what they send after a finish reason, so what the stream does for one can be configured per model.
+pub fn model_stop_action(model: &AvailableChatbots, reason: FinishReason) -> StopAction {
+    stop_action_for(&FINISH_REASON_ACTIONS, &model.0, reason)
+}
+
+/// How the content of an assistant message that only consists of tool calls is sent to a provider.
+/// The OpenAI schema wants the content omitted, but some OpenAI-compatible servers reject an assistant message without content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToolCallContent {
+    /// No content at all, as in the OpenAI schema.
+    #[default]
+    Omit,
+    /// An empty string as content.
+    EmptyString,
+}
+
+/// How all models that don't use the OpenAI schema want their tool-call-only messages.
+static TOOL_CALL_CONTENT: Lazy<HashMap<String, ToolCallContent>> = Lazy::new(|| {
+    let styles = parse_tool_call_content(include_str!("../../litellm_config.yaml"));
+    debug!("Tool call content styles: {:?}", styles);
+    styles
+});
+
+/// Reads how the models want tool-call-only messages from the LiteLLM file.
+/// Models with their own `api_base` are self-hosted behind an OpenAI-compatible server (like Ollama), which want an empty string.
+/// This can be set explicitly in the model_info as `tool_call_content: "omit"` or `tool_call_content: "empty_string"`.
+fn parse_tool_call_content(file_content: &str) -> HashMap<String, ToolCallContent> {
+    let mut styles = HashMap::new();
+    let mut explicit = Vec::new(); // The models that set it themselves, so the api_base doesn't overwrite it.
+    for (model, key, value) in model_settings(file_content) {
+        match key {
+            "api_base" if !explicit.contains(&model) => {
+                styles.insert(model.to_string(), ToolCallContent::EmptyString);
+            }
+            "tool_call_content" => {
+                let style = match value {
+                    "omit" => ToolCallContent::Omit,
+                    "empty_string" => ToolCallContent::EmptyString,
+                    _ => {
+                        warn!(
+                            "Invalid tool_call_content {:?} for model {}, skipping it.",
+                            value, model
+                        );
+                        continue;
+                    }
+                };
+                styles.insert(model.to_string(), style);
+                explicit.push(model);
+            }
+            _ => {}
+        }
+    }
+    styles
+}
+
+/// Returns how the model wants the content of assistant messages that only consist of tool calls.
+pub fn model_tool_call_content(model: &AvailableChatbots) -> ToolCallContent {
+    TOOL_CALL_CONTENT.get(&model.0).copied().unwrap_or_default()
+}
+
+/// The rules of a provider for how the tool calls and their results have to be ordered in the messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageOrdering {
+    /// Every tool call has to be answered directly after the assistant message that made it, before any other message.
+    pub strict: bool,
+    /// How many tool calls a single assistant message may contain; None for no limit.
+    pub max_tool_calls: Option<usize>,
+}
+
+/// The ordering rules of all models that set them in the LiteLLM file.
+static MESSAGE_ORDERING: Lazy<HashMap<String, MessageOrdering>> = Lazy::new(|| {
+    let orderings = parse_message_ordering(include_str!("../../litellm_config.yaml"));
+    debug!("Message orderings: {:?}", orderings);
+    orderings
+});
+
+/// Reads the ordering rules of the models from the LiteLLM file.
+/// They're set in the model_info as `tool_call_ordering: "strict"` (or `"relaxed"`) and `max_tool_calls_per_message: 1`.
+fn parse_message_ordering(file_content: &str) -> HashMap<String, MessageOrdering> {
+    let mut orderings: HashMap<String, MessageOrdering> = HashMap::new();
+    for (model, key, value) in model_settings(file_content) {
+        match key {
+            "tool_call_ordering" => {
+                let strict = match value {
+                    "strict" => true,
+                    "relaxed" => false,
+                    _ => {
+                        warn!(
+                            "Invalid tool_call_ordering {:?} for model {}, skipping it.",
+                            value, model
+                        );
+                        continue;
+                    }
+                };
+                orderings.entry(model.to_string()).or_default().strict = strict;
+            }
+            "max_tool_calls_per_message" => match value.parse::<usize>() {
+                Ok(max) if max > 0 => {
+                    orderings
+                        .entry(model.to_string())
+                        .or_default()
+                        .max_tool_calls = Some(max);
+                }
+                _ => warn!(
+                    "Invalid max_tool_calls_per_message {:?} for model {}, skipping it.",
+                    value, model
+                ),
+            },
+            _ => {}
+        }
+    }
+    orderings
+}
+
+/// Returns the rules for ordering the tool calls of the model.
+/// Models that don't set them are strict if they're Claude models, whose API rejects any message between a tool call and its result.
+pub fn model_message_ordering(model: &AvailableChatbots) -> MessageOrdering {
+    MESSAGE_ORDERING
+        .get(&model.0)
+        .copied()
+        .unwrap_or_else(|| MessageOrdering {
+            strict: model_is_claude(model),
+            max_tool_calls: None,
+        })
+}
+
+/// The models LiteLLM forwards the requests for the available chatbots to, like "anthropic/claude-sonnet-4-5" for "claude-sonnet-4-5".
+static LITELLM_MODELS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    let models = parse_litellm_models(include_str!("../../litellm_config.yaml"));
+    debug!("LiteLLM models: {:?}", models);
+    models
+});
+
+/// Reads which model of which provider each chatbot is, from the `model` in the litellm_params of the LiteLLM file.
+fn parse_litellm_models(file_content: &str) -> HashMap<String, String> {
+    model_settings(file_content)
+        .filter(|(_, key, value)| *key == "model" && !value.is_empty())
+        .map(|(model, _, value)| (model.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Whether the chatbot is one of Anthropic's Claude models, which LiteLLM routes to Anthropic (or to another provider hosting them).
+fn is_claude(models: &HashMap<String, String>, model: &str) -> bool {
+    match models.get(model) {
+        Some(litellm_model) => {
+            litellm_model.starts_with("anthropic/") || litellm_model.contains("claude")
+        }
+        None => model.starts_with("claude"),
+    }
+}
+
 /// The default chatbot that will be used when the user doesn't specify one.
 /// It's always the first one in the list of available chatbots.
 pub static DEFAULTCHATBOT: Lazy<AvailableChatbots> = Lazy::new(|| {
@@ -118,7 +511,7 @@ pub fn model_supports_images(model: AvailableChatbots) -> bool {
         {
             true
         }
-        _ => false,
+        ref model => model_is_claude(model),
     }
 }
 
@@ -131,3 +524,243 @@ pub fn model_is_reasoning(model: AvailableChatbots) -> bool {
 pub fn model_is_gpt_5(model: AvailableChatbots) -> bool {
     model.0.starts_with("gpt-5")
 }
+
+/// The Claude models are proxied by LiteLLM, which translates the requests and the streamed tool calls to and from the OpenAI format.
+/// Anthropic doesn't accept all parameters of the OpenAI API, though, so the requests to them need to leave some out.
+pub fn model_is_claude(model: &AvailableChatbots) -> bool {
+    is_claude(&LITELLM_MODELS, &model.0)
+}
+
+/// Models served through Ollama emit their tool calls as text between two marker tokens.
+/// Returns the markers of the model, as configured in the LiteLLM file, or the default ones.
+pub fn model_tool_call_markers(model: &AvailableChatbots) -> ToolCallMarkers {
+    TOOL_CALL_MARKERS.get(&model.0).cloned().unwrap_or_default()
+}
+
+/// How many tokens of input fit into the model, as configured in the LiteLLM file as `max_input_tokens`, or as known for its family.
+pub fn model_context_window(model: &AvailableChatbots) -> u32 {
+    context_window_for(&MODEL_CONTEXT_WINDOWS, &model.0)
+}
+
+/// Providers differ in how many tokens they allow to be generated; asking for too many is an error for some of them.
+/// Returns the max_tokens to request from the model, as configured in the LiteLLM file, or the default.
+pub fn model_max_tokens(model: &AvailableChatbots) -> u32 {
+    max_tokens_for(&MODEL_MAX_TOKENS, &model.0, *MAX_TOKENS_OVERRIDE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claude_names_round_trip() {
+        let file_content = r#"
+model_list:
+  - model_name: "gpt-4.1"
+    litellm_params:
+      model: "openai/gpt-4.1"
+
+  - model_name: "sonnet"
+    litellm_params:
+      model: "anthropic/claude-sonnet-4-5"
+"#;
+        let models = parse_litellm_models(file_content);
+        assert_eq!(
+            models.get("sonnet").map(String::as_str),
+            Some("anthropic/claude-sonnet-4-5")
+        );
+        // The model of the provider decides, not the name the chatbot is offered under.
+        assert!(is_claude(&models, "sonnet"));
+        assert!(!is_claude(&models, "gpt-4.1"));
+
+        // The Claude models of the LiteLLM file can be chosen by their name and are sent to LiteLLM under it.
+        for name in ["claude-sonnet-4-5", "claude-haiku-4-5"] {
+            let chatbot: AvailableChatbots = name
+                .to_string()
+                .try_into()
+                .expect("The Claude model is available");
+            assert!(model_is_claude(&chatbot));
+            assert_eq!(String::from(chatbot.clone()), name);
+
+            // Anthropic doesn't know the frequency penalty, so it's left out of the request.
+            let params = crate::chatbot::request_params::RequestParams {
+                frequency_penalty: Some(1.5),
+                temperature: Some(1.8),
+                ..Default::default()
+            };
+            let request = crate::chatbot::stream_response::build_request(vec![], chatbot, params)
+                .expect("The request can be built");
+            assert_eq!(request.model, name);
+            assert_eq!(request.frequency_penalty, None);
+            assert_eq!(request.temperature, Some(1.0));
+        }
+        let gpt: AvailableChatbots = "gpt-4.1"
+            .to_string()
+            .try_into()
+            .expect("The GPT model is available");
+        assert!(!model_is_claude(&gpt));
+    }
+
+    #[test]
+    fn test_model_settings_belong_to_their_model() {
+        let file_content = r#"
+model_list:
+  - model_name: "qwen-local"
+    litellm_params:
+      model: "ollama/qwen"
+      api_base: "http://ollama:11434/v1"
+    model_info:
+      max_output_tokens: 8192
+"#;
+        // The model_list before the first model is skipped, and the colon in the URL stays in the value.
+        assert_eq!(
+            model_settings(file_content).collect::<Vec<_>>(),
+            vec![
+                ("qwen-local", "litellm_params", ""),
+                ("qwen-local", "model", "ollama/qwen"),
+                ("qwen-local", "api_base", "http://ollama:11434/v1"),
+                ("qwen-local", "model_info", ""),
+                ("qwen-local", "max_output_tokens", "8192"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_tool_call_markers() {
+        let file_content = r#"
+model_list:
+  - model_name: "gpt-4.1"
+    model_info:
+      supports_function_calling: true
+
+  - model_name: "mistral-local"
+    litellm_params:
+      model: "ollama/mistral"
+    model_info:
+      tool_call_start_marker: "[TOOL_CALLS]"
+      tool_call_end_marker: "[/TOOL_CALLS]"
+"#;
+        let markers = parse_tool_call_markers(file_content);
+
+        // The model without configuration isn't in the map and gets the default markers.
+        assert!(!markers.contains_key("gpt-4.1"));
+
+        let custom = markers
+            .get("mistral-local")
+            .expect("The custom markers should have been parsed");
+        assert_eq!(custom.classify("[TOOL_CALLS]"), Some(true));
+        assert_eq!(custom.classify("[/TOOL_CALLS]"), Some(false));
+        // The default markers are just text for this model.
+        assert_eq!(custom.classify("<tool_call>"), None);
+        assert_eq!(
+            ToolCallMarkers::default().classify("<tool_call>"),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_message_ordering_per_model() {
+        let file_content = r#"
+model_list:
+  - model_name: "gpt-4.1"
+    model_info:
+      supports_function_calling: true
+
+  - model_name: "strict-local"
+    litellm_params:
+      model: "openai/mistral"
+      api_base: "http://ollama:11434/v1"
+    model_info:
+      tool_call_ordering: "strict"
+      max_tool_calls_per_message: 1
+
+  - model_name: "broken"
+    model_info:
+      tool_call_ordering: "sometimes"
+      max_tool_calls_per_message: 0
+"#;
+        let orderings = parse_message_ordering(file_content);
+        assert!(!orderings.contains_key("gpt-4.1"));
+        assert!(!orderings.contains_key("broken"));
+        assert_eq!(
+            orderings.get("strict-local"),
+            Some(&MessageOrdering {
+                strict: true,
+                max_tool_calls: Some(1),
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_tokens_per_model() {
+        let file_content = r#"
+model_list:
+  - model_name: "gpt-4o"
+    model_info:
+      max_output_tokens: 16384
+
+  - model_name: "gpt-4.1"
+    model_info:
+      max_output_tokens: 32768
+
+  - model_name: "qwen-local"
+    model_info:
+      supports_function_calling: true
+"#;
+        let limits = parse_token_limits(file_content, "max_output_tokens");
+
+        assert_eq!(max_tokens_for(&limits, "gpt-4o", None), 16384);
+        assert_eq!(max_tokens_for(&limits, "gpt-4.1", None), 32768);
+        // Models without a limit get the default.
+        assert_eq!(
+            max_tokens_for(&limits, "qwen-local", None),
+            DEFAULT_MAX_TOKENS
+        );
+        // The override only ever lowers the limit.
+        assert_eq!(max_tokens_for(&limits, "gpt-4.1", Some(20000)), 20000);
+        assert_eq!(max_tokens_for(&limits, "gpt-4o", Some(20000)), 16384);
+
+        // The context windows are read the same way, under their own name.
+        let windows = parse_token_limits(
+            "  - model_name: \"qwen-local\"\n    model_info:\n      max_input_tokens: 32768\n      max_output_tokens: 8192\n",
+            "max_input_tokens",
+        );
+        assert_eq!(context_window_for(&windows, "qwen-local"), 32768);
+        assert_eq!(context_window_for(&windows, "gpt-4.1-mini"), 1_047_576);
+        assert_eq!(
+            context_window_for(&windows, "llama-local"),
+            DEFAULT_CONTEXT_WINDOW
+        );
+    }
+
+    #[test]
+    fn test_finish_reason_override_changes_action() {
+        let file = r#"
+  - model_name: "gpt-4.1"
+    model_info:
+      supports_function_calling: true
+
+  - model_name: "qwen3"
+    model_info:
+      finish_reason_actions: "stop=run_tools, length=continue, unknown=end"
+"#;
+        let actions = parse_finish_reason_actions(file);
+        assert_eq!(
+            stop_action_for(&actions, "qwen3", FinishReason::Stop),
+            StopAction::RunTools
+        );
+        assert_eq!(
+            stop_action_for(&actions, "qwen3", FinishReason::Length),
+            StopAction::Continue
+        );
+        // Everything that isn't overridden keeps the default behavior.
+        assert_eq!(
+            stop_action_for(&actions, "qwen3", FinishReason::ToolCalls),
+            StopAction::RunTools
+        );
+        assert_eq!(
+            stop_action_for(&actions, "gpt-4.1", FinishReason::Stop),
+            StopAction::End
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Handles the status messages that operators broadcast to all active streams.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::auth::get_first_matching_field;
+
+use super::types::StreamVariant;
+
+/// The key operators need to send to broadcast a status message.
+/// Set via the environment variable `ADMIN_KEY`; if it's not set, the broadcast endpoint is disabled.
+pub static ADMIN_KEY: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("ADMIN_KEY")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+});
+
+/// How many status messages a stream can fall behind before it misses the oldest ones.
+/// Streams check for them between two variants, so this only matters if many are sent at once.
+const STATUS_CHANNEL_CAPACITY: usize = 16;
+
+/// The channel all active streams subscribe to; every stream receives every message sent while it's running.
+static STATUS_CHANNEL: Lazy<broadcast::Sender<String>> =
+    Lazy::new(|| broadcast::channel(STATUS_CHANNEL_CAPACITY).0);
+
+/// Subscribes to the status messages; the receiver gets every message sent from now on.
+pub fn subscribe_to_status() -> broadcast::Receiver<String> {
+    STATUS_CHANNEL.subscribe()
+}
+
+/// Sends the status message to all active streams and returns how many received it.
+pub fn broadcast_status_message(message: &str) -> usize {
+    // Sending only fails if no stream is active, which just means nobody needs to be notified.
+    STATUS_CHANNEL.send(message.to_string()).unwrap_or(0)
+}
+
+/// The ServerHint a status message is sent to the client as.
+fn status_hint(message: &str) -> StreamVariant {
+    StreamVariant::ServerHint(serde_json::json!({ "status": message }).to_string())
+}
+
+/// Returns the next status message as a ServerHint, if one was sent since the last call.
+/// If the stream fell behind, the messages it missed are skipped.
+pub fn next_status_hint(receiver: &mut broadcast::Receiver<String>) -> Option<StreamVariant> {
+    loop {
+        match receiver.try_recv() {
+            Ok(message) => return Some(status_hint(&message)),
+            Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                warn!("A stream missed {skipped} status messages, skipping them.");
+            }
+            Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed) => {
+                return None
+            }
+        }
+    }
+}
+
+/// # Broadcast
+/// Sends a status message (like "Server restarting in 5 minutes") to all currently active streams. Requires the admin key.
+/// Only available if the backend was started with an `ADMIN_KEY`, as it's meant for the operators.
+///
+/// Takes in the `message` and the `admin_key` (also as the header `x-admin-key`).
+/// Every active stream receives the message as a ServerHint `{"status": message}` between two of its variants, so the frontend can show a banner.
+/// The message is not stored in the threads.
+///
+/// Returns the number of streams that received the message.
+///
+/// If the endpoint is disabled, a NotFound response is returned.
+///
+/// If the admin key is missing or wrong, an Unauthorized response is returned.
+///
+/// If the message is not given, an UnprocessableEntity response is returned.
+#[docs_const] // writes the docstring into a variable called BROADCAST_DOCS
+pub async fn broadcast(req: HttpRequest) -> impl Responder {
+    let Some(admin_key) = ADMIN_KEY.as_deref() else {
+        debug!("The broadcast endpoint was requested, but no admin key is set.");
+        return HttpResponse::NotFound().body("404 Method Not Found, try /help");
+    };
+
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    if get_first_matching_field(&qstring, headers, &["admin_key", "x-admin-key"], false)
+        != Some(admin_key)
+    {
+        warn!("Someone tried to broadcast a status message without the correct admin key.");
+        return HttpResponse::Unauthorized().body("Admin key is missing or incorrect.");
+    }
+
+    let message = match get_first_matching_field(&qstring, headers, &["message"], false) {
+        None | Some("") => {
+            warn!("A status message was requested to be broadcast, but no message was given.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Message not found. Please provide a message in the query parameters.");
+        }
+        Some(message) => message,
+    };
+
+    let receivers = broadcast_status_message(message);
+    info!("Broadcast the status message {message:?} to {receivers} active streams.");
+    HttpResponse::Ok().body(receivers.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_stream_receives_broadcast() {
+        let mut receiver = subscribe_to_status();
+        assert_eq!(next_status_hint(&mut receiver), None);
+
+        assert!(broadcast_status_message("Server restarting in 5 minutes") >= 1);
+        assert_eq!(
+            next_status_hint(&mut receiver),
+            Some(StreamVariant::ServerHint(
+                "{\"status\":\"Server restarting in 5 minutes\"}".to_string()
+            ))
+        );
+        // Each message is only delivered once.
+        assert_eq!(next_status_hint(&mut receiver), None);
+
+        // A stream that fell behind skips what it missed, but still gets the newer messages.
+        for i in 0..=STATUS_CHANNEL_CAPACITY {
+            broadcast_status_message(&format!("Message {i}"));
+        }
+        assert!(next_status_hint(&mut receiver).is_some());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_openai::error::{ApiError, OpenAIError};
+use once_cell::sync::Lazy;
+use tracing::{debug, info, warn};
+
+/// The state of a circuit breaker, as reported by the ping endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests go through normally.
+    Closed,
+    /// Too many requests failed; new ones are rejected immediately until the cool-down is over.
+    Open,
+    /// The cool-down is over; a single request is let through to probe whether the service recovered.
+    HalfOpen,
+}
+
+/// Counts consecutive failures of a service and stops sending requests to it for a while once there are too many.
+/// This way, during an outage, every request fails immediately instead of waiting for its own timeout.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cool_down,
+            consecutive_failures: 0,
+            opened_at: None,
+            probe_started_at: None,
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BreakerState {
+        match self.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if now.duration_since(opened_at) < self.cool_down => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Returns whether a request may be sent now.
+    /// When half-open, only one probe is let through; if it never reports back (for example because the client disconnected), another one is allowed after the cool-down.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        match self.state(now) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => match self.probe_started_at {
+                Some(started) if now.duration_since(started) < self.cool_down => false,
+                _ => {
+                    debug!("Circuit breaker is half-open, letting a probe through.");
+                    self.probe_started_at = Some(now);
+                    true
+                }
+            },
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if self.opened_at.is_some() {
+            info!("The probe succeeded, closing the circuit breaker.");
+        }
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+        self.probe_started_at = None;
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
+        self.probe_started_at = None;
+        // A failed probe opens the breaker again right away, otherwise the threshold decides.
+        if self.opened_at.is_some() || self.consecutive_failures >= self.failure_threshold {
+            warn!(
+                "{} consecutive failures, opening the circuit breaker for {:?}.",
+                self.consecutive_failures, self.cool_down
+            );
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+/// The circuit breaker around the LiteLLM proxy.
+/// Configured via the environment variables `LLM_BREAKER_THRESHOLD` (consecutive failures until it opens, default 5)
+/// and `LLM_BREAKER_COOLDOWN_SECS` (how long it stays open, default 30).
+pub static LLM_CIRCUIT_BREAKER: Lazy<Mutex<CircuitBreaker>> = Lazy::new(|| {
+    let threshold = std::env::var("LLM_BREAKER_THRESHOLD")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(5);
+    let cool_down = std::env::var("LLM_BREAKER_COOLDOWN_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(30);
+    Mutex::new(CircuitBreaker::new(
+        threshold,
+        Duration::from_secs(cool_down),
+    ))
+});
+
+/// The type of the error that is returned instead of a stream while the breaker is open.
+const BREAKER_OPEN_ERROR_TYPE: &str = "llm_temporarily_unavailable";
+
+/// Runs the function on the LLM circuit breaker; a poisoned lock is still usable, the breaker's state is always valid.
+pub fn with_llm_breaker<T>(f: impl FnOnce(&mut CircuitBreaker) -> T) -> T {
+    let mut breaker = LLM_CIRCUIT_BREAKER
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    f(&mut breaker)
+}
+
+/// The error for requests that are rejected because the breaker is open.
+pub fn llm_unavailable_error() -> OpenAIError {
+    OpenAIError::ApiError(ApiError {
+        message: "LLM temporarily unavailable".to_string(),
+        r#type: Some(BREAKER_OPEN_ERROR_TYPE.to_string()),
+        param: None,
+        code: None,
+    })
+}
+
+/// Whether the error was returned by the open breaker instead of LiteLLM.
+pub fn is_llm_unavailable_error(error: &OpenAIError) -> bool {
+    matches!(error, OpenAIError::ApiError(api_error) if api_error.r#type.as_deref() == Some(BREAKER_OPEN_ERROR_TYPE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_and_half_opens() {
+        let start = Instant::now();
+        let cool_down = Duration::from_secs(30);
+        let mut breaker = CircuitBreaker::new(3, cool_down);
+
+        for _ in 0..2 {
+            assert!(breaker.try_acquire(start));
+            breaker.record_failure(start);
+        }
+        assert_eq!(breaker.state(start), BreakerState::Closed);
+        breaker.record_failure(start);
+        assert_eq!(breaker.state(start), BreakerState::Open);
+        assert!(!breaker.try_acquire(start + Duration::from_secs(10)));
+
+        // After the cool-down, exactly one probe is let through.
+        let later = start + cool_down;
+        assert_eq!(breaker.state(later), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire(later));
+        assert!(!breaker.try_acquire(later));
+
+        // A failed probe opens it again, a successful one closes it.
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(later), BreakerState::Open);
+        let even_later = later + cool_down;
+        assert!(breaker.try_acquire(even_later));
+        breaker.record_success();
+        assert_eq!(breaker.state(even_later), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,215 @@
+// Answers a request in a single JSON response instead of a stream, for scripts and cron jobs that don't want to parse one.
+
+use actix_web::{http::StatusCode, web::Bytes, HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use serde::Serialize;
+use tracing::{debug, error, warn};
+
+use crate::{
+    api_error::error_response,
+    chatbot::{stream_framing::StreamFraming, stream_response::start_stream, types::StreamVariant},
+};
+
+/// The code the LLM ran and what it printed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct CodeExecution {
+    /// The ID of the tool call.
+    id: String,
+    /// The code as the LLM sent it, as JSON like `{"code": "..."}`.
+    code: String,
+    /// The complete output of the code; None if it didn't finish, for example because it couldn't be started.
+    output: Option<String>,
+}
+
+/// The whole turn, as it's returned by the complete endpoint.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+struct Completion {
+    thread_id: Option<String>,
+    /// The text of the Assistant; if it wrote several messages (with code in between), they're separated by an empty line.
+    message: String,
+    code_outputs: Vec<CodeExecution>,
+    images: Vec<String>,
+    errors: Vec<String>,
+    usage: Option<serde_json::Value>,
+    end_reason: Option<String>,
+}
+
+impl Completion {
+    /// Collects the variants of a stream into a single answer.
+    fn from_variants(variants: impl IntoIterator<Item = StreamVariant>) -> Self {
+        let mut completion = Completion::default();
+        let mut executions: Vec<CodeExecution> = vec![];
+        // Whether the last shown variant was a delta of the Assistant, so the next delta continues its message.
+        let mut in_message = false;
+        for variant in variants {
+            match variant {
+                StreamVariant::Assistant(delta) => {
+                    if !in_message && !completion.message.is_empty() {
+                        completion.message.push_str("\n\n");
+                    }
+                    completion.message.push_str(&delta);
+                    in_message = true;
+                    continue;
+                }
+                // Hints can arrive in the middle of a message.
+                StreamVariant::ServerHint(hint) => {
+                    if let Some(thread_id) = serde_json::from_str::<serde_json::Value>(&hint)
+                        .ok()
+                        .and_then(|hint| hint.get("thread_id")?.as_str().map(str::to_string))
+                    {
+                        completion.thread_id = Some(thread_id);
+                    }
+                    continue;
+                }
+                StreamVariant::Code(delta, id) => {
+                    match executions.iter_mut().find(|execution| execution.id == id) {
+                        Some(execution) => execution.code.push_str(&delta),
+                        None => executions.push(CodeExecution {
+                            id,
+                            code: delta,
+                            output: None,
+                        }),
+                    }
+                }
+                // The lines that are streamed while the code runs are replaced by the complete output.
+                StreamVariant::CodeOutput(output, id) => {
+                    match executions.iter_mut().find(|execution| execution.id == id) {
+                        Some(execution) => execution.output = Some(output),
+                        None => executions.push(CodeExecution {
+                            id,
+                            code: String::new(),
+                            output: Some(output),
+                        }),
+                    }
+                }
+                StreamVariant::Image(image) => completion.images.push(image),
+                StreamVariant::ServerError(e)
+                | StreamVariant::OpenAIError(e)
+                | StreamVariant::CodeError(e) => completion.errors.push(e),
+                StreamVariant::Usage(usage) => {
+                    completion.usage = serde_json::from_str(&usage).ok();
+                }
+                StreamVariant::StreamEnd(reason) => completion.end_reason = Some(reason),
+                StreamVariant::Prompt(_) | StreamVariant::User(_) | StreamVariant::Summary(_) => {}
+            }
+            in_message = false;
+        }
+        completion.code_outputs = executions;
+        completion
+    }
+
+    /// Reads the stream from its JSON-lines body; empty lines are the keep-alive bytes of the stream.
+    fn from_body(body: &Bytes) -> Self {
+        let variants = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .filter_map(|line| match serde_json::from_slice::<StreamVariant>(line) {
+                Ok(variant) => Some(variant),
+                Err(e) => {
+                    warn!(
+                        "Skipping a line of the stream that isn't a variant: {:?} ({})",
+                        String::from_utf8_lossy(line),
+                        e
+                    );
+                    None
+                }
+            });
+        Self::from_variants(variants)
+    }
+}
+
+/// # Complete
+/// Answers an input in a single JSON response instead of a stream, for scripts and cron jobs. Requires Authentication.
+///
+/// Takes the same parameters as the streamresponse endpoint, except for the framing and delta_offsets.
+/// The response is only sent once the turn is done, including all code the LLM ran, so it can take a while.
+///
+/// Returns a JSON object with the `thread_id`, the `message` of the Assistant (several messages are separated by an empty line),
+/// the `code_outputs` as a list of `{"id", "code", "output"}`, the `images` as a list, the `errors` of the turn as a list,
+/// the `usage` as in the Usage variant (null if the provider didn't report it) and the `end_reason` of the StreamEnd.
+/// An example would be `{"thread_id": "1234", "message": "The mean is 290.5 K.", "code_outputs": [{"id": "call_1", "code": "{\"code\":\"ds.tas.mean()\"}", "output": "290.5"}], "images": [], "errors": [], "usage": null, "end_reason": "Generation complete"}`.
+///
+/// The errors are the same as the ones of the streamresponse endpoint, with the same codes, including the Conflict response if the thread is already being streamed.
+#[docs_const] // writes the docstring into a variable called COMPLETE_DOCS
+pub async fn complete(req: HttpRequest) -> impl Responder {
+    // JSON-lines can be split reliably, and the offsets of the deltas aren't needed to join them.
+    let response = start_stream(&req, StreamFraming::Jsonl, false).await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    // Reading the body runs the stream to its end, so the conversation is saved as usual.
+    let body = match actix_web::body::to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error reading the stream for a completion: {:?}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "The answer could not be generated.",
+            );
+        }
+    };
+    let completion = Completion::from_body(&body);
+    debug!(
+        "Completed thread {:?} with {} code executions and {} images.",
+        completion.thread_id,
+        completion.code_outputs.len(),
+        completion.images.len()
+    );
+    HttpResponse::Ok().json(completion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::{
+        stream_response::variant_to_bytes, transport_keep_alive::KEEP_ALIVE_BYTES,
+    };
+
+    #[test]
+    fn test_code_turn_yields_text_and_code_output() {
+        let variants = vec![
+            StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string()),
+            StreamVariant::Assistant("Let me ".to_string()),
+            StreamVariant::Assistant("compute that.".to_string()),
+            StreamVariant::Code("{\"code\":\"print(".to_string(), "call_1".to_string()),
+            StreamVariant::Code("1 + 1)\"}".to_string(), "call_1".to_string()),
+            StreamVariant::ServerHint("{\"heartbeat\": true}".to_string()),
+            // A line that was streamed while the code ran, then the complete output.
+            StreamVariant::CodeOutput("2".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput("2\n".to_string(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::Assistant("The result is 2.".to_string()),
+            StreamVariant::Usage("{\"total_tokens\":120,\"model\":\"gpt-4.1\"}".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        let mut body = variants
+            .iter()
+            .map(|variant| variant_to_bytes(variant, StreamFraming::Jsonl).to_vec())
+            .collect::<Vec<_>>();
+        body.insert(4, KEEP_ALIVE_BYTES.to_vec());
+
+        let completion = Completion::from_body(&Bytes::from(body.concat()));
+        assert_eq!(
+            completion,
+            Completion {
+                thread_id: Some("abc".to_string()),
+                message: "Let me compute that.\n\nThe result is 2.".to_string(),
+                code_outputs: vec![CodeExecution {
+                    id: "call_1".to_string(),
+                    code: "{\"code\":\"print(1 + 1)\"}".to_string(),
+                    output: Some("2\n".to_string()),
+                }],
+                images: vec!["aW1hZ2U=".to_string()],
+                errors: vec![],
+                usage: Some(serde_json::json!({"total_tokens": 120, "model": "gpt-4.1"})),
+                end_reason: Some("Generation complete".to_string()),
+            }
+        );
+
+        let json = serde_json::to_value(&completion).expect("The completion is JSON");
+        assert_eq!(json["code_outputs"][0]["output"], "2\n");
+        assert_eq!(json["message"], "Let me compute that.\n\nThe result is 2.");
+    }
+}
@@ -0,0 +1,301 @@
+// Retries a request with a shorter history if it doesn't fit into the context window of the model.
+
+use std::future::Future;
+
+use async_openai::{
+    error::{ApiError, OpenAIError},
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionResponseStream, CreateChatCompletionRequest,
+    },
+};
+use futures::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use tracing::{debug, info, warn};
+
+use super::types::StreamVariant;
+
+/// Whether a request whose history is too long for the context window is retried once with the older turns dropped.
+/// If it's disabled, the stream ends with the error of the provider, like before.
+/// Set via the environment variable `TRIM_HISTORY_ON_CONTEXT_OVERFLOW`; defaults to true.
+pub static TRIM_HISTORY_ON_CONTEXT_OVERFLOW: Lazy<bool> = Lazy::new(|| {
+    std::env::var("TRIM_HISTORY_ON_CONTEXT_OVERFLOW").map_or(true, |value| value.trim() != "false")
+});
+
+/// The type of the error that is put into the stream to tell that the history was trimmed; it never comes from the provider.
+const HISTORY_TRIMMED_ERROR_TYPE: &str = "history_trimmed";
+
+/// Whether the error says that the request didn't fit into the context window.
+/// Only the provider's explicit code or message counts: other bad requests (like an invalid parameter) are rejected with a 400 too,
+/// and trimming the history wouldn't fix them.
+pub fn is_context_length_error(error: &OpenAIError) -> bool {
+    let mentions_context = |message: &str| {
+        let message = message.to_lowercase();
+        [
+            "context_length_exceeded",
+            "contextwindowexceeded",
+            "maximum context length",
+            "context window",
+            "prompt is too long",
+        ]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+    };
+    match error {
+        OpenAIError::ApiError(api_error) => {
+            api_error.code.as_deref() == Some("context_length_exceeded")
+                || mentions_context(&api_error.message)
+        }
+        OpenAIError::StreamError(message) => mentions_context(message),
+        _ => false,
+    }
+}
+
+/// The item at the start of a retried stream, which tells the stream to send the client a hint that the history was trimmed.
+/// Like the error of the circuit breaker, it's an error so it can be sent through the stream of LiteLLM.
+fn history_trimmed_notice(dropped: usize) -> OpenAIError {
+    OpenAIError::ApiError(ApiError {
+        message: format!("{dropped} older messages were left out"),
+        r#type: Some(HISTORY_TRIMMED_ERROR_TYPE.to_string()),
+        param: None,
+        code: None,
+    })
+}
+
+/// If the error is the notice that the history was trimmed, returns the hint for the client.
+pub fn history_trimmed_hint(error: &OpenAIError) -> Option<StreamVariant> {
+    match error {
+        OpenAIError::ApiError(api_error)
+            if api_error.r#type.as_deref() == Some(HISTORY_TRIMMED_ERROR_TYPE) =>
+        {
+            Some(StreamVariant::ServerHint(
+                serde_json::json!({
+                    "warning": format!("The conversation was too long for the model, so the oldest part of it was left out: {}.", api_error.message)
+                })
+                .to_string(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Drops the older half of the turns (a turn starting with a message of the user) from the messages.
+/// The system prompt at the start and the current turn are always kept.
+/// Returns None if there's nothing that can be dropped.
+fn trim_history(
+    messages: &[ChatCompletionRequestMessage],
+) -> Option<Vec<ChatCompletionRequestMessage>> {
+    let prompt_end = messages
+        .iter()
+        .position(|message| {
+            !matches!(
+                message,
+                ChatCompletionRequestMessage::System(_)
+                    | ChatCompletionRequestMessage::Developer(_)
+            )
+        })
+        .unwrap_or(messages.len());
+    let turn_starts = messages
+        .iter()
+        .enumerate()
+        .skip(prompt_end)
+        .filter(|(_, message)| matches!(message, ChatCompletionRequestMessage::User(_)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    // Only whole turns are dropped, so the results of tool calls stay with their calls.
+    let keep_from = *turn_starts.get(turn_starts.len() / 2)?;
+    if keep_from == prompt_end {
+        return None;
+    }
+    Some([&messages[..prompt_end], &messages[keep_from..]].concat())
+}
+
+/// Opens the stream for the request. If the history is too long for the context window,
+/// the older half of it is dropped and the request is sent once more; the retried stream then starts with the notice for the hint.
+/// Only if the retry fails as well, its error is passed on.
+pub async fn create_stream_with_context_retry<F, Fut>(
+    request: CreateChatCompletionRequest,
+    create_stream: F,
+) -> Result<ChatCompletionResponseStream, OpenAIError>
+where
+    F: Fn(CreateChatCompletionRequest) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<ChatCompletionResponseStream, OpenAIError>> + Send,
+{
+    if !*TRIM_HISTORY_ON_CONTEXT_OVERFLOW {
+        return create_stream(request).await;
+    }
+    let mut stream = match create_stream(request.clone()).await {
+        Err(e) if is_context_length_error(&e) => {
+            return retry_trimmed(request, e, create_stream).await;
+        }
+        result => result?,
+    };
+
+    // Usually, the error only arrives as the first item of the stream, when the request is actually sent.
+    // The stream is given on lazily, so the client doesn't wait for the first token before the response starts.
+    let retried = stream::once(async move {
+        match stream.next().await {
+            Some(Err(e)) if is_context_length_error(&e) => {
+                match retry_trimmed(request, e, create_stream).await {
+                    Ok(retried) => retried,
+                    Err(e) => Box::pin(stream::iter([Err(e)])),
+                }
+            }
+            first => Box::pin(stream::iter(first).chain(stream)) as ChatCompletionResponseStream,
+        }
+    })
+    .flatten();
+    Ok(Box::pin(retried))
+}
+
+/// Sends the request once more, with the older half of the history dropped.
+async fn retry_trimmed<F, Fut>(
+    request: CreateChatCompletionRequest,
+    error: OpenAIError,
+    create_stream: F,
+) -> Result<ChatCompletionResponseStream, OpenAIError>
+where
+    F: Fn(CreateChatCompletionRequest) -> Fut,
+    Fut: Future<Output = Result<ChatCompletionResponseStream, OpenAIError>>,
+{
+    let Some(messages) = trim_history(&request.messages) else {
+        warn!("The request is too long for the context window, but there's no older history to drop: {:?}", error);
+        return Err(error);
+    };
+    let dropped = request.messages.len() - messages.len();
+    info!(
+        "The request is too long for the context window, retrying without the {} oldest messages.",
+        dropped
+    );
+    debug!("The error was: {:?}", error);
+    let retried = create_stream(CreateChatCompletionRequest {
+        messages,
+        ..request
+    })
+    .await?;
+    Ok(Box::pin(
+        stream::iter([Err(history_trimmed_notice(dropped))]).chain(retried),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessage, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestUserMessage, CreateChatCompletionStreamResponse,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_only_explicit_context_errors_count() {
+        let api_error = |code: Option<&str>, message: &str| {
+            OpenAIError::ApiError(ApiError {
+                message: message.to_string(),
+                r#type: Some("invalid_request_error".to_string()),
+                param: None,
+                code: code.map(str::to_string),
+            })
+        };
+        assert!(is_context_length_error(&api_error(
+            Some("context_length_exceeded"),
+            "Too long"
+        )));
+        assert!(is_context_length_error(&api_error(
+            None,
+            "prompt is too long: 210000 tokens > 200000 maximum"
+        )));
+        assert!(is_context_length_error(&OpenAIError::StreamError(
+            "Invalid status code: 400 Bad Request, ContextWindowExceededError".to_string()
+        )));
+
+        // Other bad requests are passed on instead of trimming the history.
+        assert!(!is_context_length_error(&api_error(
+            None,
+            "temperature must be between 0 and 2"
+        )));
+        assert!(!is_context_length_error(&OpenAIError::StreamError(
+            "Invalid status code: 400 Bad Request".to_string()
+        )));
+    }
+
+    #[actix_web::test]
+    async fn test_context_overflow_is_retried_once_with_trimmed_history() {
+        let mut messages = vec![ChatCompletionRequestSystemMessage::from("prompt").into()];
+        for turn in 0..4 {
+            messages
+                .push(ChatCompletionRequestUserMessage::from(format!("question {turn}")).into());
+            messages
+                .push(ChatCompletionRequestAssistantMessage::from(format!("answer {turn}")).into());
+        }
+        messages.push(ChatCompletionRequestUserMessage::from("current question").into());
+        let request = CreateChatCompletionRequest {
+            messages,
+            ..Default::default()
+        };
+
+        // Like LiteLLM, the fake provider only fails once the stream is polled. It only accepts up to 6 messages.
+        let sent_lengths = Arc::new(Mutex::new(Vec::new()));
+        let create_stream = |sent_lengths: Arc<Mutex<Vec<usize>>>, limit: usize| {
+            move |request: CreateChatCompletionRequest| {
+                let sent_lengths = Arc::clone(&sent_lengths);
+                async move {
+                    let length = request.messages.len();
+                    sent_lengths
+                        .lock()
+                        .expect("The mutex isn't poisoned")
+                        .push(length);
+                    let item = if length > limit {
+                        Err(OpenAIError::ApiError(ApiError {
+                            message: "This model's maximum context length is 6 messages."
+                                .to_string(),
+                            r#type: Some("invalid_request_error".to_string()),
+                            param: None,
+                            code: Some("context_length_exceeded".to_string()),
+                        }))
+                    } else {
+                        Ok(serde_json::from_value::<CreateChatCompletionStreamResponse>(
+                            serde_json::json!({"id": "1", "choices": [], "created": 0, "model": "test", "object": "chat.completion.chunk"}),
+                        )
+                        .expect("The chunk is valid"))
+                    };
+                    Ok(Box::pin(stream::iter([item])) as ChatCompletionResponseStream)
+                }
+            }
+        };
+
+        let items = create_stream_with_context_retry(
+            request.clone(),
+            create_stream(Arc::clone(&sent_lengths), 6),
+        )
+        .await
+        .expect("The stream can be created")
+        .collect::<Vec<_>>()
+        .await;
+        // The system prompt, the last two turns and the current question are left.
+        assert_eq!(
+            *sent_lengths.lock().expect("The mutex isn't poisoned"),
+            [10, 6]
+        );
+        assert!(
+            matches!(&items[..], [Err(notice), Ok(_)] if history_trimmed_hint(notice).is_some())
+        );
+
+        // If the trimmed request is still too long, there's no second retry and the error is passed on.
+        let sent_lengths = Arc::new(Mutex::new(Vec::new()));
+        let items =
+            create_stream_with_context_retry(request, create_stream(Arc::clone(&sent_lengths), 2))
+                .await
+                .expect("The stream can be created")
+                .collect::<Vec<_>>()
+                .await;
+        assert_eq!(
+            *sent_lengths.lock().expect("The mutex isn't poisoned"),
+            [10, 6]
+        );
+        assert!(
+            matches!(&items[..], [Err(notice), Err(e)] if history_trimmed_hint(notice).is_some() && is_context_length_error(e))
+        );
+    }
+}
@@ -0,0 +1,120 @@
+// Deletes a thread with everything that is stored for it.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field},
+    chatbot::{
+        handle_active_conversations::conversation_state,
+        storage_router::{delete_thread as delete_stored_thread, read_thread_and_owner},
+    },
+    logging::{silence_logger, undo_silence_logger},
+};
+
+/// # Delete Thread
+/// Deletes a thread of the user for good: the stored thread, its raw copy and shares, and the session state of the code interpreter. Requires Authentication.
+///
+/// Takes in the `thread_id` of the thread to delete.
+/// Returns a JSON object with the number of stored artifacts that were `removed`.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the vault URL is not given, an UnprocessableEntity response is returned.
+///
+/// If the thread id can't be a valid one, a BadRequest response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user or its owner isn't known (old threads on disk), a Forbidden response is returned.
+///
+/// If the thread is currently being streamed, a Conflict response is returned.
+#[docs_const] // writes the docstring into a variable called DELETE_THREAD_DOCS
+pub async fn delete_thread(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested to delete a thread without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    // A thread that is still streaming would be stored again at its end; conversation_state warns if the thread isn't active, which is the usual case.
+    silence_logger();
+    let state = conversation_state(thread_id).await;
+    undo_silence_logger();
+    if state.is_some() {
+        warn!(
+            "The User requested to delete thread {}, which is currently being streamed.",
+            thread_id
+        );
+        return HttpResponse::Conflict().body(format!(
+            "Thread {thread_id} is being streamed. Please stop it before deleting it."
+        ));
+    }
+
+    match read_thread_and_owner(thread_id, database.clone()).await {
+        // Old threads on disk don't record their owner, so nobody can prove they may delete them.
+        Ok((_, owner)) if owner.as_deref() != Some(user_id.as_str()) => {
+            warn!(
+                "User {} tried to delete thread {}, which isn't known to be theirs.",
+                user_id, thread_id
+            );
+            return HttpResponse::Forbidden().body("This thread belongs to another user.");
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested to delete thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    }
+
+    match delete_stored_thread(thread_id, database).await {
+        Ok(0) => {
+            info!("Thread {} was already deleted.", thread_id);
+            HttpResponse::NotFound().body("Thread not found.")
+        }
+        Ok(removed) => {
+            info!(
+                "User {} deleted thread {} ({} artifacts).",
+                user_id, thread_id, removed
+            );
+            HttpResponse::Ok().json(serde_json::json!({ "removed": removed }))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            warn!("The User requested to delete thread {:?}: {}", thread_id, e);
+            HttpResponse::BadRequest().body("Invalid thread ID.")
+        }
+        Err(e) => {
+            error!("Error deleting thread {}: {:?}", thread_id, e);
+            HttpResponse::InternalServerError().body("Error deleting thread.")
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Optionally tells the client where each Assistant delta starts in the message it belongs to.
+// Frontends that render the markdown incrementally can then place every delta by its position instead of appending it blindly,
+// which stays correct if deltas are ever coalesced or sent again.
+
+use std::sync::Mutex;
+
+use actix_web::{http::header::HeaderMap, web::Bytes};
+use qstring::QString;
+use tracing::warn;
+
+use crate::auth::get_first_matching_field;
+
+use super::{
+    stream_framing::StreamFraming, stream_response::variant_to_bytes, types::StreamVariant,
+};
+
+/// Whether the client asked for the offsets of the Assistant deltas, with the parameter `delta_offsets=true`.
+pub fn offsets_requested(qstring: &QString, headers: &HeaderMap) -> bool {
+    get_first_matching_field(
+        qstring,
+        headers,
+        &["delta_offsets", "x-delta-offsets"],
+        false,
+    )
+    .is_some_and(|value| value.trim() == "true")
+}
+
+/// Tracks how long the current Assistant message of a stream is, in characters (Unicode scalar values).
+#[derive(Debug, Default)]
+pub struct DeltaOffsets {
+    message_length: usize,
+}
+
+impl DeltaOffsets {
+    /// Returns the offset of an Assistant delta in its message and advances past it.
+    /// Any other variant ends the message, except for ServerHints, which aren't shown and can arrive in the middle of one.
+    pub fn advance(&mut self, variant: &StreamVariant) -> Option<usize> {
+        match variant {
+            StreamVariant::Assistant(content) => {
+                let offset = self.message_length;
+                self.message_length += content.chars().count();
+                Some(offset)
+            }
+            StreamVariant::ServerHint(_) => None,
+            _ => {
+                self.message_length = 0;
+                None
+            }
+        }
+    }
+
+    /// Serializes the variant like `variant_to_bytes`, but an Assistant delta also carries its offset,
+    /// as in `{"variant": "Assistant", "content": "world", "offset": 6}`.
+    pub fn variant_to_bytes(&mut self, variant: &StreamVariant, framing: StreamFraming) -> Bytes {
+        let Some(offset) = self.advance(variant) else {
+            return variant_to_bytes(variant, framing);
+        };
+        match serde_json::to_value(variant) {
+            Ok(mut json) => {
+                json["offset"] = offset.into();
+                framing.frame(Bytes::from(json.to_string()))
+            }
+            Err(e) => {
+                warn!(
+                    "Error adding the offset to {:?}, sending it without: {:?}",
+                    variant, e
+                );
+                variant_to_bytes(variant, framing)
+            }
+        }
+    }
+}
+
+/// Serializes a variant of a stream, with the offsets if the stream has them.
+/// The offsets are shared between the calls of the stream, so they're behind a mutex; if it's poisoned, the variant is sent without one.
+pub fn variant_to_bytes_with_offsets(
+    offsets: &Mutex<Option<DeltaOffsets>>,
+    variant: &StreamVariant,
+    framing: StreamFraming,
+) -> Bytes {
+    match offsets.lock().as_deref_mut() {
+        Ok(Some(offsets)) => offsets.variant_to_bytes(variant, framing),
+        Ok(None) | Err(_) => variant_to_bytes(variant, framing),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_are_contiguous_across_deltas() {
+        let framing = StreamFraming::Jsonl;
+        let offsets = Mutex::new(Some(DeltaOffsets::default()));
+        let variants = [
+            StreamVariant::User("plot it".to_string()),
+            StreamVariant::Assistant("Sure, ".to_string()),
+            StreamVariant::Assistant(String::new()),
+            // Characters of several bytes count once.
+            StreamVariant::Assistant("25 °C ".to_string()),
+            StreamVariant::ServerHint("{\"heartbeat\": true}".to_string()),
+            StreamVariant::Assistant("it is.".to_string()),
+            StreamVariant::Code("plt.plot(t)".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput(String::new(), "call_1".to_string()),
+            // A new message starts at 0 again.
+            StreamVariant::Assistant("Done".to_string()),
+        ];
+        let sent = variants
+            .iter()
+            .map(|variant| {
+                let bytes = variant_to_bytes_with_offsets(&offsets, variant, framing);
+                serde_json::from_slice::<serde_json::Value>(&bytes).expect("Each line is JSON")
+            })
+            .collect::<Vec<_>>();
+
+        let assistant_offsets = sent
+            .iter()
+            .filter(|json| json["variant"] == "Assistant")
+            .map(|json| json["offset"].as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            assistant_offsets,
+            vec![Some(0), Some(6), Some(6), Some(12), Some(0)]
+        );
+        // Applying the deltas at their offsets gives the message.
+        let mut message = Vec::<char>::new();
+        for json in sent
+            .iter()
+            .take(6)
+            .filter(|json| json["variant"] == "Assistant")
+        {
+            let offset = json["offset"].as_u64().expect("Deltas have an offset") as usize;
+            assert_eq!(offset, message.len());
+            message.splice(
+                offset..,
+                json["content"].as_str().unwrap_or_default().chars(),
+            );
+        }
+        assert_eq!(message.iter().collect::<String>(), "Sure, 25 °C it is.");
+        // The other variants are unchanged.
+        assert!(sent
+            .iter()
+            .filter(|json| json["variant"] != "Assistant")
+            .all(|json| json.get("offset").is_none()));
+
+        // Without the option, the deltas are sent as before.
+        let bytes = variant_to_bytes_with_offsets(&Mutex::new(None), &variants[1], framing);
+        assert_eq!(bytes, variant_to_bytes(&variants[1], framing));
+    }
+}
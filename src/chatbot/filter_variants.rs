@@ -76,14 +76,16 @@ pub fn filter_variants(
             "OpenAIError",
             "CodeError",
             "StreamEnd",
+            "Summary",
+            "Usage",
         ],
     ) {
         Some(matched_variants) => {
-            trace!("Matched variants while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, and StreamEnd: {:?}", matched_variants);
+            trace!("Matched variants while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, StreamEnd, Summary, and Usage: {:?}", matched_variants);
             return Ok(matched_variants.to_vec());
         }
         None => {
-            trace!("No matching variants found while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, and StreamEnd.");
+            trace!("No matching variants found while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, StreamEnd, Summary, and Usage.");
         }
     }
     warn!("No matching variants found starting at the beginning, trying to match from anywhere.");
@@ -131,14 +133,16 @@ pub fn filter_variants(
             "OpenAIError",
             "CodeError",
             "StreamEnd",
+            "Summary",
+            "Usage",
         ],
     ) {
         Some(matched_variants) => {
-            trace!("Matched variants from anywhere while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, and StreamEnd: {:?}", matched_variants);
+            trace!("Matched variants from anywhere while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, StreamEnd, Summary, and Usage: {:?}", matched_variants);
             return Ok(matched_variants.to_vec());
         }
         None => {
-            trace!("No matching variants found from anywhere while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, and StreamEnd.");
+            trace!("No matching variants found from anywhere while ignoring Prompt, ServerHint, ServerError, OpenAIError, CodeError, StreamEnd, Summary, and Usage.");
         }
     }
     // If we reach here, we couldn't match any variants.
@@ -0,0 +1,189 @@
+// Serves the images of a thread as raw PNGs, so clients don't have to decode them from base64.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use base64::Engine;
+use documented::docs_const;
+use mongodb::Database;
+use qstring::QString;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::database_from_request,
+    chatbot::{
+        handle_active_conversations::get_active_turn, storage_router::read_thread_and_owner,
+        types::StreamVariant,
+    },
+};
+
+/// # Get Image
+/// Returns a single image of a thread as raw PNG bytes with the content type `image/png`. Requires Authentication.
+///
+/// The path is `/api/chatbot/image/{thread_id}/{image_index}`, where the index counts only the images of the thread, starting at 0.
+/// Streams that were started with `images=ref` send these paths as the content of their Image variants instead of the base64 encoded PNG.
+/// Images of a thread that is still being streamed can already be fetched.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the image index is not a number or the vault URL is not given, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist or has no image with that index, a NotFound response is returned.
+///
+/// If the thread belongs to another user or its owner isn't known (old threads on disk), a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called GET_IMAGE_DOCS
+pub async fn get_image(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = req.match_info().get("thread_id").unwrap_or_default();
+    let Some(index) = req
+        .match_info()
+        .get("image_index")
+        .and_then(|index| index.parse::<usize>().ok())
+    else {
+        warn!("The User requested an image without a valid index.");
+        return HttpResponse::UnprocessableEntity()
+            .body("Image index is not a number. Please provide a non-negative index in the path.");
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    let (images, owner) = match thread_images(thread_id, database).await {
+        Ok(result) => result,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested an image from thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    };
+
+    // Old threads on disk don't record their owner, so nobody can prove they may read them.
+    if owner.as_deref() != Some(user_id.as_str()) {
+        warn!(
+            "User {} requested an image from thread {}, which isn't known to be theirs.",
+            user_id, thread_id
+        );
+        return HttpResponse::Forbidden().body("This thread belongs to another user.");
+    }
+
+    let Some(image) = images.get(index) else {
+        debug!(
+            "The User requested image {} of thread {}, which only has {} images.",
+            index,
+            thread_id,
+            images.len()
+        );
+        return HttpResponse::NotFound().body("Image index out of range for this thread.");
+    };
+
+    match decode_image(image) {
+        Some(png) => HttpResponse::Ok().content_type("image/png").body(png),
+        None => {
+            error!("Image {} of thread {} can't be decoded.", index, thread_id);
+            HttpResponse::InternalServerError().body("Error decoding the image.")
+        }
+    }
+}
+
+/// Reads the images of a thread in order, together with its owner.
+/// A thread that is being streamed also has the images of its current turn, which are only stored when it ends.
+pub(crate) async fn thread_images(
+    thread_id: &str,
+    database: Database,
+) -> Result<(Vec<String>, Option<String>), std::io::Error> {
+    let active = get_active_turn(thread_id);
+    let (stored, owner) = match read_thread_and_owner(thread_id, database).await {
+        Ok(result) => result,
+        // A new thread isn't stored before its first turn ends.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && active.is_some() => (vec![], None),
+        Err(e) => return Err(e),
+    };
+    let owner = owner.or_else(|| active.as_ref().map(|(_, user_id, _)| user_id.clone()));
+    let active = active.map(|(conversation, _, keep)| (conversation, keep));
+    Ok((collect_images(stored, active), owner))
+}
+
+/// Collects the images of the stored thread, followed by those of the active turn.
+/// A regenerated turn replaces everything of the stored thread after its first `keep` variants, so those images don't count.
+fn collect_images(
+    mut stored: Vec<StreamVariant>,
+    active: Option<(Vec<StreamVariant>, Option<usize>)>,
+) -> Vec<String> {
+    let active = match active {
+        Some((conversation, keep)) => {
+            if let Some(keep) = keep {
+                stored.truncate(keep);
+            }
+            conversation
+        }
+        None => vec![],
+    };
+    stored
+        .into_iter()
+        .chain(active)
+        .filter_map(|variant| match variant {
+            StreamVariant::Image(image) => Some(image),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Decodes a stored image, which is always a base64 encoded PNG.
+fn decode_image(image: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(image.trim())
+        .inspect_err(|e| warn!("Could not decode the image: {:?}", e))
+        .ok()
+}
+
+/// The path under which an image of a thread can be fetched, see the get_image endpoint.
+pub fn image_path(thread_id: &str, index: usize) -> String {
+    format!("/api/chatbot/image/{thread_id}/{index}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_image_is_fetched_as_png_by_index() {
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([31, 119, 180]))
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .expect("The image can be encoded as PNG");
+        let encoded_png = base64::engine::general_purpose::STANDARD.encode(&png);
+
+        let stored = vec![
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::User("and a square".to_string()),
+            StreamVariant::Image("b2xkIHNxdWFyZQ==".to_string()),
+        ];
+        // The turn with the square is regenerated, so its old image is replaced by the new one.
+        let active = vec![StreamVariant::Image(encoded_png)];
+        let images = collect_images(stored.clone(), Some((active, Some(4))));
+        assert_eq!(images.len(), 2);
+
+        let fetched = decode_image(&images[1]).expect("The image can be decoded");
+        assert!(fetched.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(fetched, png);
+
+        // Without an active turn, only the stored images count.
+        assert_eq!(collect_images(stored, None).len(), 2);
+    }
+}
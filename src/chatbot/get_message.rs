@@ -0,0 +1,159 @@
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    auth::{get_first_matching_field, get_tenant},
+    chatbot::{
+        get_thread::post_process, mongodb::mongodb_storage::get_database,
+        storage_router::read_thread_and_owner, types::StreamVariant,
+    },
+};
+
+/// # Get Message
+/// Returns a single variant of a thread as JSON, in the format `{"variant": "variant_name", "content": "content"}`. Requires Authentication.
+///
+/// As arguments, it takes in a `thread_id` and an `index`.
+/// The index counts the variants as they are returned by the getthread endpoint, starting at 0.
+/// This avoids transferring the whole thread when only one message is needed, for example when editing.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the index is not given, or the index is not a number, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist or the index is out of range, a NotFound response is returned.
+///
+/// If the thread belongs to another user or its owner isn't known (old threads on disk), a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called GET_MESSAGE_DOCS
+pub async fn get_message(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let maybe_vault_url = get_first_matching_field(
+        &qstring,
+        headers,
+        &[
+            "x-freva-vault-url",
+            "x-vault-url",
+            "vault-url",
+            "vault_url",
+            "freva_vault_url",
+        ],
+        true,
+    );
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested a message without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let index = match get_first_matching_field(&qstring, headers, &["index", "x-index"], false)
+        .map(str::parse::<usize>)
+    {
+        Some(Ok(index)) => index,
+        None | Some(Err(_)) => {
+            warn!("The User requested a message without a valid index.");
+            return HttpResponse::UnprocessableEntity().body(
+                "Index not found or not a number. Please provide a non-negative index in the query parameters.",
+            );
+        }
+    };
+
+    let Some(vault_url) = maybe_vault_url else {
+        warn!("No vault URL provided, cannot connect to the database for threads.");
+        return HttpResponse::UnprocessableEntity()
+            .body("Vault URL not found. Please provide a non-empty vault URL in the headers.");
+    };
+
+    let database = match get_database(vault_url, get_tenant(headers).as_deref()).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error initializing database connection: {:?}", e);
+            return e;
+        }
+    };
+
+    let (content, owner) = match read_thread_and_owner(thread_id, database).await {
+        Ok(result) => result,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested a message from thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    };
+
+    // Old threads on disk don't record their owner, so nobody can prove they may read them.
+    if owner.as_deref() != Some(user_id.as_str()) {
+        warn!(
+            "User {} requested a message from thread {}, which isn't known to be theirs.",
+            user_id, thread_id
+        );
+        return HttpResponse::Forbidden().body("This thread belongs to another user.");
+    }
+
+    let Some(message) = select_message(content, index) else {
+        debug!(
+            "The User requested message {} of thread {}, which is out of range.",
+            index, thread_id
+        );
+        return HttpResponse::NotFound().body("Index out of range for this thread.");
+    };
+
+    match serde_json::to_string(&message) {
+        Ok(json) => {
+            trace!("Returning message: {}", json);
+            HttpResponse::Ok().body(json)
+        }
+        Err(e) => {
+            error!("Error serializing message: {:?}", e);
+            HttpResponse::InternalServerError().body("Error serializing message.")
+        }
+    }
+}
+
+/// Returns the variant at the given index, counted the same way as in the getthread endpoint.
+fn select_message(content: Vec<StreamVariant>, index: usize) -> Option<StreamVariant> {
+    post_process(content).into_iter().nth(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_message() {
+        let content = vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+
+        // The prompt isn't shown to the user, so it isn't counted either.
+        assert_eq!(
+            select_message(content.clone(), 1),
+            Some(StreamVariant::Assistant("Here is your circle.".to_string()))
+        );
+        assert_eq!(select_message(content, 3), None);
+    }
+}
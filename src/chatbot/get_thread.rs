@@ -4,8 +4,11 @@ use qstring::QString;
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    auth::get_first_matching_field,
-    chatbot::{mongodb::mongodb_storage::get_database, types::StreamVariant},
+    auth::{get_first_matching_field, get_tenant},
+    chatbot::{
+        mongodb::mongodb_storage::get_database,
+        types::{help_convert_sv_ccrm, StreamVariant},
+    },
 };
 
 use super::storage_router::read_thread;
@@ -13,7 +16,17 @@ use super::storage_router::read_thread;
 /// # Get Thread
 /// Returns the content of a thread as a Json of List of Strings. Requires Authentication.
 ///
-/// As arguments, it takes in a `thread_id`.
+/// As arguments, it takes in a `thread_id` and optionally `include_images`.
+///
+/// If `include_images` is set to `false`, the content of every Image variant is replaced by a placeholder
+/// in the form of `<omitted, N bytes, index I>`, which is much smaller for text-first rendering.
+/// The image itself can then be fetched with the message endpoint, using the index from the placeholder.
+///
+/// If `format` is set to `openai`, the thread is instead returned as a list of messages in the OpenAI chat format,
+/// with the code interpreter calls as tool_calls of the assistant messages and their outputs as tool messages.
+/// That way, the thread can be used by other tools that work with OpenAI-compatible APIs.
+/// With `include_images` set to `false`, the images are left out of these messages.
+/// The default format, `variants`, is the list of Stream Variants.
 ///
 /// The thread id is the unique identifier for the thread, given to the client when the stream started in a ServerHint variant.
 ///
@@ -21,6 +34,8 @@ use super::storage_router::read_thread;
 ///
 /// If the thread id is not given, a BadRequest response is returned.
 ///
+/// If the format is unknown, an UnprocessableEntity response is returned.
+///
 /// If the thread with the given id is not found, a NotFound response is returned.
 ///
 /// If the thread is found but cannot be read or cannot be displayed, an InternalServerError response is returned.
@@ -66,7 +81,7 @@ pub async fn get_thread(req: HttpRequest) -> impl Responder {
     let database = if let Some(vault_url) = maybe_vault_url {
         // Initialize the database with the vault URL.
         debug!("Using vault URL: {}", vault_url);
-        get_database(vault_url).await
+        get_database(vault_url, get_tenant(headers).as_deref()).await
     } else {
         // We now need the vault URL, so this fails.
         warn!("No vault URL provided, cannot connect to the database for threads.");
@@ -116,9 +131,42 @@ pub async fn get_thread(req: HttpRequest) -> impl Responder {
 
     let result = post_process(result);
 
-    // We can now return the content as a JSON response using serde_json
+    // The images are included by default; only an explicit "false" omits them.
+    let include_images = get_first_matching_field(
+        &qstring,
+        headers,
+        &["include_images", "x-include-images", "include-images"],
+        false,
+    )
+    .is_none_or(|value| value.trim() != "false");
+
+    // Other tools that work with the OpenAI chat format can request the thread as OpenAI messages instead.
+    let json = match get_first_matching_field(&qstring, headers, &["format", "x-format"], false) {
+        None | Some("" | "variants") => {
+            let result = if include_images {
+                result
+            } else {
+                debug!("Omitting the images of thread {}.", thread_id);
+                omit_images(result)
+            };
+            serde_json::to_string(&result)
+        }
+        Some("openai") => {
+            debug!("Returning thread {} in the OpenAI format.", thread_id);
+            serde_json::to_string(&help_convert_sv_ccrm(result, include_images))
+        }
+        Some(other) => {
+            warn!(
+                "The User requested a thread in an unknown format: {}",
+                other
+            );
+            return HttpResponse::UnprocessableEntity()
+                .body("Unknown format. Please use \"variants\" or \"openai\".");
+        }
+    };
 
-    let json = match serde_json::to_string(&result) {
+    // We can now return the content as a JSON response using serde_json
+    let json = match json {
         Ok(json) => json,
         Err(e) => {
             // If we can't serialize the content, we'll return a generic error.
@@ -134,8 +182,75 @@ pub async fn get_thread(req: HttpRequest) -> impl Responder {
 
 /// Post-processes the Vector of Stream Variants to be sent to the user.
 /// For now, this only removes the prompt variant.
-fn post_process(v: Vec<StreamVariant>) -> Vec<StreamVariant> {
+pub fn post_process(v: Vec<StreamVariant>) -> Vec<StreamVariant> {
     v.into_iter()
         .filter(|x| !matches!(x, StreamVariant::Prompt(_)))
         .collect()
 }
+
+/// Replaces the base64 content of all images with a placeholder that contains its size and its index in the thread.
+/// The index is the same one the message endpoint takes, so the image can be loaded later.
+fn omit_images(v: Vec<StreamVariant>) -> Vec<StreamVariant> {
+    v.into_iter()
+        .enumerate()
+        .map(|(index, variant)| match variant {
+            StreamVariant::Image(image) => {
+                StreamVariant::Image(format!("<omitted, {} bytes, index {index}>", image.len()))
+            }
+            other => other,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_images_are_omitted() {
+        let content = post_process(vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Image("iVBORw0KGgoAAAANSUhEUg==".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+        ]);
+        let omitted = omit_images(content);
+        assert_eq!(
+            omitted,
+            vec![
+                StreamVariant::User("plot a circle".to_string()),
+                StreamVariant::Image("<omitted, 24 bytes, index 1>".to_string()),
+                StreamVariant::Assistant("Here is your circle.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_openai_format_contains_tool_call() {
+        let content = post_process(vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::User("What is 2+2?".to_string()),
+            StreamVariant::Code("{\"code\": \"2+2\"}".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput("4".to_string(), "call_1".to_string()),
+            StreamVariant::Assistant("It's 4.".to_string()),
+        ]);
+        let json = serde_json::to_value(help_convert_sv_ccrm(content, true))
+            .expect("The messages should serialize");
+        let messages = json.as_array().expect("The export is a list of messages");
+
+        let call = messages
+            .iter()
+            .find(|message| message["role"] == "assistant" && message["tool_calls"].is_array())
+            .expect("The code call is an assistant message with tool_calls");
+        assert_eq!(call["tool_calls"][0]["id"], "call_1");
+        assert_eq!(call["tool_calls"][0]["type"], "function");
+        assert_eq!(
+            call["tool_calls"][0]["function"]["name"],
+            "code_interpreter"
+        );
+        assert!(messages
+            .iter()
+            .any(|message| message["role"] == "tool" && message["tool_call_id"] == "call_1"));
+        assert_eq!(messages[0]["role"], "user");
+    }
+}
@@ -1,13 +1,16 @@
 use mongodb::Database;
+use once_cell::sync::Lazy;
 use rand::Rng;
 use tracing::{debug, error, trace, warn};
 
 use crate::chatbot::{
+    heartbeat::{is_heartbeat, PERSIST_HEARTBEATS},
+    storage_router::thread_exists,
     types::{ActiveConversation, ConversationState},
     ACTIVE_CONVERSATIONS,
 };
 
-use super::types::StreamVariant;
+use super::types::{StreamVariant, TokenUsage};
 
 /// Helper function to generate an ID.
 /// Mostly for creating conversation IDs.
@@ -21,35 +24,70 @@ pub fn generate_id() -> String {
         .collect()
 }
 
+/// How many IDs are generated at most before giving up on finding an unused one.
+/// Collisions are astronomically unlikely, so this only guards against a broken random number generator.
+/// Set via the environment variable `MAX_ID_ATTEMPTS`; defaults to 10.
+static MAX_ID_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    std::env::var("MAX_ID_ATTEMPTS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(10)
+});
+
 /// Helper function to return an ID for a new conversation.
-pub fn new_conversation_id() -> String {
+/// The ID is neither used by an active conversation nor by a thread in the storage.
+pub async fn new_conversation_id(database: Database) -> String {
     trace!("Generating new conversation ID.");
-    let value = generate_id();
+    unique_conversation_id(
+        generate_id,
+        |candidate| {
+            let database = database.clone();
+            async move { thread_exists(&candidate, database).await }
+        },
+        *MAX_ID_ATTEMPTS,
+    )
+    .await
+}
 
-    // If this value is already in use, we'll just try again.
-    let result = match ACTIVE_CONVERSATIONS.lock() {
-        Ok(guard) => {
+/// Generates IDs until one is found that is neither active nor stored, for at most max_attempts tries.
+async fn unique_conversation_id<F, Fut>(
+    mut generate: impl FnMut() -> String,
+    is_stored: F,
+    max_attempts: u32,
+) -> String
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let mut value = generate();
+    for attempt in 1..=max_attempts {
+        // If this value is already in use, we'll just try again.
+        let is_active = match ACTIVE_CONVERSATIONS.lock() {
             // If we can lock the mutex, we can check if the value is already in use.
-            if guard.iter().any(|x| x.id == value) {
-                warn!("Generated conversation ID is already in use, trying again.");
-                None
-            } else {
-                Some(value)
+            Ok(guard) => guard.iter().any(|x| x.id == value),
+            Err(e) => {
+                error!(
+                    "Error locking the mutex, only checking the storage for uniqueness: {:?}",
+                    e
+                );
+                false
             }
+        };
+        // The lock is released before the storage is queried, so the query doesn't block the other conversations.
+        if !is_active && !is_stored(value.clone()).await {
+            return value;
         }
-        Err(e) => {
-            error!(
-                "Error locking the mutex, falling back to hoping the value is unique: {:?}",
-                e
-            );
-            Some(value)
-        }
-    };
-
-    match result {
-        Some(value) => value,
-        None => new_conversation_id(), // Try again
+        warn!(
+            "Generated conversation ID is already in use (attempt {}), trying again.",
+            attempt
+        );
+        value = generate();
     }
+    error!(
+        "Could not generate an unused conversation ID in {} attempts, falling back to hoping the value is unique.",
+        max_attempts
+    );
+    value
 }
 
 /// Adds the given Stream Variants to the conversation with the given ID
@@ -77,6 +115,14 @@ pub fn add_to_conversation(
                     state: ConversationState::Streaming(freva_config_path),
                     last_activity: std::time::Instant::now(),
                     user_id,
+                    usage: None,
+                    disconnected_at: None,
+                    operations: 0,
+                    warnings: 0,
+                    replaces_from: None,
+                    tool_task: None,
+                    database: None,
+                    transient: Vec::new(),
                 });
             }
         }
@@ -86,8 +132,392 @@ pub fn add_to_conversation(
     }
 }
 
+/// Marks the conversation as a regenerated turn: when it's saved, the stored thread is cut to its first `keep` variants first,
+/// so the new turn replaces the old one instead of being appended after it.
+pub fn replace_stored_tail(thread_id: &str, keep: usize) {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                conversation.replaces_from = Some(keep);
+            } else {
+                warn!(
+                    "Tried to mark conversation {} as regenerated, but it is not active.",
+                    thread_id
+                );
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+        }
+    }
+}
+
+/// Remembers the task of the tool call the conversation is waiting for, so stopping the conversation can abort it.
+pub fn set_tool_task(thread_id: &str, tool_task: tokio::task::AbortHandle) {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                conversation.tool_task = Some(tool_task);
+            } else {
+                warn!(
+                    "Tried to set the tool call task of conversation {}, but it is not active.",
+                    thread_id
+                );
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+        }
+    }
+}
+
+/// Records variants that are sent to the client, but not stored with the conversation, like heartbeats or status messages.
+/// The resume cursor of a client counts them, so they're kept at their position until the conversation is removed.
+pub fn add_transient_to_conversation(thread_id: &str, variants: Vec<StreamVariant>) {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                let position = conversation.conversation.len();
+                conversation
+                    .transient
+                    .extend(variants.into_iter().map(|variant| (position, variant)));
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+        }
+    }
+}
+
+/// The variants of a conversation in the order they were sent to the client, including the ones that aren't stored.
+fn sent_variants(
+    conversation: &[StreamVariant],
+    transient: &[(usize, StreamVariant)],
+) -> Vec<StreamVariant> {
+    let mut sent = Vec::with_capacity(conversation.len() + transient.len());
+    let mut transient = transient.iter().peekable();
+    for (index, variant) in conversation.iter().enumerate() {
+        while let Some((_, variant)) = transient.next_if(|(position, _)| *position <= index) {
+            sent.push(variant.clone());
+        }
+        sent.push(variant.clone());
+    }
+    sent.extend(transient.map(|(_, variant)| variant.clone()));
+    sent
+}
+
+/// Remembers the database of the conversation, so it can be saved on shutdown without the request it belongs to.
+pub fn set_conversation_database(thread_id: &str, database: Database) {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                conversation.database = Some(database);
+            } else {
+                warn!(
+                    "Tried to set the database of conversation {}, but it is not active.",
+                    thread_id
+                );
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+        }
+    }
+}
+
+/// Stops all conversations of the user that are still running and aborts their tool calls.
+/// The streams notice that they were stopped and end themselves, like after a stop request for a single thread.
+/// Returns how many conversations were stopped.
+pub fn stop_user_conversations(user_id: &str) -> Result<usize, String> {
+    trace!("Stopping all conversations of user {}", user_id);
+    let mut guard = ACTIVE_CONVERSATIONS
+        .lock()
+        .map_err(|e| format!("Error locking the mutex: {e:?}"))?;
+    let mut stopped = 0;
+    for conversation in guard.iter_mut().filter(|x| x.user_id == user_id) {
+        if matches!(
+            conversation.state,
+            ConversationState::Streaming(_) | ConversationState::Stopping
+        ) {
+            conversation.state = ConversationState::Stopping;
+            if let Some(tool_task) = conversation.tool_task.take() {
+                debug!(
+                    "Aborting the tool call of conversation {}.",
+                    conversation.id
+                );
+                tool_task.abort();
+            }
+            stopped += 1;
+        }
+    }
+    Ok(stopped)
+}
+
+/// Adds the token usage of one response of the LLM to the running total of the conversation.
+/// A usage without any tokens is ignored, so a provider that doesn't count tokens doesn't end up with a total of zero.
+pub fn add_usage_to_conversation(thread_id: &str, usage: &TokenUsage) {
+    trace!(
+        "Adding usage {:?} to conversation with id: {}",
+        usage,
+        thread_id
+    );
+    if usage.is_empty() {
+        debug!(
+            "The provider reported a usage without any tokens for conversation {}, ignoring it.",
+            thread_id
+        );
+        return;
+    }
+
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                conversation
+                    .usage
+                    .get_or_insert_with(TokenUsage::default)
+                    .add(usage);
+            } else {
+                // The usage comes at the very end of a response, so the conversation should always exist by then.
+                warn!(
+                    "Conversation with id: {} not found, cannot record token usage.",
+                    thread_id
+                );
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+        }
+    }
+}
+
+/// Returns the token usage of the conversation so far, if the provider reported any.
+pub fn get_conversation_usage(thread_id: &str) -> Option<TokenUsage> {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(guard) => guard
+            .iter()
+            .find(|x| x.id == thread_id)
+            .and_then(|conversation| conversation.usage),
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            None
+        }
+    }
+}
+
+/// The maximum number of operations (tool calls, and anything else that makes the LLM go another round) in a single turn.
+/// This is a safety net against the LLM getting stuck in a loop, independent of any individual limits.
+/// Set via the environment variable `MAX_OPERATIONS_PER_TURN`; defaults to 25.
+pub static MAX_OPERATIONS_PER_TURN: Lazy<u32> = Lazy::new(|| {
+    std::env::var("MAX_OPERATIONS_PER_TURN")
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(25)
+});
+
+/// Counts an operation for the current turn of the conversation.
+/// Returns whether the operation is still within the budget; if not, the turn should be ended.
+pub fn count_operation(thread_id: &str, budget: u32) -> bool {
+    trace!("Counting operation for conversation with id: {}", thread_id);
+
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) {
+                conversation.operations += 1;
+                debug!(
+                    "Conversation with id: {} is at {} of {} operations.",
+                    thread_id, conversation.operations, budget
+                );
+                conversation.operations <= budget
+            } else {
+                // Without a conversation, there's nothing to count against; don't block the operation.
+                warn!(
+                    "Conversation with id: {} not found, cannot count the operation.",
+                    thread_id
+                );
+                true
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            true
+        }
+    }
+}
+
+/// The maximum number of warnings (ServerHints with a "warning" key) a single stream sends to the client.
+/// A misbehaving model can cause the same warning over and over; past the maximum, they are only logged and not stored.
+/// Set via the environment variable `MAX_WARNINGS_PER_STREAM`; defaults to 5.
+pub static MAX_WARNINGS_PER_STREAM: Lazy<u32> = Lazy::new(|| {
+    std::env::var("MAX_WARNINGS_PER_STREAM")
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(5)
+});
+
+/// Whether the variant is a ServerHint that carries a warning.
+fn is_warning_hint(variant: &StreamVariant) -> bool {
+    match variant {
+        StreamVariant::ServerHint(content) => serde_json::from_str::<serde_json::Value>(content)
+            .is_ok_and(|value| value.get("warning").is_some()),
+        _ => false,
+    }
+}
+
+/// Removes the warnings from the variants once the conversation already sent the maximum of them.
+/// The removed warnings are logged instead, so they aren't lost for debugging.
+pub fn cap_warnings(thread_id: &str, variants: Vec<StreamVariant>, max: u32) -> Vec<StreamVariant> {
+    if !variants.iter().any(is_warning_hint) {
+        return variants;
+    }
+
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            let Some(conversation) = guard.iter_mut().find(|x| x.id == thread_id) else {
+                // Without a conversation, there's nothing to count against; keep the warnings.
+                warn!(
+                    "Conversation with id: {} not found, cannot count its warnings.",
+                    thread_id
+                );
+                return variants;
+            };
+            variants
+                .into_iter()
+                .filter(|variant| {
+                    if !is_warning_hint(variant) {
+                        return true;
+                    }
+                    conversation.warnings += 1;
+                    if conversation.warnings <= max {
+                        return true;
+                    }
+                    warn!(
+                        "Conversation with id: {} reached its maximum of {} warnings, not sending: {:?}",
+                        thread_id, max, variant
+                    );
+                    false
+                })
+                .collect()
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            variants
+        }
+    }
+}
+
+/// How long a conversation is kept after its client disconnected, so that the client can reconnect and resume it.
+/// Set via the environment variable `DISCONNECT_GRACE_SECS`; defaults to 30 seconds.
+pub static DISCONNECT_GRACE_PERIOD: Lazy<std::time::Duration> = Lazy::new(|| {
+    let secs = std::env::var("DISCONNECT_GRACE_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(30);
+    std::time::Duration::from_secs(secs)
+});
+
+/// Whether conversations of disconnected clients are kept for the grace period at all.
+/// If false, they are saved and removed as soon as the disconnect is noticed, which frees the resources immediately.
+/// Set via the environment variable `KEEP_DISCONNECTED_CONVERSATIONS`; defaults to true.
+pub static KEEP_DISCONNECTED_CONVERSATIONS: Lazy<bool> = Lazy::new(|| {
+    std::env::var("KEEP_DISCONNECTED_CONVERSATIONS").map_or(true, |value| value.trim() != "false")
+});
+
+/// Marks the conversation with the given ID as disconnected, starting its grace period.
+/// Its tool call is aborted, because the stream that would have given the result to the LLM is gone; a resumed conversation ends with what was produced until the disconnect.
+/// Only conversations that are still streaming are marked; returns whether the conversation was marked.
+pub fn mark_disconnected(thread_id: &str) -> bool {
+    trace!(
+        "Marking conversation with id: {} as disconnected.",
+        thread_id
+    );
+
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            if let Some(conversation) = guard
+                .iter_mut()
+                .find(|x| x.id == thread_id && matches!(x.state, ConversationState::Streaming(_)))
+            {
+                conversation.disconnected_at = Some(std::time::Instant::now());
+                if let Some(tool_task) = conversation.tool_task.take() {
+                    debug!(
+                        "Aborting the tool call of conversation {}, its client disconnected.",
+                        thread_id
+                    );
+                    tool_task.abort();
+                }
+                true
+            } else {
+                // If the stream ended normally, the conversation was already removed.
+                false
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether the client of the conversation disconnected at least the grace period ago and didn't resume it since.
+pub fn disconnected_for(thread_id: &str, grace_period: std::time::Duration) -> bool {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(guard) => guard.iter().any(|x| {
+            x.id == thread_id
+                && x.disconnected_at
+                    .is_some_and(|disconnected_at| disconnected_at.elapsed() >= grace_period)
+        }),
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Resumes a conversation whose client disconnected, if it is still within the grace period and belongs to the user.
+/// Returns the variants of the conversation starting at the cursor, which is the number of variants the client already recieved.
+/// That includes the variants that were only sent and aren't stored, like heartbeats, so they're counted at the same position here.
+pub fn resume_conversation(
+    thread_id: &str,
+    user_id: &str,
+    cursor: usize,
+) -> Option<Vec<StreamVariant>> {
+    trace!(
+        "Trying to resume conversation with id: {} at cursor {}",
+        thread_id,
+        cursor
+    );
+
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => {
+            let conversation = guard
+                .iter_mut()
+                .find(|x| x.id == thread_id && x.user_id == user_id)?;
+            match conversation.disconnected_at {
+                Some(disconnected_at) if disconnected_at.elapsed() <= *DISCONNECT_GRACE_PERIOD => {
+                    conversation.disconnected_at = None;
+                    conversation.last_activity = std::time::Instant::now();
+                    Some(
+                        sent_variants(&conversation.conversation, &conversation.transient)
+                            .into_iter()
+                            .skip(cursor)
+                            .collect(),
+                    )
+                }
+                _ => {
+                    debug!("Conversation with id: {} cannot be resumed, it is either still connected or its grace period is over.", thread_id);
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            None
+        }
+    }
+}
+
 /// Returns the state of the conversation, if possible
-pub async fn conversation_state(thread_id: &str, database: Database) -> Option<ConversationState> {
+pub async fn conversation_state(thread_id: &str) -> Option<ConversationState> {
     trace!("Checking the state of conversation with id: {}", thread_id);
 
     let mut to_save = None;
@@ -108,7 +538,7 @@ pub async fn conversation_state(thread_id: &str, database: Database) -> Option<C
                 None
             };
             // Before returning, we'll clean up stale conversations.
-            to_save = Some(cleanup_conversations(&mut guard));
+            to_save = Some(cleanup_conversations(&mut guard, std::time::Instant::now()));
             return_val
         }
         Err(e) => {
@@ -120,7 +550,14 @@ pub async fn conversation_state(thread_id: &str, database: Database) -> Option<C
     // In order to not save the conversations while the mutex is locked, we'll save it here.
     if let Some(conversations) = to_save {
         for conversation in conversations {
-            save_conversation(conversation, database.clone()).await;
+            // Each one goes into its own database, not the one of whoever made this request.
+            match conversation.database.clone() {
+                Some(database) => save_conversation(conversation, database).await,
+                None => warn!(
+                    "Stale conversation {} has no database to be saved to, it's lost.",
+                    conversation.id
+                ),
+            }
         }
     }
 
@@ -169,21 +606,56 @@ pub async fn save_and_remove_conversation(thread_id: &str, database: Database) {
     }
 }
 
+/// Whether an uncleaned copy of every conversation is stored next to the cleaned one.
+/// The stored thread is always cleaned; the raw copy keeps the individual deltas and hints as they were streamed, for debugging.
+/// Set via the environment variable `STORE_RAW_CONVERSATIONS`; defaults to false.
+pub static STORE_RAW_CONVERSATIONS: Lazy<bool> = Lazy::new(|| {
+    std::env::var("STORE_RAW_CONVERSATIONS").is_ok_and(|value| value.trim() == "true")
+});
+
 /// Helper function to save a conversation to disk.
 async fn save_conversation(conversation: ActiveConversation, database: Database) {
     debug!("Writing conversation to disk.");
 
+    // The conversation is over, so its inline freva config isn't needed anymore.
+    crate::chatbot::inline_freva_config::remove_inline_config(&conversation.id);
+
+    // If enabled, the variants are also stored exactly as they were streamed, for debugging.
+    let raw_conversation = STORE_RAW_CONVERSATIONS.then(|| conversation.conversation.clone());
+
     // Before we'll write it to disk, we'll fold all the consecutive Assistant messages into one.
 
     let new_conversation = concat_variants(conversation.conversation);
 
+    // A regenerated turn replaces the old one, which is only removed now, so it isn't lost if the new one fails to start.
+    if let Some(keep) = conversation.replaces_from {
+        crate::chatbot::storage_router::truncate_thread(
+            &conversation.id,
+            &conversation.user_id,
+            keep,
+            database.clone(),
+        )
+        .await;
+    }
+
     crate::chatbot::storage_router::append_thread(
         &conversation.id,
         &conversation.user_id,
         new_conversation,
-        database,
+        conversation.usage,
+        database.clone(),
     )
     .await;
+
+    if let Some(raw_conversation) = raw_conversation {
+        crate::chatbot::storage_router::append_raw_thread(
+            &conversation.id,
+            &conversation.user_id,
+            raw_conversation,
+            database,
+        )
+        .await;
+    }
 }
 
 /// The assistant and code messages are streamed, so the variants that come from OpenAI contain only one or a few tokens of the message.
@@ -191,6 +663,7 @@ async fn save_conversation(conversation: ActiveConversation, database: Database)
 ///
 /// So instead of having multiple variants like this: "Assistant": "He", "Assistant": "llo", "Assistant": "!"
 /// we'll have one variant like this: "Assistant": "Hello!". The same goes for the Code messages.
+/// Heartbeats are dropped, unless PERSIST_HEARTBEATS is set.
 fn concat_variants(input: Vec<StreamVariant>) -> Vec<StreamVariant> {
     let mut output = Vec::new();
     let mut assistant_buffer = String::new();
@@ -198,10 +671,20 @@ fn concat_variants(input: Vec<StreamVariant>) -> Vec<StreamVariant> {
 
     for variant in input {
         match variant {
+            // Unless they should be stored, heartbeats only matter while the stream runs.
+            // The first one of a tool call is returned together with the tool call and gets here that way.
+            heartbeat if !*PERSIST_HEARTBEATS && is_heartbeat(&heartbeat) => {}
             StreamVariant::Assistant(message) => {
                 assistant_buffer.push_str(&message);
             }
             StreamVariant::Code(message, id) => {
+                // Parallel tool calls follow each other directly, so a new id starts a new code message.
+                if !id.is_empty() && id != code_buffer.1 && !code_buffer.0.is_empty() {
+                    output.push(StreamVariant::Code(
+                        std::mem::take(&mut code_buffer.0),
+                        std::mem::take(&mut code_buffer.1),
+                    ));
+                }
                 code_buffer.0.push_str(&message);
                 code_buffer.1 = id;
             }
@@ -264,28 +747,90 @@ pub fn get_conversation(thread_id: &str) -> Option<Vec<StreamVariant>> {
     found_conversation.map(concat_variants) // If the conversation is found, we'll concatenate the messages, else we'll return None.
 }
 
-static MAX_INACTIVE_TIME: std::time::Duration = std::time::Duration::from_secs(3 * 60); // 3 minutes
+/// Returns the variants of the active conversation with the given thread_ID together with its user
+/// and, for a regenerated turn, how many variants of the stored thread it keeps (see `replace_stored_tail`).
+/// Unlike `get_conversation`, a conversation that isn't active is expected and not warned about.
+pub fn get_active_turn(thread_id: &str) -> Option<(Vec<StreamVariant>, String, Option<usize>)> {
+    match ACTIVE_CONVERSATIONS.lock() {
+        Ok(guard) => guard
+            .iter()
+            .find(|x| x.id == thread_id)
+            .map(|conversation| {
+                (
+                    conversation.conversation.clone(),
+                    conversation.user_id.clone(),
+                    conversation.replaces_from,
+                )
+            }),
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            None
+        }
+    }
+}
+
+/// How long a conversation may be inactive before it's saved and removed from the active conversations.
+/// Set via the environment variable `MAX_INACTIVE_SECS`; defaults to 180 seconds.
+static MAX_INACTIVE_TIME: Lazy<std::time::Duration> = Lazy::new(|| {
+    let secs = std::env::var("MAX_INACTIVE_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(180);
+    std::time::Duration::from_secs(secs)
+});
+
+/// How long a conversation that waits for a tool call may be inactive before it's removed anyway.
+/// The code interpreter can legitimately run for a long time without the conversation changing, so this is a lot longer than `MAX_INACTIVE_TIME`.
+/// Set via the environment variable `MAX_INACTIVE_IN_TOOL_CALL_SECS`; defaults to 30 minutes.
+static MAX_INACTIVE_IN_TOOL_CALL_TIME: Lazy<std::time::Duration> = Lazy::new(|| {
+    let secs = std::env::var("MAX_INACTIVE_IN_TOOL_CALL_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(30 * 60);
+    std::time::Duration::from_secs(secs)
+});
 
-/// Cleans up all stae conversations to avoid the ACTIVE_CONVERSATIONS vector from growing indefinitely.
+/// Cleans up all stale conversations to avoid the ACTIVE_CONVERSATIONS vector from growing indefinitely.
 /// The vector grows because when a client loses connection, the stream ends shortly after, so the cleanup doesn't happen.
-fn cleanup_conversations(guard: &mut Vec<ActiveConversation>) -> Vec<ActiveConversation> {
+/// The current time is passed in, so the thresholds can be tested without waiting for them.
+fn cleanup_conversations(
+    guard: &mut Vec<ActiveConversation>,
+    now: std::time::Instant,
+) -> Vec<ActiveConversation> {
     // Store the conversations that need to be saved, because we shouldn't save them while the mutex is locked.
     let mut to_save = Vec::new();
-    guard.retain(|x| {
-        if x.last_activity.elapsed() > MAX_INACTIVE_TIME {
+    guard.retain_mut(|x| {
+        // Conversations whose client disconnected and didn't come back in time are removed, no matter what they were doing.
+        if x.disconnected_at.is_some_and(|disconnected_at| {
+            now.saturating_duration_since(disconnected_at) > *DISCONNECT_GRACE_PERIOD
+        }) {
             debug!(
-                "Removing conversation with id: {} because it's inactive.",
+                "Removing conversation with id: {} because its client disconnected and didn't resume it.",
                 x.id
             );
+            to_save.push(x.clone());
+            return false;
+        }
+
+        // While a tool call is running, nothing is added to the conversation, so it gets more time.
+        let in_tool_call = x
+            .tool_task
+            .as_ref()
+            .is_some_and(|tool_task| !tool_task.is_finished());
+        let max_inactive_time = if in_tool_call {
+            *MAX_INACTIVE_IN_TOOL_CALL_TIME
+        } else {
+            *MAX_INACTIVE_TIME
+        };
+        if now.saturating_duration_since(x.last_activity) > max_inactive_time {
+            debug!(
+                "Removing conversation with id: {} because it's inactive (in a tool call: {}).",
+                x.id, in_tool_call
+            );
             trace!("Conversation: {:?}", x);
-            // TODO, FIXME: this currently doesn't clean up conversations that used the code_interpreter, because the heartbeat is currently not working and the
-            // This will be fixed once the heartbeat is working, but this is a temporary fix.
-            if x.conversation
-                .last()
-                .is_some_and(|x| matches!(x, StreamVariant::ServerHint(_)))
-            {
-                trace!("Conversation used the code_interpreter, not removing.");
-                return true;
+            // A tool call that hangs for that long won't be of use anymore.
+            if let Some(tool_task) = x.tool_task.take() {
+                tool_task.abort();
             }
             // If the conversation is inactive, we'll save it to disk and remove it from the active conversations.
             to_save.push(x.clone());
@@ -299,7 +844,7 @@ fn cleanup_conversations(guard: &mut Vec<ActiveConversation>) -> Vec<ActiveConve
 
 /// This function is run when the frontend sends an edit-input.
 /// It generates a new thread_id and manages the python_pickles file.
-pub fn switch_to_new_thread_id(thread_id: &str) -> String {
+pub async fn switch_to_new_thread_id(thread_id: &str, database: Database) -> String {
     trace!(
         "Switching to new thread_id for conversation with id: {}",
         thread_id
@@ -308,7 +853,7 @@ pub fn switch_to_new_thread_id(thread_id: &str) -> String {
     // The conversation wasn't started yet at this point in the code, so we'll just create a new conversation with the new thread_id.
     // This will happen automatically when this function returns a new thread_id.
 
-    let new_thread_id = new_conversation_id();
+    let new_thread_id = new_conversation_id(database).await;
 
     // We need to copy the python_pickles file to the new thread_id. This previously only happened within python.
     // Both files lie in `python_pickles/{thread_id}.pickle` and `python_pickles/{new_thread_id}.pickle`.
@@ -338,3 +883,290 @@ pub fn switch_to_new_thread_id(thread_id: &str) -> String {
     // Return the new thread_id.
     new_thread_id
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_stop_all_only_stops_own_conversations() {
+        // The user ids are unique, because the conversations of the other tests are in the same pool.
+        let user_id = generate_id();
+        let other_user_id = generate_id();
+        let own_threads = [generate_id(), generate_id()];
+        let other_thread = generate_id();
+        for thread_id in &own_threads {
+            add_to_conversation(thread_id, vec![], String::new(), user_id.clone());
+        }
+        add_to_conversation(&other_thread, vec![], String::new(), other_user_id.clone());
+
+        // Both users wait for a tool call.
+        let own_tool_call = tokio::spawn(std::future::pending::<()>());
+        set_tool_task(&own_threads[0], own_tool_call.abort_handle());
+        let other_tool_call = tokio::spawn(std::future::pending::<()>());
+        set_tool_task(&other_thread, other_tool_call.abort_handle());
+
+        assert_eq!(stop_user_conversations(&user_id), Ok(2));
+        let state_of = |thread_id: &str| {
+            ACTIVE_CONVERSATIONS
+                .lock()
+                .expect("The mutex isn't poisoned")
+                .iter()
+                .find(|x| x.id == thread_id)
+                .map(|x| x.state.clone())
+        };
+        for thread_id in &own_threads {
+            assert!(matches!(
+                state_of(thread_id),
+                Some(ConversationState::Stopping)
+            ));
+        }
+        assert!(own_tool_call.await.is_err_and(|e| e.is_cancelled()));
+
+        // The other user's stream and tool call keep running.
+        assert!(matches!(
+            state_of(&other_thread),
+            Some(ConversationState::Streaming(_))
+        ));
+        tokio::task::yield_now().await;
+        assert!(!other_tool_call.is_finished());
+        other_tool_call.abort();
+        assert_eq!(stop_user_conversations(&other_user_id), Ok(1));
+    }
+
+    #[actix_web::test]
+    async fn test_stale_conversations_are_reaped_even_in_tool_calls() {
+        let start = std::time::Instant::now();
+        let conversation =
+            |id: &str, tool_task: Option<tokio::task::AbortHandle>| ActiveConversation {
+                id: id.to_string(),
+                state: ConversationState::Streaming(String::new()),
+                conversation: vec![StreamVariant::User("plot a circle".to_string())],
+                last_activity: start,
+                user_id: "testuser".to_string(),
+                usage: None,
+                disconnected_at: None,
+                operations: 0,
+                warnings: 0,
+                replaces_from: None,
+                tool_task,
+                database: None,
+                transient: Vec::new(),
+            };
+        let tool_call = tokio::spawn(std::future::pending::<()>());
+        let finished_tool_call = tokio::spawn(async {});
+        let finished = finished_tool_call.abort_handle();
+        finished_tool_call
+            .await
+            .expect("The tool call finishes right away");
+        let mut conversations = vec![
+            conversation("normal", None),
+            conversation("in_tool_call", Some(tool_call.abort_handle())),
+            conversation("after_tool_call", Some(finished)),
+        ];
+        let ids = |conversations: &[ActiveConversation]| {
+            conversations
+                .iter()
+                .map(|x| x.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Within the normal threshold, nothing is removed.
+        let at = |duration: std::time::Duration| start + duration;
+        assert!(cleanup_conversations(&mut conversations, at(*MAX_INACTIVE_TIME)).is_empty());
+
+        // After it, only the conversation that waits for a running tool call is kept; a finished tool call doesn't count.
+        let reaped = cleanup_conversations(
+            &mut conversations,
+            at(*MAX_INACTIVE_TIME + std::time::Duration::from_secs(1)),
+        );
+        assert_eq!(ids(&reaped), ["normal", "after_tool_call"]);
+        assert_eq!(ids(&conversations), ["in_tool_call"]);
+
+        // A tool call that hangs is eventually given up as well, and its task is aborted.
+        let reaped = cleanup_conversations(
+            &mut conversations,
+            at(*MAX_INACTIVE_IN_TOOL_CALL_TIME + std::time::Duration::from_secs(1)),
+        );
+        assert_eq!(ids(&reaped), ["in_tool_call"]);
+        assert!(conversations.is_empty());
+        assert!(tool_call.await.is_err_and(|e| e.is_cancelled()));
+    }
+
+    #[test]
+    fn test_resume_within_grace_period() {
+        let thread_id = generate_id();
+        add_to_conversation(
+            &thread_id,
+            vec![
+                StreamVariant::User("plot a circle".to_string()),
+                StreamVariant::Assistant("Sure".to_string()),
+                StreamVariant::Assistant(", here it is".to_string()),
+            ],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        // The client drops the connection after having recieved the first variant.
+        assert!(mark_disconnected(&thread_id));
+
+        // Another user cannot pick up the conversation.
+        assert_eq!(resume_conversation(&thread_id, "otheruser", 1), None);
+
+        // The same user reconnects right away and gets what they missed of the same conversation.
+        let resumed = resume_conversation(&thread_id, "testuser", 1);
+        assert_eq!(
+            resumed,
+            Some(vec![
+                StreamVariant::Assistant("Sure".to_string()),
+                StreamVariant::Assistant(", here it is".to_string()),
+            ])
+        );
+
+        // After resuming, the conversation is connected again and cannot be resumed a second time.
+        assert_eq!(resume_conversation(&thread_id, "testuser", 0), None);
+    }
+
+    #[test]
+    fn test_resume_counts_variants_that_are_not_stored() {
+        let thread_id = generate_id();
+        let heartbeat = StreamVariant::ServerHint(r#"{"memory": 1}"#.to_string());
+        let status = StreamVariant::ServerHint(r#"{"status": "Restarting soon"}"#.to_string());
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::User("plot a circle".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+        add_transient_to_conversation(&thread_id, vec![heartbeat.clone()]);
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::Assistant("Sure".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+        add_transient_to_conversation(&thread_id, vec![status.clone()]);
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::Assistant(", here it is".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        // The client received the user input, the heartbeat and the first delta; the status message is the first one it missed.
+        assert!(mark_disconnected(&thread_id));
+        assert_eq!(
+            resume_conversation(&thread_id, "testuser", 3),
+            Some(vec![
+                status,
+                StreamVariant::Assistant(", here it is".to_string()),
+            ])
+        );
+
+        // Only the stored variants are part of the conversation.
+        assert_eq!(
+            get_conversation(&thread_id),
+            Some(vec![
+                StreamVariant::User("plot a circle".to_string()),
+                StreamVariant::Assistant("Sure, here it is".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_operation_budget_terminates_turn() {
+        let thread_id = generate_id();
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::User(
+                "keep calling tools forever".to_string(),
+            )],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        // A pathological turn that never stops calling tools is allowed exactly as many operations as the budget.
+        let allowed = (0..10)
+            .take_while(|_| count_operation(&thread_id, 3))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn test_warnings_beyond_cap_are_suppressed() {
+        let thread_id = generate_id();
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::User("plot the temperature".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+        let warning = StreamVariant::ServerHint(
+            "{\"warning\": \"Tool call expected known tool, but found ->plot<-\"}".to_string(),
+        );
+        let delta = StreamVariant::Assistant("Let me try again.".to_string());
+
+        // The model calls the same unknown tool again and again; only the first two warnings reach the client.
+        let sent = (0..4)
+            .flat_map(|_| cap_warnings(&thread_id, vec![warning.clone(), delta.clone()], 2))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent.iter().filter(|variant| **variant == warning).count(),
+            2
+        );
+        assert_eq!(sent.iter().filter(|variant| **variant == delta).count(), 4);
+
+        // Other ServerHints aren't warnings and always get through.
+        let hint = StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string());
+        assert_eq!(cap_warnings(&thread_id, vec![hint.clone()], 2), vec![hint]);
+    }
+
+    #[test]
+    fn test_missing_usage_is_not_recorded_as_zero() {
+        let thread_id = generate_id();
+        add_to_conversation(
+            &thread_id,
+            vec![StreamVariant::User("plot the temperature".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        // The provider never sent a usage, or only one without any tokens.
+        add_usage_to_conversation(&thread_id, &TokenUsage::default());
+        assert_eq!(get_conversation_usage(&thread_id), None);
+        assert_eq!(
+            TokenUsage::combine(None, get_conversation_usage(&thread_id)),
+            None
+        );
+
+        let usage = TokenUsage {
+            prompt_tokens: 1200,
+            completion_tokens: 300,
+            total_tokens: 1500,
+        };
+        add_usage_to_conversation(&thread_id, &usage);
+        assert_eq!(get_conversation_usage(&thread_id), Some(usage));
+    }
+
+    #[actix_web::test]
+    async fn test_stored_id_is_rejected_and_retried() {
+        let mut candidates =
+            vec!["stored_thread".to_string(), "new_thread".to_string()].into_iter();
+        let id = unique_conversation_id(
+            || candidates.next().unwrap_or_default(),
+            |candidate| async move { candidate == "stored_thread" },
+            10,
+        )
+        .await;
+        assert_eq!(id, "new_thread");
+
+        // A generator that only returns used IDs doesn't loop forever.
+        let id = unique_conversation_id(
+            || "stored_thread".to_string(),
+            |candidate| async move { candidate == "stored_thread" },
+            3,
+        )
+        .await;
+        assert_eq!(id, "stored_thread");
+    }
+}
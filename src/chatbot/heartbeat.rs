@@ -1,17 +1,91 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
 use tokio::sync::RwLock;
 
 use super::types::StreamVariant;
+use crate::tool_calls::code_interpreter::progress::current_progress;
 
 pub static SYSINFO: Lazy<RwLock<(sysinfo::System, Instant)>> =
     Lazy::new(|| RwLock::new(((sysinfo::System::new_all()), Instant::now())));
 
+/// Whether the heartbeats contain the memory and CPU usage of the server.
+/// These are details about the infrastructure that clients don't need, so they're only meant for debugging.
+/// Set via the environment variable `DETAILED_HEARTBEAT`; defaults to false.
+pub static DETAILED_HEARTBEAT: Lazy<bool> =
+    Lazy::new(|| std::env::var("DETAILED_HEARTBEAT").is_ok_and(|value| value.trim() == "true"));
+
+/// How often a heartbeat is sent while a tool call runs. The output the code interpreter printed in the meantime is sent at the same pace.
+/// Set via the environment variable `HEARTBEAT_INTERVAL_SECS`; defaults to 5 seconds.
+pub static HEARTBEAT_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("HEARTBEAT_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(5),
+    )
+});
+
+/// Whether the heartbeats are stored in the thread too, instead of only being sent to the client.
+/// They only show that the server was alive at the time, so storing them just bloats the thread.
+/// Set via the environment variable `PERSIST_HEARTBEATS`; defaults to false.
+pub static PERSIST_HEARTBEATS: Lazy<bool> =
+    Lazy::new(|| std::env::var("PERSIST_HEARTBEATS").is_ok_and(|value| value.trim() == "true"));
+
+/// Whether the variant is a heartbeat.
+/// Detailed heartbeats of older versions didn't contain the `heartbeat` field, they're recognized by their memory usage.
+pub fn is_heartbeat(variant: &StreamVariant) -> bool {
+    let StreamVariant::ServerHint(content) = variant else {
+        return false;
+    };
+    serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(content)
+        .is_ok_and(|hint| hint.contains_key("heartbeat") || hint.contains_key("process_memory"))
+}
+
+/// Returns a StreamVariant::ServerHint that is sent as a heartbeat to the client.
+/// It only marks that the server is alive, unless `DETAILED_HEARTBEAT` is set.
+/// If the code interpreter of the thread reported its progress, the latest report and the elapsed seconds are added as `progress` and `elapsed_secs`.
+pub async fn heartbeat_content(thread_id: &str) -> StreamVariant {
+    let heartbeat = if *DETAILED_HEARTBEAT {
+        detailed_heartbeat_content().await
+    } else {
+        minimal_heartbeat_content()
+    };
+    match current_progress(thread_id) {
+        Some((progress, elapsed)) => with_progress(heartbeat, &progress, elapsed),
+        None => heartbeat,
+    }
+}
+
+/// Adds the progress of the code interpreter to the heartbeat.
+fn with_progress(heartbeat: StreamVariant, progress: &str, elapsed: Duration) -> StreamVariant {
+    let StreamVariant::ServerHint(content) = &heartbeat else {
+        return heartbeat;
+    };
+    let Ok(serde_json::Value::Object(mut heartbeat_json)) = serde_json::from_str(content) else {
+        return heartbeat;
+    };
+    heartbeat_json.insert(
+        "progress".to_string(),
+        serde_json::Value::String(progress.to_string()),
+    );
+    heartbeat_json.insert(
+        "elapsed_secs".to_string(),
+        serde_json::Value::Number(serde_json::Number::from(elapsed.as_secs())),
+    );
+    StreamVariant::ServerHint(serde_json::Value::Object(heartbeat_json).to_string())
+}
+
+/// The heartbeat without any information about the server.
+fn minimal_heartbeat_content() -> StreamVariant {
+    StreamVariant::ServerHint(serde_json::json!({ "heartbeat": true }).to_string())
+}
+
 /// Returns a StreamVariant::ServerHint that contains some information about the server.
-/// Is intended to be sent as a heartbeat to the client.
-pub async fn heartbeat_content() -> StreamVariant {
+async fn detailed_heartbeat_content() -> StreamVariant {
     let mut heartbeat_json = serde_json::Map::new();
+    heartbeat_json.insert("heartbeat".to_string(), serde_json::Value::Bool(true));
 
     maybe_update(); // Update the system information to get the most recent data.
 
@@ -109,3 +183,27 @@ fn maybe_update() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_heartbeat_reveals_nothing_about_the_server() {
+        let StreamVariant::ServerHint(content) = minimal_heartbeat_content() else {
+            panic!("The heartbeat should be a ServerHint");
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&content).expect("The heartbeat should be valid JSON");
+        let keys = json
+            .as_object()
+            .expect("The heartbeat should be a JSON object")
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["heartbeat"]);
+        for field in ["memory", "cpu", "process", "host"] {
+            assert!(!content.contains(field), "Heartbeat contains {field}");
+        }
+        assert!(is_heartbeat(&minimal_heartbeat_content()));
+    }
+}
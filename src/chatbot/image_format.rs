@@ -0,0 +1,192 @@
+// The format in which the images of the code interpreter are sent to the client.
+
+use std::io::Cursor;
+
+use actix_web::http::header::HeaderMap;
+use base64::Engine;
+use mongodb::Database;
+use qstring::QString;
+use tracing::{debug, warn};
+
+use crate::auth::get_first_matching_field;
+
+use super::{
+    get_image::{image_path, thread_images},
+    types::StreamVariant,
+};
+
+/// The format of the images in the stream. The stored threads and the LLM always get the PNG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    /// The PNG as it comes from matplotlib, base64 encoded, which is what the frontend always expected.
+    #[default]
+    Png,
+    /// The image is re-encoded as (lossless) WebP, which is a lot smaller for plots.
+    /// The content of the Image variant is then a data URL (`data:image/webp;base64,...`), so it's tagged with its format.
+    WebP,
+    /// Instead of the image itself, the Image variant contains the path under which the client can fetch it as raw PNG,
+    /// see the get_image endpoint. That saves the base64 overhead and the decoding on the client.
+    Ref,
+}
+
+impl ImageFormat {
+    /// Clients can choose the format with the `image_format` parameter (`png` or `webp`),
+    /// or get references instead of the images with `images=ref`.
+    /// Unknown formats are ignored, so the client gets the PNG it can always decode.
+    pub fn from_request(qstring: &QString, headers: &HeaderMap) -> Self {
+        match get_first_matching_field(qstring, headers, &["images", "x-images"], false) {
+            Some("ref") => return Self::Ref,
+            Some("inline") | None => {}
+            Some(other) => warn!("Unknown image mode {:?}, sending the images inline.", other),
+        }
+        match get_first_matching_field(qstring, headers, &["image_format", "x-image-format"], false)
+        {
+            Some("webp") => Self::WebP,
+            Some("png") | None => Self::Png,
+            Some(other) => {
+                warn!("Unknown image format {:?}, sending PNGs.", other);
+                Self::Png
+            }
+        }
+    }
+
+    /// Converts an Image variant to the format; all other variants stay as they are.
+    /// If the image can't be re-encoded, the PNG is sent instead.
+    /// References need to know the thread of the image, so they're only created by `encode_for_thread`.
+    pub fn encode(self, variant: StreamVariant) -> StreamVariant {
+        match (self, variant) {
+            (Self::WebP, StreamVariant::Image(png)) => match png_to_webp(&png) {
+                Some(webp) => StreamVariant::Image(webp),
+                None => StreamVariant::Image(png),
+            },
+            (_, variant) => variant,
+        }
+    }
+
+    /// Converts the Image variants of a thread to the format, like `encode`.
+    /// References point to the images as they are in the thread (or its active conversation), so the images have to be added to it first.
+    /// An image that can't be found in the thread is sent inline instead.
+    pub async fn encode_for_thread(
+        self,
+        variants: Vec<StreamVariant>,
+        thread_id: &str,
+        database: Database,
+    ) -> Vec<StreamVariant> {
+        if self != Self::Ref
+            || !variants
+                .iter()
+                .any(|v| matches!(v, StreamVariant::Image(_)))
+        {
+            return variants
+                .into_iter()
+                .map(|variant| self.encode(variant))
+                .collect();
+        }
+        let images = match thread_images(thread_id, database).await {
+            Ok((images, _)) => images,
+            Err(e) => {
+                warn!(
+                    "Could not read the images of thread {} to reference them, sending them inline: {:?}",
+                    thread_id, e
+                );
+                vec![]
+            }
+        };
+        variants
+            .into_iter()
+            .map(|variant| match variant {
+                StreamVariant::Image(png) => match reference(&images, &png, thread_id) {
+                    Some(path) => StreamVariant::Image(path),
+                    None => StreamVariant::Image(png),
+                },
+                variant => variant,
+            })
+            .collect()
+    }
+}
+
+/// The path of an image among the images of its thread. Identical images have identical bytes, so any of them can be referenced.
+fn reference(images: &[String], image: &str, thread_id: &str) -> Option<String> {
+    images
+        .iter()
+        .rposition(|stored| stored == image)
+        .map(|index| image_path(thread_id, index))
+}
+
+/// Re-encodes a base64 encoded PNG as WebP and returns it as a base64 data URL.
+fn png_to_webp(encoded_png: &str) -> Option<String> {
+    let png = base64::engine::general_purpose::STANDARD
+        .decode(encoded_png.trim())
+        .inspect_err(|e| warn!("Could not decode the image to re-encode it: {:?}", e))
+        .ok()?;
+    let image = image::load_from_memory_with_format(&png, image::ImageFormat::Png)
+        .inspect_err(|e| warn!("Could not read the PNG to re-encode it: {:?}", e))
+        .ok()?;
+
+    let mut webp = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut webp), image::ImageFormat::WebP)
+        .inspect_err(|e| warn!("Could not encode the image as WebP: {:?}", e))
+        .ok()?;
+    debug!(
+        "Re-encoded image as WebP: {} bytes instead of {} bytes ({:.1}% smaller).",
+        webp.len(),
+        png.len(),
+        100.0 * (1.0 - webp.len() as f64 / png.len().max(1) as f64)
+    );
+
+    Some(format!(
+        "data:image/webp;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(webp)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webp_is_smaller_than_png() {
+        // A plot like matplotlib draws it: white background, black axes and a blue sine curve.
+        let plot = image::RgbImage::from_fn(640, 480, |x, y| {
+            let curve = 240.0 - 150.0 * (f64::from(x) / 50.0).sin();
+            if x == 60 || y == 420 {
+                image::Rgb([0, 0, 0])
+            } else if (f64::from(y) - curve).abs() < 2.0 {
+                image::Rgb([31, 119, 180])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let mut png = Vec::new();
+        plot.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .expect("The plot can be encoded as PNG");
+        let encoded_png = base64::engine::general_purpose::STANDARD.encode(&png);
+
+        let StreamVariant::Image(webp) =
+            ImageFormat::WebP.encode(StreamVariant::Image(encoded_png.clone()))
+        else {
+            panic!("The image stays an image");
+        };
+        let webp = webp
+            .strip_prefix("data:image/webp;base64,")
+            .expect("The WebP is tagged with its format");
+        assert!(webp.len() < encoded_png.len());
+
+        // PNG stays the default and is sent unchanged.
+        assert_eq!(
+            ImageFormat::default().encode(StreamVariant::Image(encoded_png.clone())),
+            StreamVariant::Image(encoded_png)
+        );
+    }
+
+    #[test]
+    fn test_image_reference_points_into_thread() {
+        let images = ["aW1hZ2U=".to_string(), "c3F1YXJl".to_string()];
+        assert_eq!(
+            reference(&images, "c3F1YXJl", "abc"),
+            Some("/api/chatbot/image/abc/1".to_string())
+        );
+        assert_eq!(reference(&images, "bmV3", "abc"), None);
+    }
+}
@@ -0,0 +1,206 @@
+// Some clients don't share a filesystem with the backend, so they can't point it to a freva config file.
+// They can send the content of the config instead, which is written to a private file for the thread and removed again when its conversation ends.
+
+use std::{io::Write, path::PathBuf};
+
+use actix_web::http::header::HeaderMap;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use tracing::{debug, warn};
+
+use crate::auth::get_first_matching_field;
+
+/// The directory the inline configs are written to, one file per thread.
+/// Set via the environment variable `INLINE_FREVA_CONFIG_DIR`; defaults to `freva_gpt_configs` in the temporary directory of the system.
+pub static INLINE_FREVA_CONFIG_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    std::env::var("INLINE_FREVA_CONFIG_DIR")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map_or_else(
+            || std::env::temp_dir().join("freva_gpt_configs"),
+            |value| PathBuf::from(value.trim()),
+        )
+});
+
+/// Freva configs are a few kilobytes at most; anything much larger isn't one.
+const MAX_INLINE_CONFIG_BYTES: usize = 64 * 1024;
+
+/// Reads the content of the freva config, if the client sent it instead of a path, as `freva_config_content`.
+pub fn freva_config_content_from_request<'a>(
+    qstring: &'a QString,
+    headers: &'a HeaderMap,
+) -> Option<&'a str> {
+    get_first_matching_field(
+        qstring,
+        headers,
+        &["freva_config_content", "x-freva-config-content"],
+        false,
+    )
+    .filter(|content| !content.trim().is_empty())
+}
+
+/// Checks that the content looks like a freva config: an INI file with an `[evaluation_system]` section.
+/// It doesn't check the values, freva itself reports those when it's used.
+pub fn validate_freva_config(content: &str) -> Result<(), String> {
+    if content.len() > MAX_INLINE_CONFIG_BYTES {
+        return Err(format!(
+            "The freva config is too large; it may be at most {MAX_INLINE_CONFIG_BYTES} bytes."
+        ));
+    }
+    if content.contains('\0') {
+        return Err("The freva config contains null bytes.".to_string());
+    }
+    let mut has_evaluation_system = false;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            has_evaluation_system |= line == "[evaluation_system]";
+        } else if !line.contains('=') && !line.contains(':') {
+            return Err(format!(
+                "The freva config isn't an INI file; the line {line:?} is neither a section nor a key and value."
+            ));
+        }
+    }
+    if !has_evaluation_system {
+        return Err("The freva config has no [evaluation_system] section.".to_string());
+    }
+    Ok(())
+}
+
+/// The file of the inline config of a thread. Only the safe characters of the thread id are used, so it can't point outside of the directory.
+pub fn inline_config_path(thread_id: &str) -> PathBuf {
+    let file_name = thread_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>();
+    INLINE_FREVA_CONFIG_DIR.join(format!("{file_name}.conf"))
+}
+
+/// The written inline config of a thread. Dropping it removes the file again, unless it was handed over to the conversation with `keep`,
+/// so a request that fails before its stream starts doesn't leave the config behind.
+pub struct InlineConfigFile {
+    thread_id: String,
+    keep: bool,
+}
+
+impl InlineConfigFile {
+    /// Leaves the file to the conversation of the thread, which removes it when it ends.
+    pub fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for InlineConfigFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            remove_inline_config(&self.thread_id);
+        }
+    }
+}
+
+/// Writes the inline config of a thread to its file at `inline_config_path`, to be used as `EVALUATION_SYSTEM_CONFIG_FILE`.
+/// The thread id has to be the final one, the conversation removes the file under the id it ends with.
+/// The directory and the file are only accessible to the backend, because the config may contain credentials.
+pub fn write_inline_config(thread_id: &str, content: &str) -> std::io::Result<InlineConfigFile> {
+    let mut dir_builder = std::fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut dir_builder, 0o700);
+    dir_builder.create(&*INLINE_FREVA_CONFIG_DIR)?;
+
+    let path = inline_config_path(thread_id);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)?.write_all(content.as_bytes())?;
+    debug!(
+        "Wrote the inline freva config of thread {} to {:?}",
+        thread_id, path
+    );
+    Ok(InlineConfigFile {
+        thread_id: thread_id.to_string(),
+        keep: false,
+    })
+}
+
+/// Removes the inline config of a thread, if it has one. Called when its conversation ends.
+pub fn remove_inline_config(thread_id: &str) {
+    match std::fs::remove_file(inline_config_path(thread_id)) {
+        Ok(()) => debug!("Removed the inline freva config of thread {}", thread_id),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(
+            "Error removing the inline freva config of thread {}: {:?}",
+            thread_id, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chatbot::handle_active_conversations::generate_id,
+        tool_calls::code_interpreter::{verify_can_access, ConfigAccessError},
+    };
+
+    #[test]
+    fn test_inline_config_is_written_used_and_removed() {
+        let content = "[evaluation_system]\n# The project\nproject_name = freva\nbase_dir_location = /tmp/freva\n\n[scheduler_options]\nqueue: compute\n";
+        assert_eq!(validate_freva_config(content), Ok(()));
+        for invalid in [
+            "",
+            "project_name = freva",
+            "[evaluation_system]\nthis is not a config",
+            "[evaluation_system]\nproject_name = \0",
+        ] {
+            assert!(validate_freva_config(invalid).is_err(), "{invalid:?}");
+        }
+
+        // The thread id can't escape the directory.
+        let thread_id = format!("../{}", generate_id());
+        let config = write_inline_config(&thread_id, content).expect("The config can be written");
+        let path = inline_config_path(&thread_id);
+        assert_eq!(path.parent(), Some(INLINE_FREVA_CONFIG_DIR.as_path()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)
+                .expect("The config exists")
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(&path).expect("The config can be read"),
+            content
+        );
+        // Only the backend sets it as the config of the thread, a client can't send its path.
+        assert_eq!(
+            verify_can_access(&path.to_string_lossy()),
+            Err(ConfigAccessError::NotAllowed)
+        );
+
+        // Once it's kept, the conversation removes it when it ends.
+        config.keep();
+        assert!(path.exists());
+        remove_inline_config(&thread_id);
+        assert!(!path.exists());
+        // Removing it again, like for threads without an inline config, does nothing.
+        remove_inline_config(&thread_id);
+    }
+
+    #[test]
+    fn test_inline_config_is_removed_if_the_stream_doesnt_start() {
+        let thread_id = generate_id();
+        let config = write_inline_config(&thread_id, "[evaluation_system]\nproject_name = freva\n")
+            .expect("The config can be written");
+        let path = inline_config_path(&thread_id);
+        assert!(path.exists());
+        drop(config);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,104 @@
+// Handles llama tool calls whose JSON can't be parsed, so they aren't silently lost.
+
+use once_cell::sync::Lazy;
+use tracing::debug;
+
+use super::types::StreamVariant;
+
+/// Whether the code of a malformed llama tool call is extracted leniently, so the tool call can still be run.
+/// If it's disabled or the extraction fails, the LLM is told that its tool call was malformed, so it can retry.
+/// Set via the environment variable `LENIENT_TOOL_CALLS`; defaults to true.
+pub static LENIENT_TOOL_CALLS: Lazy<bool> =
+    Lazy::new(|| std::env::var("LENIENT_TOOL_CALLS").map_or(true, |value| value.trim() != "false"));
+
+/// What the user and the LLM are told if a tool call couldn't be parsed.
+const MALFORMED_TOOL_CALL_MESSAGE: &str = "The tool call could not be executed because its JSON was malformed (for example because of unescaped quotes or newlines in the code). Please call the tool again with valid JSON.";
+
+/// Extracts the code from a code interpreter tool call whose JSON can't be parsed.
+/// The LLMs mostly break the JSON by not escaping the quotes in their code, so the code is taken to be
+/// everything from the opening quote of the `code` field to the last quote that is only followed by closing braces.
+pub fn extract_code_leniently(content: &str) -> Option<String> {
+    // Only the code interpreter takes code, so other tools aren't guessed at.
+    if !content.contains("\"code_interpreter\"") {
+        return None;
+    }
+    let (_, after_key) = content.split_once("\"code\"")?;
+    let value = after_key
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('"')?;
+    let end = value.rfind('"')?;
+    if !value[end + 1..]
+        .chars()
+        .all(|c| c == '}' || c.is_whitespace())
+    {
+        return None;
+    }
+    let code = unescape_leniently(&value[..end]);
+    debug!("Leniently extracted code from tool call: {:?}", code);
+    Some(code)
+}
+
+/// Resolves the JSON escapes of a string, but keeps everything that isn't a valid escape as it is.
+fn unescape_leniently(raw: &str) -> String {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(escaped @ ('"' | '\\' | '/')) => unescaped.push(escaped),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// The variants that answer a tool call that couldn't be parsed at all.
+/// The raw content is stored as the code, so the user sees what the LLM wrote; the LLM gets the error as the output of its tool call.
+pub fn malformed_tool_call_variants(content: &str, id: String) -> Vec<StreamVariant> {
+    vec![
+        StreamVariant::Code(
+            serde_json::json!({ "code": content }).to_string(),
+            id.clone(),
+        ),
+        StreamVariant::CodeError(MALFORMED_TOOL_CALL_MESSAGE.to_string()),
+        StreamVariant::CodeOutput(MALFORMED_TOOL_CALL_MESSAGE.to_string(), id),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_malformed_tool_call_payload() {
+        // The quotes inside of the code aren't escaped, so this isn't valid JSON.
+        let malformed = r#"{"name": "code_interpreter", "arguments": {"code": "ds = xr.open_dataset("tas.nc")\nprint(ds["tas"].mean())"}}"#;
+        assert!(serde_json::from_str::<serde_json::Value>(malformed).is_err());
+        assert_eq!(
+            extract_code_leniently(malformed).as_deref(),
+            Some("ds = xr.open_dataset(\"tas.nc\")\nprint(ds[\"tas\"].mean())")
+        );
+
+        // Without a recognizable code field, the LLM is told to retry instead.
+        let garbled = r#"{"name": "code_interpreter", "arguments": {"code": print(1)}}"#;
+        assert_eq!(extract_code_leniently(garbled), None);
+        let variants = malformed_tool_call_variants(garbled, "call_1".to_string());
+        assert!(matches!(&variants[..], [
+            StreamVariant::Code(code, code_id),
+            StreamVariant::CodeError(_),
+            StreamVariant::CodeOutput(output, output_id),
+        ] if code_id == output_id && output.contains("malformed") && code.contains("print(1)")));
+    }
+}
@@ -13,6 +13,15 @@ pub mod stop;
 /// Returns a thread as a list of strings
 pub mod get_thread;
 
+/// Deletes a thread with everything that is stored for it
+pub mod delete_thread;
+
+/// Returns a single message of a thread
+pub mod get_message;
+
+/// Returns a single image of a thread as raw PNG
+pub mod get_image;
+
 /// Internal use: handles the storing and retrieval of the streamed data
 pub mod thread_storage;
 
@@ -25,27 +34,96 @@ pub mod topic_extraction;
 /// Streams the response from the chatbot
 pub mod stream_response;
 
+/// Broadcasts status messages from the operators to all active streams
+pub mod broadcast;
+
+/// Re-runs the last turn of a thread
+pub mod regenerate;
+
+/// Answers an input in a single JSON response instead of a stream
+pub mod complete;
+
+/// Keeps the connection of long, silent streams alive
+pub mod transport_keep_alive;
+
+/// Separates the variants of a stream, either raw or as Server-Sent-Events
+pub mod stream_framing;
+
+/// Summarizes a turn at the end of its stream, if the client asks for it
+pub mod stream_summary;
+
+/// Adds the offset in their message to the Assistant deltas, if the client asks for it
+pub mod delta_offsets;
+
+/// Recovers or reports llama tool calls with malformed JSON
+pub mod lenient_tool_call;
+
+/// The parameters of the LLM that can be set per request
+pub mod request_params;
+
+/// Runs several tool calls of one response at the same time
+pub mod parallel_tool_calls;
+
+/// The format of the images in the stream, PNG or WebP
+pub mod image_format;
+
+/// Stores a freva config that was sent as content instead of a path for the duration of a conversation
+pub mod inline_freva_config;
+
+/// Stops sending requests to the LLM proxy for a while after it failed repeatedly
+pub mod circuit_breaker;
+
+/// Retries requests whose history doesn't fit into the context window with the older turns dropped
+pub mod context_overflow;
+
+/// Retries opening the stream of the LLM with backoff when it fails with a temporary error
+pub mod stream_retry;
+
+/// Replays a stored thread as if it was streamed live, for demos and development
+pub mod replay;
+
+/// Limits how many streams a single user can run at once and start per minute
+pub mod rate_limit;
+
 /// Routes requests to the storage backend (disk or mongoDB)
 pub mod storage_router;
 
 /// Handles the logic for storing and using the global conversation state
 pub mod handle_active_conversations;
 
+/// Saves the active conversations when the server shuts down
+pub mod shutdown;
+
 /// Defines the prompts for the chatbot
 pub mod prompting;
 
 /// The endpoint for returning the available chatbots
 pub mod available_chatbots_endpoint;
 
+/// The endpoint for returning the tools the LLM can call
+pub mod tools_endpoint;
+
+/// The endpoint for estimating the tokens of the next request of a thread
+pub mod token_estimate;
+
 /// Internally used to handle the heartbeat that is happening while the code interpreter is running.
 pub mod heartbeat;
 
+/// Optionally normalizes the user input and strips invisible or bidirectional control characters.
+pub mod sanitize_input;
+
+/// Truncates text on character boundaries, so no output is ever cut inside a multibyte character
+pub mod truncation;
+
 /// Handles the logic for continuing a conversation from a previous point in time. Specifically, the logic for finding the right point in time to continue from.
 pub mod filter_variants;
 
 // Defines a few useful static variables that are used throughout the chatbot.
 
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use async_openai::config::OpenAIConfig;
 use once_cell::sync::Lazy;
@@ -85,8 +163,13 @@ static REQWEST_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
 /// We want to use the LiteLLM Proxy. This is to check whether it is up. If it is, it'll return "I'm alive!".
 /// Timeout is 200 milliseconds; it's on another container on the same machine, the delay should be minimal.
 pub async fn is_lite_llm_running() -> bool {
+    is_lite_llm_running_at(&LITE_LLM_ADDRESS).await
+}
+
+/// Checks whether the LiteLLM Proxy at the address is up.
+async fn is_lite_llm_running_at(address: &str) -> bool {
     let response = REQWEST_CLIENT
-        .get(LITE_LLM_ADDRESS.to_string() + "/health/liveliness")
+        .get(address.to_string() + "/health/liveliness")
         .send()
         .await;
     if let Ok(response) = response {
@@ -99,3 +182,68 @@ pub async fn is_lite_llm_running() -> bool {
         false
     }
 }
+
+/// How long the result of a liveness check of the LiteLLM Proxy is reused, so not every stream pings it.
+const LITE_LLM_LIVENESS_TTL: Duration = Duration::from_secs(2);
+
+/// The result of the last liveness check of the LiteLLM Proxy and when it was made.
+#[derive(Debug, Default)]
+struct LivenessCache {
+    last_check: Option<(Instant, bool)>,
+}
+
+impl LivenessCache {
+    /// The result of the last check, if it's recent enough to be reused.
+    fn get(&self, now: Instant) -> Option<bool> {
+        self.last_check
+            .filter(|(checked_at, _)| now.duration_since(*checked_at) < LITE_LLM_LIVENESS_TTL)
+            .map(|(_, running)| running)
+    }
+
+    fn set(&mut self, running: bool, now: Instant) {
+        self.last_check = Some((now, running));
+    }
+}
+
+static LITE_LLM_LIVENESS: Lazy<Mutex<LivenessCache>> =
+    Lazy::new(|| Mutex::new(LivenessCache::default()));
+
+/// Like `is_lite_llm_running`, but reuses the result of a check from the last two seconds.
+/// Concurrent requests may all ping the proxy when the result is outdated; that's cheap and keeps the lock out of the await.
+pub async fn is_lite_llm_running_cached() -> bool {
+    let cached = LITE_LLM_LIVENESS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .get(Instant::now());
+    if let Some(running) = cached {
+        return running;
+    }
+    let running = is_lite_llm_running().await;
+    LITE_LLM_LIVENESS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .set(running, Instant::now());
+    running
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_unreachable_lite_llm_is_down_and_cached_briefly() {
+        // Nothing listens on a port that was just freed again.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("A free port can be found")
+            .port();
+        assert!(!is_lite_llm_running_at(&format!("http://127.0.0.1:{port}")).await);
+
+        let start = Instant::now();
+        let mut cache = LivenessCache::default();
+        assert_eq!(cache.get(start), None);
+        cache.set(false, start);
+        assert_eq!(cache.get(start + Duration::from_millis(1500)), Some(false));
+        assert_eq!(cache.get(start + LITE_LLM_LIVENESS_TTL), None);
+    }
+}
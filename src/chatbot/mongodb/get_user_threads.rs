@@ -3,14 +3,56 @@ use documented::docs_const;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    auth::get_first_matching_field,
-    chatbot::mongodb::mongodb_storage::{get_database, read_threads_and_num},
+    auth::{get_first_matching_field, get_tenant},
+    chatbot::mongodb::mongodb_storage::{get_database, read_thread_page},
 };
 
+/// How many threads a page contains if the client doesn't say.
+const DEFAULT_LIMIT: u32 = 20;
+
+/// The most threads a single page can contain.
+const MAX_LIMIT: u32 = 100;
+
+/// Parses the size and start of the page. Larger limits than MAX_LIMIT are reduced to it.
+/// The page number of older clients is still understood, if no offset is given.
+fn parse_pagination(
+    limit: Option<&str>,
+    offset: Option<&str>,
+    page: Option<&str>,
+) -> Result<(u32, u64), String> {
+    let limit = match limit {
+        None => DEFAULT_LIMIT,
+        Some(limit) => limit
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("The limit must be a non-negative integer, not {limit:?}."))?
+            .min(MAX_LIMIT),
+    };
+    let offset =
+        match (offset, page) {
+            (Some(offset), _) => offset.trim().parse::<u64>().map_err(|_| {
+                format!("The offset must be a non-negative integer, not {offset:?}.")
+            })?,
+            (None, Some(page)) => {
+                page.trim().parse::<u64>().map_err(|_| {
+                    format!("The page must be a non-negative integer, not {page:?}.")
+                })? * u64::from(limit)
+            }
+            (None, None) => 0,
+        };
+    Ok((limit, offset))
+}
+
 /// # getuserthreads
-/// Takes in a vault_url and returns the latest n threads of the user. Requires Authentication.
-/// n is an optional parameter that defaults to 10.
-/// if a page number (0-based) is passed, it instead paginates and uses that page number
+/// Takes in a vault_url and returns a page of the threads of the user, newest first. Requires Authentication.
+///
+/// The page is chosen with the optional `limit` (how many threads, 20 by default and at most 100) and `offset` (how many of the newest threads to skip, 0 by default).
+/// For older clients, `n` is understood as the limit and a (0-based) `page` number as the offset in pages.
+///
+/// Returns a JSON object with the `threads` of the page, each with its `thread_id`, `topic` and `date`,
+/// and the `total` number of threads of the user. A page past the last thread is empty.
+///
+/// If the limit, offset or page isn't a non-negative integer, a BadRequest response is returned.
 ///
 /// If the vault_url is missing or empty, an UnprocessableEntity response is returned.
 ///
@@ -30,6 +72,32 @@ pub async fn get_user_threads(req: HttpRequest) -> impl Responder {
 
     debug!("User ID: {}", user_id);
 
+    // The page is checked before connecting to the database, there's no need to connect for an invalid request.
+    let (limit, offset) = match parse_pagination(
+        get_first_matching_field(
+            &qstring,
+            headers,
+            &[
+                "limit",
+                "num_threads",
+                "num-threads",
+                "n_threads",
+                "n-threads",
+                "n",
+            ],
+            false,
+        ),
+        get_first_matching_field(&qstring, headers, &["offset"], false),
+        get_first_matching_field(&qstring, headers, &["page"], false),
+    ) {
+        Ok(pagination) => pagination,
+        Err(e) => {
+            warn!("The User requested threads with an invalid page: {}", e);
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+    trace!("Final limit and offset: {}, {}", limit, offset);
+
     // We first need to check whether we have a vault URL to connect to the database from.
     let maybe_vault_url = get_first_matching_field(
         &qstring,
@@ -50,37 +118,70 @@ pub async fn get_user_threads(req: HttpRequest) -> impl Responder {
             .body("Vault URL not found. Please provide a non-empty vault URL in the headers.");
     };
 
-    let database = match get_database(vault_url).await {
+    let database = match get_database(vault_url, get_tenant(headers).as_deref()).await {
         Ok(db) => db,
         Err(e) => {
             debug!("Failed to connect to the database: {:?}", e);
-            return HttpResponse::ServiceUnavailable().body("Failed to connect to the database.");
+            return e;
         }
     };
 
-    // Try to get n from the qstring
-    let n = match get_first_matching_field(
-        &qstring,
-        headers,
-        &["num_threads", "num-threads", "n_threads", "n-threads", "n"],
-        false,
-    ) {
-        Some(n) => {
-            debug!("Parsed num_threads: {}", n);
-            n.parse::<u32>().unwrap_or(10)
-        }
-        None => 10,
+    // Retrieve the page of threads of the user from the database.
+    let (threads, total) = read_thread_page(&user_id, database, limit, offset).await;
+
+    debug!("Threads: {:?}", threads);
+    HttpResponse::Ok().json(serde_json::json!({
+        "threads": threads,
+        "total": total,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::{
+        mongodb::mongodb_storage::{append_thread, test_database},
+        types::StreamVariant,
     };
-    trace!("Final num_threads: {}", n);
 
-    let page = get_first_matching_field(&qstring, headers, &["page"], false)
-        .and_then(|p| p.parse::<u32>().ok());
+    #[test]
+    fn test_pagination_is_parsed() {
+        assert_eq!(parse_pagination(None, None, None), Ok((20, 0)));
+        assert_eq!(
+            parse_pagination(Some("500"), Some("40"), None),
+            Ok((100, 40))
+        );
+        assert_eq!(parse_pagination(Some("10"), None, Some("2")), Ok((10, 20)));
+        assert!(parse_pagination(Some("-1"), None, None).is_err());
+        assert!(parse_pagination(None, Some("ten"), None).is_err());
+    }
 
-    // Retrieve the latest n threads of the user from the database.
-    let threads = read_threads_and_num(&user_id, database, n, page).await;
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_pages_past_the_end_are_empty() {
+        let database = test_database("freva_gpt_pagination_test").await;
+        for thread in 0..5 {
+            append_thread(
+                &format!("thread{thread}"),
+                "testuser",
+                vec![StreamVariant::User(format!("question {thread}"))],
+                None,
+                database.clone(),
+            )
+            .await;
+        }
 
-    debug!("Threads: {:?}", threads);
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .json(threads)
+        // The last page only has the rest of the threads.
+        let (threads, total) = read_thread_page("testuser", database.clone(), 2, 4).await;
+        assert_eq!(total, 5);
+        assert_eq!(threads.len(), 1);
+        // After the last thread, the page is empty, but still knows how many threads there are.
+        let (threads, total) = read_thread_page("testuser", database.clone(), 2, 6).await;
+        assert_eq!((threads, total), (vec![], 5));
+
+        database
+            .drop()
+            .await
+            .expect("The test database can be cleared");
+    }
 }
@@ -7,3 +7,11 @@ pub mod get_user_threads;
 pub mod set_thread_topic;
 
 pub mod search_threads;
+
+pub mod share_thread;
+
+pub mod text_search;
+
+pub mod thread_meta;
+
+pub mod user_settings;
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     env,
     sync::{Arc, Mutex},
 };
@@ -15,7 +16,12 @@ use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     auth::get_mongodb_uri,
-    chatbot::{thread_storage::cleanup_conversation, topic_extraction::summarize_topic, types},
+    chatbot::{
+        thread_storage::{cleanup_conversation, CURRENT_SCHEMA_VERSION},
+        topic_extraction::{summarize_topic, topic_source, TOPIC_STRATEGY},
+        truncation::truncate_chars,
+        types::{StreamVariant, TokenUsage},
+    },
 };
 
 /// Stores and loads threads from the mongoDB
@@ -30,6 +36,34 @@ pub struct MongoDBThread {
     pub date: String,  // ISO 8601 date
     pub topic: String, // The first message in the thread, for now. Later maybe a summary of the thread.
     pub content: Conversation,
+    #[serde(default)]
+    // Threads stored before the full-text search existed don't have it, they can only be found by their topic.
+    pub user_text: String, // All inputs of the user, for the full-text search (see user_text).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    // Older threads were stored without the usage, and some providers never report it.
+    pub usage: Option<TokenUsage>, // The tokens used over all turns of the thread.
+    #[serde(default)] // Threads stored before the schema was versioned are version 0.
+    pub schema_version: u32, // See CURRENT_SCHEMA_VERSION.
+}
+
+/// Upgrades a thread that was stored with an older schema version to the current one.
+/// Threads in the MongoDB were always stored as serialized variants, so version 0 and 1 have the same shape;
+/// the version is only recorded, so the thread is stored with it the next time it's appended to.
+pub fn migrate_thread(mut thread: MongoDBThread) -> MongoDBThread {
+    if thread.schema_version == 0 {
+        debug!(
+            "Migrating thread {} from schema version 0.",
+            thread.thread_id
+        );
+        thread.schema_version = 1;
+    }
+    if thread.schema_version > CURRENT_SCHEMA_VERSION {
+        warn!(
+            "Thread {} has schema version {}, which is newer than {}; reading it as the current version.",
+            thread.thread_id, thread.schema_version, CURRENT_SCHEMA_VERSION
+        );
+    }
+    thread
 }
 
 /// Stores a thread in the mongoDB database, appending the content if the thread already exists.
@@ -37,8 +71,30 @@ pub async fn append_thread(
     thread_id: &str,
     user_id: &str,
     content: Conversation,
+    usage: Option<TokenUsage>,
     database: Database,
 ) {
+    if let Some((topic_source, provisional)) =
+        store_thread(thread_id, user_id, content, usage, database.clone()).await
+    {
+        spawn_topic_update(
+            thread_id.to_string(),
+            provisional,
+            async move { summarize_topic(&topic_source).await },
+            database,
+        );
+    }
+}
+
+/// Stores the thread like `append_thread`, but leaves its summary to the caller.
+/// For a new thread, returns the source of its topic and the provisional topic it was stored with.
+async fn store_thread(
+    thread_id: &str,
+    user_id: &str,
+    content: Conversation,
+    usage: Option<TokenUsage>,
+    database: Database,
+) -> Option<(String, String)> {
     debug!(
         "Will append content to thread {} for user {}",
         thread_id, user_id
@@ -50,7 +106,7 @@ pub async fn append_thread(
 
     if content.is_empty() {
         debug!("Content is empty, will not append to thread.");
-        return;
+        return None;
     }
 
     // We first need to retrieve the thread from the database, if it exists.
@@ -58,33 +114,45 @@ pub async fn append_thread(
 
     // If there is some existing thread, we need to update the content.
     // The new content is the old content + the new content.
-    let (content, thread_exists, maybe_topic) = if let Some(existing_thread) = existing_thread {
-        let mut existing_content = existing_thread.content;
-        existing_content.append(&mut content);
-        debug!("Found existing thread, will append content.");
-        (existing_content, true, Some(existing_thread.topic))
-    } else {
-        debug!("No existing thread found, will create a new one.");
-        (content, false, None)
-    };
+    // The same goes for the token usage, which is a running total over all turns.
+    let (content, thread_exists, maybe_topic, usage) =
+        if let Some(existing_thread) = existing_thread {
+            let mut existing_content = existing_thread.content;
+            existing_content.append(&mut content);
+            let total_usage = TokenUsage::combine(existing_thread.usage, usage);
+            debug!("Found existing thread, will append content.");
+            (
+                existing_content,
+                true,
+                Some(existing_thread.topic),
+                total_usage,
+            )
+        } else {
+            debug!("No existing thread found, will create a new one.");
+            (content, false, None, usage)
+        };
 
     // If the thread exists in the DB, we need to overwrite it.
     // If not, we need to create a new thread.
 
-    // We also need to find the first message of the thread, which should be the user input (for now).
-    let first_message = content.iter().rev().find_map(|variant| match variant {
-        types::StreamVariant::User(input) => Some(input),
-        _ => None,
-    });
+    // We also need to find what to base the topic on, usually the first message of the thread (see the TOPIC_STRATEGY).
+    // Only new threads need it, the existing ones keep their topic.
+    let topic_source = if maybe_topic.is_none() {
+        topic_source(&content, *TOPIC_STRATEGY)
+    } else {
+        None
+    };
 
-    debug!("Found first message: {:?}", first_message);
+    debug!("Found topic source: {:?}", topic_source);
 
-    // The topic is either what is already in the database, or the first message, summarized.
-    let topic = match (maybe_topic, first_message) {
-        (Some(existing_topic), _) => existing_topic,
-        (None, Some(first_message)) => summarize_topic(first_message).await,
-        _ => "No message found".to_owned(),
+    // The topic is either what is already in the database, or the topic source, summarized.
+    // Summarizing asks the LLM, so a new thread is stored with a provisional topic first, which the summary replaces once it's done.
+    let (topic, pending_summary) = match (maybe_topic, topic_source) {
+        (Some(existing_topic), _) => (existing_topic, None),
+        (None, Some(topic_source)) => (provisional_topic(&content), Some(topic_source)),
+        _ => ("No message found".to_owned(), None),
     };
+    let provisional = pending_summary.as_ref().map(|_| topic.clone());
 
     let date = chrono::Utc::now().to_rfc3339(); // Also ISO 8601 compliant
 
@@ -96,12 +164,35 @@ pub async fn append_thread(
                 "Failed to convert content to BSON: {:?}; cannot store thread!",
                 e
             );
-            return;
+            return None;
+        }
+    };
+
+    // If the provider never reported the usage, none is stored, instead of a total of zero tokens.
+    let usage_bson = match usage.as_ref().map(mongodb::bson::to_bson).transpose() {
+        Ok(usage_bson) => usage_bson,
+        Err(e) => {
+            warn!(
+                "Failed to convert usage to BSON: {:?}; cannot store thread!",
+                e
+            );
+            return None;
         }
     };
 
     // If the topic exists, we need to update the thread.
     if thread_exists {
+        let mut update = doc! {
+            "content": content_bson,
+            "user_text": user_text(&content),
+            "date": date,
+            "topic": topic,
+            "user_id": user_id,
+            "schema_version": CURRENT_SCHEMA_VERSION,
+        };
+        if let Some(usage_bson) = usage_bson {
+            update.insert("usage", usage_bson);
+        }
         let result = database
             .clone()
             .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
@@ -110,12 +201,7 @@ pub async fn append_thread(
                     "thread_id": thread_id
                 },
                 doc! {
-                    "$set": {
-                        "content": content_bson,
-                        "date": date,
-                        "topic": topic,
-                        "user_id": user_id,
-                    }
+                    "$set": update
                 },
             )
             .await;
@@ -139,7 +225,10 @@ pub async fn append_thread(
             thread_id: thread_id.to_string(),
             date,
             topic,
+            user_text: user_text(&content),
             content,
+            usage,
+            schema_version: CURRENT_SCHEMA_VERSION,
         };
 
         let result = database
@@ -160,6 +249,159 @@ pub async fn append_thread(
             }
         }
     }
+
+    pending_summary.zip(provisional)
+}
+
+/// The topic a new thread is stored with until its summary is done: the start of the first message of the user.
+fn provisional_topic(content: &Conversation) -> String {
+    const MAX_CHARS: usize = 60;
+    let first_line = content
+        .iter()
+        .find_map(|variant| match variant {
+            StreamVariant::User(input) => input.lines().find(|line| !line.trim().is_empty()),
+            _ => None,
+        })
+        .unwrap_or_default()
+        .trim();
+    if first_line.is_empty() {
+        "New conversation".to_string()
+    } else if first_line.chars().count() > MAX_CHARS {
+        format!("{}...", truncate_chars(first_line, MAX_CHARS))
+    } else {
+        first_line.to_string()
+    }
+}
+
+/// The inputs of the user in a thread, which is what the full-text search looks through besides the topic.
+/// The answers, code and prompt aren't included; they contain too many common words to be useful.
+fn user_text(content: &Conversation) -> String {
+    content
+        .iter()
+        .filter_map(|variant| match variant {
+            StreamVariant::User(input) => Some(input.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Waits for the summary of a new thread in the background and then replaces its provisional topic with it.
+/// If the topic was changed in the meantime (for example by the user), it's left alone.
+fn spawn_topic_update<F>(
+    thread_id: String,
+    provisional: String,
+    summary: F,
+    database: Database,
+) -> tokio::task::JoinHandle<()>
+where
+    F: std::future::Future<Output = String> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let summary = summary.await;
+        let result = database
+            .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
+            .update_one(
+                doc! { "thread_id": &thread_id, "topic": &provisional },
+                doc! { "$set": { "topic": &summary } },
+            )
+            .await;
+        match result {
+            Ok(update_result) if update_result.matched_count == 0 => {
+                debug!(
+                    "The topic of thread {} was changed before its summary was done, keeping it.",
+                    thread_id
+                );
+            }
+            Ok(_) => debug!("Set the topic of thread {} to {:?}.", thread_id, summary),
+            Err(e) => warn!(
+                "Failed to set the summarized topic of thread {}: {:?}",
+                thread_id, e
+            ),
+        }
+    })
+}
+
+/// Cuts the content of a stored thread to its first `keep` variants, for a turn that is regenerated.
+/// The token usage and topic stay as they are.
+pub async fn truncate_thread(thread_id: &str, keep: usize, database: Database) {
+    let Some(thread) = read_thread(thread_id, database.clone()).await else {
+        warn!(
+            "Tried to truncate thread {}, but it doesn't exist.",
+            thread_id
+        );
+        return;
+    };
+    let mut content = thread.content;
+    content.truncate(keep);
+    let content_bson = match mongodb::bson::to_bson(&content) {
+        Ok(content_bson) => content_bson,
+        Err(e) => {
+            warn!(
+                "Failed to convert content to BSON: {:?}; cannot truncate thread!",
+                e
+            );
+            return;
+        }
+    };
+
+    let result = database
+        .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
+        .update_one(
+            doc! { "thread_id": thread_id },
+            doc! { "$set": { "content": content_bson, "user_text": user_text(&content) } },
+        )
+        .await;
+    match result {
+        Ok(update_result) => {
+            debug!("Truncated thread {} to {} variants.", thread_id, keep);
+            trace!("Update result: {:?}", update_result);
+        }
+        Err(e) => warn!("Failed to truncate thread in database: {:?}", e),
+    }
+}
+
+/// A turn of a thread as it was streamed, before it was concatenated and cleaned up.
+/// These are stored in their own collection (see `MONGODB_RAW_COLLECTION_NAME`), one document per turn.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MongoDBRawTurn {
+    pub user_id: String,
+    pub thread_id: String,
+    pub date: String, // ISO 8601 date
+    pub content: Conversation,
+}
+
+/// Stores the uncleaned variants of a turn next to the thread, see `STORE_RAW_CONVERSATIONS`.
+/// The raw copy is never read by the backend itself, it's only for debugging and analysis.
+pub async fn append_raw_thread(
+    thread_id: &str,
+    user_id: &str,
+    content: Conversation,
+    database: Database,
+) {
+    if content.is_empty() {
+        debug!("Raw content is empty, will not store it.");
+        return;
+    }
+    let turn = MongoDBRawTurn {
+        user_id: user_id.to_string(),
+        thread_id: thread_id.to_string(),
+        date: chrono::Utc::now().to_rfc3339(),
+        content,
+    };
+    match database
+        .collection::<MongoDBRawTurn>(&MONGODB_RAW_COLLECTION_NAME)
+        .insert_one(turn)
+        .await
+    {
+        Ok(insert_result) => {
+            debug!("Inserted raw turn into database.");
+            trace!("Insert result: {:?}", insert_result);
+        }
+        Err(e) => {
+            warn!("Failed to insert raw turn into database: {:?}", e);
+        }
+    }
 }
 
 /// Loads a thread from the mongoDB database, by thread_id.
@@ -179,7 +421,7 @@ pub async fn read_thread(thread_id: &str, database: Database) -> Option<MongoDBT
         Ok(inner) => {
             debug!("Loaded thread from database.");
             // The thread may or may not exist, but we just return the option.
-            inner
+            inner.map(migrate_thread)
         }
         Err(e) => {
             info!("Failed to load thread: {:?}; expecting it to not exist", e);
@@ -188,37 +430,87 @@ pub async fn read_thread(thread_id: &str, database: Database) -> Option<MongoDBT
     }
 }
 
-/// Recieves a user_id and returns the last n threads of the user as well as the number of threads that user has.
-/// Supports naive pagination.
-pub async fn read_threads_and_num(
-    user_id: &str,
-    database: Database,
-    n: u32,
-    page: Option<u32>,
-) -> (Vec<MongoDBThread>, u64) {
-    debug!("Will load threads for user {}", user_id);
-
-    // Query the database by user_id.
+/// Checks whether a thread with the given ID exists in the database.
+/// If the database can't be queried, the thread is assumed not to exist.
+pub async fn thread_exists(thread_id: &str, database: Database) -> bool {
     let result = database
-        .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
-        .find(doc! {
-            "user_id": user_id
-        })
-        .limit(-std::convert::Into::<i64>::into(n)) // Don't do n requests, do a single one for all n.
-        .sort(doc! {
-            "date": -1
+        .collection::<Document>(&MONGODB_COLLECTION_NAME)
+        .count_documents(doc! {
+            "thread_id": thread_id
         })
-        .skip(page.unwrap_or(0) as u64 * n as u64) // Skip to the correct page
+        .limit(1) // We only care whether there is one, so the database can stop at the first.
         .await;
 
-    // TODO: skip+limit is an antipattern for a good reason; this basically needs to look through the entire database because of the skip.
-    // Maybe (depending on the inner workings of MongoDB), using a Single Field Index with the user_id as key might improve performance.
-    // Additionally, using aggregation pipelines could help with performance much more, but I am not sure how they work, and the examples don't make sense to me.
-    // If we get some performance problems, I'll look into it again.
+    match result {
+        Ok(count) => count > 0,
+        Err(e) => {
+            warn!(
+                "Failed to check whether thread {} exists: {:?}; assuming it doesn't",
+                thread_id, e
+            );
+            false
+        }
+    }
+}
 
-    // Additionally, we need to ask the database how many threads the user has in total.
+/// Deletes a thread from the database, together with its raw copies and shares.
+/// Returns whether the thread existed.
+pub async fn delete_thread(
+    thread_id: &str,
+    database: Database,
+) -> Result<bool, mongodb::error::Error> {
+    let result = database
+        .collection::<Document>(&MONGODB_COLLECTION_NAME)
+        .delete_one(doc! { "thread_id": thread_id })
+        .await?;
+    // The copies only exist for the thread, they're not counted separately.
+    for collection in [
+        &*MONGODB_RAW_COLLECTION_NAME,
+        &*MONGODB_SHARES_COLLECTION_NAME,
+    ] {
+        let copies = database
+            .collection::<Document>(collection)
+            .delete_many(doc! { "thread_id": thread_id })
+            .await?;
+        trace!(
+            "Deleted {} documents of thread {} from {}.",
+            copies.deleted_count,
+            thread_id,
+            collection
+        );
+    }
+    debug!(
+        "Deleted thread {} from the database: {}",
+        thread_id,
+        result.deleted_count > 0
+    );
+    Ok(result.deleted_count > 0)
+}
+
+/// A thread in the list of the threads of a user, without its content.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThreadSummary {
+    pub thread_id: String,
+    pub topic: String,
+    pub date: String, // ISO 8601 date
+}
+
+/// Recieves a user_id and returns a page of the threads of the user, newest first, as well as the number of threads that user has.
+/// The page starts after the first `offset` threads and contains at most `limit` threads.
+pub async fn read_thread_page(
+    user_id: &str,
+    database: Database,
+    limit: u32,
+    offset: u64,
+) -> (Vec<ThreadSummary>, u64) {
+    debug!(
+        "Will load {} threads starting at {} for user {}",
+        limit, offset, user_id
+    );
+
+    // We need to ask the database how many threads the user has in total.
     let total_threads = database
-        .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
+        .collection::<ThreadSummary>(&MONGODB_COLLECTION_NAME)
         .count_documents(doc! {
             "user_id": user_id
         })
@@ -235,17 +527,43 @@ pub async fn read_threads_and_num(
         }
     };
 
-    match result {
-        Ok(mut inner) => {
-            debug!("Loaded threads from database.");
-            // The logic for collecting the threads is a bit tricky.
-            let mut thread_vec = Vec::new();
+    // A limit of 0 would mean no limit for MongoDB, but the client asked for an empty page.
+    if limit == 0 {
+        return (vec![], total_threads);
+    }
 
-            while let Ok(Some(inner)) = inner.try_next().await {
-                thread_vec.push(inner);
-            }
+    // Query the database by user_id. Threads with the same date are ordered by their id, so the pages don't overlap.
+    let result = database
+        .collection::<ThreadSummary>(&MONGODB_COLLECTION_NAME)
+        .find(doc! {
+            "user_id": user_id
+        })
+        .projection(doc! {
+            "_id": 0,
+            "thread_id": 1,
+            "topic": 1,
+            "date": 1,
+        })
+        .sort(doc! {
+            "date": -1,
+            "thread_id": -1,
+        })
+        .skip(offset)
+        .limit(-i64::from(limit)) // Don't do n requests, do a single one for all n.
+        .await;
+
+    // TODO: skip+limit is an antipattern for a good reason; this basically needs to look through the entire database because of the skip.
+    // Maybe (depending on the inner workings of MongoDB), using a Single Field Index with the user_id as key might improve performance.
+    // If we get some performance problems, I'll look into it again.
 
-            (thread_vec, total_threads)
+    match result {
+        Ok(cursor) => {
+            debug!("Loaded threads from database.");
+            let threads = cursor.try_collect().await.unwrap_or_else(|e| {
+                warn!("Failed to read the threads of user {}: {:?}", user_id, e);
+                vec![]
+            });
+            (threads, total_threads)
         }
         Err(e) => {
             info!("Failed to load threads: {:?}; expecting it to not exist", e);
@@ -254,6 +572,56 @@ pub async fn read_threads_and_num(
     }
 }
 
+/// The metadata of a thread, for showing it in a tab without loading its content.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThreadMeta {
+    pub thread_id: String,
+    pub user_id: String,
+    pub topic: String,
+    pub date: String,       // ISO 8601 date
+    pub message_count: u64, // The number of User and Assistant variants.
+}
+
+/// The aggregation that reads the metadata of a thread.
+/// The messages are counted by the database, so the content itself is never sent over or deserialized.
+fn thread_meta_pipeline(thread_id: &str) -> Vec<Document> {
+    vec![
+        doc! { "$match": { "thread_id": thread_id } },
+        doc! { "$limit": 1 },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "thread_id": 1,
+                "user_id": 1,
+                "topic": 1,
+                "date": 1,
+                "message_count": {
+                    "$size": {
+                        "$filter": {
+                            "input": { "$ifNull": ["$content", []] },
+                            "cond": { "$in": ["$$this.variant", ["User", "Assistant"]] },
+                        }
+                    }
+                },
+            }
+        },
+    ]
+}
+
+/// Reads the metadata of a thread, by thread_id. Returns None if the thread doesn't exist.
+pub async fn read_thread_meta(
+    thread_id: &str,
+    database: Database,
+) -> Result<Option<ThreadMeta>, mongodb::error::Error> {
+    debug!("Will load the metadata of thread {}", thread_id);
+    let mut cursor = database
+        .collection::<Document>(&MONGODB_COLLECTION_NAME)
+        .aggregate(thread_meta_pipeline(thread_id))
+        .with_type::<ThreadMeta>()
+        .await?;
+    cursor.try_next().await
+}
+
 /// Updates the topic of a given thread of a specific user
 pub async fn update_topic(
     thread_id: &str,
@@ -297,6 +665,228 @@ pub async fn update_topic(
     }
 }
 
+/// A read-only link to a thread, which the owner can give to colleagues.
+/// These are stored in their own collection (see `MONGODB_SHARES_COLLECTION_NAME`), one document per link.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MongoDBShare {
+    pub token: String,
+    pub thread_id: String,
+    pub user_id: String, // The owner of the thread, who shared it.
+    pub expires_at: i64, // Unix timestamp in seconds
+    #[serde(default)]
+    pub revoked: bool,
+    #[serde(default)] // Shares created before the maximum lifetime existed only have their expiry.
+    pub created_at: Option<i64>, // Unix timestamp in seconds
+    #[serde(default)]
+    pub version: u32, // The share version of the thread when it was shared, see `bump_share_version`.
+}
+
+/// Stores a new share of a thread.
+pub async fn insert_share(share: MongoDBShare, database: Database) -> Result<(), HttpResponse> {
+    match database
+        .collection::<MongoDBShare>(&MONGODB_SHARES_COLLECTION_NAME)
+        .insert_one(share)
+        .await
+    {
+        Ok(insert_result) => {
+            debug!("Inserted share into database.");
+            trace!("Insert result: {:?}", insert_result);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to insert share into database: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to share the thread"))
+        }
+    }
+}
+
+/// Loads a share by its token. Expired and revoked shares are returned as well, so the caller can tell them apart.
+pub async fn read_share(token: &str, database: Database) -> Option<MongoDBShare> {
+    match database
+        .collection::<MongoDBShare>(&MONGODB_SHARES_COLLECTION_NAME)
+        .find_one(doc! { "token": token })
+        .await
+    {
+        Ok(share) => share,
+        Err(e) => {
+            info!("Failed to load share: {:?}; expecting it to not exist", e);
+            None
+        }
+    }
+}
+
+/// Revokes a share of the given user. Returns whether there was such a share.
+pub async fn revoke_share(
+    token: &str,
+    user_id: &str,
+    database: Database,
+) -> Result<bool, HttpResponse> {
+    let result = database
+        .collection::<MongoDBShare>(&MONGODB_SHARES_COLLECTION_NAME)
+        .update_one(
+            doc! {
+                "token": token,
+                "user_id": user_id
+            },
+            doc! {
+                "$set": {
+                    "revoked": true,
+                }
+            },
+        )
+        .await;
+
+    match result {
+        Ok(update_result) => {
+            debug!("Revoked share in database.");
+            trace!("Update result: {:?}", update_result);
+            Ok(update_result.matched_count > 0)
+        }
+        Err(e) => {
+            warn!("Failed to revoke share in database: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to revoke the share"))
+        }
+    }
+}
+
+/// Lists the shares of a thread of the given user that are neither revoked nor expired.
+/// Shares of an older share version are listed too, the caller has to compare the versions.
+pub async fn list_shares(
+    thread_id: &str,
+    user_id: &str,
+    now: i64,
+    database: Database,
+) -> Result<Vec<MongoDBShare>, HttpResponse> {
+    let result = database
+        .collection::<MongoDBShare>(&MONGODB_SHARES_COLLECTION_NAME)
+        .find(doc! {
+            "thread_id": thread_id,
+            "user_id": user_id,
+            "revoked": false,
+            "expires_at": { "$gt": now },
+        })
+        .await;
+
+    match result {
+        Ok(cursor) => cursor.try_collect().await.map_err(|e| {
+            warn!("Failed to read shares from database: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to list the shares")
+        }),
+        Err(e) => {
+            warn!("Failed to list shares in database: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to list the shares"))
+        }
+    }
+}
+
+/// Reads the share version of a thread. Threads that were never revoked (or aren't in the database) are at version 0.
+pub async fn read_share_version(thread_id: &str, database: Database) -> Result<u32, HttpResponse> {
+    let result = database
+        .collection::<Document>(&MONGODB_COLLECTION_NAME)
+        .find_one(doc! { "thread_id": thread_id })
+        .projection(doc! { "_id": 0, "share_version": 1 })
+        .await;
+
+    match result {
+        Ok(document) => Ok(document
+            .and_then(|document| document.get_i64("share_version").ok())
+            .and_then(|version| u32::try_from(version).ok())
+            .unwrap_or(0)),
+        Err(e) => {
+            // Without the version, revoked links can't be told apart, so they aren't let through.
+            warn!("Failed to read the share version from database: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to check the share"))
+        }
+    }
+}
+
+/// Revokes all shares of a thread of the given user at once by bumping its share version;
+/// shares created with an older version can't be used anymore. Returns whether the user has such a thread.
+pub async fn bump_share_version(
+    thread_id: &str,
+    user_id: &str,
+    database: Database,
+) -> Result<bool, HttpResponse> {
+    let result = database
+        .collection::<Document>(&MONGODB_COLLECTION_NAME)
+        .update_one(
+            doc! {
+                "thread_id": thread_id,
+                "user_id": user_id
+            },
+            doc! {
+                "$inc": {
+                    "share_version": 1_i64,
+                }
+            },
+        )
+        .await;
+
+    match result {
+        Ok(update_result) => {
+            debug!("Bumped share version of thread {} in database.", thread_id);
+            trace!("Update result: {:?}", update_result);
+            Ok(update_result.matched_count > 0)
+        }
+        Err(e) => {
+            warn!("Failed to bump share version in database: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to revoke the shares"))
+        }
+    }
+}
+
+/// The settings profile of a user, which supplies the parameters of a stream that the request leaves out.
+/// These are stored in their own collection (see `MONGODB_SETTINGS_COLLECTION_NAME`), one document per user.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct MongoDBUserSettings {
+    pub user_id: String,
+    #[serde(default)]
+    pub chatbot: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+}
+
+/// Loads the settings profile of a user, if they stored one.
+pub async fn read_user_settings(user_id: &str, database: Database) -> Option<MongoDBUserSettings> {
+    match database
+        .collection::<MongoDBUserSettings>(&MONGODB_SETTINGS_COLLECTION_NAME)
+        .find_one(doc! { "user_id": user_id })
+        .await
+    {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!(
+                "Failed to load the settings of the user: {:?}; using none",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Stores the settings profile of a user, replacing the one they had before.
+pub async fn write_user_settings(
+    settings: MongoDBUserSettings,
+    database: Database,
+) -> Result<(), HttpResponse> {
+    match database
+        .collection::<MongoDBUserSettings>(&MONGODB_SETTINGS_COLLECTION_NAME)
+        .replace_one(doc! { "user_id": &settings.user_id }, &settings)
+        .upsert(true)
+        .await
+    {
+        Ok(update_result) => {
+            debug!("Stored the settings of the user in database.");
+            trace!("Update result: {:?}", update_result);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to store the settings of the user: {:?}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to store the settings"))
+        }
+    }
+}
+
 /// Searches the database for threads from a specific user based on the variants that occur in it, i.E if a search searches ("user", "ERA6"),
 /// It searches for all threads that include a variant of user that contains ERA6.
 pub async fn query_by_variant(
@@ -353,6 +943,105 @@ pub async fn query_by_topic(
     query_by_mongodb_filter(filter, num_threads, page, database).await
 }
 
+/// The name of the text index for the full-text search over the threads.
+const TEXT_INDEX_NAME: &str = "thread_text_search";
+
+/// The error code of MongoDB if a text search is run on a collection without a text index.
+const INDEX_NOT_FOUND_CODE: i32 = 27;
+
+/// A thread that matches a full-text search.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ThreadSearchResult {
+    pub thread_id: String,
+    pub topic: String,
+    pub date: String, // ISO 8601 date
+    pub score: f64,   // The relevance of the thread to the query, as calculated by MongoDB.
+}
+
+/// Searches the topics and the inputs of all threads of a user for the words of the query, using a MongoDB text search.
+/// Returns at most `limit` threads, the most relevant first.
+/// If the text index doesn't exist yet, it's created and the search is run again.
+pub async fn text_search_threads(
+    user_id: &str,
+    query: &str,
+    limit: u32,
+    database: Database,
+) -> Result<Vec<ThreadSearchResult>, HttpResponse> {
+    debug!(
+        "Running a full-text search for user {} with query {}",
+        user_id, query
+    );
+    let result = match run_text_search(user_id, query, limit, database.clone()).await {
+        Err(e) if is_missing_text_index(&e) => {
+            info!("The text index doesn't exist yet, creating it.");
+            if let Err(e) = create_text_index(database.clone()).await {
+                warn!("Failed to create the text index: {:?}", e);
+                return Err(HttpResponse::InternalServerError()
+                    .body("Failed to create the index for the search"));
+            }
+            run_text_search(user_id, query, limit, database).await
+        }
+        result => result,
+    };
+
+    result.map_err(|e| {
+        warn!("Failed to execute the full-text search: {:?}", e);
+        HttpResponse::InternalServerError().body("Failed to execute query")
+    })
+}
+
+async fn run_text_search(
+    user_id: &str,
+    query: &str,
+    limit: u32,
+    database: Database,
+) -> mongodb::error::Result<Vec<ThreadSearchResult>> {
+    let cursor = database
+        .collection::<ThreadSearchResult>(&MONGODB_COLLECTION_NAME)
+        .find(doc! {
+            "user_id": user_id,
+            "$text": { "$search": query },
+        })
+        .projection(doc! {
+            "_id": 0,
+            "thread_id": 1,
+            "topic": 1,
+            "date": 1,
+            "score": { "$meta": "textScore" },
+        })
+        .sort(doc! { "score": { "$meta": "textScore" } })
+        .limit(-i64::from(limit))
+        .await?;
+    cursor.try_collect().await
+}
+
+/// Whether the search failed because the collection doesn't have a text index.
+fn is_missing_text_index(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        mongodb::error::ErrorKind::Command(command_error) if command_error.code == INDEX_NOT_FOUND_CODE
+    )
+}
+
+/// Creates the text index over the topic and the inputs of the user. The topic weighs more, because it summarizes the thread.
+async fn create_text_index(database: Database) -> mongodb::error::Result<()> {
+    let index = mongodb::IndexModel::builder()
+        .keys(doc! { "topic": "text", "user_text": "text" })
+        .options(
+            mongodb::options::IndexOptions::builder()
+                .name(TEXT_INDEX_NAME.to_string())
+                .weights(doc! { "topic": 3, "user_text": 1 })
+                .build(),
+        )
+        .build();
+    database
+        .collection::<MongoDBThread>(&MONGODB_COLLECTION_NAME)
+        .create_index(index)
+        .await?;
+    debug!("Created the text index {}.", TEXT_INDEX_NAME);
+    Ok(())
+}
+
 async fn query_by_mongodb_filter(
     filter: Document,
     num_threads: u32,
@@ -403,13 +1092,24 @@ async fn query_by_mongodb_filter(
 // Statically holds a list of Client connections, one per vault URL.
 // This is to avoid creating a new connection for each request, which is expensive and can also lead to
 // nonlinearity (und thus inconsistency) because mongodb's consistency is eventual and each request is modeled as a separate client.
-static MONGOCLIENTPOOL: Lazy<Arc<Mutex<Vec<(String, mongodb::Client)>>>> =
-    Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+type ClientPool = Arc<Mutex<Vec<(String, mongodb::Client)>>>;
+static MONGOCLIENTPOOL: Lazy<ClientPool> = Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
 // Note that officially, client pools are not recommended by mongodb as the client itself already does connection pooling.
 // However, in our case, we can have multiple vault URLs, so we need different clients for each vault URL.
 
 /// Constructs a MongoDB database connection using the Vault URL.
-pub async fn get_database(vault_url: &str) -> Result<Database, HttpResponse> {
+/// If the deployment hosts several tenants, the database is the one of the user's tenant (see `TENANT_DATABASES`).
+pub async fn get_database(vault_url: &str, tenant: Option<&str>) -> Result<Database, HttpResponse> {
+    // Check the tenant first, there's no need to connect for a user that isn't allowed anyway.
+    let database_name =
+        match database_name_for_tenant(&TENANT_DATABASES, &MONGODB_DATABASE_NAME, tenant) {
+            Ok(database_name) => database_name,
+            Err(e) => {
+                warn!("{}", e);
+                return Err(HttpResponse::Forbidden().body(e));
+            }
+        };
+
     let mongodb_uri = get_mongodb_uri(vault_url).await?;
 
     // First check if we already have a client for this URI.
@@ -535,11 +1235,71 @@ pub async fn get_database(vault_url: &str) -> Result<Database, HttpResponse> {
     // While that wasn't tested explicetly, the testing battery ran 15/15 even with a fresh database.
 
     // We don't need the entire client, just the database.
-    let database = client.database(&MONGODB_DATABASE_NAME);
-    debug!("Using database: {}", *MONGODB_DATABASE_NAME);
+    let database = client.database(database_name);
+    debug!("Using database: {}", database_name);
     Ok(database)
 }
 
+/// The databases of the tenants, for deployments that host several organizations, whose threads need to be isolated.
+/// Set via the environment variable `TENANT_DATABASES` as comma-separated pairs like "dkrz=chatbot_dkrz,uhh=chatbot_uhh".
+/// If it isn't set, all users share the database `MONGODB_DATABASE_NAME`.
+static TENANT_DATABASES: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    let tenants = parse_tenant_databases(&env::var("TENANT_DATABASES").unwrap_or_default());
+    debug!("Tenant databases: {:?}", tenants);
+    tenants
+});
+
+/// Parses the mapping of tenants to databases; entries that aren't valid are skipped with a warning.
+fn parse_tenant_databases(value: &str) -> HashMap<String, String> {
+    let mut tenants = HashMap::new();
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let Some((tenant, database)) = entry.split_once('=') else {
+            warn!(
+                "Invalid tenant database {:?}, expected tenant=database; skipping it.",
+                entry
+            );
+            continue;
+        };
+        let (tenant, database) = (tenant.trim(), database.trim());
+        if tenant.is_empty() || !is_valid_database_name(database) {
+            warn!("Invalid tenant database {:?}, skipping it.", entry);
+            continue;
+        }
+        if let Some(previous) = tenants.insert(tenant.to_string(), database.to_string()) {
+            warn!(
+                "Tenant {} is configured twice, using {} instead of {}.",
+                tenant, database, previous
+            );
+        }
+    }
+    tenants
+}
+
+/// Whether MongoDB would accept the name for a database.
+fn is_valid_database_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() < 64
+        && !name.contains(|c: char| "/\\. \"$*<>:|?".contains(c) || c.is_control())
+}
+
+/// Chooses the database for a tenant.
+/// Users without a tenant, and all users if no tenants are configured, use the default database.
+/// A tenant that isn't configured is rejected, so its threads don't end up in the database of another one.
+fn database_name_for_tenant<'a>(
+    tenants: &'a HashMap<String, String>,
+    default: &'a str,
+    tenant: Option<&str>,
+) -> Result<&'a str, String> {
+    match tenant {
+        _ if tenants.is_empty() => Ok(default),
+        None => Ok(default),
+        Some(tenant) => tenants
+            .get(tenant)
+            .map(String::as_str)
+            .ok_or_else(|| format!("The tenant {tenant} is not configured on this server.")),
+    }
+}
+
 static MONGODB_DATABASE_NAME: Lazy<String> = Lazy::new(|| {
     env::var("MONGODB_DATABASE_NAME")
         .expect("\nMONGODB_DATABASE_NAME is not set in the .env file.\n")
@@ -549,3 +1309,314 @@ static MONGODB_COLLECTION_NAME: Lazy<String> = Lazy::new(|| {
     env::var("MONGODB_COLLECTION_NAME")
         .expect("\nMONGODB_COLLECTION_NAME is not set in the .env file.\n")
 });
+
+/// The collection for the raw copies of the threads; the name of the main collection with "_raw" appended.
+static MONGODB_RAW_COLLECTION_NAME: Lazy<String> =
+    Lazy::new(|| format!("{}_raw", *MONGODB_COLLECTION_NAME));
+
+/// The collection for the shares of threads; the name of the main collection with "_shares" appended.
+static MONGODB_SHARES_COLLECTION_NAME: Lazy<String> =
+    Lazy::new(|| format!("{}_shares", *MONGODB_COLLECTION_NAME));
+
+/// The collection for the settings profiles of the users; the name of the main collection with "_settings" appended.
+static MONGODB_SETTINGS_COLLECTION_NAME: Lazy<String> =
+    Lazy::new(|| format!("{}_settings", *MONGODB_COLLECTION_NAME));
+
+/// Connects to the database with the given name on the MongoDB at `MONGODB_TEST_URI` and clears it.
+/// The tests that need a MongoDB are ignored by default; run them with `cargo test -- --ignored`
+/// and `MONGODB_TEST_URI` and `MONGODB_COLLECTION_NAME` set.
+#[cfg(test)]
+pub(crate) async fn test_database(name: &str) -> Database {
+    let uri = env::var("MONGODB_TEST_URI").expect("The database tests need MONGODB_TEST_URI");
+    env::var("MONGODB_COLLECTION_NAME").expect("The database tests need MONGODB_COLLECTION_NAME");
+    let database = mongodb::Client::with_uri_str(&uri)
+        .await
+        .expect("The test database can be connected to")
+        .database(name);
+    database
+        .drop()
+        .await
+        .expect("The test database can be cleared");
+    database
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use mongodb::options::{ClientOptions, ServerAddress};
+
+    use super::*;
+    use crate::auth::tenant_from_token;
+
+    /// Builds an (unsigned) token with the given claims, only the payload matters here.
+    fn token_with_claims(claims: &serde_json::Value) -> String {
+        let encode = |value: &serde_json::Value| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string())
+        };
+        format!(
+            "{}.{}.signature",
+            encode(&serde_json::json!({"alg": "RS256"})),
+            encode(claims)
+        )
+    }
+
+    /// A client for a server that isn't there; building it doesn't connect.
+    fn unconnected_client() -> mongodb::Client {
+        mongodb::Client::with_options(
+            ClientOptions::builder()
+                .hosts(vec![ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Building the client doesn't connect")
+    }
+
+    #[test]
+    fn test_version_0_thread_is_migrated_on_read() {
+        // A thread from before the schema version was stored.
+        let document = doc! {
+            "user_id": "testuser",
+            "thread_id": "abc",
+            "date": "2024-01-01T00:00:00+00:00",
+            "topic": "Old thread",
+            "content": [{"variant": "User", "content": "hi"}],
+        };
+        let thread: MongoDBThread =
+            mongodb::bson::from_document(document).expect("Old threads should still be readable");
+        assert_eq!(thread.schema_version, 0);
+        let thread = migrate_thread(thread);
+        assert_eq!(thread.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(thread.content, vec![StreamVariant::User("hi".to_string())]);
+
+        // On disk, version 0 files may contain lines in the old encoding.
+        let file =
+            "// user_id: testuser\nUser:hi\n{\"variant\":\"Assistant\",\"content\":\"Hello!\"}\n";
+        let expected = vec![
+            StreamVariant::User("hi".to_string()),
+            StreamVariant::Assistant("Hello!".to_string()),
+        ];
+        assert_eq!(
+            crate::chatbot::thread_storage::parse_thread_file(file),
+            expected
+        );
+        // The current version is JSON only, so the old encoding isn't guessed anymore.
+        let versioned = file.replace("User:hi\n", "// schema_version: 1\nUser:hi\n");
+        assert_eq!(
+            crate::chatbot::thread_storage::parse_thread_file(&versioned),
+            expected[1..].to_vec()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_tenants_use_different_databases() {
+        let tenants = parse_tenant_databases("dkrz=chatbot_dkrz, uhh=chatbot_uhh, bad=chat.bot");
+        // The database name with a dot is rejected.
+        assert_eq!(tenants.len(), 2);
+
+        let alice =
+            token_with_claims(&serde_json::json!({"pw_name": "alice", "organization": "dkrz"}));
+        let bob = token_with_claims(&serde_json::json!({"pw_name": "bob", "organization": "uhh"}));
+        let carol = token_with_claims(&serde_json::json!({"pw_name": "carol"}));
+
+        // No connection is made, the client is only needed to name the databases.
+        let client = unconnected_client();
+        let database_of = |token: &str| {
+            let tenant = tenant_from_token(token, "organization");
+            database_name_for_tenant(&tenants, "chatbot", tenant.as_deref())
+                .map(|name| client.database(name).name().to_string())
+        };
+
+        assert_eq!(database_of(&alice), Ok("chatbot_dkrz".to_string()));
+        assert_eq!(database_of(&bob), Ok("chatbot_uhh".to_string()));
+        // Without a tenant, the default database is used; unknown tenants are rejected.
+        assert_eq!(database_of(&carol), Ok("chatbot".to_string()));
+        let mallory = token_with_claims(&serde_json::json!({"organization": "elsewhere"}));
+        assert!(database_of(&mallory).is_err());
+        // If there are no tenants, everyone shares the default database.
+        assert_eq!(
+            database_name_for_tenant(&HashMap::new(), "chatbot", Some("dkrz")),
+            Ok("chatbot")
+        );
+    }
+
+    #[test]
+    fn test_provisional_topic_is_the_start_of_the_first_input() {
+        let content = vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::User(
+                "\nPlot the annual mean near-surface air temperature of ERA5 over Europe for 2023\nand add coastlines".to_string(),
+            ),
+        ];
+        assert_eq!(
+            provisional_topic(&content),
+            "Plot the annual mean near-surface air temperature of ERA5 ov..."
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_new_thread_gets_provisional_topic_before_summary() {
+        let content = vec![StreamVariant::User(
+            "Plot the annual mean temperature of ERA5 over Europe".to_string(),
+        )];
+        let provisional = provisional_topic(&content);
+        let database = test_database("freva_gpt_provisional_topic_test").await;
+
+        let (topic_source, stored_provisional) =
+            store_thread("abc", "testuser", content, None, database.clone())
+                .await
+                .expect("A new thread waits for its summary");
+        assert_eq!(stored_provisional, provisional);
+        assert!(topic_source.contains("Plot the annual mean"));
+
+        // Until the summary is done, the stored thread has the provisional topic.
+        let (summary_sender, summary) = tokio::sync::oneshot::channel::<String>();
+        let update = spawn_topic_update(
+            "abc".to_string(),
+            stored_provisional,
+            async move { summary.await.expect("The summary is sent") },
+            database.clone(),
+        );
+        tokio::task::yield_now().await;
+        assert!(!update.is_finished());
+        let thread = read_thread("abc", database.clone())
+            .await
+            .expect("The thread was stored");
+        assert_eq!(thread.topic, provisional);
+
+        // Once it's done, it replaces the provisional topic.
+        summary_sender
+            .send("ERA5 temperature over Europe".to_string())
+            .expect("The update waits for the summary");
+        update.await.expect("The update finishes");
+        let thread = read_thread("abc", database.clone())
+            .await
+            .expect("The thread is still stored");
+        assert_eq!(thread.topic, "ERA5 temperature over Europe");
+
+        database
+            .drop()
+            .await
+            .expect("The test database can be cleared");
+    }
+
+    fn search_test_content() -> Vec<StreamVariant> {
+        vec![
+            StreamVariant::Prompt("[\"precipitation\"]".to_string()),
+            StreamVariant::User("Plot the precipitation over Hamburg".to_string()),
+            StreamVariant::Assistant("Here is the temperature.".to_string()),
+            StreamVariant::User("Now for Berlin".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_only_user_input_is_searched() {
+        // Only the inputs of the user are searched, the prompt and the answers aren't.
+        assert_eq!(
+            user_text(&search_test_content()),
+            "Plot the precipitation over Hamburg\nNow for Berlin"
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_text_search_finds_threads_by_user_input() {
+        let content = search_test_content();
+        let database = test_database("freva_gpt_text_search_test").await;
+
+        let threads = [
+            ("testuser", "rain", content),
+            (
+                "testuser",
+                "temperature",
+                vec![StreamVariant::User("Mean temperature of ERA5".to_string())],
+            ),
+            (
+                "otheruser",
+                "rain",
+                vec![StreamVariant::User("Precipitation in Munich".to_string())],
+            ),
+        ];
+        for (user_id, thread_id, content) in threads {
+            append_thread(thread_id, user_id, content, None, database.clone()).await;
+        }
+
+        // The index doesn't exist yet, so it's created by the first search.
+        let results = text_search_threads("testuser", "precipitation", 20, database.clone())
+            .await
+            .expect("The search works without an index");
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| result.thread_id.as_str())
+                .collect::<Vec<_>>(),
+            ["rain"]
+        );
+        // The answer of the first thread mentions the temperature too, but isn't searched.
+        let results = text_search_threads("testuser", "temperature", 20, database.clone())
+            .await
+            .expect("The index exists now");
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| result.thread_id.as_str())
+                .collect::<Vec<_>>(),
+            ["temperature"]
+        );
+
+        database
+            .drop()
+            .await
+            .expect("The test database can be cleared");
+    }
+
+    #[test]
+    fn test_thread_meta_pipeline_leaves_out_the_content() {
+        // Only the metadata and the computed count are projected, the content itself never leaves the database.
+        let pipeline = thread_meta_pipeline("abc");
+        let projection = pipeline
+            .iter()
+            .find_map(|stage| stage.get_document("$project").ok())
+            .expect("The pipeline projects the thread");
+        assert!(!projection.contains_key("content"));
+        assert!(projection.contains_key("message_count"));
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_thread_meta_counts_messages_without_content() {
+        let database = test_database("freva_gpt_thread_meta_test").await;
+
+        let content = vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Code("{\"code\": \"plot()\"}".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput("".to_string(), "call_1".to_string()),
+            StreamVariant::Image("iVBORw0KGgoAAAANSUhEUg==".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+            StreamVariant::User("and a square".to_string()),
+            StreamVariant::Assistant("Here is your square.".to_string()),
+        ];
+        append_thread("abc", "testuser", content, None, database.clone()).await;
+
+        let meta = read_thread_meta("abc", database.clone())
+            .await
+            .expect("The metadata can be read")
+            .expect("The thread exists");
+        assert_eq!(meta.thread_id, "abc");
+        assert_eq!(meta.user_id, "testuser");
+        assert_eq!(meta.message_count, 4);
+        assert!(read_thread_meta("missing", database.clone())
+            .await
+            .expect("The metadata can be read")
+            .is_none());
+
+        database
+            .drop()
+            .await
+            .expect("The test database can be cleared");
+    }
+}
@@ -3,7 +3,7 @@ use documented::docs_const;
 use tracing::{debug, warn};
 
 use crate::{
-    auth::get_first_matching_field,
+    auth::{get_first_matching_field, get_tenant},
     chatbot::mongodb::mongodb_storage::{get_database, query_by_topic, query_by_variant},
 };
 
@@ -91,7 +91,7 @@ pub async fn search_threads(req: HttpRequest) -> impl Responder {
         .and_then(|h| h.to_str().ok());
 
     let database = if let Some(vault_url) = maybe_vault_url {
-        get_database(vault_url).await
+        get_database(vault_url, get_tenant(headers).as_deref()).await
     } else {
         warn!("Failed to get vault URL");
         return HttpResponse::BadRequest()
@@ -102,7 +102,7 @@ pub async fn search_threads(req: HttpRequest) -> impl Responder {
         Ok(db) => db,
         Err(e) => {
             warn!("Failed to get database: {:?}", e);
-            return HttpResponse::InternalServerError().body("Failed to get database.");
+            return e;
         }
     };
 
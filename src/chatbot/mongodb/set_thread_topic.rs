@@ -3,7 +3,7 @@ use documented::docs_const;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    auth::get_first_matching_field,
+    auth::{get_first_matching_field, get_tenant},
     chatbot::mongodb::mongodb_storage::{get_database, update_topic},
 };
 
@@ -54,7 +54,7 @@ pub async fn set_thread_topic(req: HttpRequest) -> impl Responder {
         .and_then(|h| h.to_str().ok());
 
     let database = if let Some(vault_url) = maybe_vault_url {
-        get_database(vault_url).await
+        get_database(vault_url, get_tenant(headers).as_deref()).await
     } else {
         warn!("Vault URL not found");
         Err(actix_web::HttpResponse::BadRequest()
@@ -0,0 +1,608 @@
+// Read-only links to threads, so owners can show a conversation to colleagues.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field, get_tenant},
+    chatbot::{
+        get_thread::post_process,
+        handle_active_conversations::generate_id,
+        mongodb::mongodb_storage::{
+            bump_share_version, get_database, insert_share, list_shares, read_share,
+            read_share_version, revoke_share, MongoDBShare,
+        },
+        storage_router::{read_thread, read_thread_and_owner},
+    },
+};
+
+/// How long a share link is valid, in seconds.
+/// Set via the environment variable `SHARE_TTL_SECS`; defaults to a week.
+pub static SHARE_TTL_SECS: Lazy<i64> = Lazy::new(|| {
+    std::env::var("SHARE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(7 * 24 * 60 * 60)
+});
+
+/// The longest a share link can be valid, in seconds, whatever lifetime was requested when it was created.
+/// Links are checked against it when they are used, so lowering it also shortens the links that already exist.
+/// Set via the environment variable `SHARE_TOKEN_MAX_TTL`; defaults to 30 days.
+pub static SHARE_TOKEN_MAX_TTL: Lazy<i64> = Lazy::new(|| {
+    std::env::var("SHARE_TOKEN_MAX_TTL")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(30 * 24 * 60 * 60)
+});
+
+/// Why a share token can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShareError {
+    NotFound,
+    Expired,
+    Revoked,
+}
+
+/// Checks whether the share can be used at the given time (as a Unix timestamp).
+/// Shares of an older share version than the thread's current one were revoked together with all other links of the thread.
+fn check_share(
+    share: Option<MongoDBShare>,
+    share_version: u32,
+    now: i64,
+    max_ttl: i64,
+) -> Result<MongoDBShare, ShareError> {
+    match share {
+        None => Err(ShareError::NotFound),
+        Some(share) if share.revoked || share.version < share_version => Err(ShareError::Revoked),
+        Some(share) if share.expires_at <= now => Err(ShareError::Expired),
+        Some(share)
+            if share
+                .created_at
+                .is_some_and(|created| created + max_ttl <= now) =>
+        {
+            Err(ShareError::Expired)
+        }
+        Some(share) => Ok(share),
+    }
+}
+
+/// The lifetime of a new share: the requested one or the default, but never longer than the maximum.
+fn share_lifetime(requested: Option<i64>, default: i64, max_ttl: i64) -> i64 {
+    requested.unwrap_or(default).min(max_ttl)
+}
+
+/// The vault URL the shared endpoint finds the shares and threads with.
+/// The endpoint doesn't require a login, so it must not connect to a vault URL the client chose; the shares need to be stored in the database behind this one.
+/// Set via the environment variable `SHARE_VAULT_URL`; the shared endpoint is disabled if it's not set.
+static SHARE_VAULT_URL: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("SHARE_VAULT_URL")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+});
+
+/// # Share Thread
+/// Creates a read-only link to a thread, which anyone with the link can view with the shared endpoint. Requires Authentication.
+///
+/// Takes in the `thread_id` of a thread of the user and optionally the `ttl` of the link in seconds.
+/// Returns a JSON object with the `token` of the share, the Unix timestamp `expires_at` after which it can't be used anymore
+/// and the `tenant` of the user (or null), which the shared endpoint needs to find the share.
+/// Links are valid for SHARE_TTL_SECS seconds (a week by default), or for `ttl` seconds if it is given, but never longer than SHARE_TOKEN_MAX_TTL (30 days by default).
+/// They can be revoked earlier with the revokeshare endpoint, or all at once with the revokeshares endpoint.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the vault URL is not given or the ttl isn't a positive number, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user or its owner isn't known, a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called SHARE_THREAD_DOCS
+pub async fn share_thread(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested to share a thread without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let requested_ttl = match get_first_matching_field(&qstring, headers, &["ttl", "x-ttl"], false)
+    {
+        None | Some("") => None,
+        Some(ttl) => match ttl.trim().parse::<i64>() {
+            Ok(ttl) if ttl > 0 => Some(ttl),
+            _ => {
+                warn!("The User requested a share with an invalid ttl: {:?}", ttl);
+                return HttpResponse::UnprocessableEntity()
+                    .body("Invalid ttl. Please provide the lifetime of the link as a positive number of seconds.");
+            }
+        },
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    match read_thread_and_owner(thread_id, database.clone()).await {
+        // Old threads on disk don't record their owner, so nobody can prove they may share them.
+        Ok((_, owner)) if owner.as_deref() != Some(user_id.as_str()) => {
+            warn!(
+                "User {} tried to share thread {}, which isn't known to be theirs.",
+                user_id, thread_id
+            );
+            return HttpResponse::Forbidden().body("This thread belongs to another user.");
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested to share thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    }
+
+    // The share starts at the current version, so it's only revoked by the next bump.
+    let version = match read_share_version(thread_id, database.clone()).await {
+        Ok(version) => version,
+        Err(e) => return e,
+    };
+    let now = chrono::Utc::now().timestamp();
+    let share = MongoDBShare {
+        token: generate_id(),
+        thread_id: thread_id.to_string(),
+        user_id: user_id.clone(),
+        expires_at: now + share_lifetime(requested_ttl, *SHARE_TTL_SECS, *SHARE_TOKEN_MAX_TTL),
+        revoked: false,
+        created_at: Some(now),
+        version,
+    };
+    let response = serde_json::json!({
+        "token": share.token,
+        "expires_at": share.expires_at,
+        "tenant": get_tenant(headers),
+    });
+    if let Err(e) = insert_share(share, database).await {
+        return e;
+    }
+
+    info!("User {} shared thread {}.", user_id, thread_id);
+    HttpResponse::Ok().json(response)
+}
+
+/// # Revoke Share
+/// Revokes a read-only link to a thread before it expires, so it can't be used anymore. Requires Authentication.
+///
+/// Takes in the `token` of the share, as returned by the sharethread endpoint. Only the user who shared the thread can revoke the link.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the token or the vault URL is not given, an UnprocessableEntity response is returned.
+///
+/// If the user has no share with that token, a NotFound response is returned.
+#[docs_const] // writes the docstring into a variable called REVOKE_SHARE_ENDPOINT_DOCS
+pub async fn revoke_share_endpoint(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let token =
+        match get_first_matching_field(&qstring, headers, &["token", "x-share-token"], false) {
+            None | Some("") => {
+                warn!("The User requested to revoke a share without a token.");
+                return HttpResponse::UnprocessableEntity()
+                    .body("Token not found. Please provide the token of the share.");
+            }
+            Some(token) => token,
+        };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    match revoke_share(token, &user_id, database).await {
+        Ok(true) => {
+            info!("User {} revoked a share.", user_id);
+            HttpResponse::Ok().body("Share revoked.")
+        }
+        Ok(false) => {
+            debug!(
+                "User {} tried to revoke a share that isn't theirs.",
+                user_id
+            );
+            HttpResponse::NotFound().body("Share not found.")
+        }
+        Err(e) => e,
+    }
+}
+
+/// # List Shares
+/// Lists the read-only links to a thread that can still be used. Requires Authentication.
+///
+/// Takes in the `thread_id` of a thread of the user.
+/// Returns a JSON list of objects with the `token` of each link and the Unix timestamp `expires_at` after which it can't be used anymore.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the vault URL is not given, an UnprocessableEntity response is returned.
+#[docs_const] // writes the docstring into a variable called LIST_SHARES_ENDPOINT_DOCS
+pub async fn list_shares_endpoint(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested the shares of a thread without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let (shares, version) = match (
+        list_shares(thread_id, &user_id, now, database.clone()).await,
+        read_share_version(thread_id, database).await,
+    ) {
+        (Ok(shares), Ok(version)) => (shares, version),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+
+    // Only the shares that would be let through by the shared endpoint are active.
+    let active = shares
+        .into_iter()
+        .filter_map(|share| check_share(Some(share), version, now, *SHARE_TOKEN_MAX_TTL).ok())
+        .map(|share| {
+            serde_json::json!({
+                "token": share.token,
+                "expires_at": share.expires_at,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    debug!(
+        "User {} has {} active shares of thread {}.",
+        user_id,
+        active.len(),
+        thread_id
+    );
+    HttpResponse::Ok().json(active)
+}
+
+/// # Revoke Shares
+/// Revokes all read-only links to a thread at once, so none of them can be used anymore. Requires Authentication.
+///
+/// Takes in the `thread_id` of a thread of the user. Links that are created afterwards work as usual.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the vault URL is not given, an UnprocessableEntity response is returned.
+///
+/// If the user has no thread with that id in the database, a NotFound response is returned.
+#[docs_const] // writes the docstring into a variable called REVOKE_THREAD_SHARES_DOCS
+pub async fn revoke_thread_shares(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested to revoke the shares of a thread without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    match bump_share_version(thread_id, &user_id, database).await {
+        Ok(true) => {
+            info!(
+                "User {} revoked all shares of thread {}.",
+                user_id, thread_id
+            );
+            HttpResponse::Ok().body("All shares of the thread revoked.")
+        }
+        Ok(false) => {
+            debug!(
+                "User {} tried to revoke the shares of thread {}, which isn't theirs.",
+                user_id, thread_id
+            );
+            HttpResponse::NotFound().body("Thread not found.")
+        }
+        Err(e) => e,
+    }
+}
+
+/// # Shared
+/// Returns a shared thread, read-only, to anyone with the link. Does not require Authentication, the token of the share is enough.
+///
+/// Takes in the `token` of the share and the `tenant`, as returned by the sharethread endpoint.
+/// Returns the content of the thread in the same format as the getthread endpoint.
+/// The database is the one of SHARE_VAULT_URL; without it, the endpoint is disabled and returns a NotFound response.
+///
+/// If the token is not given, an UnprocessableEntity response is returned.
+///
+/// If the tenant isn't configured on this server, a Forbidden response is returned.
+///
+/// If there is no share with that token (or the shared thread doesn't exist anymore), a NotFound response is returned.
+///
+/// If the share expired or was revoked, a Gone response is returned.
+#[docs_const] // writes the docstring into a variable called SHARED_THREAD_DOCS
+pub async fn shared_thread(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    trace!("Query string: {:?}", qstring);
+
+    let token =
+        match get_first_matching_field(&qstring, headers, &["token", "x-share-token"], false) {
+            None | Some("") => {
+                warn!("A shared thread was requested without a token.");
+                return HttpResponse::UnprocessableEntity()
+                    .body("Token not found. Please provide the token of the share.");
+            }
+            Some(token) => token,
+        };
+
+    // Anyone can call this endpoint, so the vault URL comes from the server and the tenant can only pick one of its databases.
+    let Some(vault_url) = SHARE_VAULT_URL.as_deref() else {
+        warn!("A shared thread was requested, but SHARE_VAULT_URL isn't set.");
+        return HttpResponse::NotFound().body("404 Method Not Found, try /help");
+    };
+    let tenant = get_first_matching_field(&qstring, headers, &["tenant", "x-tenant"], false)
+        .filter(|tenant| !tenant.is_empty());
+    let database = match get_database(vault_url, tenant).await {
+        Ok(database) => database,
+        Err(e) => {
+            error!("Error initializing database connection for shares: {:?}", e);
+            return e;
+        }
+    };
+
+    // The token replaces the ownership check, so it has to be valid right now.
+    let share = read_share(token, database.clone()).await;
+    let version = match &share {
+        Some(share) => match read_share_version(&share.thread_id, database.clone()).await {
+            Ok(version) => version,
+            Err(e) => return e,
+        },
+        None => 0,
+    };
+    let share = match check_share(
+        share,
+        version,
+        chrono::Utc::now().timestamp(),
+        *SHARE_TOKEN_MAX_TTL,
+    ) {
+        Ok(share) => share,
+        Err(ShareError::NotFound) => {
+            info!("A shared thread was requested with an unknown token.");
+            return HttpResponse::NotFound().body("Share not found.");
+        }
+        Err(ShareError::Expired) => {
+            info!("A shared thread was requested with an expired token.");
+            return HttpResponse::Gone().body("This link has expired.");
+        }
+        Err(ShareError::Revoked) => {
+            info!("A shared thread was requested with a revoked token.");
+            return HttpResponse::Gone().body("This link was revoked by its owner.");
+        }
+    };
+
+    let content = match read_thread(&share.thread_id, database).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The shared thread {} doesn't exist anymore.",
+                share.thread_id
+            );
+            return HttpResponse::NotFound().body("The shared thread doesn't exist anymore.");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    };
+
+    debug!("Returning shared thread {}.", share.thread_id);
+    HttpResponse::Ok().json(post_process(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::Database;
+
+    use super::*;
+    use crate::chatbot::{
+        mongodb::mongodb_storage::{append_thread, test_database},
+        types::StreamVariant,
+    };
+
+    const MAX_TTL: i64 = 30 * 24 * 60 * 60;
+
+    fn share_at(now: i64) -> MongoDBShare {
+        MongoDBShare {
+            token: "token".to_string(),
+            thread_id: "abc".to_string(),
+            user_id: "testuser".to_string(),
+            expires_at: now + 60,
+            revoked: false,
+            created_at: Some(now),
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn test_share_validity() {
+        let now = 1_700_000_000;
+        let share = share_at(now);
+
+        // A valid share gives access to its thread.
+        assert_eq!(
+            check_share(Some(share.clone()), 0, now, MAX_TTL).map(|share| share.thread_id),
+            Ok("abc".to_string())
+        );
+
+        // Once it expired, it can't be used anymore.
+        assert_eq!(
+            check_share(Some(share.clone()), 0, now + 60, MAX_TTL),
+            Err(ShareError::Expired)
+        );
+
+        // A revoked share can't be used, even before it expires.
+        let revoked = MongoDBShare {
+            revoked: true,
+            ..share
+        };
+        assert_eq!(
+            check_share(Some(revoked), 0, now, MAX_TTL),
+            Err(ShareError::Revoked)
+        );
+
+        assert_eq!(
+            check_share(None, 0, now, MAX_TTL),
+            Err(ShareError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_share_lifetime_is_capped() {
+        let now = 1_700_000_000;
+        assert_eq!(share_lifetime(None, 600, MAX_TTL), 600);
+        assert_eq!(share_lifetime(Some(60), 600, MAX_TTL), 60);
+        assert_eq!(share_lifetime(Some(MAX_TTL * 2), 600, MAX_TTL), MAX_TTL);
+
+        // A share from before the maximum was lowered stops working once it's older than the new maximum.
+        let share = MongoDBShare {
+            expires_at: now + MAX_TTL,
+            ..share_at(now)
+        };
+        assert!(check_share(Some(share.clone()), 0, now + 3600, MAX_TTL).is_ok());
+        assert_eq!(
+            check_share(Some(share.clone()), 0, now + 3600, 3600),
+            Err(ShareError::Expired)
+        );
+        // Old shares without a creation time only have their expiry.
+        let old = MongoDBShare {
+            created_at: None,
+            ..share
+        };
+        assert!(check_share(Some(old), 0, now + 3600, 3600).is_ok());
+    }
+
+    #[test]
+    fn test_older_share_versions_are_revoked() {
+        let now = 1_700_000_000;
+        // Bumping the version of the thread revokes the shares of the older versions, but not the newer ones.
+        assert_eq!(
+            check_share(Some(share_at(now)), 1, now, MAX_TTL),
+            Err(ShareError::Revoked)
+        );
+        let newer = MongoDBShare {
+            version: 1,
+            ..share_at(now)
+        };
+        assert!(check_share(Some(newer), 1, now, MAX_TTL).is_ok());
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_share_version_bump_revokes_all_shares() {
+        let database = test_database("freva_gpt_share_test").await;
+
+        let content = vec![StreamVariant::User("plot a circle".to_string())];
+        append_thread("abc", "testuser", content, None, database.clone()).await;
+        let now = chrono::Utc::now().timestamp();
+        let share = MongoDBShare {
+            version: read_share_version("abc", database.clone())
+                .await
+                .expect("The version can be read"),
+            ..share_at(now)
+        };
+        insert_share(share, database.clone())
+            .await
+            .expect("The share can be stored");
+
+        let check = |database: Database| async move {
+            let share = read_share("token", database.clone()).await;
+            let version = read_share_version("abc", database)
+                .await
+                .expect("The version can be read");
+            check_share(share, version, now, MAX_TTL).map(|share| share.token)
+        };
+        assert_eq!(check(database.clone()).await, Ok("token".to_string()));
+        assert_eq!(
+            list_shares("abc", "testuser", now, database.clone())
+                .await
+                .expect("The shares can be listed")
+                .len(),
+            1
+        );
+
+        // Other users can't revoke the shares, the owner can, and it takes effect right away.
+        assert!(!bump_share_version("abc", "otheruser", database.clone())
+            .await
+            .expect("The version can be bumped"));
+        assert!(bump_share_version("abc", "testuser", database.clone())
+            .await
+            .expect("The version can be bumped"));
+        assert_eq!(check(database.clone()).await, Err(ShareError::Revoked));
+
+        database
+            .drop()
+            .await
+            .expect("The test database can be cleared");
+    }
+}
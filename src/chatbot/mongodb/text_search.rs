@@ -0,0 +1,69 @@
+// Full-text search over the stored threads of a user.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use once_cell::sync::Lazy;
+use qstring::QString;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field},
+    chatbot::mongodb::mongodb_storage::text_search_threads,
+};
+
+/// How many threads a full-text search returns at most.
+/// Set via the environment variable `TEXT_SEARCH_MAX_RESULTS`; defaults to 20.
+pub static TEXT_SEARCH_MAX_RESULTS: Lazy<u32> = Lazy::new(|| {
+    std::env::var("TEXT_SEARCH_MAX_RESULTS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(20)
+});
+
+/// # Full Text Search
+/// Searches the topics and the inputs of all threads of the user for the words of a query. Requires Authentication.
+///
+/// Takes in the search words in the `query` parameter. Unlike the searchthreads endpoint, the words don't have to appear next to each other,
+/// and different forms of a word are found as well (like "plots" for "plot"). Phrases can be searched for in quotes.
+///
+/// Returns a JSON list of the matching threads, the most relevant first, each with its `thread_id`, `topic`, `date` and the relevance `score`.
+/// At most TEXT_SEARCH_MAX_RESULTS threads (20 by default) are returned.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the query or the vault URL is not given, an UnprocessableEntity response is returned.
+#[docs_const] // writes the docstring into a variable called FULL_TEXT_SEARCH_DOCS
+pub async fn full_text_search(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // Only the user's own threads are searched, so the user needs to be authenticated.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let query = match get_first_matching_field(&qstring, headers, &["query", "q"], false) {
+        Some(query) if !query.trim().is_empty() => query,
+        _ => {
+            warn!("The User requested a full-text search without a query.");
+            return HttpResponse::UnprocessableEntity().body(
+                "Query not found. Please provide the words to search for in the query parameter.",
+            );
+        }
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    match text_search_threads(&user_id, query, *TEXT_SEARCH_MAX_RESULTS, database).await {
+        Ok(results) => {
+            debug!(
+                "Full-text search for {:?} found {} threads.",
+                query,
+                results.len()
+            );
+            HttpResponse::Ok().json(results)
+        }
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,85 @@
+// The metadata of a thread, for the tabs of the frontend that don't need its content.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field},
+    chatbot::mongodb::mongodb_storage::read_thread_meta,
+};
+
+/// # Thread Meta
+/// Returns the metadata of a thread without its content, which is much cheaper than getting the whole thread. Requires Authentication.
+///
+/// Takes in the `thread_id` of a thread of the user.
+/// Returns a JSON object with the `thread_id`, `user_id`, `topic` and `date` of the thread,
+/// as well as its `message_count`, the number of User and Assistant variants in it.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id or the vault URL is not given, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user, a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called THREAD_META_DOCS
+pub async fn thread_meta(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested the metadata of a thread without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    match read_thread_meta(thread_id, database).await {
+        Ok(Some(meta)) if meta.user_id != user_id => {
+            warn!(
+                "User {} requested the metadata of thread {}, which belongs to another user.",
+                user_id, thread_id
+            );
+            HttpResponse::Forbidden().body("This thread belongs to another user.")
+        }
+        Ok(Some(meta)) => {
+            debug!(
+                "Returning the metadata of thread {} with {} messages.",
+                thread_id, meta.message_count
+            );
+            HttpResponse::Ok().json(meta)
+        }
+        Ok(None) => {
+            info!(
+                "The User requested the metadata of thread {} that does not exist.",
+                thread_id
+            );
+            HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?")
+        }
+        Err(e) => {
+            error!(
+                "Error reading the metadata of thread {}: {:?}",
+                thread_id, e
+            );
+            HttpResponse::InternalServerError().body("Error reading thread.")
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// The settings profile of a user, which supplies the parameters of a stream that the request leaves out.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field},
+    chatbot::{
+        available_chatbots::AvailableChatbots,
+        mongodb::mongodb_storage::{read_user_settings, write_user_settings, MongoDBUserSettings},
+        request_params::RequestParams,
+    },
+};
+
+impl MongoDBUserSettings {
+    /// The chatbot of the profile, if it's set and still available on this server.
+    pub fn default_chatbot(&self) -> Option<AvailableChatbots> {
+        let chatbot = self.chatbot.clone()?;
+        match chatbot.clone().try_into() {
+            Ok(chatbot) => Some(chatbot),
+            Err(()) => {
+                warn!(
+                    "The chatbot {:?} in the settings of user {} isn't available anymore, ignoring it.",
+                    chatbot, self.user_id
+                );
+                None
+            }
+        }
+    }
+
+    /// Fills in the parameters that the request didn't set; the ones of the request always win.
+    pub fn fill_defaults(&self, params: RequestParams) -> RequestParams {
+        RequestParams {
+            temperature: params.temperature.or(self.temperature),
+            ..params
+        }
+    }
+}
+
+/// # User Settings
+/// Gets or changes the settings profile of the user. Requires Authentication.
+///
+/// The profile supplies the `chatbot` and the `temperature` of the streamresponse and regenerate endpoints when the request leaves them out.
+/// Parameters of the request always override the profile.
+///
+/// Optionally takes in a `chatbot` (see the /availablechatbots endpoint) and a `temperature` (0 to 2), which are stored in the profile.
+/// A parameter with an empty value removes the setting from the profile; parameters that aren't given stay as they are.
+/// Returns the profile as a JSON object with the `chatbot` and the `temperature`, which are null if they aren't set.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the vault URL is not given or the chatbot is not available, an UnprocessableEntity response is returned.
+///
+/// If the temperature is invalid, a BadRequest response is returned.
+#[docs_const] // writes the docstring into a variable called USER_SETTINGS_DOCS
+pub async fn user_settings(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // The profile belongs to the user, so the user needs to be authenticated.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let chatbot = get_first_matching_field(&qstring, headers, &["chatbot", "x-chatbot"], false);
+    if let Some(chatbot) = chatbot.filter(|chatbot| !chatbot.is_empty()) {
+        let valid: Result<AvailableChatbots, ()> = chatbot.to_string().try_into();
+        if valid.is_err() {
+            warn!(
+                "The User tried to store a chatbot that is not available: {:?}",
+                chatbot
+            );
+            return HttpResponse::UnprocessableEntity().body("Chatbot not found. Consult the /availablechatbots endpoint for available chatbots.");
+        }
+    }
+    let temperature =
+        get_first_matching_field(&qstring, headers, &["temperature", "x-temperature"], false);
+    let parsed_temperature = match RequestParams::parse(temperature, None, None, None, None) {
+        Ok(params) => params.temperature,
+        Err(message) => {
+            warn!(
+                "The User tried to store an invalid temperature: {}",
+                message
+            );
+            return HttpResponse::BadRequest().body(message);
+        }
+    };
+
+    let database = match database_from_request(&qstring, headers).await {
+        Ok(database) => database,
+        Err(e) => return e,
+    };
+
+    let mut settings = read_user_settings(&user_id, database.clone())
+        .await
+        .unwrap_or_else(|| MongoDBUserSettings {
+            user_id: user_id.clone(),
+            ..Default::default()
+        });
+    if chatbot.is_none() && temperature.is_none() {
+        debug!("Returning the settings of user {}.", user_id);
+        return HttpResponse::Ok().json(serde_json::json!({
+            "chatbot": settings.chatbot,
+            "temperature": settings.temperature,
+        }));
+    }
+
+    if let Some(chatbot) = chatbot {
+        settings.chatbot = Some(chatbot.to_string()).filter(|chatbot| !chatbot.is_empty());
+    }
+    if temperature.is_some() {
+        settings.temperature = parsed_temperature;
+    }
+    let response = serde_json::json!({
+        "chatbot": settings.chatbot,
+        "temperature": settings.temperature,
+    });
+    match write_user_settings(settings, database).await {
+        Ok(()) => {
+            info!("User {} changed their settings: {}", user_id, response);
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::{
+        available_chatbots::AVAILABLE_CHATBOTS,
+        stream_response::{chatbot_from_request, request_params_from_request},
+    };
+
+    #[test]
+    fn test_omitted_parameters_use_profile_defaults() {
+        let profile_chatbot = AVAILABLE_CHATBOTS
+            .last()
+            .expect("There is at least one chatbot")
+            .0
+            .clone();
+        let profile = MongoDBUserSettings {
+            user_id: "testuser".to_string(),
+            chatbot: Some(profile_chatbot.clone()),
+            temperature: Some(0.3),
+        };
+        let headers = actix_web::http::header::HeaderMap::new();
+        let settings_of = |query: &str| {
+            let qstring = QString::from(query);
+            let chatbot = chatbot_from_request(&qstring, &headers, profile.default_chatbot())
+                .expect("The chatbot is valid");
+            let params = profile.fill_defaults(
+                request_params_from_request(&qstring, &headers).expect("The parameters are valid"),
+            );
+            (chatbot.0, params.temperature, params.max_tokens)
+        };
+
+        // A stream that leaves the parameters out gets the ones of the profile.
+        assert_eq!(settings_of(""), (profile_chatbot.clone(), Some(0.3), None));
+
+        // The parameters of the request always override the profile, and the other parameters are kept.
+        let default_chatbot = AVAILABLE_CHATBOTS[0].0.clone();
+        assert_eq!(
+            settings_of(&format!(
+                "chatbot={default_chatbot}&temperature=1.5&max_tokens=100"
+            )),
+            (default_chatbot, Some(1.5), Some(100))
+        );
+
+        // A chatbot that was removed from the server is ignored.
+        let outdated = MongoDBUserSettings {
+            chatbot: Some("removed-model".to_string()),
+            ..profile.clone()
+        };
+        assert!(outdated.default_chatbot().is_none());
+    }
+}
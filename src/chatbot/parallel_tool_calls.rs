@@ -0,0 +1,191 @@
+// Runs several tool calls of a single response of the LLM at the same time.
+
+use mongodb::Database;
+use once_cell::sync::Lazy;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use crate::tool_calls::{
+    code_interpreter::prepare_execution::CodeVerbosity,
+    route_call::{route_call, ToolUpdate},
+};
+
+use super::{request_params::RequestParams, types::StreamVariant};
+
+/// Whether the LLM may call several tools in one response, which are then run at the same time.
+/// Only useful for models that reliably emit independent tool calls, like querying two datasets.
+/// Set via the environment variable `ENABLE_PARALLEL_TOOL_CALLS`; defaults to false.
+pub static ENABLE_PARALLEL_TOOL_CALLS: Lazy<bool> = Lazy::new(|| {
+    std::env::var("ENABLE_PARALLEL_TOOL_CALLS").is_ok_and(|value| value.trim() == "true")
+});
+
+/// Whether parallel tool calls are enabled for a request; the request can override the default of the server.
+pub fn parallel_tool_calls_enabled(params: RequestParams) -> bool {
+    params
+        .parallel_tool_calls
+        .unwrap_or(*ENABLE_PARALLEL_TOOL_CALLS)
+}
+
+/// A tool call that was completely streamed, but not run yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingToolCall {
+    pub name: Option<String>,
+    pub arguments: String,
+    pub id: String,
+}
+
+/// Runs all tool calls at the same time and sends their combined output once all of them are done.
+/// The outputs are ordered like the calls, and all CodeOutputs come before everything else (like images),
+/// because the LLM expects the results of the tool calls directly after the calls.
+/// Partial output of the calls is passed on as soon as it arrives.
+pub async fn route_calls_concurrently(
+    calls: Vec<PendingToolCall>,
+    thread_id: String,
+    user_id: String,
+    sender: mpsc::Sender<ToolUpdate>,
+    database: Database,
+    code_verbosity: CodeVerbosity,
+) {
+    debug!("Running {} tool calls concurrently.", calls.len());
+    // Every call gets its own task; they all report to the same channel, tagged with their position.
+    let (result_sender, mut result_receiver) = mpsc::channel(calls.len().max(1));
+    for (index, call) in calls.into_iter().enumerate() {
+        let result_sender = result_sender.clone();
+        let thread_id = thread_id.clone();
+        let user_id = user_id.clone();
+        let database = database.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let (call_sender, mut call_receiver) = mpsc::channel(64);
+            let call_id = call.id.clone();
+            // The call runs in its own task, so its partial output can be passed on while it's running.
+            let call_task = tokio::spawn(route_call(
+                call.name.unwrap_or_default(),
+                Some(call.arguments),
+                call.id,
+                thread_id,
+                user_id,
+                call_sender,
+                database,
+                code_verbosity,
+            ));
+            let mut output = None;
+            while let Some(update) = call_receiver.recv().await {
+                match update {
+                    ToolUpdate::Partial(partial) => {
+                        // The partial output is only a preview, so it doesn't matter if it's lost.
+                        let _ = sender.try_send(ToolUpdate::Partial(partial));
+                    }
+                    ToolUpdate::Done(done) => output = Some(done),
+                }
+            }
+            if let Err(e) = call_task.await {
+                error!("Tool call {} panicked: {:?}", call_id, e);
+            }
+            let output = output.unwrap_or_else(|| {
+                warn!("Tool call {} finished without an output.", call_id);
+                vec![StreamVariant::CodeOutput(
+                    "The tool call didn't return an output.".to_string(),
+                    call_id,
+                )]
+            });
+            if result_sender.send((index, output)).await.is_err() {
+                debug!(
+                    "The tool calls were aborted before call {} finished.",
+                    index
+                );
+            }
+        });
+    }
+    // Only the tasks hold senders now, so the channel closes once all of them are done.
+    drop(result_sender);
+
+    let mut results = Vec::new();
+    while let Some(result) = result_receiver.recv().await {
+        results.push(result);
+    }
+    results.sort_by_key(|(index, _)| *index);
+
+    let (outputs, rest): (Vec<_>, Vec<_>) = results
+        .into_iter()
+        .flat_map(|(_, output)| output)
+        .partition(|variant| matches!(variant, StreamVariant::CodeOutput(_, _)));
+    if let Err(e) = sender
+        .send(ToolUpdate::Done([outputs, rest].concat()))
+        .await
+    {
+        error!("Failed to send the answer to the chatbot: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::types::help_convert_sv_ccrm;
+    use async_openai::types::ChatCompletionRequestMessage;
+
+    #[actix_web::test]
+    async fn test_two_tool_calls_give_two_outputs() {
+        let database = mongodb::Client::with_options(
+            mongodb::options::ClientOptions::builder()
+                .hosts(vec![mongodb::options::ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Creating a client doesn't connect yet")
+        .database("test");
+
+        // Tools that don't exist are answered right away, without running any code.
+        let calls = vec![
+            PendingToolCall {
+                name: Some("query_era5".to_string()),
+                arguments: "{}".to_string(),
+                id: "call_1".to_string(),
+            },
+            PendingToolCall {
+                name: Some("query_cmip6".to_string()),
+                arguments: "{}".to_string(),
+                id: "call_2".to_string(),
+            },
+        ];
+        let (sender, mut receiver) = mpsc::channel(1);
+        route_calls_concurrently(
+            calls,
+            "testthread".to_string(),
+            "testuser".to_string(),
+            sender,
+            database,
+            CodeVerbosity::Concise,
+        )
+        .await;
+        let Some(ToolUpdate::Done(output)) = receiver.recv().await else {
+            panic!("The outputs are sent once");
+        };
+        let ids = output
+            .iter()
+            .filter_map(|variant| match variant {
+                StreamVariant::CodeOutput(_, id) => Some(id.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["call_1", "call_2"]);
+
+        // Both calls end up in the same assistant message, followed by both results.
+        let conversation = [
+            vec![
+                StreamVariant::Code("{}".to_string(), "call_1".to_string()),
+                StreamVariant::Code("{}".to_string(), "call_2".to_string()),
+            ],
+            output,
+        ]
+        .concat();
+        let messages = help_convert_sv_ccrm(conversation, false);
+        assert!(matches!(&messages[..], [
+            ChatCompletionRequestMessage::Assistant(assistant),
+            ChatCompletionRequestMessage::Tool(_),
+            ChatCompletionRequestMessage::Tool(_),
+        ] if assistant.tool_calls.as_ref().is_some_and(|calls| calls.len() == 2)));
+    }
+}
@@ -2,9 +2,13 @@
 
 use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage};
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Read;
-use tracing::{debug, error, trace};
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, trace};
+
+use crate::chatbot::available_chatbots::{model_is_gpt_5, AvailableChatbots, AVAILABLE_CHATBOTS};
 
 /// The basic starting prompt as a const of the correct type.
 static STARTING_PROMPT_STR: Lazy<String> = Lazy::new(|| {
@@ -98,6 +102,7 @@ pub fn get_entire_prompt_json(user_id: &str, thread_id: &str) -> String {
     result
 }
 
+#[cfg(test)] // New threads get the prompt of their chatbot, only the tests need the built-in one directly.
 pub fn get_entire_prompt(user_id: &str, thread_id: &str) -> Vec<ChatCompletionRequestMessage> {
     recursively_create_dir_at_rw_dir(user_id, thread_id);
     // Note that this function allows for the user_id and thread_id to be non-alphanumeric, as it is not used in the JSON parsing.
@@ -202,18 +207,114 @@ pub fn get_entire_prompt_json_gpt_5(user_id: &str, thread_id: &str) -> String {
     result
 }
 
-pub fn get_entire_prompt_gpt_5(
+/// The directory with the prompts that replace the built-in ones for single chatbots, so they can be changed without recompiling.
+/// A prompt for a chatbot is stored as `{chatbot}.json`, a JSON list of messages like the Prompt variant of a stored thread.
+/// Set via the environment variable `PROMPT_DIR`; if it isn't set, all chatbots use the built-in prompts.
+static PROMPT_DIR: Lazy<Option<PathBuf>> = Lazy::new(|| {
+    std::env::var("PROMPT_DIR")
+        .ok()
+        .filter(|dir| !dir.trim().is_empty())
+        .map(PathBuf::from)
+});
+
+/// The prompts from the PROMPT_DIR, by the name of the chatbot they're for.
+pub static PROMPT_OVERRIDES: Lazy<HashMap<String, Vec<ChatCompletionRequestMessage>>> =
+    Lazy::new(|| {
+        let Some(dir) = PROMPT_DIR.as_deref() else {
+            return HashMap::new();
+        };
+        match load_prompt_overrides(dir, &AVAILABLE_CHATBOTS) {
+            Ok(overrides) => {
+                info!(
+                    "Loaded the prompts of {} chatbots from {}.",
+                    overrides.len(),
+                    dir.display()
+                );
+                overrides
+            }
+            Err(e) => {
+                // A broken prompt would only be noticed when a user starts a thread, so this is fatal.
+                // It's initialized in the runtime checks, so it exits before the server is started.
+                error!("{e}");
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+    });
+
+/// Reads the prompts of the chatbots from the directory. Chatbots without a file of their own aren't in the result.
+/// # Errors
+/// Returns a description of the first prompt that can't be read or isn't a non-empty list of messages.
+fn load_prompt_overrides(
+    dir: &Path,
+    chatbots: &[AvailableChatbots],
+) -> Result<HashMap<String, Vec<ChatCompletionRequestMessage>>, String> {
+    let mut overrides = HashMap::new();
+    for chatbot in chatbots {
+        let path = dir.join(format!("{}.json", chatbot.0));
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("The prompt {} can't be read: {e}", path.display())),
+        };
+        let messages: Vec<ChatCompletionRequestMessage> =
+            serde_json::from_str(&content).map_err(|e| {
+                format!(
+                    "The prompt {} isn't a list of messages: {e}",
+                    path.display()
+                )
+            })?;
+        if messages.is_empty() {
+            return Err(format!("The prompt {} is empty.", path.display()));
+        }
+        debug!("Using the prompt {} for {}.", path.display(), chatbot.0);
+        overrides.insert(chatbot.0.clone(), messages);
+    }
+    Ok(overrides)
+}
+
+/// The messages a new conversation with the chatbot starts with: its prompt from the PROMPT_DIR, or the built-in one.
+fn prompt_ccrm_from(
+    overrides: &HashMap<String, Vec<ChatCompletionRequestMessage>>,
+    chatbot: &AvailableChatbots,
+) -> Vec<ChatCompletionRequestMessage> {
+    match overrides.get(&chatbot.0) {
+        Some(messages) => messages.clone(),
+        None if model_is_gpt_5(chatbot.clone()) => entire_prompt_ccrm_gpt_5(),
+        None => entire_prompt_ccrm(),
+    }
+}
+
+/// The messages a new conversation with the chatbot starts with, without creating its directory.
+pub(crate) fn prompt_ccrm_for_chatbot(
+    chatbot: &AvailableChatbots,
+) -> Vec<ChatCompletionRequestMessage> {
+    prompt_ccrm_from(&PROMPT_OVERRIDES, chatbot)
+}
+
+/// The prompt for a new conversation with the chatbot, see `prompt_ccrm_for_chatbot`.
+pub fn get_prompt_for_chatbot(
+    chatbot: &AvailableChatbots,
     user_id: &str,
     thread_id: &str,
 ) -> Vec<ChatCompletionRequestMessage> {
     recursively_create_dir_at_rw_dir(user_id, thread_id);
-    // Note that this function allows for the user_id and thread_id to be non-alphanumeric, as it is not used in the JSON parsing.
-    let result = entire_prompt_ccrm_gpt_5();
-
-    trace!("Returning templated starting prompt: {:?}", result);
+    let result = prompt_ccrm_for_chatbot(chatbot);
+    trace!("Returning starting prompt for {}: {:?}", chatbot.0, result);
     result
 }
 
+/// The prompt for a new conversation with the chatbot as a JSON string, to be stored as the Prompt variant.
+pub fn get_prompt_json_for_chatbot(
+    chatbot: &AvailableChatbots,
+    user_id: &str,
+    thread_id: &str,
+) -> String {
+    // The messages were either built here or deserialized from JSON, so they can always be serialized again.
+    serde_json::to_string(&get_prompt_for_chatbot(chatbot, user_id, thread_id))
+        .expect("Error converting starting prompt to JSON.")
+}
+
 /// Every time a prompt is requested, the folder at rw_dir needs to be created because else, some python functions
 /// might not find it. (We cannot expect all the functions to alwas recursively create the folders)
 fn recursively_create_dir_at_rw_dir(user_id: &str, thread_id: &str) {
@@ -253,3 +354,47 @@ fn recursively_create_dir_at_rw_dir(user_id: &str, thread_id: &str) {
         trace!("rw_dir created: {}", rw_dir);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_prompt_is_used_for_new_thread() {
+        let dir = std::env::temp_dir().join(format!(
+            "prompt_dir_{}",
+            crate::chatbot::handle_active_conversations::generate_id()
+        ));
+        fs::create_dir_all(&dir).expect("The prompt directory can be created");
+        let custom = AvailableChatbots("custom-llama".to_string());
+        let builtin = AvailableChatbots("gpt-4.1".to_string());
+        fs::write(
+            dir.join("custom-llama.json"),
+            r#"[{"role": "system", "content": "You are a climate scientist.", "name": "prompt"}]"#,
+        )
+        .expect("The prompt can be written");
+
+        let overrides = load_prompt_overrides(&dir, &[custom.clone(), builtin.clone()]);
+        let overrides = overrides.expect("The custom prompt is valid");
+        let prompt = prompt_ccrm_from(&overrides, &custom);
+        assert_eq!(prompt.len(), 1);
+        let ChatCompletionRequestMessage::System(system) = &prompt[0] else {
+            panic!("Expected the system message of the custom prompt, got {prompt:?}");
+        };
+        assert_eq!(
+            system.content,
+            async_openai::types::ChatCompletionRequestSystemMessageContent::Text(
+                "You are a climate scientist.".to_string()
+            )
+        );
+        // Chatbots without a file of their own keep the built-in prompt.
+        assert_eq!(prompt_ccrm_from(&overrides, &builtin), entire_prompt_ccrm());
+
+        // A malformed prompt is reported with its file.
+        fs::write(dir.join("custom-llama.json"), r#"{"role": "system"}"#)
+            .expect("The prompt can be written");
+        let error = load_prompt_overrides(&dir, &[custom]);
+        fs::remove_dir_all(&dir).expect("The prompt directory can be removed");
+        assert!(error.is_err_and(|e| e.contains("custom-llama.json")));
+    }
+}
@@ -0,0 +1,313 @@
+// Limits how many streams a single user can run at once and start per minute, so one user can't exhaust the code interpreter.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    http::{
+        header::{HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    web::Bytes,
+    HttpResponse,
+};
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+
+use crate::{api_error::error_response, auth::has_guest_username};
+
+/// Reads a limit from the environment; zero and invalid values fall back to the default.
+fn limit_from_env(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(default)
+}
+
+/// How many streams a user can run at the same time.
+/// Set via the environment variable `MAX_STREAMS_PER_USER`; defaults to 3.
+static MAX_STREAMS_PER_USER: Lazy<usize> = Lazy::new(|| limit_from_env("MAX_STREAMS_PER_USER", 3));
+
+/// How many streams a guest can run at the same time.
+/// Set via the environment variable `MAX_STREAMS_PER_GUEST`; defaults to 1.
+static MAX_STREAMS_PER_GUEST: Lazy<usize> =
+    Lazy::new(|| limit_from_env("MAX_STREAMS_PER_GUEST", 1));
+
+/// How many streams a user can start per minute.
+/// Set via the environment variable `MAX_STREAM_REQUESTS_PER_MINUTE`; defaults to 20.
+static MAX_STREAM_REQUESTS_PER_MINUTE: Lazy<usize> =
+    Lazy::new(|| limit_from_env("MAX_STREAM_REQUESTS_PER_MINUTE", 20));
+
+/// How many streams a guest can start per minute.
+/// Set via the environment variable `MAX_GUEST_STREAM_REQUESTS_PER_MINUTE`; defaults to 5.
+static MAX_GUEST_STREAM_REQUESTS_PER_MINUTE: Lazy<usize> =
+    Lazy::new(|| limit_from_env("MAX_GUEST_STREAM_REQUESTS_PER_MINUTE", 5));
+
+/// The window the requests per minute are counted in.
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a client that has too many streams open should wait; the streams don't know when they end.
+const STREAMS_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// The limits of a single user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_streams: usize,
+    pub max_requests_per_minute: usize,
+}
+
+impl Limits {
+    /// The limits of the user; guests get lower ones.
+    fn for_user(user_id: &str) -> Self {
+        if has_guest_username(user_id) {
+            Self {
+                max_streams: *MAX_STREAMS_PER_GUEST,
+                max_requests_per_minute: *MAX_GUEST_STREAM_REQUESTS_PER_MINUTE,
+            }
+        } else {
+            Self {
+                max_streams: *MAX_STREAMS_PER_USER,
+                max_requests_per_minute: *MAX_STREAM_REQUESTS_PER_MINUTE,
+            }
+        }
+    }
+}
+
+/// Why a stream wasn't allowed, with how long the client should wait before trying again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    TooManyStreams(Duration),
+    TooManyRequests(Duration),
+}
+
+/// What a single user is currently using.
+#[derive(Debug, Default)]
+struct Usage {
+    active_streams: usize,
+    recent_requests: VecDeque<Instant>,
+}
+
+/// Counts the active streams and the recent requests of every user.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    users: HashMap<String, Usage>,
+}
+
+impl RateLimiter {
+    /// Takes a stream slot for the user if both limits allow it; the request is only counted if it is.
+    pub fn try_acquire(
+        &mut self,
+        user_id: &str,
+        limits: Limits,
+        now: Instant,
+    ) -> Result<(), RateLimited> {
+        let usage = self.users.entry(user_id.to_string()).or_default();
+        while usage
+            .recent_requests
+            .front()
+            .is_some_and(|request| now.duration_since(*request) >= REQUEST_WINDOW)
+        {
+            usage.recent_requests.pop_front();
+        }
+
+        if usage.active_streams >= limits.max_streams {
+            return Err(RateLimited::TooManyStreams(STREAMS_RETRY_AFTER));
+        }
+        if usage.recent_requests.len() >= limits.max_requests_per_minute {
+            // The oldest request leaves the window first.
+            let oldest = usage.recent_requests.front().copied().unwrap_or(now);
+            return Err(RateLimited::TooManyRequests(
+                REQUEST_WINDOW.saturating_sub(now.duration_since(oldest)),
+            ));
+        }
+
+        usage.active_streams += 1;
+        usage.recent_requests.push_back(now);
+        Ok(())
+    }
+
+    /// Frees a stream slot of the user. Users without streams or recent requests are forgotten.
+    pub fn release(&mut self, user_id: &str, now: Instant) {
+        if let Some(usage) = self.users.get_mut(user_id) {
+            usage.active_streams = usage.active_streams.saturating_sub(1);
+        }
+        self.users.retain(|_, usage| {
+            usage.active_streams > 0
+                || usage
+                    .recent_requests
+                    .back()
+                    .is_some_and(|request| now.duration_since(*request) < REQUEST_WINDOW)
+        });
+    }
+}
+
+static RATE_LIMITER: Lazy<Mutex<RateLimiter>> = Lazy::new(|| Mutex::new(RateLimiter::default()));
+
+/// A stream slot of a user, which is freed when it's dropped.
+/// It's moved into the body of the stream, so the slot is freed however the stream ends, also when the client disconnects.
+#[derive(Debug)]
+pub struct StreamPermit {
+    user_id: String,
+}
+
+impl StreamPermit {
+    /// Keeps the slot until the body of the response is dropped.
+    pub fn attach(self, response: HttpResponse) -> HttpResponse {
+        response.map_body(|_, body| {
+            BoxBody::new(PermitBody {
+                body,
+                _permit: self,
+            })
+        })
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        debug!("Freeing a stream slot of user {}.", self.user_id);
+        RATE_LIMITER
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .release(&self.user_id, Instant::now());
+    }
+}
+
+/// Takes a stream slot for the user, or returns why the user has to wait; see `RateLimited::into_response` for the answer to the client.
+pub fn acquire_stream(user_id: &str) -> Result<StreamPermit, RateLimited> {
+    RATE_LIMITER
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .try_acquire(user_id, Limits::for_user(user_id), Instant::now())
+        .inspect_err(|limited| match limited {
+            RateLimited::TooManyStreams(_) => warn!("User {} has too many streams open.", user_id),
+            RateLimited::TooManyRequests(_) => warn!(
+                "User {} started too many streams in the last minute.",
+                user_id
+            ),
+        })?;
+    Ok(StreamPermit {
+        user_id: user_id.to_string(),
+    })
+}
+
+impl RateLimited {
+    /// The TooManyRequests response for the client, with a Retry-After header.
+    pub fn into_response(self) -> HttpResponse {
+        let (retry_after, message) = match self {
+            RateLimited::TooManyStreams(retry_after) => (
+                retry_after,
+                "Too many streams are running at the same time. Please wait for one of them to finish.",
+            ),
+            RateLimited::TooManyRequests(retry_after) => (
+                retry_after,
+                "Too many streams were started in the last minute. Please try again later.",
+            ),
+        };
+        let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "rate_limited", message);
+        // Retry-After is in whole seconds, so it's rounded up to not be too early.
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        response
+    }
+}
+
+/// The body of a stream that holds a stream slot.
+struct PermitBody {
+    body: BoxBody,
+    _permit: StreamPermit,
+}
+
+impl MessageBody for PermitBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_are_hit_and_recovered_from() {
+        let limits = Limits {
+            max_streams: 2,
+            max_requests_per_minute: 3,
+        };
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        // Two streams can run at once, the third has to wait until one of them ends.
+        assert!(limiter.try_acquire("testuser", limits, start).is_ok());
+        assert!(limiter.try_acquire("testuser", limits, start).is_ok());
+        assert_eq!(
+            limiter.try_acquire("testuser", limits, start),
+            Err(RateLimited::TooManyStreams(STREAMS_RETRY_AFTER))
+        );
+        // Other users have their own limits.
+        assert!(limiter.try_acquire("otheruser", limits, start).is_ok());
+
+        limiter.release("testuser", start);
+        assert!(limiter
+            .try_acquire("testuser", limits, start + Duration::from_secs(20))
+            .is_ok());
+
+        // That was the third request within a minute, so the fourth has to wait until the first leaves the window.
+        limiter.release("testuser", start);
+        limiter.release("testuser", start);
+        assert_eq!(
+            limiter.try_acquire("testuser", limits, start + Duration::from_secs(30)),
+            Err(RateLimited::TooManyRequests(Duration::from_secs(30)))
+        );
+        assert!(limiter
+            .try_acquire("testuser", limits, start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_stream_slot_is_freed_when_the_body_is_dropped() {
+        // Guests get one stream at a time by default.
+        let user_id = format!(
+            "guest_{}",
+            crate::chatbot::handle_active_conversations::generate_id()
+        );
+        let permit = acquire_stream(&user_id).expect("The first stream is allowed");
+        let rejected = acquire_stream(&user_id)
+            .expect_err("The second stream is rejected")
+            .into_response();
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            rejected
+                .headers()
+                .get(RETRY_AFTER)
+                .expect("The response has a Retry-After header"),
+            "10"
+        );
+
+        // The stream ends abnormally: the client disconnects and the body is dropped without being read.
+        let response = permit.attach(
+            HttpResponse::Ok()
+                .streaming(futures::stream::pending::<Result<Bytes, actix_web::Error>>()),
+        );
+        assert!(acquire_stream(&user_id).is_err());
+        drop(response);
+        assert!(acquire_stream(&user_id).is_ok());
+    }
+}
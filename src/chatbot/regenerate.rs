@@ -0,0 +1,309 @@
+// Re-runs the last turn of a thread, so the user gets a new answer to the same input.
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::{get_first_matching_field, get_tenant, is_guest},
+    chatbot::{
+        available_chatbots::{
+            model_message_ordering, model_supports_images, model_tool_call_content,
+        },
+        delta_offsets::offsets_requested,
+        handle_active_conversations::{
+            add_to_conversation, conversation_state, replace_stored_tail,
+        },
+        image_format::ImageFormat,
+        mongodb::mongodb_storage::{get_database, read_user_settings},
+        rate_limit::acquire_stream,
+        storage_router::read_thread_and_owner,
+        stream_framing::StreamFraming,
+        stream_response::{
+            build_request, chatbot_from_request, code_verbosity_from_request, create_and_stream,
+            ensure_lite_llm_running, freva_config_path_from_request, model_hint,
+            request_params_from_request, RECORD_TURN_MODEL,
+        },
+        stream_summary::summary_requested,
+        types::{
+            fit_message_order, fit_tool_call_only_messages, help_convert_sv_ccrm, Conversation,
+            StreamVariant,
+        },
+    },
+    logging::{silence_logger, undo_silence_logger},
+    tool_calls::code_interpreter::{verify_can_access, ConfigAccessError},
+};
+
+/// Cuts the thread directly after its last user message, dropping the answer to it.
+/// Returns None if the thread doesn't contain a user message yet.
+fn truncate_after_last_user_message(mut content: Conversation) -> Option<Conversation> {
+    let last_user_message = content
+        .iter()
+        .rposition(|variant| matches!(variant, StreamVariant::User(_)))?;
+    content.truncate(last_user_message + 1);
+    Some(content)
+}
+
+/// # Regenerate
+/// Re-runs the last turn of a thread: the answer to the last user message is dropped and the LLM answers the same input again. Requires Authentication.
+///
+/// Takes in the `thread_id` as well as the same parameters as the streamresponse endpoint, except for the input:
+/// the vault URL, the freva config path, and optionally the chatbot, code_verbosity, temperature, max_tokens, frequency_penalty, parallel_tool_calls, reasoning_effort, image_format, images, summary and delta_offsets, which may differ from the ones of the original answer.
+/// If the chatbot or the temperature isn't set, the one of the user's settings profile is used.
+///
+/// The response is a stream in the same format as the one of the streamresponse endpoint, starting with the ServerHint of the thread_id.
+/// When the stream is saved, the new answer replaces the old one in the stored thread.
+///
+/// If the authorization fails or the user is considered a guest, an Unauthorized response is returned.
+///
+/// If the user runs or started too many streams, a TooManyRequests response is returned, with the same limits as for the streamresponse endpoint.
+///
+/// If the thread id or the vault URL is not given, or the chatbot or code_verbosity is invalid, an UnprocessableEntity response is returned.
+///
+/// If the temperature, max_tokens, frequency_penalty, parallel_tool_calls or reasoning_effort is invalid, or the freva config path is outside of FREVA_CONFIG_BASE, a BadRequest response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user, a Forbidden response is returned.
+///
+/// If the thread doesn't contain a user message yet, a BadRequest response is returned.
+///
+/// If the thread is currently being streamed, a Conflict response is returned.
+///
+/// If the LiteLLM proxy is down, a ServiceUnavailable response is returned and the old answer is kept.
+#[docs_const] // writes the docstring into a variable called REGENERATE_DOCS
+pub async fn regenerate(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    // Same as for the streamresponse endpoint, guests can't use the chatbot.
+    if !is_guest(&user_id) {
+        warn!(
+            "The User requested a regeneration, but is considered a guest. User ID: {}",
+            user_id
+        );
+        return HttpResponse::Unauthorized().body("You are not allowed to use the chatbot as a guest. Please log in with a Levante account.");
+    }
+
+    // Regenerating starts a stream too, so it counts towards the same limits.
+    let permit = match acquire_stream(&user_id) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested a regeneration without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id.to_string(),
+    };
+
+    let maybe_vault_url = get_first_matching_field(
+        &qstring,
+        headers,
+        &[
+            "x-freva-vault-url",
+            "x-vault-url",
+            "vault-url",
+            "vault_url",
+            "freva_vault_url",
+        ],
+        true,
+    );
+    let Some(vault_url) = maybe_vault_url else {
+        warn!("The User requested a regeneration without a vault URL.");
+        return HttpResponse::UnprocessableEntity().body(
+            "Vault URL not found. Please provide a non-empty vault URL in the headers, of type String.",
+        );
+    };
+
+    let code_verbosity = match code_verbosity_from_request(&qstring, headers) {
+        Ok(code_verbosity) => code_verbosity,
+        Err(response) => return *response,
+    };
+    let freva_config_path = freva_config_path_from_request(&qstring, headers);
+    let freva_config_path = match verify_can_access(&freva_config_path) {
+        Ok(resolved_path) => resolved_path,
+        Err(ConfigAccessError::NotAllowed) => {
+            warn!("The User requested a regeneration with a freva_config path outside of the allowed directories. Path: {}", freva_config_path);
+            return HttpResponse::BadRequest().body(
+                "The freva config path is outside of the directories the freva configs are in.",
+            );
+        }
+        Err(e) => {
+            warn!("The User requested a regeneration with a freva_config path that cannot be accessed ({:?}). Path: {}", e, freva_config_path);
+            freva_config_path
+        }
+    };
+
+    let database = match get_database(vault_url, get_tenant(headers).as_deref()).await {
+        Ok(db) => db,
+        Err(e) => {
+            warn!("Failed to connect to the database: {:?}", e);
+            return e;
+        }
+    };
+
+    // Like for the streamresponse endpoint, the parameters the request leaves out come from the user's settings profile.
+    let profile = read_user_settings(&user_id, database.clone())
+        .await
+        .unwrap_or_default();
+    let chatbot = match chatbot_from_request(&qstring, headers, profile.default_chatbot()) {
+        Ok(chatbot) => chatbot,
+        Err(response) => return *response,
+    };
+    let params = match request_params_from_request(&qstring, headers) {
+        Ok(params) => profile.fill_defaults(params),
+        Err(response) => return *response,
+    };
+    if let Err(response) = ensure_lite_llm_running().await {
+        return response;
+    }
+
+    // A thread that is still streaming can't be regenerated; conversation_state warns if the thread isn't active, which is the usual case.
+    silence_logger();
+    let state = conversation_state(&thread_id).await;
+    undo_silence_logger();
+    if state.is_some() {
+        warn!(
+            "The User requested a regeneration of thread {}, which is currently being streamed.",
+            thread_id
+        );
+        return HttpResponse::Conflict().body(format!(
+            "Thread {thread_id} is already being streamed. Please wait until it's done."
+        ));
+    }
+
+    let (content, owner) = match read_thread_and_owner(&thread_id, database.clone()).await {
+        Ok(result) => result,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested a regeneration of thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    };
+    if owner.as_deref().is_some_and(|owner| owner != user_id) {
+        warn!(
+            "User {} requested a regeneration of thread {}, which belongs to another user.",
+            user_id, thread_id
+        );
+        return HttpResponse::Forbidden().body("This thread belongs to another user.");
+    }
+
+    let Some(content) = truncate_after_last_user_message(content) else {
+        warn!(
+            "The User requested a regeneration of thread {}, which has no user message yet.",
+            thread_id
+        );
+        return HttpResponse::BadRequest()
+            .body("The thread doesn't contain a user message to answer again.");
+    };
+    let keep = content.len();
+    debug!(
+        "Regenerating thread {} from its first {} variants.",
+        thread_id, keep
+    );
+
+    // The truncated thread already ends with the user's input, so it's the entire request.
+    let messages = fit_tool_call_only_messages(
+        fit_message_order(
+            help_convert_sv_ccrm(content, model_supports_images(chatbot.clone())),
+            model_message_ordering(&chatbot),
+        ),
+        model_tool_call_content(&chatbot),
+    );
+
+    // The new turn only consists of the answer; the input and the thread_id hint are already stored.
+    let new_variants = if *RECORD_TURN_MODEL {
+        vec![model_hint(&chatbot)]
+    } else {
+        Vec::new()
+    };
+    add_to_conversation(
+        &thread_id,
+        new_variants,
+        freva_config_path.clone(),
+        user_id.clone(),
+    );
+    replace_stored_tail(&thread_id, keep);
+
+    let request = match build_request(messages, chatbot.clone(), params) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Error building request: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error building request.");
+        }
+    };
+
+    info!("Regenerating the last turn of thread {}.", thread_id);
+    let response = create_and_stream(
+        request,
+        thread_id,
+        freva_config_path,
+        chatbot,
+        user_id,
+        database,
+        None,
+        code_verbosity,
+        params,
+        StreamFraming::from_request(&qstring, headers),
+        ImageFormat::from_request(&qstring, headers),
+        summary_requested(&qstring, headers),
+        offsets_requested(&qstring, headers),
+    )
+    .await;
+    permit.attach(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_answer_is_dropped() {
+        let first_turn = vec![
+            StreamVariant::Prompt("[]".to_string()),
+            StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string()),
+            StreamVariant::User("plot the temperature".to_string()),
+            StreamVariant::Assistant("Here is the plot.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        let second_turn = vec![
+            StreamVariant::User("now in red".to_string()),
+            StreamVariant::Code("plt.plot(t, color='red')".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput(String::new(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::Assistant("Here it is in red.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+
+        let truncated =
+            truncate_after_last_user_message([first_turn.clone(), second_turn.clone()].concat())
+                .expect("The thread has a user message");
+        assert_eq!(truncated, [first_turn, second_turn[..1].to_vec()].concat());
+
+        // A thread without any input can't be regenerated.
+        assert_eq!(
+            truncate_after_last_user_message(vec![StreamVariant::Prompt("[]".to_string())]),
+            None
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{convert::Infallible, time::Duration};
+
+use actix_web::{web::Bytes, HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use futures::{stream, Stream, StreamExt};
+use once_cell::sync::Lazy;
+use qstring::QString;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::{get_first_matching_field, get_tenant},
+    chatbot::{
+        get_thread::post_process, mongodb::mongodb_storage::get_database,
+        storage_router::read_thread_and_owner, stream_framing::StreamFraming,
+        stream_response::variant_to_bytes, types::StreamVariant,
+    },
+};
+
+/// Whether the replay endpoint is available. It's meant for demos and frontend development, not for production.
+/// Set via the environment variable `ENABLE_REPLAY`; defaults to false.
+pub static ENABLE_REPLAY: Lazy<bool> =
+    Lazy::new(|| std::env::var("ENABLE_REPLAY").is_ok_and(|value| value.trim() == "true"));
+
+/// The delay between two variants of a replay at speed 1.
+/// This is roughly the pace in which the LLMs send their deltas.
+const REPLAY_BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// # Replay
+/// Streams a stored thread to the client as if it was generated live, in the same format as the streamresponse endpoint. Requires Authentication.
+/// Only available if the backend was started with `ENABLE_REPLAY=true`, as it's meant for demos and frontend development.
+///
+/// As arguments, it takes in a `thread_id` and optionally a `speed`, which is a factor for the pacing (default 1, larger is faster).
+/// The variants are the same as the ones returned by the getthread endpoint. The LLM is not involved.
+///
+/// If the endpoint is disabled, a NotFound response is returned.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the thread id is not given or the speed is not a positive number, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user, a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called REPLAY_DOCS
+pub async fn replay(req: HttpRequest) -> impl Responder {
+    if !*ENABLE_REPLAY {
+        debug!("The replay endpoint was requested, but it is disabled.");
+        return HttpResponse::NotFound().body("404 Method Not Found, try /help");
+    }
+
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let maybe_vault_url = get_first_matching_field(
+        &qstring,
+        headers,
+        &[
+            "x-freva-vault-url",
+            "x-vault-url",
+            "vault-url",
+            "vault_url",
+            "freva_vault_url",
+        ],
+        true,
+    );
+
+    let thread_id = match get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    ) {
+        None | Some("") => {
+            warn!("The User requested a replay without a thread ID.");
+            return HttpResponse::UnprocessableEntity()
+                .body("Thread ID not found. Please provide a thread_id in the query parameters.");
+        }
+        Some(thread_id) => thread_id,
+    };
+
+    let speed = match get_first_matching_field(&qstring, headers, &["speed", "x-speed"], false)
+        .map(str::parse::<f64>)
+    {
+        None => 1.0,
+        Some(Ok(speed)) if speed.is_finite() && speed > 0.0 => speed,
+        Some(_) => {
+            warn!("The User requested a replay with an invalid speed.");
+            return HttpResponse::UnprocessableEntity()
+                .body("The speed has to be a positive number.");
+        }
+    };
+
+    let Some(vault_url) = maybe_vault_url else {
+        warn!("No vault URL provided, cannot connect to the database for threads.");
+        return HttpResponse::UnprocessableEntity()
+            .body("Vault URL not found. Please provide a non-empty vault URL in the headers.");
+    };
+
+    let database = match get_database(vault_url, get_tenant(headers).as_deref()).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Error initializing database connection: {:?}", e);
+            return e;
+        }
+    };
+
+    let (content, owner) = match read_thread_and_owner(thread_id, database).await {
+        Ok(result) => result,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "The User requested a replay of thread {} that does not exist.",
+                thread_id
+            );
+            return HttpResponse::NotFound()
+                .body("Thread not found. Maybe it exists on another freva instance?");
+        }
+        Err(e) => {
+            error!("Error reading thread: {:?}", e);
+            return HttpResponse::InternalServerError().body("Error reading thread.");
+        }
+    };
+
+    if owner.is_some_and(|owner| owner != user_id) {
+        warn!(
+            "User {} requested a replay of thread {}, which belongs to another user.",
+            user_id, thread_id
+        );
+        return HttpResponse::Forbidden().body("This thread belongs to another user.");
+    }
+
+    info!("Replaying thread {} at speed {}.", thread_id, speed);
+    let framing = StreamFraming::from_request(&qstring, headers);
+    framing.response().streaming(replay_stream(
+        post_process(content),
+        REPLAY_BASE_DELAY.div_f64(speed),
+        framing,
+    ))
+}
+
+/// Turns the stored variants into a stream that sends one of them after every delay.
+fn replay_stream(
+    variants: Vec<StreamVariant>,
+    delay: Duration,
+    framing: StreamFraming,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    stream::iter(variants).then(move |variant| async move {
+        tokio::time::sleep(delay).await;
+        Ok(variant_to_bytes(&variant, framing))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_replay_emits_variants_in_order() {
+        let variants = vec![
+            StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string()),
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        let replayed = replay_stream(variants.clone(), Duration::ZERO, StreamFraming::Raw)
+            .collect::<Vec<_>>()
+            .await;
+
+        let expected = variants
+            .iter()
+            .map(|variant| Ok(variant_to_bytes(variant, StreamFraming::Raw)))
+            .collect::<Vec<_>>();
+        assert_eq!(replayed, expected);
+    }
+}
@@ -0,0 +1,182 @@
+// The parameters of the LLM that can be set per request.
+
+use std::ops::RangeInclusive;
+
+/// The temperatures the LLM can be asked for.
+const TEMPERATURE_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+
+/// The maximum number of tokens the LLM can be asked to generate per response.
+const MAX_TOKENS_RANGE: RangeInclusive<u32> = 1..=32000;
+
+/// The frequency penalties the LLM can be asked for; the same range as the OpenAI API allows.
+const FREQUENCY_PENALTY_RANGE: RangeInclusive<f32> = -2.0..=2.0;
+
+/// How much the GPT-5 models reason before they answer. More effort is slower, but better at hard questions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReasoningEffort {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+impl std::str::FromStr for ReasoningEffort {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(format!(
+                "Invalid reasoning_effort {value:?}. It has to be low, medium or high."
+            )),
+        }
+    }
+}
+
+impl From<ReasoningEffort> for async_openai::types::ReasoningEffort {
+    fn from(effort: ReasoningEffort) -> Self {
+        match effort {
+            ReasoningEffort::Low => Self::Low,
+            ReasoningEffort::Medium => Self::Medium,
+            ReasoningEffort::High => Self::High,
+        }
+    }
+}
+
+/// Parameters of the LLM that power users can tune per request.
+/// Everything that isn't set keeps the default of the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestParams {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub frequency_penalty: Option<f32>,
+    /// Whether the LLM may call several tools at once; see `ENABLE_PARALLEL_TOOL_CALLS`.
+    pub parallel_tool_calls: Option<bool>,
+    /// Only sent to the GPT-5 models, which default to medium; the other models reject it.
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+impl RequestParams {
+    /// Parses and validates the parameters as they were sent by the client.
+    /// Values that can't be parsed or are out of range are an error with a message for the client, they aren't clamped.
+    pub fn parse(
+        temperature: Option<&str>,
+        max_tokens: Option<&str>,
+        frequency_penalty: Option<&str>,
+        parallel_tool_calls: Option<&str>,
+        reasoning_effort: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            temperature: parse_in_range("temperature", temperature, TEMPERATURE_RANGE)?,
+            max_tokens: parse_in_range("max_tokens", max_tokens, MAX_TOKENS_RANGE)?,
+            frequency_penalty: parse_in_range(
+                "frequency_penalty",
+                frequency_penalty,
+                FREQUENCY_PENALTY_RANGE,
+            )?,
+            parallel_tool_calls: parse_flag("parallel_tool_calls", parallel_tool_calls)?,
+            reasoning_effort: reasoning_effort
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::parse)
+                .transpose()?,
+        })
+    }
+}
+
+/// Parses a single parameter; an empty or missing value means it's not set.
+fn parse_in_range<T>(
+    name: &str,
+    value: Option<&str>,
+    range: RangeInclusive<T>,
+) -> Result<Option<T>, String>
+where
+    T: std::str::FromStr + PartialOrd + std::fmt::Display,
+{
+    let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(None);
+    };
+    match value.parse::<T>() {
+        Ok(parsed) if range.contains(&parsed) => Ok(Some(parsed)),
+        _ => Err(format!(
+            "Invalid {name} {value:?}. It has to be a number between {} and {}.",
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+/// Parses a flag, which has to be either `true` or `false`; an empty or missing value means it's not set.
+fn parse_flag(name: &str, value: Option<&str>) -> Result<Option<bool>, String> {
+    match value.map(str::trim).filter(|value| !value.is_empty()) {
+        None => Ok(None),
+        Some("true") => Ok(Some(true)),
+        Some("false") => Ok(Some(false)),
+        Some(value) => Err(format!(
+            "Invalid {name} {value:?}. It has to be either true or false."
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate_bounds() {
+        assert_eq!(
+            RequestParams::parse(None, None, None, None, None),
+            Ok(RequestParams::default())
+        );
+        assert_eq!(
+            RequestParams::parse(
+                Some("0"),
+                Some("32000"),
+                Some("-2"),
+                Some("true"),
+                Some("high")
+            ),
+            Ok(RequestParams {
+                temperature: Some(0.0),
+                max_tokens: Some(32000),
+                frequency_penalty: Some(-2.0),
+                parallel_tool_calls: Some(true),
+                reasoning_effort: Some(ReasoningEffort::High),
+            })
+        );
+        // An empty value is the same as not sending it.
+        assert_eq!(
+            RequestParams::parse(Some("2.0"), Some(""), None, None, Some(" ")),
+            Ok(RequestParams {
+                temperature: Some(2.0),
+                ..Default::default()
+            })
+        );
+
+        for (temperature, max_tokens, frequency_penalty, parallel_tool_calls, reasoning_effort) in [
+            (Some("2.1"), None, None, None, None),
+            (Some("-0.1"), None, None, None, None),
+            (Some("NaN"), None, None, None, None),
+            (Some("warm"), None, None, None, None),
+            (None, Some("0"), None, None, None),
+            (None, Some("32001"), None, None, None),
+            (None, Some("1.5"), None, None, None),
+            (None, None, Some("2.5"), None, None),
+            (None, None, None, Some("yes"), None),
+            (None, None, None, None, Some("minimal")),
+            (None, None, None, None, Some("HIGH")),
+        ] {
+            let error = RequestParams::parse(
+                temperature,
+                max_tokens,
+                frequency_penalty,
+                parallel_tool_calls,
+                reasoning_effort,
+            )
+            .expect_err("The value is out of range");
+            assert!(error.starts_with("Invalid "), "{error}");
+        }
+    }
+}
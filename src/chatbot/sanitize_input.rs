@@ -0,0 +1,84 @@
+// Pasted content can contain invisible or direction-changing Unicode characters.
+// They don't change what the user sees, but they do change what the LLM sees, which can confuse it or be used to smuggle instructions past a human reader.
+// If enabled, the input of the user is normalized (NFC) and those characters are stripped before the request is built.
+
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+use unicode_normalization::UnicodeNormalization;
+
+/// Whether to normalize the user input before sending it to the LLM.
+/// Set via the environment variable `NORMALIZE_USER_INPUT`; defaults to false.
+pub static NORMALIZE_USER_INPUT: Lazy<bool> =
+    Lazy::new(|| std::env::var("NORMALIZE_USER_INPUT").is_ok_and(|value| value.trim() == "true"));
+
+/// Returns whether the character is one of the invisible or bidirectional control characters we don't want to forward to the LLM.
+fn is_dangerous_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}'..='\u{200F}' // zero width space, (non-)joiner and the left-to-right/right-to-left marks
+        | '\u{202A}'..='\u{202E}' // bidi embeddings and overrides
+        | '\u{2060}' // word joiner
+        | '\u{2066}'..='\u{2069}' // bidi isolates
+        | '\u{061C}' // arabic letter mark
+        | '\u{FEFF}' // zero width no-break space (BOM)
+    )
+}
+
+/// Normalizes the input to NFC and strips all dangerous control characters.
+/// Returns the cleaned input and the characters that were removed, so the caller can flag them.
+pub fn sanitize_input(input: &str) -> (String, Vec<char>) {
+    let mut removed = Vec::new();
+    let cleaned = input
+        .nfc()
+        .filter(|c| {
+            if is_dangerous_char(*c) {
+                removed.push(*c);
+                false
+            } else {
+                true
+            }
+        })
+        .collect();
+    (cleaned, removed)
+}
+
+/// Applies `sanitize_input` if it is enabled via `NORMALIZE_USER_INPUT`, otherwise returns the input unchanged.
+pub fn maybe_sanitize_input(input: String) -> String {
+    if !*NORMALIZE_USER_INPUT {
+        return input;
+    }
+
+    let (cleaned, removed) = sanitize_input(&input);
+    if removed.is_empty() {
+        debug!("User input did not contain any dangerous Unicode characters.");
+    } else {
+        // Not necessarily malicious, copying from PDFs or websites can also produce these, but it's worth knowing about.
+        warn!(
+            "Stripped {} dangerous Unicode characters from the user input: {:?}",
+            removed.len(),
+            removed
+        );
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bidi_override_is_stripped() {
+        // The right-to-left override makes "txt.exe" display as "exe.txt".
+        let (cleaned, removed) = sanitize_input("open the file \u{202E}txt.exe please");
+        assert_eq!(cleaned, "open the file txt.exe please");
+        assert_eq!(removed, vec!['\u{202E}']);
+    }
+
+    #[test]
+    fn test_input_is_nfc_normalized() {
+        // "e" followed by a combining acute accent should become a single "é".
+        let (cleaned, removed) = sanitize_input("caf\u{0065}\u{0301}");
+        assert_eq!(cleaned, "caf\u{00E9}");
+        assert!(removed.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+// Saves the conversations that are still streaming when the server is asked to shut down.
+// The active conversations only live in memory, so without this, a restart in the middle of a stream loses them entirely.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use actix_web::dev::ServerHandle;
+use futures::future::{join_all, select};
+use mongodb::Database;
+use once_cell::sync::Lazy;
+use tracing::{error, info, warn};
+
+use crate::chatbot::{
+    handle_active_conversations::save_and_remove_conversation,
+    types::{ActiveConversation, ConversationState, StreamVariant},
+    ACTIVE_CONVERSATIONS,
+};
+
+/// How long the server waits for the active conversations to be saved before it exits anyway.
+/// Set via the environment variable `SHUTDOWN_SAVE_TIMEOUT_SECS`; defaults to 10 seconds.
+pub static SHUTDOWN_SAVE_TIMEOUT_SECS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("SHUTDOWN_SAVE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(10)
+});
+
+/// Waits for SIGINT (ctrl-c) or SIGTERM, saves all active conversations and then stops the server.
+/// The conversations are saved at that point, so the connections are closed right away instead of letting the streams continue.
+pub async fn shutdown_on_signal(server: ServerHandle) {
+    wait_for_signal().await;
+    info!(
+        "Received a shutdown signal, saving the active conversations before stopping the server."
+    );
+    println!("Shutting down, saving the active conversations...");
+    let flushed =
+        flush_active_conversations(Duration::from_secs(*SHUTDOWN_SAVE_TIMEOUT_SECS)).await;
+    info!("Saved {} conversations, stopping the server.", flushed);
+    println!("Saved {flushed} conversations.");
+    server.stop(false).await;
+}
+
+/// Returns once the process is asked to terminate, be it by ctrl-c or by the container runtime.
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select(
+                    Box::pin(tokio::signal::ctrl_c()),
+                    Box::pin(terminate.recv()),
+                )
+                .await;
+                return;
+            }
+            Err(e) => error!(
+                "Could not listen for SIGTERM, only ctrl-c shuts down gracefully: {:?}",
+                e
+            ),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Could not listen for ctrl-c: {:?}", e);
+        // Without a signal to wait for, the server would never save its conversations; waiting forever keeps the old behaviour.
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Stops and saves all active conversations. Returns how many were saved within the timeout.
+pub async fn flush_active_conversations(timeout: Duration) -> usize {
+    flush_conversations(|_| true, timeout).await
+}
+
+/// Stops and saves the active conversations that match the filter.
+/// Each one is marked as Stopping, so its stream doesn't continue, and ends with a StreamEnd, unless it already ended.
+/// The saves run at the same time; the ones that don't finish within the timeout are given up.
+async fn flush_conversations(
+    filter: impl Fn(&ActiveConversation) -> bool,
+    timeout: Duration,
+) -> usize {
+    let to_save: Vec<(String, Database)> = match ACTIVE_CONVERSATIONS.lock() {
+        Ok(mut guard) => guard
+            .iter_mut()
+            .filter(|conversation| filter(conversation))
+            .filter_map(|conversation| {
+                if !matches!(conversation.state, ConversationState::Ended) {
+                    conversation
+                        .conversation
+                        .push(StreamVariant::StreamEnd("Server shutting down".to_string()));
+                    conversation.state = ConversationState::Stopping;
+                }
+                if let Some(tool_task) = conversation.tool_task.take() {
+                    tool_task.abort();
+                }
+                match &conversation.database {
+                    Some(database) => Some((conversation.id.clone(), database.clone())),
+                    None => {
+                        warn!(
+                            "Conversation {} has no database to be saved to, it's lost.",
+                            conversation.id
+                        );
+                        None
+                    }
+                }
+            })
+            .collect(),
+        Err(e) => {
+            error!("Error locking the mutex: {:?}", e);
+            return 0;
+        }
+    };
+
+    let total = to_save.len();
+    let saved = AtomicUsize::new(0);
+    let saves = join_all(to_save.into_iter().map(|(thread_id, database)| {
+        let saved = &saved;
+        async move {
+            save_and_remove_conversation(&thread_id, database).await;
+            saved.fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+    if tokio::time::timeout(timeout, saves).await.is_err() {
+        warn!(
+            "Saving the active conversations took longer than {:?}; {} of {} were saved.",
+            timeout,
+            saved.load(Ordering::Relaxed),
+            total
+        );
+    }
+    saved.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::{
+        handle_active_conversations::{
+            add_to_conversation, generate_id, set_conversation_database,
+        },
+        mongodb::mongodb_storage::test_database,
+        storage_router::{delete_thread, read_thread},
+    };
+
+    #[actix_web::test]
+    async fn test_shutdown_stops_conversations_without_database() {
+        // The conversations of the other tests are in the same pool, so only this test's conversation is flushed.
+        let without_database = generate_id();
+        add_to_conversation(
+            &without_database,
+            vec![StreamVariant::User("plot the temperature".to_string())],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        // A conversation that doesn't know its database can't be saved, but it's still stopped.
+        assert_eq!(
+            flush_conversations(|c| c.id == without_database, Duration::from_secs(60)).await,
+            0
+        );
+        assert!(ACTIVE_CONVERSATIONS
+            .lock()
+            .expect("The mutex isn't poisoned")
+            .iter()
+            .any(|c| c.id == without_database && matches!(c.state, ConversationState::Stopping)));
+
+        ACTIVE_CONVERSATIONS
+            .lock()
+            .expect("The mutex isn't poisoned")
+            .retain(|c| c.id != without_database);
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_shutdown_saves_active_conversations() {
+        let thread_id = generate_id();
+        let input = StreamVariant::User("plot the temperature".to_string());
+        add_to_conversation(
+            &thread_id,
+            vec![input.clone()],
+            String::new(),
+            "testuser".to_string(),
+        );
+        let database = test_database("freva_gpt_shutdown_test").await;
+        set_conversation_database(&thread_id, database.clone());
+
+        assert_eq!(
+            flush_conversations(|c| c.id == thread_id, Duration::from_secs(60)).await,
+            1
+        );
+        assert_eq!(
+            read_thread(&thread_id, database.clone())
+                .await
+                .expect("The conversation was saved"),
+            vec![
+                input,
+                StreamVariant::StreamEnd("Server shutting down".to_string())
+            ]
+        );
+        assert!(!ACTIVE_CONVERSATIONS
+            .lock()
+            .expect("The mutex isn't poisoned")
+            .iter()
+            .any(|c| c.id == thread_id));
+
+        delete_thread(&thread_id, database)
+            .await
+            .expect("The test thread can be deleted");
+    }
+}
@@ -2,11 +2,14 @@
 
 use actix_web::{HttpRequest, HttpResponse, Responder};
 use documented::docs_const;
-use tracing::{debug, trace, warn};
+use tracing::{debug, info, trace, warn};
 
 use crate::auth::get_first_matching_field;
 
-use super::{types::ConversationState, ACTIVE_CONVERSATIONS};
+use super::{
+    handle_active_conversations::stop_user_conversations, types::ConversationState,
+    ACTIVE_CONVERSATIONS,
+};
 
 // TODO: guarentee panic safety
 
@@ -98,3 +101,35 @@ pub async fn stop(req: HttpRequest) -> impl Responder {
         }
     }
 }
+
+/// # Stop All
+/// Stops all running conversations of the user at once, for example when the user leaves the page. Requires Authentication.
+///
+/// Takes no parameters besides the authentication; only the conversations of the authenticated user are stopped.
+/// Running tool calls, like code executions, are aborted right away, the streams themselves end like after the stop endpoint.
+///
+/// Returns a JSON object with the number of conversations that were `stopped`, which is 0 if none were running.
+///
+/// If there is an error stopping the conversations, an InternalServerError response is returned.
+#[docs_const] // writes the docstring into a variable called STOP_ALL_DOCS
+pub async fn stop_all(req: HttpRequest) -> impl Responder {
+    let qstring = qstring::QString::from(req.query_string());
+    let headers = req.headers();
+
+    // Only the user's own conversations are stopped, so the user needs to be known.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    match stop_user_conversations(&user_id) {
+        Ok(stopped) => {
+            info!("Stopped {} conversations of user {}.", stopped, user_id);
+            HttpResponse::Ok().json(serde_json::json!({ "stopped": stopped }))
+        }
+        Err(e) => {
+            warn!(
+                "Error stopping the conversations of user {}: {:?}",
+                user_id, e
+            );
+            HttpResponse::InternalServerError().body("Error stopping conversations.")
+        }
+    }
+}
@@ -1,33 +1,102 @@
 use mongodb::Database;
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
 
 use crate::chatbot::mongodb::mongodb_storage;
 
-use super::types::Conversation;
+use super::types::{Conversation, TokenUsage};
 
-#[allow(dead_code)] // Only one variant of this enum is ever used, so this shuts up the warning
 /// Represents the possible available storage options for the threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AvailableStorages {
     Disk,
     MongoDB,
+    /// Writes to the disk and the MongoDB, for migrating between them or for redundancy.
+    /// Reads prefer the MongoDB and fall back to the disk for threads that are only stored there.
+    Both,
 }
 
-/// The currently active storage for the threads
-pub static STORAGE: AvailableStorages = AvailableStorages::MongoDB;
+impl AvailableStorages {
+    fn uses_disk(self) -> bool {
+        matches!(self, Self::Disk | Self::Both)
+    }
 
-/// Appends a thread to the storage. User_Id is ignored for the disk storage.
+    fn uses_mongodb(self) -> bool {
+        matches!(self, Self::MongoDB | Self::Both)
+    }
+}
+
+/// The currently active storage for the threads.
+/// Set via the environment variable `STORAGE_MODE` to "disk", "mongo" or "both"; defaults to "mongo".
+pub static STORAGE: Lazy<AvailableStorages> = Lazy::new(|| {
+    match std::env::var("STORAGE_MODE")
+        .map(|mode| mode.trim().to_lowercase())
+        .as_deref()
+    {
+        Ok("disk") => AvailableStorages::Disk,
+        Ok("both") => AvailableStorages::Both,
+        Ok("mongo" | "mongodb") | Err(_) => AvailableStorages::MongoDB,
+        Ok(other) => {
+            warn!(
+                "Unknown STORAGE_MODE {:?}, expected disk, mongo or both; using mongo.",
+                other
+            );
+            AvailableStorages::MongoDB
+        }
+    }
+});
+
+/// Appends a thread to the storage. The token usage is ignored for the disk storage.
 pub async fn append_thread(
     thread_id: &str,
     user_id: &str,
     content: Conversation,
+    usage: Option<TokenUsage>,
     database: Database,
 ) {
-    match STORAGE {
-        AvailableStorages::Disk => {
-            super::thread_storage::append_thread(thread_id, content);
-        }
-        AvailableStorages::MongoDB => {
-            mongodb_storage::append_thread(thread_id, user_id, content, database).await;
-        }
+    append_thread_to(*STORAGE, thread_id, user_id, content, usage, database).await;
+}
+
+/// Appends a thread to the given storage.
+/// Both storages log their own errors, so if one of them fails, the thread is still written to the other.
+async fn append_thread_to(
+    storage: AvailableStorages,
+    thread_id: &str,
+    user_id: &str,
+    content: Conversation,
+    usage: Option<TokenUsage>,
+    database: Database,
+) {
+    if storage.uses_disk() {
+        super::thread_storage::append_thread(thread_id, user_id, content.clone());
+    }
+    if storage.uses_mongodb() {
+        mongodb_storage::append_thread(thread_id, user_id, content, usage, database).await;
+    }
+}
+
+/// Cuts the stored thread to its first `keep` variants, for a turn that is regenerated.
+pub async fn truncate_thread(thread_id: &str, user_id: &str, keep: usize, database: Database) {
+    if STORAGE.uses_disk() {
+        super::thread_storage::truncate_thread(thread_id, user_id, keep);
+    }
+    if STORAGE.uses_mongodb() {
+        mongodb_storage::truncate_thread(thread_id, keep, database).await;
+    }
+}
+
+/// Stores an uncleaned copy of the variants of a turn next to the thread, see `STORE_RAW_CONVERSATIONS`.
+pub async fn append_raw_thread(
+    thread_id: &str,
+    user_id: &str,
+    content: Conversation,
+    database: Database,
+) {
+    if STORAGE.uses_disk() {
+        super::thread_storage::append_raw_thread(thread_id, user_id, content.clone());
+    }
+    if STORAGE.uses_mongodb() {
+        mongodb_storage::append_raw_thread(thread_id, user_id, content, database).await;
     }
 }
 
@@ -36,16 +105,248 @@ pub async fn read_thread(
     thread_id: &str,
     database: Database,
 ) -> Result<Conversation, std::io::Error> {
-    match STORAGE {
-        AvailableStorages::Disk => super::thread_storage::read_thread(thread_id),
-        AvailableStorages::MongoDB => {
-            match mongodb_storage::read_thread(thread_id, database).await {
-                Some(thread) => Ok(thread.content),
-                None => Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "Thread not found",
-                )),
-            }
+    read_thread_and_owner_from(*STORAGE, thread_id, database)
+        .await
+        .map(|(content, _)| content)
+}
+
+/// Reads a thread from the storage together with the user_id of its owner.
+/// Threads written to disk before the owner was recorded return None.
+pub async fn read_thread_and_owner(
+    thread_id: &str,
+    database: Database,
+) -> Result<(Conversation, Option<String>), std::io::Error> {
+    read_thread_and_owner_from(*STORAGE, thread_id, database).await
+}
+
+/// Reads a thread and its owner from the given storage, preferring the MongoDB if both are used.
+async fn read_thread_and_owner_from(
+    storage: AvailableStorages,
+    thread_id: &str,
+    database: Database,
+) -> Result<(Conversation, Option<String>), std::io::Error> {
+    if storage.uses_mongodb() {
+        if let Some(thread) = mongodb_storage::read_thread(thread_id, database).await {
+            return Ok((thread.content, Some(thread.user_id)));
+        }
+        if storage.uses_disk() {
+            debug!(
+                "Thread {} isn't in the MongoDB, falling back to the disk.",
+                thread_id
+            );
         }
     }
+    if storage.uses_disk() {
+        return super::thread_storage::read_thread_and_owner(thread_id);
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "Thread not found",
+    ))
+}
+
+/// Deletes everything that is stored for a thread: the document in the MongoDB, the files on disk and the pickle file of the code interpreter.
+/// The files on disk are removed in all modes, they might be left from before the storage was switched.
+/// Returns how many of them existed.
+pub async fn delete_thread(thread_id: &str, database: Database) -> Result<usize, std::io::Error> {
+    let mut removed = super::thread_storage::delete_thread_files(thread_id)?;
+    if STORAGE.uses_mongodb()
+        && mongodb_storage::delete_thread(thread_id, database)
+            .await
+            .map_err(std::io::Error::other)?
+    {
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Checks whether a thread with the given ID is stored, without reading its content.
+pub async fn thread_exists(thread_id: &str, database: Database) -> bool {
+    (STORAGE.uses_mongodb() && mongodb_storage::thread_exists(thread_id, database).await)
+        || (STORAGE.uses_disk() && super::thread_storage::thread_file_exists(thread_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::{
+        handle_active_conversations::generate_id, mongodb::mongodb_storage::test_database,
+        types::StreamVariant,
+    };
+
+    #[test]
+    fn test_deleted_thread_files_leave_nothing_behind() {
+        let thread_id = generate_id();
+        let content = vec![
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+        ];
+        let pickle_file = format!("python_pickles/{thread_id}.pickle");
+        super::super::thread_storage::append_thread(&thread_id, "testuser", content);
+        std::fs::create_dir_all("python_pickles").expect("The pickle directory can be created");
+        std::fs::write(&pickle_file, b"pickle").expect("The pickle file can be written");
+
+        // The thread file and the pickle file.
+        assert_eq!(
+            super::super::thread_storage::delete_thread_files(&thread_id)
+                .expect("The files can be deleted"),
+            2
+        );
+        assert!(!super::super::thread_storage::thread_file_exists(
+            &thread_id
+        ));
+        assert!(!std::path::Path::new(&pickle_file).exists());
+
+        // IDs that could point somewhere else are refused.
+        assert_eq!(
+            super::super::thread_storage::delete_thread_files("../Cargo").map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::InvalidInput)
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_deleted_thread_leaves_nothing_behind_in_the_database() {
+        let thread_id = generate_id();
+        let content = vec![StreamVariant::User("plot a circle".to_string())];
+        let pickle_file = format!("python_pickles/{thread_id}.pickle");
+        super::super::thread_storage::append_thread(&thread_id, "testuser", content.clone());
+        std::fs::create_dir_all("python_pickles").expect("The pickle directory can be created");
+        std::fs::write(&pickle_file, b"pickle").expect("The pickle file can be written");
+        let database = test_database("freva_gpt_delete_test").await;
+        append_thread(&thread_id, "testuser", content, None, database.clone()).await;
+
+        // The document in the MongoDB goes together with the files.
+        assert_eq!(
+            delete_thread(&thread_id, database.clone())
+                .await
+                .expect("The thread can be deleted"),
+            3
+        );
+        assert!(!thread_exists(&thread_id, database).await);
+        assert!(!super::super::thread_storage::thread_file_exists(
+            &thread_id
+        ));
+        assert!(!std::path::Path::new(&pickle_file).exists());
+    }
+
+    fn storage_test_content() -> Vec<StreamVariant> {
+        vec![
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ]
+    }
+
+    async fn unreachable_database() -> Database {
+        mongodb::Client::with_uri_str(
+            "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100&connectTimeoutMS=100",
+        )
+        .await
+        .expect("The URI is valid, the client only connects when it's used")
+        .database("freva_gpt_storage_test")
+    }
+
+    #[actix_web::test]
+    async fn test_disk_storage_never_touches_the_database() {
+        let content = storage_test_content();
+        let unreachable = unreachable_database().await;
+        let thread_id = generate_id();
+        append_thread_to(
+            AvailableStorages::Disk,
+            &thread_id,
+            "testuser",
+            content.clone(),
+            None,
+            unreachable.clone(),
+        )
+        .await;
+        assert_eq!(
+            read_thread_and_owner_from(AvailableStorages::Disk, &thread_id, unreachable.clone())
+                .await
+                .expect("The thread is read from disk"),
+            (content.clone(), Some("testuser".to_string()))
+        );
+        super::super::thread_storage::delete_thread_files(&thread_id)
+            .expect("The files can be deleted");
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires MongoDB"]
+    async fn test_both_storages_are_written_independently() {
+        let content = storage_test_content();
+        let unreachable = unreachable_database().await;
+
+        // A MongoDB that can't be reached doesn't keep the thread from being written to disk, where it's then read from.
+        let thread_id = generate_id();
+        append_thread_to(
+            AvailableStorages::Both,
+            &thread_id,
+            "testuser",
+            content.clone(),
+            None,
+            unreachable.clone(),
+        )
+        .await;
+        assert_eq!(
+            super::super::thread_storage::read_thread(&thread_id)
+                .expect("The thread was written to disk"),
+            content
+        );
+        assert_eq!(
+            read_thread_and_owner_from(AvailableStorages::Both, &thread_id, unreachable)
+                .await
+                .expect("The thread is read from disk"),
+            (content.clone(), Some("testuser".to_string()))
+        );
+        super::super::thread_storage::delete_thread_files(&thread_id)
+            .expect("The files can be deleted");
+
+        // Both storages get the thread.
+        let database = test_database("freva_gpt_storage_test").await;
+        let thread_id = generate_id();
+        append_thread_to(
+            AvailableStorages::Both,
+            &thread_id,
+            "testuser",
+            content.clone(),
+            None,
+            database.clone(),
+        )
+        .await;
+        assert_eq!(
+            mongodb_storage::read_thread(&thread_id, database.clone())
+                .await
+                .expect("The thread was written to the MongoDB")
+                .content,
+            content
+        );
+        assert!(super::super::thread_storage::read_thread(&thread_id).is_ok());
+        delete_thread(&thread_id, database.clone())
+            .await
+            .expect("The test thread can be deleted");
+
+        // A thread that can't be written to disk still ends up in the MongoDB.
+        let thread_id = format!("missing_directory/{}", generate_id());
+        append_thread_to(
+            AvailableStorages::Both,
+            &thread_id,
+            "testuser",
+            content.clone(),
+            None,
+            database.clone(),
+        )
+        .await;
+        assert!(super::super::thread_storage::read_thread(&thread_id).is_err());
+        assert_eq!(
+            read_thread_and_owner_from(AvailableStorages::Both, &thread_id, database.clone())
+                .await
+                .expect("The thread is read from the MongoDB")
+                .0,
+            content
+        );
+        mongodb_storage::delete_thread(&thread_id, database)
+            .await
+            .expect("The test thread can be deleted");
+    }
 }
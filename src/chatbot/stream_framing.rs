@@ -0,0 +1,80 @@
+use actix_web::{
+    http::header::{self, HeaderMap},
+    web::Bytes,
+    HttpResponse, HttpResponseBuilder,
+};
+use qstring::QString;
+use tracing::warn;
+
+use crate::auth::get_first_matching_field;
+
+/// How the variants of a stream are separated from each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamFraming {
+    /// The JSON objects are sent directly after each other, which is what the frontend always expected.
+    #[default]
+    Raw,
+    /// Every variant is a Server-Sent-Event (`data: <json>\n\n`), so clients can split the stream even if two variants arrive in one chunk.
+    Sse,
+    /// Every variant is followed by a newline (JSON-lines), so clients can split the stream on `\n`.
+    Jsonl,
+}
+
+impl StreamFraming {
+    /// Clients can choose the framing with the `framing` parameter (`raw`, `jsonl` or `sse`).
+    /// Without it, clients that accept `text/event-stream` get Server-Sent-Events; everyone else keeps getting the raw stream.
+    pub fn from_request(qstring: &QString, headers: &HeaderMap) -> Self {
+        match get_first_matching_field(qstring, headers, &["framing", "x-framing"], false) {
+            Some("raw") => return Self::Raw,
+            Some("jsonl") => return Self::Jsonl,
+            Some("sse") => return Self::Sse,
+            Some(other) => warn!("Unknown stream framing {:?}, ignoring it.", other),
+            None => {}
+        }
+
+        let accepts_sse = headers
+            .get_all(header::ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_type| {
+                // Parameters like a quality (`;q=0.9`) don't matter here.
+                media_type
+                    .split(';')
+                    .next()
+                    .is_some_and(|media_type| media_type.trim() == "text/event-stream")
+            });
+        if accepts_sse {
+            Self::Sse
+        } else {
+            Self::Raw
+        }
+    }
+
+    /// Frames the JSON of a single variant.
+    pub fn frame(self, json: Bytes) -> Bytes {
+        match self {
+            Self::Raw => json,
+            // The JSON of a variant never contains a raw newline, so a single data line is enough.
+            Self::Sse => [b"data: ".as_slice(), &json, b"\n\n"].concat().into(),
+            // Same for JSON-lines, where the newline is the only separator.
+            Self::Jsonl => [json.as_ref(), b"\n"].concat().into(),
+        }
+    }
+
+    /// The start of a successful streaming response, with the content type matching the framing.
+    pub fn response(self) -> HttpResponseBuilder {
+        let mut response = HttpResponse::Ok();
+        match self {
+            Self::Raw => {}
+            Self::Sse => {
+                response
+                    .content_type("text/event-stream")
+                    .insert_header((header::CACHE_CONTROL, "no-cache"));
+            }
+            Self::Jsonl => {
+                response.content_type("application/x-ndjson");
+            }
+        }
+        response
+    }
+}
@@ -1,6 +1,15 @@
-use std::{cell::Cell, collections::VecDeque};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
-use actix_web::{web::Bytes, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    http::{header::HeaderMap, StatusCode},
+    web::Bytes,
+    HttpRequest, HttpResponse, Responder,
+};
+use async_openai::error::WrappedError;
 use async_openai::types::{
     ChatChoiceStream, ChatCompletionMessageToolCallChunk, ChatCompletionRequestMessage,
     ChatCompletionRequestUserMessage, ChatCompletionResponseStream, ChatCompletionToolChoiceOption,
@@ -14,33 +23,72 @@ use futures::{
 };
 use mongodb::Database;
 use once_cell::sync::Lazy;
+use qstring::QString;
+use serde::Deserialize;
 use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    auth::{get_first_matching_field, is_guest},
+    api_error::error_response,
+    auth::{get_first_matching_field, get_tenant, is_guest},
     chatbot::{
         available_chatbots::{
-            model_ends_on_no_choice, model_is_gpt_5, model_is_reasoning, model_supports_images,
-            DEFAULTCHATBOT,
+            model_ends_on_no_choice, model_is_claude, model_is_gpt_5, model_is_reasoning,
+            model_max_tokens, model_message_ordering, model_stop_action, model_supports_images,
+            model_tool_call_content, model_tool_call_markers, StopAction, DEFAULTCHATBOT,
         },
+        broadcast::{next_status_hint, subscribe_to_status},
+        circuit_breaker::{
+            is_llm_unavailable_error, llm_unavailable_error, with_llm_breaker, CircuitBreaker,
+        },
+        context_overflow::{create_stream_with_context_retry, history_trimmed_hint},
+        delta_offsets::{offsets_requested, variant_to_bytes_with_offsets, DeltaOffsets},
         filter_variants::filter_variants,
         handle_active_conversations::{
-            add_to_conversation, conversation_state, end_conversation, get_conversation,
-            new_conversation_id, save_and_remove_conversation, switch_to_new_thread_id,
+            add_to_conversation, add_transient_to_conversation, add_usage_to_conversation,
+            cap_warnings, conversation_state, count_operation, disconnected_for, end_conversation,
+            get_conversation, get_conversation_usage, mark_disconnected, new_conversation_id,
+            resume_conversation, save_and_remove_conversation, set_conversation_database,
+            set_tool_task, switch_to_new_thread_id, DISCONNECT_GRACE_PERIOD,
+            KEEP_DISCONNECTED_CONVERSATIONS, MAX_OPERATIONS_PER_TURN, MAX_WARNINGS_PER_STREAM,
+        },
+        heartbeat::{heartbeat_content, HEARTBEAT_INTERVAL, PERSIST_HEARTBEATS},
+        image_format::ImageFormat,
+        inline_freva_config::{
+            freva_config_content_from_request, inline_config_path, validate_freva_config,
+            write_inline_config,
         },
-        heartbeat::heartbeat_content,
-        mongodb::mongodb_storage::get_database,
-        prompting::{
-            get_entire_prompt, get_entire_prompt_gpt_5, get_entire_prompt_json,
-            get_entire_prompt_json_gpt_5,
+        is_lite_llm_running_cached,
+        lenient_tool_call::{
+            extract_code_leniently, malformed_tool_call_variants, LENIENT_TOOL_CALLS,
         },
+        mongodb::mongodb_storage::{get_database, read_user_settings},
+        parallel_tool_calls::{
+            parallel_tool_calls_enabled, route_calls_concurrently, PendingToolCall,
+        },
+        prompting::{get_prompt_for_chatbot, get_prompt_json_for_chatbot},
+        rate_limit::acquire_stream,
+        request_params::RequestParams,
+        sanitize_input::maybe_sanitize_input,
         storage_router::read_thread,
-        types::{help_convert_sv_ccrm, ConversationState, StreamVariant},
+        stream_framing::StreamFraming,
+        stream_retry::{create_stream_with_retries, LLM_STREAM_RETRY_POLICY},
+        stream_summary::{summary_requested, turn_summary},
+        transport_keep_alive::{with_transport_keep_alive, TRANSPORT_KEEP_ALIVE_INTERVAL},
+        types::{
+            fit_message_order, fit_tool_call_only_messages, help_convert_sv_ccrm,
+            ConversationState, StreamVariant, TokenUsage,
+        },
         LITE_LLM_CLIENT,
     },
     logging::{silence_logger, undo_silence_logger},
-    tool_calls::{code_interpreter::verify_can_access, route_call::route_call, ALL_TOOLS},
+    tool_calls::{
+        code_interpreter::{
+            prepare_execution::CodeVerbosity, verify_can_access, ConfigAccessError,
+        },
+        route_call::{route_call, ToolUpdate},
+        ALL_TOOLS,
+    },
 };
 
 use super::{available_chatbots::AvailableChatbots, handle_active_conversations::generate_id};
@@ -57,32 +105,106 @@ use super::{available_chatbots::AvailableChatbots, handle_active_conversations::
 /// If it's empty or not given, a new thread is created.
 ///
 /// The freva config file should be always set, as it's needed for the freva library to work.
+/// Clients without access to the filesystem of the backend can send the content of the config as freva_config_content instead.
+/// It's stored in a file only the backend can read, which is used in place of the path until the conversation ends.
 ///
 /// The chatbot parameter can be one of the possibilities as described in the /availablechatbots endpoint.
 /// If it's not set, the default chatbot is used, which is the first one in the list.
 ///
+/// The code_verbosity parameter can be "concise" (the default) or "full". It controls how much of the code interpreter's output the LLM sees:
+/// concise output is truncated and tracebacks only contain the frames of the executed code and the one where the error was raised,
+/// while full output contains the entire tracebacks and is only limited by the general cap on tool results.
+///
+/// Power users can tune the LLM with the optional parameters temperature (0 to 2), max_tokens (1 to 32000) and frequency_penalty (-2 to 2).
+/// With parallel_tool_calls set to true (or false), the LLM may (or may not) call several tools at once, overriding the default of the server.
+/// If they aren't set, the defaults of the server are used. Reasoning models ignore the temperature and the frequency penalty.
+/// The GPT-5 models also take a reasoning_effort of low, medium (the default) or high; the other models ignore it.
+/// If the chatbot or the temperature isn't set, the one of the user's settings profile is used (see the /usersettings endpoint).
+///
 /// The stream consists of StreamVariants and their content. See the different Stream Variants above.
 /// If the stream creates a new thread, the new thread_id will be sent as a ServerHint.
 /// The stream always ends with a StreamEnd event, unless a server error occurs.
 ///
 /// A usual stream consists mostly of Assistant messages many times a second. This is to give the impression of a real-time conversation.
-/// Because code execution might lead to a long period of silence, Heartbeat events (ServerHint) are sent every five seconds.
+/// Because code execution might lead to a long period of silence, Heartbeat events (ServerHint) are sent every five seconds (HEARTBEAT_INTERVAL_SECS), together with the progress the code reports.
+/// If the stream is silent for half of the keep-alive time (KEEP_ALIVE_SECS, 120 seconds by default), a single newline is sent to keep the connection alive.
+/// Clients should ignore whitespace between the variants.
+///
+/// By default, the variants are JSON objects sent directly after each other.
+/// If the request has the header `Accept: text/event-stream`, the response is a stream of Server-Sent-Events (with that content type) instead:
+/// every variant is sent as `data: <json>` followed by an empty line, so clients can split the stream without parsing the JSON.
+/// With the parameter `framing=jsonl`, the stream is JSON-lines (`application/x-ndjson`): every variant is followed by a newline and never contains one itself.
+/// The keep-alive newlines are empty lines then, which clients should skip. The framing can also be chosen explicitly with `framing=sse` or `framing=raw`.
+///
+/// Images are sent as base64 encoded PNGs. With the parameter `image_format=webp`, they are re-encoded as WebP, which is a lot smaller;
+/// the content of the Image variant is then a data URL (`data:image/webp;base64,...`). Stored threads always contain the PNGs.
+/// With the parameter `images=ref`, the Image variant instead contains the path under which the PNG can be fetched as raw bytes,
+/// like `/api/chatbot/image/{thread_id}/{image_index}` (see the image endpoint). The default, `images=inline`, sends the images themselves.
+///
+/// With the parameter `summary=true`, a Summary variant with the counts of the turn's messages, code executions, images and errors and its total tokens
+/// is sent before the StreamEnd. Streams that are stopped by the client end without one.
+///
+/// If the provider reported the tokens of the turn, a Usage variant with its prompt, completion and total tokens and the model is sent directly before the StreamEnd.
+///
+/// With the parameter `delta_offsets=true`, every Assistant variant also has an `offset`: where its content starts in the message, counted in characters (Unicode code points).
+/// A message is made of the consecutive Assistant variants; any variant other than an Assistant or a ServerHint ends it, and the next message starts at 0 again.
+/// Clients can then place each delta by its offset instead of appending it.
+///
+/// Errors are returned as a JSON object like `{"error": {"code": "missing_input", "message": "...", "status": 422}}`.
+/// The code is given in brackets for each error below; the message is meant for humans and may change.
 ///
-/// If the authorization fails, an Unauthorized response is returned.
+/// If the authorization fails, an Unauthorized response is returned (see the Authentication for its codes).
 /// If the authorization succeeds but the user could not determined, an UnprocessableEntity response is returned.
-/// If the authorization succeeds, but the user is considered a guest, an Unauthorized response is returned.
+/// If the authorization succeeds, but the user is considered a guest, an Unauthorized response is returned (`guest_not_allowed`).
 ///
-/// If the input is not given, an UnprocessableEntity response is returned.
+/// If the user already runs too many streams (MAX_STREAMS_PER_USER, 3 by default) or started too many in the last minute (MAX_STREAM_REQUESTS_PER_MINUTE, 20 by default),
+/// a TooManyRequests response with a Retry-After header is returned (`rate_limited`). Guests have lower limits (MAX_STREAMS_PER_GUEST and MAX_GUEST_STREAM_REQUESTS_PER_MINUTE).
 ///
-/// If the vault URL is not given, an UnprocessableEntity response is returned.
+/// If the input is not given, an UnprocessableEntity response is returned (`missing_input`).
 ///
-/// If the thread_id is already being streamed, a Conflict response is returned.
+/// If the temperature, max_tokens, frequency_penalty, parallel_tool_calls or reasoning_effort is invalid, a BadRequest response is returned (`invalid_parameter`).
 ///
-/// If the chatbot is not valid, an UnprocessableEntity response is returned.
+/// If the vault URL is not given, an UnprocessableEntity response is returned (`missing_vault_url`).
 ///
-/// If the stream fails due to something else on the backend, an InternalServerError response is returned.
+/// If the freva config content is not a plausible freva config, an UnprocessableEntity response is returned (`invalid_freva_config`).
+///
+/// If the freva config path is outside of FREVA_CONFIG_BASE (`/work` by default), also after resolving `..` and symlinks, a BadRequest response is returned (`invalid_freva_config_path`).
+/// A path inside of it that doesn't exist is accepted, but the freva library can't be used in the conversation then.
+///
+/// If the thread_id is already being streamed, a Conflict response is returned (`thread_busy`).
+/// The exception is a client that lost its connection: it can reconnect with the same thread_id and the resume parameter set to the number of variants it already recieved.
+/// Within the grace period after the disconnect, it then gets the remaining variants of the conversation, followed by a StreamEnd.
+/// A resume only replays what was produced until the disconnect; the turn isn't continued, so the client sends a new request to go on.
+///
+/// If the chatbot is not valid, an UnprocessableEntity response is returned (`chatbot_not_found`).
+///
+/// If the code_verbosity is neither "concise" nor "full", an UnprocessableEntity response is returned (`invalid_code_verbosity`).
+///
+/// If the chat variants to edit the thread with can't be matched to it, an UnprocessableEntity response is returned (`invalid_chat_variants`).
+///
+/// If the LiteLLM proxy is down, a ServiceUnavailable response is returned before the stream starts (`llm_unavailable`).
+/// Whether it's up is checked at most every two seconds.
+///
+/// If the LLM can't be reached or is rate limited, opening its stream is retried a few times with increasing delays.
+/// If it still fails, the stream consists of an OpenAIError variant, followed by a StreamEnd.
+///
+/// If the stream fails due to something else on the backend, an InternalServerError response is returned (`internal_error`).
 #[docs_const]
 pub async fn stream_response(req: HttpRequest) -> impl Responder {
+    let qstring = qstring::QString::from(req.query_string());
+    // Clients that accept Server-Sent-Events get every variant as its own event; all others get the raw stream.
+    let framing = StreamFraming::from_request(&qstring, req.headers());
+    let with_offsets = offsets_requested(&qstring, req.headers());
+    start_stream(&req, framing, with_offsets).await
+}
+
+/// Does everything the streamresponse endpoint does, but with the framing and the delta offsets set by the caller,
+/// so other endpoints can consume the stream themselves.
+pub(crate) async fn start_stream(
+    req: &HttpRequest,
+    framing: StreamFraming,
+    with_offsets: bool,
+) -> HttpResponse {
     let qstring = qstring::QString::from(req.query_string());
     let headers = req.headers();
 
@@ -101,8 +223,9 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
     ) {
         None | Some("") => {
             // If the thread ID is empty, we'll create a new thread.
+            // Its ID is generated once the database is available, because it has to be checked against the stored threads.
             debug!("Creating a new thread.");
-            (new_conversation_id(), true)
+            (String::new(), true)
         }
         Some(thread_id) => (thread_id.to_string(), false),
     };
@@ -114,20 +237,35 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
             "The User requested a stream, but is considered a guest. User ID: {}",
             user_id
         );
-        return HttpResponse::Unauthorized().body("You are not allowed to use the chatbot as a guest. Please log in with a Levante account.");
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            "guest_not_allowed",
+            "You are not allowed to use the chatbot as a guest. Please log in with a Levante account.",
+        );
     }
 
+    // A single user can't run too many streams at once or start too many of them per minute; the slot is freed when the stream ends.
+    let permit = match acquire_stream(&user_id) {
+        Ok(permit) => permit,
+        Err(limited) => return limited.into_response(),
+    };
+
     let input = match get_first_matching_field(&qstring, headers, &["input", "x-input"], false) {
         None | Some("") => {
             // If the input is not found (neither in header nor parameters), we'll return a 422
             warn!("The User requested a stream without an input.");
-            return HttpResponse::UnprocessableEntity().body(
-                    "Input not found. Please provide a non-empty input in the query parameters or the headers, of type String.",
-                );
+            return error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "missing_input",
+                "Input not found. Please provide a non-empty input in the query parameters or the headers, of type String.",
+            );
         }
         Some(input) => input.to_string(),
     };
 
+    // If enabled, clean up the input before it's sent to the LLM or stored.
+    let input = maybe_sanitize_input(input);
+
     debug!("Thread ID: {}, Input: {}", thread_id, input);
 
     // First try to get the vault_url from the headers, if it is not set, we'll have to tell the user that we now need it.
@@ -146,79 +284,137 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
 
     let Some(vault_url) = maybe_vault_url else {
         warn!("The User requested a stream without a vault URL.");
-        return HttpResponse::UnprocessableEntity().body(
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "missing_vault_url",
             "Vault URL not found. Please provide a non-empty vault URL in the headers, of type String.",
         );
     };
 
-    let database = match get_database(vault_url).await {
+    let database = match get_database(vault_url, get_tenant(headers).as_deref()).await {
         Ok(db) => db,
         Err(e) => {
             warn!("Failed to connect to the database: {:?}", e);
-            return HttpResponse::ServiceUnavailable().body("Failed to connect to the database.");
+            return e;
         }
     };
 
+    let image_format = ImageFormat::from_request(&qstring, headers);
+
+    if create_new {
+        thread_id = new_conversation_id(database.clone()).await;
+        debug!("New thread ID: {}", thread_id);
+    }
+
     // Because the call to conversation_state writes a warning if the thread is not found, we'll temporarily silence the logging.
     silence_logger();
-    let state = conversation_state(&thread_id, database.clone()).await;
+    let state = conversation_state(&thread_id).await;
     undo_silence_logger();
 
     // To avoid one thread being streamed more than once at the same time, we'll check if the thread is already being streamed.
     if let Some(state) = state {
+        // The exception is a client that lost its connection and reconnects with the number of variants it already recieved.
+        // Within the grace period, it gets the rest of the conversation instead of a conflict.
+        let resume_cursor =
+            get_first_matching_field(&qstring, headers, &["resume", "x-resume"], false)
+                .and_then(|cursor| cursor.parse::<usize>().ok());
+        if let Some(cursor) = resume_cursor {
+            if let Some(missed_variants) = replay_missed_variants(&thread_id, &user_id, cursor) {
+                info!(
+                    "Resuming thread {} for a reconnected client at cursor {}.",
+                    thread_id, cursor
+                );
+                save_and_remove_conversation(&thread_id, database.clone()).await;
+                // The conversation is stored now, so its images can be referenced.
+                let missed_variants = image_format
+                    .encode_for_thread(missed_variants, &thread_id, database)
+                    .await;
+                let bytes = missed_variants
+                    .iter()
+                    .map(|variant| {
+                        Ok::<Bytes, std::convert::Infallible>(variant_to_bytes(variant, framing))
+                    })
+                    .collect::<Vec<_>>();
+                return framing.response().streaming(stream::iter(bytes));
+            }
+        }
+
         warn!("The User requested a stream for a thread that is already being streamed. Thread ID: {}", thread_id);
         info!("Conversation state: {:?}", state);
         // Just send an error to the client. A 409 Conflict is the most appropriate status code.
-        return HttpResponse::Conflict().body(format!(
-            "Thread {thread_id} is already being streamed. Please wait until it's done."
-        ));
+        return error_response(
+            StatusCode::CONFLICT,
+            "thread_busy",
+            format!("Thread {thread_id} is already being streamed. Please wait until it's done."),
+        );
     }
 
-    // We also require the freva_config_path to be set. From the frontend, it's called "freva_config".
-    // It can also be send via headers, there it is called "X-Freva-ConfigPath".
-    let freva_config_path = match get_first_matching_field(
-        &qstring,
-        headers,
-        &[
-            "freva_config",
-            "freva-config",
-            "x-freva-config",
-            "x-freva-configpath",
-        ],
-        false,
-    ) {
-        // allow both freva_config and freva-config
-        None | Some("") => {
-            warn!("The User requested a stream without a freva_config path being set.");
-            // FIXME: remove this temporary fix
-            "/work/ch1187/clint/nextgems/freva/evaluation_system.conf".to_string()
-        }
-        Some(freva_config_path) => freva_config_path.to_string(),
+    // The parameters the request leaves out are taken from the settings profile of the user, if they stored one.
+    let profile = read_user_settings(&user_id, database.clone())
+        .await
+        .unwrap_or_default();
+
+    // Set chatbot to the one the user requested, the one of their profile or the default one.
+    let chatbot = match chatbot_from_request(&qstring, headers, profile.default_chatbot()) {
+        Ok(chatbot) => chatbot,
+        Err(response) => return *response,
+    };
+
+    // How much of the code interpreter's output the LLM gets to see; power users can ask for the full output.
+    let code_verbosity = match code_verbosity_from_request(&qstring, headers) {
+        Ok(code_verbosity) => code_verbosity,
+        Err(response) => return *response,
+    };
+
+    // Power users can also tune the temperature, the maximum tokens and the frequency penalty.
+    let params = match request_params_from_request(&qstring, headers) {
+        Ok(params) => profile.fill_defaults(params),
+        Err(response) => return *response,
     };
 
-    if !verify_can_access(&freva_config_path) {
-        warn!("The User requested a stream with a freva_config path that cannot be accessed. Path: {}", freva_config_path);
-        warn!("Because it is not set, any usage of the freva library will fail.");
+    // Nothing is stored yet, so a client whose request can't be answered right now can simply try again.
+    if let Err(response) = ensure_lite_llm_running().await {
+        return response;
     }
 
-    // Set chatbot to the one the user requested or the default one.
-    let chatbot = match get_first_matching_field(
-        &qstring,
-        headers,
-        &["chatbot", "x-chatbot"],
-        false,
-    ) {
-        None | Some("") => {
-            debug!("Using default chatbot as user didn't supply one.");
-            DEFAULTCHATBOT.clone()
-        }
-        Some(chatbot) => match String::try_into((*chatbot).to_owned()) {
-            Ok(chatbot) => chatbot,
-            Err(()) => {
-                warn!("Error converting chatbot to string, user requested chatbot that is not available: {:?}", chatbot);
-                return HttpResponse::UnprocessableEntity().body("Chatbot not found. Consult the /availablechatbots endpoint for available chatbots.");
+    // Clients that can't share a config file with the backend can send its content instead, which is stored for the thread until its conversation ends.
+    // It's only written once the thread id is final, as an edit switches to a new thread below.
+    let inline_config = freva_config_content_from_request(&qstring, headers);
+    if let Some(Err(message)) = inline_config.map(validate_freva_config) {
+        warn!(
+            "The User requested a stream with an invalid inline freva config: {}",
+            message
+        );
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_freva_config",
+            message,
+        );
+    }
+
+    let mut freva_config_path = if inline_config.is_some() {
+        inline_config_path(&thread_id)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        let freva_config_path = freva_config_path_from_request(&qstring, headers);
+        // The path comes from the client, so it must not lead the backend to files outside of the freva configs.
+        match verify_can_access(&freva_config_path) {
+            Ok(resolved_path) => resolved_path,
+            Err(ConfigAccessError::NotAllowed) => {
+                warn!("The User requested a stream with a freva_config path outside of the allowed directories. Path: {}", freva_config_path);
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_freva_config_path",
+                    "The freva config path is outside of the directories the freva configs are in.",
+                );
             }
-        },
+            Err(e) => {
+                warn!("The User requested a stream with a freva_config path that cannot be accessed ({:?}). Path: {}", e, freva_config_path);
+                warn!("Because it is not set, any usage of the freva library will fail.");
+                freva_config_path
+            }
+        }
     };
 
     info!(
@@ -238,6 +434,9 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
     // (The frontend should get the entire thread, not just the new stuff.)
     let mut starting_variants: Option<Vec<StreamVariant>> = None;
 
+    // The thread_id hint is stored once per thread, so a continued thread that already has one doesn't get another.
+    let mut store_thread_id_hint = true;
+
     let messages = if create_new {
         // The thread should not be new if there are past variants from the frontend.
         if past_variants_from_frontend.is_some() {
@@ -245,20 +444,13 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
         }
 
         // If the thread is new, we'll start with the base messages and the user's input.
-        let mut base_message: Vec<ChatCompletionRequestMessage> = if model_is_gpt_5(chatbot.clone())
-        {
-            get_entire_prompt_gpt_5(&user_id, &thread_id)
-        } else {
-            get_entire_prompt(&user_id, &thread_id)
-        };
+        // The prompt of the chatbot from the PROMPT_DIR takes the place of the built-in one.
+        let mut base_message: Vec<ChatCompletionRequestMessage> =
+            get_prompt_for_chatbot(&chatbot, &user_id, &thread_id);
 
         trace!("Adding base message to stream.");
 
-        let entire_prompt = if model_is_gpt_5(chatbot.clone()) {
-            get_entire_prompt_json_gpt_5(&user_id, &thread_id)
-        } else {
-            get_entire_prompt_json(&user_id, &thread_id)
-        };
+        let entire_prompt = get_prompt_json_for_chatbot(&chatbot, &user_id, &thread_id);
 
         // We need to also store the prompt, which we do in JSON to avoid conversion issues here.
         let starting_prompt = StreamVariant::Prompt(entire_prompt);
@@ -285,7 +477,11 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
             Err(e) => {
                 // If we can't read the thread, we'll return a generic error.
                 warn!("Error reading thread: {:?}", e);
-                return HttpResponse::InternalServerError().body("Error reading thread.");
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "Error reading thread.",
+                );
             }
         };
 
@@ -293,6 +489,7 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
         let content = match past_variants_from_frontend {
             None | Some("") => {
                 debug!("No past variants from frontend, using all content.");
+                store_thread_id_hint = !content.iter().any(is_thread_id_hint);
                 content
             }
             Some(past_variants) => {
@@ -304,15 +501,26 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
                     Ok(new_content) => new_content,
                     Err(e) => {
                         error!("Error filtering variants from frontend, the format was likely misunderstood: {:?}", e);
-                        return HttpResponse::UnprocessableEntity()
-                            .body(format!("Error filtering variants: {e}"));
+                        return error_response(
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            "invalid_chat_variants",
+                            format!("Error filtering variants: {e}"),
+                        );
                     }
                 };
 
                 // If we succeed to find the past variants, we'll also need to send a new ServerHint with the thread_id.
                 // We'll simply have to set the thread_id to a new one.
-                thread_id = switch_to_new_thread_id(&thread_id);
+                thread_id = switch_to_new_thread_id(&thread_id, database.clone()).await;
                 debug!("Switched to new thread_id: {}", thread_id);
+                if inline_config.is_some() {
+                    freva_config_path = inline_config_path(&thread_id)
+                        .to_string_lossy()
+                        .into_owned();
+                }
+
+                // The past variants contain the hint of the old thread, which is replaced by the one of the new thread.
+                let (new_content, new_starting_variants) = edit_variants(new_content, &thread_id);
 
                 // In order for them to be saved to the new conversation, they need to be added to the conversation.
                 add_to_conversation(
@@ -323,27 +531,20 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
                 );
 
                 // We also need to send them to the stream, so we'll save them to starting_variants.
-                // However, in this case, the user gets the past variants in the same stream as the actual stream,
-                // So there is no delimiter. The frontend would be able to handle that, but as per the protocol, consecutive assistant variants are to be joined.
-                // So if the past variants end with an assistant message, and the new stream starts with an assistant message, they should be joined, which would confuse the user.
-                // This is why a single ServerHint with the new thread_id is sent before the past variants.
-                // (If an edit is done, the Serverhint with the thread_id is not sent at the very start, but between the past variants and the new stream.)
-                let server_hint =
-                    StreamVariant::ServerHint(format!("{{\"thread_id\": \"{thread_id}\"}}"));
-                // let new_content_and_server_hint = std::iter::once(server_hint)
-                //     .chain(new_content.clone().into_iter())
-                //     .collect();
-                let mut new_content_and_server_hint = new_content.clone();
-                new_content_and_server_hint.push(server_hint);
-                starting_variants = Some(new_content_and_server_hint);
+                starting_variants = Some(new_starting_variants);
 
                 new_content
             }
         };
 
         // We have a Vec of StreamVariant, but we want a Vec of ChatCompletionRequestMessage.
-        let mut past_messages =
-            help_convert_sv_ccrm(content, model_supports_images(chatbot.clone()));
+        let mut past_messages = fit_tool_call_only_messages(
+            fit_message_order(
+                help_convert_sv_ccrm(content, model_supports_images(chatbot.clone())),
+                model_message_ordering(&chatbot),
+            ),
+            model_tool_call_content(&chatbot),
+        );
         let user_message = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
             name: Some("user".to_string()),
             content: async_openai::types::ChatCompletionRequestUserMessageContent::Text(
@@ -356,28 +557,58 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
         past_messages
     };
 
-    // We'll also add a ServerHint about the thread_id to the messages.
-    let server_hint = StreamVariant::ServerHint(format!("{{\"thread_id\": \"{thread_id}\"}}")); // resolves to {"thread_id": "<thread_id>"}
+    // The thread id is final now, so the inline config can be written; until the stream starts, returning early removes it again.
+    let inline_config_file =
+        match inline_config.map(|content| write_inline_config(&thread_id, content)) {
+            None => None,
+            Some(Ok(file)) => Some(file),
+            Some(Err(e)) => {
+                error!("Error writing the inline freva config: {:?}", e);
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "The freva config could not be stored.",
+                );
+            }
+        };
 
+    // The hint is stored here, while create_and_stream (or the starting variants of an edit) sends it to the client.
     // Also don't forget to add the user's input to the thread file.
+    let mut new_variants = vec![StreamVariant::User(input.clone())];
+    if *RECORD_TURN_MODEL {
+        new_variants.push(model_hint(&chatbot));
+    }
+    if store_thread_id_hint {
+        new_variants.insert(0, thread_id_hint(&thread_id));
+    }
     add_to_conversation(
         &thread_id,
-        vec![server_hint, StreamVariant::User(input.clone())],
+        new_variants,
         freva_config_path.clone(),
         user_id.clone(),
     );
 
-    let request: CreateChatCompletionRequest = match build_request(messages, chatbot.clone()) {
-        Ok(request) => request,
-        Err(e) => {
-            // If we can't build the request, we'll return a generic error.
-            warn!("Error building request: {:?}", e);
-            return HttpResponse::InternalServerError().body("Error building request.");
-        }
-    };
+    let request: CreateChatCompletionRequest =
+        match build_request(messages, chatbot.clone(), params) {
+            Ok(request) => request,
+            Err(e) => {
+                // If we can't build the request, we'll return a generic error.
+                warn!("Error building request: {:?}", e);
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "Error building request.",
+                );
+            }
+        };
     trace!("Request built!");
 
-    create_and_stream(
+    // From here on, the conversation removes the inline config when it ends.
+    if let Some(file) = inline_config_file {
+        file.keep();
+    }
+
+    let response = create_and_stream(
         request,
         thread_id,
         freva_config_path,
@@ -385,21 +616,276 @@ pub async fn stream_response(req: HttpRequest) -> impl Responder {
         user_id,
         database,
         starting_variants,
+        code_verbosity,
+        params,
+        framing,
+        image_format,
+        summary_requested(&qstring, headers),
+        with_offsets,
+    )
+    .await;
+    permit.attach(response)
+}
+
+/// Reads the path to the freva config file from the request; from the frontend, it's called "freva_config".
+pub(crate) fn freva_config_path_from_request(qstring: &QString, headers: &HeaderMap) -> String {
+    // It can also be send via headers, there it is called "X-Freva-ConfigPath".
+    match get_first_matching_field(
+        qstring,
+        headers,
+        &[
+            "freva_config",
+            "freva-config",
+            "x-freva-config",
+            "x-freva-configpath",
+        ],
+        false,
+    ) {
+        // allow both freva_config and freva-config
+        None | Some("") => {
+            warn!("The User requested a stream without a freva_config path being set.");
+            // FIXME: remove this temporary fix
+            "/work/ch1187/clint/nextgems/freva/evaluation_system.conf".to_string()
+        }
+        Some(freva_config_path) => freva_config_path.to_string(),
+    }
+}
+
+/// Reads the chatbot the user requested, or else the one of the user's settings profile, or the default one.
+/// Returns an UnprocessableEntity response if the chatbot isn't available.
+pub(crate) fn chatbot_from_request(
+    qstring: &QString,
+    headers: &HeaderMap,
+    profile_chatbot: Option<AvailableChatbots>,
+) -> Result<AvailableChatbots, Box<HttpResponse>> {
+    match get_first_matching_field(qstring, headers, &["chatbot", "x-chatbot"], false) {
+        None | Some("") => match profile_chatbot {
+            Some(chatbot) => {
+                debug!("Using the chatbot of the user's settings as user didn't supply one.");
+                Ok(chatbot)
+            }
+            None => {
+                debug!("Using default chatbot as user didn't supply one.");
+                Ok(DEFAULTCHATBOT.clone())
+            }
+        },
+        Some(chatbot) => match String::try_into((*chatbot).to_owned()) {
+            Ok(chatbot) => Ok(chatbot),
+            Err(()) => {
+                warn!("Error converting chatbot to string, user requested chatbot that is not available: {:?}", chatbot);
+                Err(Box::new(error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "chatbot_not_found",
+                    "Chatbot not found. Consult the /availablechatbots endpoint for available chatbots.",
+                )))
+            }
+        },
+    }
+}
+
+/// Reads how much of the code interpreter's output the LLM gets to see.
+/// Returns an UnprocessableEntity response if it's neither "concise" nor "full".
+pub(crate) fn code_verbosity_from_request(
+    qstring: &QString,
+    headers: &HeaderMap,
+) -> Result<CodeVerbosity, Box<HttpResponse>> {
+    match get_first_matching_field(
+        qstring,
+        headers,
+        &["code_verbosity", "x-code-verbosity"],
+        false,
+    ) {
+        None | Some("") => Ok(CodeVerbosity::default()),
+        Some(value) => CodeVerbosity::from_param(value).ok_or_else(|| {
+            warn!("The User requested an unknown code verbosity: {}", value);
+            Box::new(error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_code_verbosity",
+                "Invalid code_verbosity. Please use \"concise\" or \"full\".",
+            ))
+        }),
+    }
+}
+
+/// Reads the parameters of the LLM that can be set per request.
+/// Returns a BadRequest response describing the problem if one of them is invalid.
+pub(crate) fn request_params_from_request(
+    qstring: &QString,
+    headers: &HeaderMap,
+) -> Result<RequestParams, Box<HttpResponse>> {
+    RequestParams::parse(
+        get_first_matching_field(qstring, headers, &["temperature", "x-temperature"], false),
+        get_first_matching_field(qstring, headers, &["max_tokens", "x-max-tokens"], false),
+        get_first_matching_field(
+            qstring,
+            headers,
+            &["frequency_penalty", "x-frequency-penalty"],
+            false,
+        ),
+        get_first_matching_field(
+            qstring,
+            headers,
+            &["parallel_tool_calls", "x-parallel-tool-calls"],
+            false,
+        ),
+        get_first_matching_field(
+            qstring,
+            headers,
+            &["reasoning_effort", "x-reasoning-effort"],
+            false,
+        ),
+    )
+    .map_err(|message| {
+        warn!(
+            "The User requested invalid parameters for the LLM: {}",
+            message
+        );
+        Box::new(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_parameter",
+            message,
+        ))
+    })
+}
+
+/// All chatbots are routed through LiteLLM, so a stream can't be answered while it's down.
+/// Returns a ServiceUnavailable response then, instead of starting a stream that fails midway.
+pub(crate) async fn ensure_lite_llm_running() -> Result<(), HttpResponse> {
+    if is_lite_llm_running_cached().await {
+        return Ok(());
+    }
+    warn!("LiteLLM can't be reached, not starting the stream.");
+    Err(lite_llm_down_response())
+}
+
+fn lite_llm_down_response() -> HttpResponse {
+    error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "llm_unavailable",
+        "The LLM backend is currently unavailable. Please try again later.",
     )
-    .await
+}
+
+/// The ServerHint that tells the client the thread_id of the stream.
+fn thread_id_hint(thread_id: &str) -> StreamVariant {
+    StreamVariant::ServerHint(format!("{{\"thread_id\": \"{thread_id}\"}}")) // resolves to {"thread_id": "<thread_id>"}
+}
+
+/// Whether the model that answers a turn is stored with it, so a thread that switched models shows which one produced which answer.
+/// Set via the environment variable `RECORD_TURN_MODEL`; defaults to false.
+pub static RECORD_TURN_MODEL: Lazy<bool> =
+    Lazy::new(|| std::env::var("RECORD_TURN_MODEL").is_ok_and(|value| value.trim() == "true"));
+
+/// The ServerHint that records the model answering the turn. Like all ServerHints, it's never sent to the LLM.
+pub(crate) fn model_hint(chatbot: &AvailableChatbots) -> StreamVariant {
+    StreamVariant::ServerHint(serde_json::json!({ "model": chatbot.0 }).to_string())
+}
+
+/// Whether the variant is a ServerHint carrying a thread_id, as opposed to a heartbeat or a warning.
+fn is_thread_id_hint(variant: &StreamVariant) -> bool {
+    match variant {
+        StreamVariant::ServerHint(content) => serde_json::from_str::<serde_json::Value>(content)
+            .is_ok_and(|value| value.get("thread_id").is_some()),
+        _ => false,
+    }
+}
+
+/// Prepares the past variants of an edited thread for the new thread.
+/// Returns the variants to store, without the thread_id hints of the old thread,
+/// and the variants to send to the client before the stream, which end in the hint of the new thread.
+fn edit_variants(
+    past_variants: Vec<StreamVariant>,
+    thread_id: &str,
+) -> (Vec<StreamVariant>, Vec<StreamVariant>) {
+    let past_variants = past_variants
+        .into_iter()
+        .filter(|variant| !is_thread_id_hint(variant))
+        .collect::<Vec<_>>();
+    // The client gets the past variants in the same stream as the actual stream, so there is no delimiter.
+    // The frontend would be able to handle that, but as per the protocol, consecutive assistant variants are to be joined.
+    // So if the past variants end with an assistant message, and the new stream starts with an assistant message, they would be joined, which would confuse the user.
+    // This is why the ServerHint with the new thread_id is sent between the past variants and the new stream, instead of at the very start.
+    let mut starting_variants = past_variants.clone();
+    starting_variants.push(thread_id_hint(thread_id));
+    (past_variants, starting_variants)
+}
+
+/// Checks that the reconstructed messages make for a valid request: there has to be a system prompt, followed by a message of the user.
+/// If a conversion dropped them, the provider would only return an opaque error, so a ServerError with the roles of the messages is returned instead.
+fn validate_messages(messages: &[ChatCompletionRequestMessage]) -> Result<(), StreamVariant> {
+    let roles = messages
+        .iter()
+        .map(|message| match message {
+            ChatCompletionRequestMessage::System(_) => "system",
+            ChatCompletionRequestMessage::Developer(_) => "developer",
+            ChatCompletionRequestMessage::User(_) => "user",
+            ChatCompletionRequestMessage::Assistant(_) => "assistant",
+            ChatCompletionRequestMessage::Tool(_) => "tool",
+            ChatCompletionRequestMessage::Function(_) => "function",
+        })
+        .collect::<Vec<_>>();
+    let last_prompt = roles
+        .iter()
+        .rposition(|role| *role == "system" || *role == "developer");
+    let problem = match last_prompt {
+        _ if roles.is_empty() => "the list of messages is empty",
+        None => "there is no system prompt",
+        Some(index) if !roles[index..].contains(&"user") => {
+            "there is no message of the user after the system prompt"
+        }
+        Some(_) => return Ok(()),
+    };
+    warn!(
+        "Not sending the request to the LLM, {}; roles: {:?}",
+        problem, roles
+    );
+    Err(StreamVariant::ServerError(format!(
+        "The conversation could not be converted into a valid request for the LLM: {problem}. ({} messages: [{}])",
+        roles.len(),
+        roles.join(", ")
+    )))
+}
+
+/// Ends a stream before the LLM was asked: the client gets the starting variants (or the thread_id hint) and the given end,
+/// which is also stored with the conversation.
+async fn end_stream_early(
+    end: Vec<StreamVariant>,
+    thread_id: String,
+    freva_config_path: String,
+    user_id: String,
+    database: Database,
+    starting_variants: Option<Vec<StreamVariant>>,
+    framing: StreamFraming,
+    with_offsets: bool,
+) -> HttpResponse {
+    let mut variants = starting_variants.unwrap_or_else(|| vec![thread_id_hint(&thread_id)]);
+    add_to_conversation(&thread_id, end.clone(), freva_config_path, user_id);
+    save_and_remove_conversation(&thread_id, database).await;
+    variants.extend(end);
+    let offsets = Mutex::new(with_offsets.then(DeltaOffsets::default));
+    let bytes = variants
+        .iter()
+        .map(|variant| {
+            Ok::<Bytes, std::convert::Infallible>(variant_to_bytes_with_offsets(
+                &offsets, variant, framing,
+            ))
+        })
+        .collect::<Vec<_>>();
+    framing.response().streaming(stream::iter(bytes))
 }
 
 /// A simple helper function to build the stream.
-fn build_request(
+pub(crate) fn build_request(
     messages: Vec<ChatCompletionRequestMessage>,
     chatbot: AvailableChatbots,
+    params: RequestParams,
 ) -> Result<CreateChatCompletionRequest, async_openai::error::OpenAIError> {
     // Because some errors occured around here, we'll log the messages.
     trace!("Messages sending to OpenAI: {:?}", messages);
 
     // The reasoning models do not allow you to specify whether or not you want them to do parallel tool calls.
     // The request will be denied with an 400 error. However, if it is not specified whether or not to do parallel tool calls, it will default to "auto".
-    // Because dealing with multiple tool calls at the same time is not yet implemented, we'll have to set it to false, but not for the reasoning models.
+    // Parallel tool calls are opt-in (see ENABLE_PARALLEL_TOOL_CALLS), so for all other models they are usually set to false.
 
     let mut default_args = CreateChatCompletionRequestArgs::default(); // If the partial_request would be set to default here, the lifetime would be too short.
     let mut partial_request = default_args
@@ -413,14 +899,40 @@ fn build_request(
             include_usage: true,
         });
 
-    if model_is_reasoning(chatbot) {
-        partial_request = partial_request.max_completion_tokens(16000u32); // The max tokens parameter is called differently for the reasoning models.
+    // Power users can override the defaults per request.
+    let max_tokens = params
+        .max_tokens
+        .unwrap_or_else(|| model_max_tokens(&chatbot));
+    if params.reasoning_effort.is_some() && !model_is_gpt_5(chatbot.clone()) {
+        // The other models reject it, so it's only sent to the GPT-5 models.
+        debug!("Ignoring the reasoning effort for a model that isn't GPT-5.");
+    }
+    if model_is_reasoning(chatbot.clone()) {
+        partial_request = partial_request.max_completion_tokens(max_tokens); // The max tokens parameter is called differently for the reasoning models.
+        if params.temperature.is_some() || params.frequency_penalty.is_some() {
+            // The reasoning models reject them, so they are ignored instead of failing the request.
+            debug!("Ignoring the temperature and frequency penalty for a reasoning model.");
+        }
+        if model_is_gpt_5(chatbot.clone()) {
+            partial_request =
+                partial_request.reasoning_effort(params.reasoning_effort.unwrap_or_default());
+        }
+    } else if model_is_claude(&chatbot) {
+        // Anthropic has no frequency penalty, and LiteLLM rejects some values of it instead of dropping it, so it's never sent.
+        // Its temperatures also only go up to 1.
+        if params.frequency_penalty.is_some() {
+            debug!("Ignoring the frequency penalty for a Claude model.");
+        }
+        partial_request = partial_request
+            .parallel_tool_calls(parallel_tool_calls_enabled(params))
+            .temperature(params.temperature.unwrap_or(0.4).min(1.0))
+            .max_tokens(max_tokens);
     } else {
         partial_request = partial_request
-            .parallel_tool_calls(false) // No parallel tool calls!
-            .temperature(0.4) // The model shouldn't be too creative, but also not too boring.
-            .frequency_penalty(0.1) // The chatbot sometimes repeats the empty string endlessly, so we'll try to prevent that.
-            .max_tokens(16000u32);
+            .parallel_tool_calls(parallel_tool_calls_enabled(params)) // Only if enabled, usually one tool call at a time.
+            .temperature(params.temperature.unwrap_or(0.4)) // The model shouldn't be too creative, but also not too boring.
+            .frequency_penalty(params.frequency_penalty.unwrap_or(0.1)) // The chatbot sometimes repeats the empty string endlessly, so we'll try to prevent that.
+            .max_tokens(max_tokens);
     }
 
     partial_request.build()
@@ -437,11 +949,52 @@ pub static STREAM_STOP_CONTENT: Lazy<actix_web::web::Bytes> = Lazy::new(|| {
     )
 });
 
+/// Dropped together with the stream of a conversation.
+/// If the stream ended normally, the conversation was already removed and nothing happens.
+/// If the conversation is still streaming, the client disconnected: like after a stop request, the LLM isn't polled anymore and the tool call is aborted.
+/// The conversation is marked as disconnected, so it can be resumed within the grace period, and saved and removed once it's over (or right away, if that is disabled).
+struct DisconnectGuard {
+    thread_id: String,
+    database: Database,
+    /// How long the conversation is kept for the client to resume it; None removes it right away.
+    grace_period: Option<std::time::Duration>,
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !mark_disconnected(&self.thread_id) {
+            return;
+        }
+        info!(
+            "The client of thread {} disconnected while it was still streaming.",
+            self.thread_id
+        );
+        // Saving is async, but drop isn't, so it needs to happen in a new task.
+        let thread_id = self.thread_id.clone();
+        let database = self.database.clone();
+        let grace_period = self.grace_period;
+        tokio::spawn(async move {
+            // Without the timer, a conversation that isn't resumed would only be removed by the next cleanup, whenever another conversation changes.
+            if let Some(grace_period) = grace_period {
+                tokio::time::sleep(grace_period).await;
+                if !disconnected_for(&thread_id, grace_period) {
+                    return;
+                }
+                debug!(
+                    "The client of thread {} didn't resume it within the grace period.",
+                    thread_id
+                );
+            }
+            save_and_remove_conversation(&thread_id, database).await;
+        });
+    }
+}
+
 /// First creates a stream from the `OpenAI` client.
 /// Then transforms the Stream from the `OpenAI` client into a Stream for Actix.
 /// Note that there will also be added events that don't come from the `OpenAI::Client`, like `ServerHint` events.
 /// This is only possible due to using `Stream::unfold`, which allows the manual construction of the stream.
-async fn create_and_stream(
+pub(crate) async fn create_and_stream(
     request: CreateChatCompletionRequest,
     thread_id: String,
     freva_config_path: String,
@@ -449,18 +1002,80 @@ async fn create_and_stream(
     user_id: String,
     database: Database,
     starting_variants: Option<Vec<StreamVariant>>,
+    code_verbosity: CodeVerbosity,
+    params: RequestParams,
+    framing: StreamFraming,
+    image_format: ImageFormat,
+    with_summary: bool,
+    with_offsets: bool,
 ) -> actix_web::HttpResponse {
-    let open_ai_stream = match LITE_LLM_CLIENT.chat().create_stream(request).await {
-        Ok(stream) => stream.fuse(), // Fuse the stream so calling next() will return None after the stream ends instead of blocking.
-        Err(e) => {
-            // If we can't create the stream, we'll return a generic error.
-            warn!("Error creating stream: {:?}", e);
-            return HttpResponse::InternalServerError().body("Error creating stream.");
-        }
-    };
+    // The conversation was already started by the caller; if the server shuts down mid-stream, it's saved to this database.
+    set_conversation_database(&thread_id, database.clone());
+
+    if let Err(error) = validate_messages(&request.messages) {
+        let end = vec![
+            error,
+            StreamVariant::StreamEnd("Invalid conversation".to_string()),
+        ];
+        return end_stream_early(
+            end,
+            thread_id,
+            freva_config_path,
+            user_id,
+            database,
+            starting_variants,
+            framing,
+            with_offsets,
+        )
+        .await;
+    }
 
-    // If the starting_variants is Some, they will contain the new thread_id already.
-    let should_hint_thread_id = starting_variants.is_none();
+    let open_ai_stream =
+        match create_stream_with_context_retry(request, create_litellm_stream_with_retries).await {
+            Ok(stream) => stream.fuse(), // Fuse the stream so calling next() will return None after the stream ends instead of blocking.
+            Err(e) if is_llm_unavailable_error(&e) => {
+                // Instead of a generic error, the client gets a proper end of the stream, without waiting for a timeout.
+                let end = vec![
+                    StreamVariant::OpenAIError("LLM temporarily unavailable".to_string()),
+                    StreamVariant::StreamEnd("LLM temporarily unavailable".to_string()),
+                ];
+                return end_stream_early(
+                    end,
+                    thread_id,
+                    freva_config_path,
+                    user_id,
+                    database,
+                    starting_variants,
+                    framing,
+                    with_offsets,
+                )
+                .await;
+            }
+            Err(e) => {
+                // The temporary errors were already retried, so the client gets the error as the end of the stream.
+                warn!("Error creating stream: {:?}", e);
+                let end = vec![
+                    StreamVariant::OpenAIError(format!("Error creating stream: {e}")),
+                    StreamVariant::StreamEnd("Error creating stream".to_string()),
+                ];
+                return end_stream_early(
+                    end,
+                    thread_id,
+                    freva_config_path,
+                    user_id,
+                    database,
+                    starting_variants,
+                    framing,
+                    with_offsets,
+                )
+                .await;
+            }
+        };
+
+    // If the starting_variants contain the new thread_id already, it mustn't be sent a second time.
+    let should_hint_thread_id = !starting_variants
+        .as_ref()
+        .is_some_and(|variants| variants.iter().any(is_thread_id_hint));
 
     // The variant_queue of the unfold state requires a VecDeque, but we have an Option<Vec<StreamVariant>> of variants to send if the user edited their input
     // (They get the previous content to make sure they actually see it).
@@ -469,19 +1084,33 @@ async fn create_and_stream(
         Some(variants) => variants.into(),
     };
 
+    // Notices when actix drops the stream because the client went away, so the conversation can be resumed or cleaned up.
+    let disconnect_guard = DisconnectGuard {
+        thread_id: thread_id.clone(),
+        database: database.clone(),
+        grace_period: KEEP_DISCONNECTED_CONVERSATIONS.then(|| *DISCONNECT_GRACE_PERIOD),
+    };
+
+    // Status messages from the operators are sent to the client between two variants, but aren't part of the conversation.
+    let status_receiver = Arc::new(Mutex::new(subscribe_to_status()));
+
+    // If the client asked for them, the Assistant deltas carry their offset in the message, which is counted as they're sent.
+    let delta_offsets = Arc::new(Mutex::new(with_offsets.then(DeltaOffsets::default)));
+
     trace!("Stream created!");
     let out_stream = stream::unfold(
         (
             open_ai_stream, // the stream from the OpenAI client
             thread_id,
-            false,                 // whether the stream should stop
-            should_hint_thread_id, // whether the stream should hint the thread_id
-            variant_queue,         // the queue of variants to send
-            None,                  // The tool name, if it was called
-            String::new(),         // the tool arguments,
-            String::new(),         // the tool id
+            false,                         // whether the stream should stop
+            should_hint_thread_id,         // whether the stream should hint the thread_id
+            variant_queue,                 // the queue of variants to send
+            None,                          // The tool name, if it was called
+            String::new(),                 // the tool arguments,
+            String::new(),                 // the tool id
+            Vec::<PendingToolCall>::new(), // the earlier tool calls of the same response, if parallel tool calls are enabled
             Cell::new(None), // the content of a llama tool call (See https://github.com/ollama/ollama/issues/5796 for why this needs to be done manually)
-            None::<(mpsc::Receiver<Vec<StreamVariant>>, JoinHandle<()>)>, // the reciever for the tool call and the join handle for the tool call
+            None::<(mpsc::Receiver<ToolUpdate>, JoinHandle<()>)>, // the reciever for the tool call and the join handle for the tool call
         ),
         move |(
             mut open_ai_stream,
@@ -492,31 +1121,32 @@ async fn create_and_stream(
             mut tool_name,
             mut tool_arguments,
             mut tool_id,
+            mut parallel_calls,
             mut llama_tool_call_content,
             mut reciever,
         )| {
+            // The guard lives as long as the closure, which is as long as the stream.
+            let _disconnect_guard = &disconnect_guard;
             // It is required to clone the freva_config_path, because it is moved into the closure. Same with the user_id. And the database. And now the chatbot.
             let freva_config_path_clone = freva_config_path.clone();
             let user_id = user_id.clone();
             let database = database.clone();
             let chatbot = chatbot.clone();
+            let status_receiver = Arc::clone(&status_receiver);
+            let delta_offsets = Arc::clone(&delta_offsets);
             async move {
+                let variant_to_bytes = |variant: &StreamVariant, framing| {
+                    variant_to_bytes_with_offsets(&delta_offsets, variant, framing)
+                };
                 // Even higher priority than stopping the stream is sending the thread_id hint.
                 if should_hint_thread_id {
                     // If we should hint the thread_id, we'll send a ServerHint event.
-                    let hint =
-                        StreamVariant::ServerHint(format!("{{\"thread_id\": \"{thread_id}\"}}")); // resolves to {"thread_id":"<thread_id>"}
-                                                                                                  // return the hint and the new state
+                    let hint = thread_id_hint(&thread_id);
+                    // return the hint and the new state
                     return Some((
-                        Ok::<actix_web::web::Bytes, std::convert::Infallible>(
-                            actix_web::web::Bytes::copy_from_slice(
-                                serde_json::to_string(&hint)
-                                    .unwrap_or_else(|e| {warn!("Error converting ServerHint to string: {:?}; falling back to byte ServerHint.", e);
-                                    format!(r#"{{"variant":"ServerHint", "content":"{{\"thread_id\": \"{thread_id}\"}}"}}"#)
-                                })
-                                    .as_bytes(),
-                            ),
-                        ),
+                        Ok::<actix_web::web::Bytes, std::convert::Infallible>(variant_to_bytes(
+                            &hint, framing,
+                        )),
                         (
                             open_ai_stream,
                             thread_id,
@@ -526,6 +1156,7 @@ async fn create_and_stream(
                             tool_name,
                             tool_arguments,
                             tool_id,
+                            parallel_calls,
                             llama_tool_call_content,
                             reciever,
                         ),
@@ -534,7 +1165,7 @@ async fn create_and_stream(
 
                 // After potentially sending a thread_id hint, but before stopping, check whether the variants queue contains something; if so, send it.
                 if let Some(content) = variant_queue.pop_front() {
-                    let bytes = variant_to_bytes(&content);
+                    let bytes = variant_to_bytes(&content, framing);
 
                     // Everything worked, so we'll return the bytes and the new state.
                     Some((
@@ -548,6 +1179,7 @@ async fn create_and_stream(
                             tool_name,
                             tool_arguments,
                             tool_id,
+                            parallel_calls,
                             llama_tool_call_content,
                             reciever,
                         ),
@@ -556,12 +1188,13 @@ async fn create_and_stream(
                     // If the stream should stop, we'll simply return None.
 
                     // However, the usage stats are contained after the stop event, so we'll poll the stream until it's completely stopped.
-                    while let Some(content) = open_ai_stream.next().await {
-                        if let Ok(response) = content {
-                            if let Some(usage) = response.usage {
-                                info!("Tokens used: {:?}; with chatbot: {:?}", usage, chatbot);
-                            }
-                        }
+                    record_remaining_usage(&mut open_ai_stream, &thread_id, &chatbot).await;
+                    // Some providers never send the usage, even though it was requested; then there is none to record.
+                    if get_conversation_usage(&thread_id).is_none() {
+                        info!(
+                            "No token usage was reported for thread {} with chatbot {:?}, the provider might not support it.",
+                            thread_id, chatbot
+                        );
                     }
 
                     // In order to not do unnecessary work, we'll abort the tool call task if it's still running.
@@ -577,9 +1210,35 @@ async fn create_and_stream(
                 } else {
                     // If the stream should not stop, we'll continue.
 
+                    // A status message is only sent once the queue is empty, so it doesn't end up between the variants of a single response.
+                    let status_hint = status_receiver
+                        .lock()
+                        .ok()
+                        .and_then(|mut receiver| next_status_hint(&mut receiver));
+                    if let Some(hint) = status_hint {
+                        debug!("Sending status message to thread {}: {:?}", thread_id, hint);
+                        add_transient_to_conversation(&thread_id, vec![hint.clone()]);
+                        return Some((
+                            Ok(variant_to_bytes(&hint, framing)),
+                            (
+                                open_ai_stream,
+                                thread_id,
+                                should_stop,
+                                false,
+                                variant_queue,
+                                tool_name,
+                                tool_arguments,
+                                tool_id,
+                                parallel_calls,
+                                llama_tool_call_content,
+                                reciever,
+                            ),
+                        ));
+                    }
+
                     // First checks whether it should stop the stream. (This happens if the client sent a stop request.)
                     if matches!(
-                        conversation_state(&thread_id, database.clone()).await,
+                        conversation_state(&thread_id).await,
                         Some(ConversationState::Stopping)
                     ) {
                         debug!("Conversation with thread_id {} has been stopped, sending one last event and then aborting stream.", thread_id);
@@ -592,7 +1251,7 @@ async fn create_and_stream(
                         );
                         end_conversation(&thread_id);
                         Some((
-                            Ok(STREAM_STOP_CONTENT.clone()),
+                            Ok(framing.frame(STREAM_STOP_CONTENT.clone())),
                             (
                                 open_ai_stream,
                                 thread_id,
@@ -602,6 +1261,7 @@ async fn create_and_stream(
                                 tool_name,
                                 tool_arguments,
                                 tool_id,
+                                parallel_calls,
                                 llama_tool_call_content,
                                 reciever,
                             ),
@@ -614,7 +1274,7 @@ async fn create_and_stream(
                         // In the waiting, we'll return a heartbeat to the client.
                         if let Some((mut inner_reciever, handle)) = reciever {
                             // tokio::select! didn't seem to work when called on the reciever and sleep,
-                            // So we'll sacrifice some efficiency and only check the reciever every 5 seconds.
+                            // So we'll sacrifice some efficiency and only check the reciever at every heartbeat.
 
                             //DEBUG
                             // println!("Starting tool call reciever loop.");
@@ -628,32 +1288,56 @@ async fn create_and_stream(
                             // note: the tokio timeout, select! as well as all async functions son't seem to work correctly.
                             // I'll use std::thread::sleep for now, but it's not ideal.
                             // I didn't yet manage to reproduce the bug in a smaller example, but I'll try again later.
-                            // For now, we'll just poll the reciever every 5 seconds.
+                            // For now, we'll just poll the reciever at every heartbeat.
                             let output = match state {
                                 Err(mpsc::error::TryRecvError::Empty) => {
                                     trace!("Reciever has no data yet, sending timeout.");
                                     //DEBUG
                                     // println!("Reciever has no data yet, sending timeout.");
-                                    // Also add the heartbeat to the conversation.
-                                    let heartbeat = heartbeat_content().await;
+                                    let heartbeat = heartbeat_content(&thread_id).await;
                                     trace!("Sending heartbeat: {:?}", heartbeat);
-                                    add_to_conversation(
+                                    let heartbeat_bytes = emit_heartbeat(
                                         &thread_id,
-                                        vec![heartbeat.clone()],
+                                        &heartbeat,
                                         freva_config_path_clone.clone(),
                                         user_id.clone(),
+                                        framing,
                                     );
-                                    // Actually sleep three seconds
-                                    // std::thread::sleep(std::time::Duration::from_secs(5)); // Works
-                                    tokio::time::sleep(std::time::Duration::from_secs(5)).await; // Doesn't
-                                                                                                 // tokio::time::delay_for(std::time::Duration::from_secs(5)).await; // Doesn't exist anymore
-                                                                                                 // If the timeout expires, we'll send a heartbeat to the client.
+                                    // The code interpreter sends the output it printed in the meantime at the same interval, so it arrives with every heartbeat.
+                                    tokio::time::sleep(*HEARTBEAT_INTERVAL).await;
 
                                     //DEBUG
                                     // println!("Sent heartbeat: {:?}", heartbeat);
 
                                     return Some((
-                                        Ok(variant_to_bytes(&heartbeat)),
+                                        Ok(heartbeat_bytes),
+                                        (
+                                            open_ai_stream,
+                                            thread_id,
+                                            should_stop,
+                                            false,
+                                            variant_queue,
+                                            tool_name,
+                                            tool_arguments,
+                                            tool_id,
+                                            parallel_calls,
+                                            llama_tool_call_content,
+                                            Some((inner_reciever, handle)),
+                                        ),
+                                    ));
+                                }
+                                Ok(ToolUpdate::Partial(partial)) => {
+                                    // The partial output is sent right away, but not stored; the complete output replaces it.
+                                    trace!("Sending partial tool output: {:?}", partial);
+                                    add_transient_to_conversation(&thread_id, partial.clone());
+                                    // The queue is always empty while waiting for the tool call, so it can take the partial output.
+                                    let mut variant_queue = VecDeque::from(partial);
+                                    let bytes = variant_queue
+                                        .pop_front()
+                                        .map(|variant| variant_to_bytes(&variant, framing))
+                                        .unwrap_or_default();
+                                    return Some((
+                                        Ok(bytes),
                                         (
                                             open_ai_stream,
                                             thread_id,
@@ -663,19 +1347,20 @@ async fn create_and_stream(
                                             tool_name,
                                             tool_arguments,
                                             tool_id,
+                                            parallel_calls,
                                             llama_tool_call_content,
                                             Some((inner_reciever, handle)),
                                         ),
                                     ));
                                 }
-                                Ok(output) => Some(output),
+                                Ok(ToolUpdate::Done(output)) => Some(output),
                                 Err(mpsc::error::TryRecvError::Disconnected) => None,
                             };
                             trace!("Reciever sent result!");
 
                             // The output might fail if the tool call was not successful.
-                            let mut output = if let Some(output) = output {
-                                output
+                            let output = if let Some(output) = output {
+                                cap_warnings(&thread_id, output, *MAX_WARNINGS_PER_STREAM)
                             } else {
                                 error!(
                                     "Error recieving tool call output, the reciever was closed."
@@ -685,12 +1370,16 @@ async fn create_and_stream(
                                 )]
                             };
 
+                            // The next stream is a new turn for the LLM, so it has to start with a clean tool state.
+                            take_tool_state(&mut tool_name, &mut tool_arguments, &mut tool_id);
+
                             // Before returning the bytes, we need to restart the stream.
                             restart_stream(
                                 &thread_id,
                                 output.clone(),
                                 chatbot,
                                 &mut open_ai_stream,
+                                params,
                             )
                             .await;
 
@@ -702,15 +1391,20 @@ async fn create_and_stream(
                                 user_id.clone(),
                             );
 
+                            // The client may want the images in another format or as references; the conversation and the LLM keep the PNG.
+                            let mut output = image_format
+                                .encode_for_thread(output, &thread_id, database.clone())
+                                .await;
+
                             // The output can contain more than one variant, so we'll add them to the queue.
                             let first = output.pop().unwrap_or_else(|| {
                                 StreamVariant::ServerError(
                                     "No variants found in tool call output.".to_string(),
                                 )
                             });
-                            variant_queue.extend(output.into_iter());
+                            variant_queue.extend(output);
 
-                            let bytes = variant_to_bytes(&first);
+                            let bytes = variant_to_bytes(&first, framing);
 
                             return Some((
                                 Ok(bytes),
@@ -723,6 +1417,7 @@ async fn create_and_stream(
                                     tool_name,
                                     tool_arguments,
                                     tool_id,
+                                    parallel_calls,
                                     llama_tool_call_content,
                                     None,
                                 ),
@@ -739,16 +1434,56 @@ async fn create_and_stream(
                             &mut tool_name,
                             &mut tool_arguments,
                             &mut tool_id,
+                            &mut parallel_calls,
                             &thread_id,
                             &user_id,
                             database,
                             &mut open_ai_stream,
-                            chatbot,
+                            chatbot.clone(),
                             &mut llama_tool_call_content,
                             &mut reciever,
+                            code_verbosity,
+                            params,
                         )
                         .await;
 
+                        // A misbehaving model can cause the same warning over and over, so they are capped per stream.
+                        // If only dropped warnings were left, an empty delta takes their place, like for an empty event.
+                        let had_variants = !variants.is_empty();
+                        let mut variants =
+                            cap_warnings(&thread_id, variants, *MAX_WARNINGS_PER_STREAM);
+                        if had_variants && variants.is_empty() {
+                            variants.push(StreamVariant::Assistant(String::new()));
+                        }
+
+                        // Check whether the stream should end by checking the variants.
+                        let stream_end = variants
+                            .iter()
+                            .position(|v| matches!(v, StreamVariant::StreamEnd(_)));
+                        let should_end = stream_end.is_some();
+
+                        // The usage of the turn and, if the client asked for it, its summary are sent directly before the StreamEnd.
+                        // The usage only arrives after the end of the response, so the stream is polled until it's done first.
+                        if let Some(stream_end) = stream_end {
+                            record_remaining_usage(&mut open_ai_stream, &thread_id, &chatbot).await;
+                            let turn = if with_summary {
+                                get_conversation(&thread_id)
+                                    .unwrap_or_default()
+                                    .into_iter()
+                                    .chain(variants[..stream_end].iter().cloned())
+                                    .collect::<Vec<_>>()
+                            } else {
+                                Vec::new()
+                            };
+                            let before_end = end_of_turn_variants(
+                                &turn,
+                                get_conversation_usage(&thread_id),
+                                &chatbot,
+                                with_summary,
+                            );
+                            variants.splice(stream_end..stream_end, before_end);
+                        }
+
                         // Also add the variants into the active conversation
                         add_to_conversation(
                             &thread_id,
@@ -757,11 +1492,6 @@ async fn create_and_stream(
                             user_id.clone(),
                         );
 
-                        // Check whether the stream should end by checking the variants.
-                        let should_end = variants
-                            .iter()
-                            .any(|v| matches!(v, StreamVariant::StreamEnd(_)));
-
                         // The variant to return if there are no variants in the response.
                         let error_variant = StreamVariant::ServerError(
                             "No variants found in response.".to_string(),
@@ -772,7 +1502,7 @@ async fn create_and_stream(
                         let mut variants: VecDeque<StreamVariant> = variants.into();
                         let first_variant = variants.pop_front().unwrap_or(error_variant);
 
-                        let bytes = variant_to_bytes(&first_variant);
+                        let bytes = variant_to_bytes(&first_variant, framing);
 
                         // Everything worked, so we'll return the bytes and the new state.
                         Some((
@@ -786,6 +1516,7 @@ async fn create_and_stream(
                                 tool_name,
                                 tool_arguments,
                                 tool_id,
+                                parallel_calls,
                                 llama_tool_call_content,
                                 reciever,
                             ),
@@ -797,7 +1528,120 @@ async fn create_and_stream(
         },
     );
 
-    HttpResponse::Ok().streaming(out_stream)
+    // Generating the next token can take longer than the keep-alive time, so the connection gets keep-alive bytes while it's silent.
+    framing.response().streaming(with_transport_keep_alive(
+        out_stream,
+        *TRANSPORT_KEEP_ALIVE_INTERVAL,
+    ))
+}
+
+/// Polls the rest of the stream of the LLM after its response ended and records the token usage, which is only sent at the very end.
+async fn record_remaining_usage(
+    open_ai_stream: &mut Fuse<ChatCompletionResponseStream>,
+    thread_id: &str,
+    chatbot: &AvailableChatbots,
+) {
+    while let Some(content) = open_ai_stream.next().await {
+        if let Ok(response) = content {
+            if let Some(usage) = response.usage {
+                info!("Tokens used: {:?}; with chatbot: {:?}", usage, chatbot);
+                add_usage_to_conversation(thread_id, &TokenUsage::from(&usage));
+            }
+        }
+    }
+}
+
+/// The variants that are sent directly before the StreamEnd of a turn: its Summary, if the client asked for it,
+/// and its Usage, if the provider reported any.
+fn end_of_turn_variants(
+    turn: &[StreamVariant],
+    usage: Option<TokenUsage>,
+    chatbot: &AvailableChatbots,
+    with_summary: bool,
+) -> Vec<StreamVariant> {
+    let mut variants = Vec::new();
+    if with_summary {
+        variants.push(turn_summary(turn, usage));
+    }
+    match usage {
+        Some(usage) => variants.push(StreamVariant::Usage(
+            serde_json::json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+                "total_tokens": usage.total_tokens,
+                "model": chatbot.0,
+            })
+            .to_string(),
+        )),
+        None => debug!("No usage to send for the turn with chatbot {:?}.", chatbot),
+    }
+    variants
+}
+
+/// A single chunk of the stream, as LiteLLM sends it.
+/// Usually it's a completion chunk, but if the provider fails mid-stream, LiteLLM sends an error object (`{"error": {...}}`) instead,
+/// which async-openai can't deserialize into a chunk and would only report as a deserialization error, losing the actual message.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LiteLLMStreamChunk {
+    Chunk(CreateChatCompletionStreamResponse),
+    Error(WrappedError),
+}
+
+impl From<LiteLLMStreamChunk>
+    for Result<CreateChatCompletionStreamResponse, async_openai::error::OpenAIError>
+{
+    fn from(chunk: LiteLLMStreamChunk) -> Self {
+        match chunk {
+            LiteLLMStreamChunk::Chunk(chunk) => Ok(chunk),
+            LiteLLMStreamChunk::Error(wrapped) => {
+                Err(async_openai::error::OpenAIError::ApiError(wrapped.error))
+            }
+        }
+    }
+}
+
+/// Opens the stream to LiteLLM, retrying it with backoff if it fails with a temporary error like a rate limit.
+async fn create_litellm_stream_with_retries(
+    request: CreateChatCompletionRequest,
+) -> Result<ChatCompletionResponseStream, async_openai::error::OpenAIError> {
+    create_stream_with_retries(request, *LLM_STREAM_RETRY_POLICY, create_litellm_stream).await
+}
+
+/// Creates the stream from LiteLLM, turning error-shaped chunks into `ApiError`s so they can be handled like any other error.
+/// If LiteLLM failed too often in a row, the circuit breaker rejects the request immediately.
+async fn create_litellm_stream(
+    request: CreateChatCompletionRequest,
+) -> Result<ChatCompletionResponseStream, async_openai::error::OpenAIError> {
+    if !with_llm_breaker(|breaker| breaker.try_acquire(std::time::Instant::now())) {
+        info!("The circuit breaker is open, not sending the request to LiteLLM.");
+        return Err(llm_unavailable_error());
+    }
+    let stream = match LITE_LLM_CLIENT
+        .chat()
+        .create_stream_byot::<_, LiteLLMStreamChunk>(request)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            with_llm_breaker(|breaker| breaker.record_failure(std::time::Instant::now()));
+            return Err(e);
+        }
+    };
+    // The connection is only made once the stream is polled, so the first item tells whether LiteLLM could be reached.
+    let mut is_first = true;
+    Ok(Box::pin(stream.map(move |chunk| {
+        if std::mem::take(&mut is_first) {
+            match &chunk {
+                Err(
+                    async_openai::error::OpenAIError::StreamError(_)
+                    | async_openai::error::OpenAIError::Reqwest(_),
+                ) => with_llm_breaker(|breaker| breaker.record_failure(std::time::Instant::now())),
+                _ => with_llm_breaker(CircuitBreaker::record_success),
+            }
+        }
+        chunk.and_then(Into::into)
+    })))
 }
 
 /// Helper Enum to describe the different Stream Events that can be recieved from OpenAI/OLLama.
@@ -807,6 +1651,7 @@ enum StreamEvents {
     ToolCall(Vec<ChatCompletionMessageToolCallChunk>), // A tool delta was recieved.
     Empty,        // An event was recieved that contained no useful content, but was unexpected.
     LiveToolCall, // The LLama tool call is running; nothing can be streamed.
+    MalformedToolCall(String), // The LLama tool call ended, but its content couldn't be parsed.
     Error(ChatChoiceStream), // An error occured, contains the raw event.
 }
 
@@ -821,19 +1666,23 @@ async fn oai_stream_to_variants(
     tool_name: &mut Option<String>,
     tool_arguments: &mut String,
     tool_id: &mut String,
+    parallel_calls: &mut Vec<PendingToolCall>,
     thread_id: &String,
     user_id: &String,
     database: Database,
     open_ai_stream: &mut Fuse<ChatCompletionResponseStream>,
     chatbot: AvailableChatbots,
     llama_tool_call_content: &mut Cell<Option<Cell<String>>>,
-    reciever: &mut Option<(mpsc::Receiver<Vec<StreamVariant>>, JoinHandle<()>)>,
+    reciever: &mut Option<(mpsc::Receiver<ToolUpdate>, JoinHandle<()>)>,
+    code_verbosity: CodeVerbosity,
+    params: RequestParams,
 ) -> Vec<StreamVariant> {
     match response {
         Some(Ok(response)) => {
             // Debug info: how many tokens were used?
             if let Some(usage) = response.clone().usage {
                 debug!("Tokens used: {:?}", usage);
+                add_usage_to_conversation(thread_id, &TokenUsage::from(&usage));
             }
             // The choices represent the multiple completions that the LLM can make. We always set n=1, so there is exactly one choice.
             if let Some(choice) = response.choices.first() {
@@ -846,14 +1695,12 @@ async fn oai_stream_to_variants(
                 ) {
                     (None, Some(string_delta), _) => {
                         // Because the ollama implementation of the openAI-compliant API is not yet implemented for streaming,
-                        // We need to manually detect the tokens for the start of a tool call: "<tool_call>" and end: "</tool_call>".
+                        // We need to manually detect the tokens for the start of a tool call (by default "<tool_call>") and end (by default "</tool_call>").
+                        // The markers differ between fine-tunes, so they can be configured per model in the LiteLLM file.
                         // Depending on them, we need to either emit a Delta or a ToolCall event.
 
-                        let tool_call_started = match string_delta.as_str() {
-                            "<tool_call>" => Some(true), // Because that's how the tokens are represented in ASCII, they're sent inside one delta, not split and with no other content.
-                            "</tool_call>" => Some(false),
-                            _ => None,
-                        };
+                        let tool_call_started =
+                            model_tool_call_markers(&chatbot).classify(string_delta);
 
                         match (tool_call_started, llama_tool_call_content.take()) {
                             (None, None) => {
@@ -926,17 +1773,22 @@ async fn oai_stream_to_variants(
                             }
                             (Some(false), inner_llama_tool_call_content) => {
                                 // The end of the tool calls was reached; just emit a streamend event due to the tool call.
-
-                                if let Some(content) = inner_llama_tool_call_content {
+                                // The content is only left over if it never became valid JSON, so the tool call would be lost.
+                                let leftover = inner_llama_tool_call_content
+                                    .map(|content| content.take())
+                                    .unwrap_or_default();
+                                // Clear the content just to be sure the next call is not affected.
+                                llama_tool_call_content.set(None);
+
+                                if leftover.trim().is_empty() {
+                                    StreamEvents::StopEvent(FinishReason::ToolCalls)
+                                } else {
                                     warn!(
-                                        "Tool call ended, but content was not empty: {:?}",
-                                        content.take()
+                                        "Tool call ended, but its content couldn't be parsed: {:?}",
+                                        leftover
                                     );
-                                    // Clear the content just to be sure the next call is not affected.
-                                    llama_tool_call_content.set(None);
+                                    StreamEvents::MalformedToolCall(leftover)
                                 }
-
-                                StreamEvents::StopEvent(FinishReason::ToolCalls)
                             }
                         }
                     }
@@ -970,6 +1822,7 @@ async fn oai_stream_to_variants(
                             tool_arguments,
                             tool_name,
                             tool_id,
+                            parallel_calls,
                             thread_id,
                             user_id,
                             database,
@@ -977,6 +1830,8 @@ async fn oai_stream_to_variants(
                             &response,
                             chatbot,
                             reciever,
+                            code_verbosity,
+                            params,
                         )
                         .await
                     }
@@ -986,94 +1841,69 @@ async fn oai_stream_to_variants(
                             "A tool was called, converting the delta to a Code variant: {:?}",
                             tool_calls
                         );
-                        if tool_calls.len() > 1 {
-                            warn!("Multiple tool calls found, but only one is supported. All are ignored except the first: {:?}", tool_calls);
-                        }
-                        if let Some(tool_call) = tool_calls.first() {
-                            // We now know that we are sending the delta of a tool call.
-                            // For the user to see a stream of i.e. the code interpreter's code being written by the LLM, we need to send the code interpreter's code as a stream.
-                            if let Some(function) = &tool_call.function {
-                                // Now we need to check what function was called. For now, we only have the code interpreter.
-                                let mut arguments =
-                                    function.arguments.clone().unwrap_or(String::new());
-
-                                // Instead of just storing the arguments as-is, if the arguments contain no code yet, we'll ignore whitespace and newlines.
-                                // This will effectively trim the arguments.
-                                if arguments.trim().is_empty() {
-                                    // Only set the arguments to the empty String, if no code was written yet.
-                                    if tool_arguments.is_empty() {
-                                        arguments = String::new();
-                                    }
-                                }
-
-                                // Because of the genius way OpenAI constructed this very good API, the name of the tool call is only sent in the very first delta.
-                                // So if the name is not None, we store it in the tool_name variable that is passed to the next iteration of the stream.
-                                // If the name is None, we try to read the tool_name from the tool_name variable.
-                                if let Some(name) = function.name.clone() {
-                                    debug!("New tool call started: {:?}", name);
-                                    *tool_name = Some(name);
-                                }
-
-                                // Another things is that the arguments for the tool calls, even though they are strings, are not repeated when the actual tool call is made.
-                                // that means that I need to add another state to the closure to keep track of the tool arguments.
-                                tool_arguments.push_str(&arguments);
-
-                                // The same thing goes for the tool call id, which is neccessary to be matched later on in the response.
-                                match tool_call.id.clone() {
-                                    Some(id) => {
-                                        // We need to store the id in the tool_name variable, because the id is not repeated in the response.
-                                        *tool_id = id;
-                                    }
-                                    None => {
-                                        if tool_id.is_empty() {
-                                            warn!("Tool call expected id, but not found in response: {:?}", response);
-                                        }
+                        if parallel_tool_calls_enabled(params) {
+                            // The deltas of several tool calls are told apart by their index. The current tool state is always the last call,
+                            // the earlier ones wait in parallel_calls until the response is done.
+                            tool_calls
+                                .iter()
+                                .map(|tool_call| {
+                                    let index = tool_call.index as usize;
+                                    if let Some(call) = parallel_calls.get_mut(index) {
+                                        return tool_call_chunk_to_variant(
+                                            tool_call,
+                                            &mut call.name,
+                                            &mut call.arguments,
+                                            &mut call.id,
+                                            &response,
+                                        );
                                     }
-                                }
-
-                                let name_copy = tool_name.clone(); // because tool_name will be used at the end to pass the tool name to the next iteration of the stream, we need to clone it here.
-                                if name_copy == Some("code_interpreter".to_string()) {
-                                    // We know it's the code interpreter and can send it as a delta.
-                                    trace!(
-                                        "Tool call: {:?} with arguments: {:?} and id: {}",
-                                        name_copy,
-                                        arguments,
-                                        tool_id
-                                    );
-                                    if tool_id.is_empty() {
-                                        warn!(
-                                            "Tool call expected id, but not set yet: {:?}",
-                                            response
+                                    if index > parallel_calls.len()
+                                        && (tool_name.is_some() || !tool_arguments.is_empty())
+                                    {
+                                        debug!(
+                                            "Tool call {} started, the previous one is complete.",
+                                            index
                                         );
+                                        let (name, arguments, id) =
+                                            take_tool_state(tool_name, tool_arguments, tool_id);
+                                        parallel_calls.push(PendingToolCall {
+                                            name,
+                                            arguments,
+                                            id,
+                                        });
                                     }
-                                    vec![StreamVariant::Code(arguments, tool_id.clone())]
-                                } else {
-                                    warn!(
-                                        "Tool call expected known tool, but found: {:?}",
-                                        name_copy
-                                    );
-                                    // Instead of ending the stream, we'll just ignore the tool call, but send the user a ServerHint.
-                                    // Depending on the implementation of the OpenAI API, this might result in a unspecified Server Error on the LLM side.
-                                    vec![StreamVariant::ServerHint(format!("{{\"warning\": \"Tool call expected known tool, but found ->{}<-; content: ->{}<-\"}}", name_copy.unwrap_or_default(), arguments))]
-                                }
+                                    tool_call_chunk_to_variant(
+                                        tool_call,
+                                        tool_name,
+                                        tool_arguments,
+                                        tool_id,
+                                        &response,
+                                    )
+                                })
+                                .collect()
+                        } else {
+                            if tool_calls.len() > 1 {
+                                warn!("Multiple tool calls found, but only one is supported. All are ignored except the first: {:?}", tool_calls);
+                            }
+                            if let Some(tool_call) = tool_calls.first() {
+                                // We now know that we are sending the delta of a tool call.
+                                // For the user to see a stream of i.e. the code interpreter's code being written by the LLM, we need to send the code interpreter's code as a stream.
+                                vec![tool_call_chunk_to_variant(
+                                    tool_call,
+                                    tool_name,
+                                    tool_arguments,
+                                    tool_id,
+                                    &response,
+                                )]
                             } else {
                                 warn!(
-                                    "Tool call expected function, but not found in response: {:?}",
+                                    "Tool call expected, but not found in response: {:?}",
                                     response
                                 );
                                 vec![StreamVariant::CodeError(
-                                    "Tool call expected function, but not found in response."
-                                        .to_string(),
+                                    "Tool call expected, but not found in response.".to_string(),
                                 )]
                             }
-                        } else {
-                            warn!(
-                                "Tool call expected, but not found in response: {:?}",
-                                response
-                            );
-                            vec![StreamVariant::CodeError(
-                                "Tool call expected, but not found in response.".to_string(),
-                            )]
                         }
                     }
                     StreamEvents::Empty => {
@@ -1091,6 +1921,61 @@ async fn oai_stream_to_variants(
                             vec![StreamVariant::StreamEnd("Tool call AND content found in response, the API specified that this couldn't happen.".to_string())]
                         }
                     }
+                    StreamEvents::MalformedToolCall(content) => {
+                        // The LLMs mostly break the JSON in the code, which can often still be recovered.
+                        match extract_code_leniently(&content).filter(|_| *LENIENT_TOOL_CALLS) {
+                            Some(code) => {
+                                info!("Recovered the code of a malformed tool call, running it.");
+                                // The recovered tool call is run as if it had been streamed normally.
+                                *tool_name = Some("code_interpreter".to_string());
+                                *tool_arguments = serde_json::json!({ "code": code }).to_string();
+                                *tool_id = generate_id();
+                                let mut variants = vec![StreamVariant::Code(
+                                    tool_arguments.clone(),
+                                    tool_id.clone(),
+                                )];
+                                variants.extend(
+                                    handle_stop_event(
+                                        FinishReason::ToolCalls,
+                                        Some(choice),
+                                        tool_arguments,
+                                        tool_name,
+                                        tool_id,
+                                        parallel_calls,
+                                        thread_id,
+                                        user_id,
+                                        database,
+                                        open_ai_stream,
+                                        &response,
+                                        chatbot,
+                                        reciever,
+                                        code_verbosity,
+                                        params,
+                                    )
+                                    .await,
+                                );
+                                variants
+                            }
+                            None => {
+                                // The LLM is told about it and can retry, which counts against the budget of the turn like any tool call.
+                                if !count_operation(thread_id, *MAX_OPERATIONS_PER_TURN) {
+                                    warn!("Thread {} exceeded the maximum number of operations per turn, ending the turn.", thread_id);
+                                    return vec![StreamVariant::StreamEnd(format!(
+                                        "Reached the maximum of {} operations (like tool calls) in a single turn",
+                                        *MAX_OPERATIONS_PER_TURN
+                                    ))];
+                                }
+                                restart_stream(
+                                    thread_id,
+                                    malformed_tool_call_variants(&content, generate_id()),
+                                    chatbot,
+                                    open_ai_stream,
+                                    params,
+                                )
+                                .await
+                            }
+                        }
+                    }
                     StreamEvents::LiveToolCall => {
                         // The tool call is still running, so we'll just send an empty event.
                         vec![StreamVariant::Code(String::new(), String::new())] // Just empty ID because it is necessary.
@@ -1112,6 +1997,7 @@ async fn oai_stream_to_variants(
                         tool_arguments,
                         tool_name,
                         tool_id,
+                        parallel_calls,
                         thread_id,
                         user_id,
                         database,
@@ -1119,6 +2005,8 @@ async fn oai_stream_to_variants(
                         &response,
                         chatbot,
                         reciever,
+                        code_verbosity,
+                        params,
                     )
                     .await
                     // vec![StreamVariant::StreamEnd("Qwen-like stream ended".to_string())]
@@ -1128,6 +2016,24 @@ async fn oai_stream_to_variants(
                 }
             }
         }
+        Some(Err(e)) if history_trimmed_hint(&e).is_some() => {
+            // Not an actual error: the history was too long and the stream was retried without its oldest part.
+            history_trimmed_hint(&e).into_iter().collect()
+        }
+        Some(Err(async_openai::error::OpenAIError::ApiError(api_error))) => {
+            // The provider sent an error inside the stream (see LiteLLMStreamChunk).
+            // Nothing useful will come after it, so we'll pass on the message and end the stream.
+            warn!(
+                "Recieved an error from the LLM provider mid-stream: {:?}",
+                api_error
+            );
+            vec![
+                StreamVariant::OpenAIError(format!(
+                    "The LLM provider returned an error: {api_error}"
+                )),
+                StreamVariant::StreamEnd("Error from the LLM provider".to_string()),
+            ]
+        }
         Some(Err(e)) => {
             // If we can't get the response, we'll return a generic error.
             warn!("Error getting response: {:?}", e);
@@ -1140,18 +2046,49 @@ async fn oai_stream_to_variants(
             // If it's not an openAI chatbot, check whether we can get some tool call from the running tool.
             // if matches!(chatbot, AvailableChatbots::Ollama(_)) {
             // Try to get the tool call from the running tool.
-            let tool_call = try_extract_tool_call(
-                &llama_tool_call_content
-                    .take()
-                    .map(|c| c.take())
-                    .unwrap_or_default(),
-            );
+            let buffered_content = llama_tool_call_content
+                .take()
+                .map(|c| c.take())
+                .unwrap_or_default();
+            let tool_call = try_extract_tool_call(&buffered_content);
             match tool_call {
                 None => {
                     info!("Stream ended abruptly and without error.");
-                    vec![StreamVariant::StreamEnd(
+                    let mut variants = vec![];
+                    // Whatever was buffered for the llama tool call was never sent to the client, so it would be lost.
+                    // If it looks like the start of a tool call, it's surfaced as code, otherwise it was just text of the assistant.
+                    let buffered_content = buffered_content.trim();
+                    if buffered_content.starts_with('{') {
+                        warn!(
+                            "Stream ended during a tool call, surfacing the partial arguments: {:?}",
+                            buffered_content
+                        );
+                        variants.push(StreamVariant::Code(
+                            buffered_content.to_string(),
+                            generate_id(),
+                        ));
+                    } else if !buffered_content.is_empty() {
+                        debug!("Flushing buffered assistant text: {:?}", buffered_content);
+                        variants.push(StreamVariant::Assistant(buffered_content.to_string()));
+                    }
+                    // A streamed tool call that was cut off was already sent as code deltas, but will never be executed.
+                    if (tool_name.is_some() && !tool_arguments.is_empty())
+                        || !parallel_calls.is_empty()
+                    {
+                        warn!(
+                            "Stream ended before the tool call {:?} was complete, it won't be executed.",
+                            tool_name
+                        );
+                        variants.push(StreamVariant::CodeError(
+                            "The stream ended before the tool call was complete, so it wasn't executed.".to_string(),
+                        ));
+                    }
+                    take_tool_state(tool_name, tool_arguments, tool_id);
+                    parallel_calls.clear();
+                    variants.push(StreamVariant::StreamEnd(
                         "Stream ended abruptly.".to_string(),
-                    )]
+                    ));
+                    variants
                 }
                 Some((name, arguments)) => {
                     // We know it's the code interpreter and can send it as a delta.
@@ -1173,20 +2110,214 @@ async fn oai_stream_to_variants(
     }
 }
 
+/// Adds the delta of a single tool call to the state of that tool call and converts it to the variant for the client.
+fn tool_call_chunk_to_variant(
+    tool_call: &ChatCompletionMessageToolCallChunk,
+    tool_name: &mut Option<String>,
+    tool_arguments: &mut String,
+    tool_id: &mut String,
+    response: &CreateChatCompletionStreamResponse,
+) -> StreamVariant {
+    if let Some(function) = &tool_call.function {
+        // Now we need to check what function was called. For now, we only have the code interpreter.
+        let mut arguments = function.arguments.clone().unwrap_or(String::new());
+
+        // Instead of just storing the arguments as-is, if the arguments contain no code yet, we'll ignore whitespace and newlines.
+        // This will effectively trim the arguments.
+        if arguments.trim().is_empty() {
+            // Only set the arguments to the empty String, if no code was written yet.
+            if tool_arguments.is_empty() {
+                arguments = String::new();
+            }
+        }
+
+        // Because of the genius way OpenAI constructed this very good API, the name of the tool call is only sent in the very first delta.
+        // So if the name is not None, we store it in the tool_name variable that is passed to the next iteration of the stream.
+        // If the name is None, we try to read the tool_name from the tool_name variable.
+        if let Some(name) = function.name.clone() {
+            debug!("New tool call started: {:?}", name);
+            *tool_name = Some(name);
+        }
+
+        // Another things is that the arguments for the tool calls, even though they are strings, are not repeated when the actual tool call is made.
+        // that means that I need to add another state to the closure to keep track of the tool arguments.
+        tool_arguments.push_str(&arguments);
+
+        // The same thing goes for the tool call id, which is neccessary to be matched later on in the response.
+        match tool_call.id.clone() {
+            Some(id) => {
+                // We need to store the id in the tool_name variable, because the id is not repeated in the response.
+                *tool_id = id;
+            }
+            None => {
+                if tool_id.is_empty() {
+                    warn!(
+                        "Tool call expected id, but not found in response: {:?}",
+                        response
+                    );
+                }
+            }
+        }
+
+        let name_copy = tool_name.clone(); // because tool_name will be used at the end to pass the tool name to the next iteration of the stream, we need to clone it here.
+        if name_copy == Some("code_interpreter".to_string()) {
+            // We know it's the code interpreter and can send it as a delta.
+            trace!(
+                "Tool call: {:?} with arguments: {:?} and id: {}",
+                name_copy,
+                arguments,
+                tool_id
+            );
+            if tool_id.is_empty() {
+                warn!("Tool call expected id, but not set yet: {:?}", response);
+            }
+            StreamVariant::Code(arguments, tool_id.clone())
+        } else {
+            warn!("Tool call expected known tool, but found: {:?}", name_copy);
+            // Instead of ending the stream, we'll just ignore the tool call, but send the user a ServerHint.
+            // Depending on the implementation of the OpenAI API, this might result in a unspecified Server Error on the LLM side.
+            StreamVariant::ServerHint(format!("{{\"warning\": \"Tool call expected known tool, but found ->{}<-; content: ->{}<-\"}}", name_copy.unwrap_or_default(), arguments))
+        }
+    } else {
+        warn!(
+            "Tool call expected function, but not found in response: {:?}",
+            response
+        );
+        StreamVariant::CodeError(
+            "Tool call expected function, but not found in response.".to_string(),
+        )
+    }
+}
+
 async fn handle_stop_event(
     reason: async_openai::types::FinishReason,
     choice: Option<&ChatChoiceStream>,
     tool_arguments: &mut String,
     tool_name: &mut Option<String>,
     tool_id: &mut String,
+    parallel_calls: &mut Vec<PendingToolCall>,
     thread_id: &String,
     user_id: &String,
     database: Database,
     open_ai_stream: &mut Fuse<ChatCompletionResponseStream>,
     response: &CreateChatCompletionStreamResponse,
     chatbot: AvailableChatbots,
-    reciever: &mut Option<(mpsc::Receiver<Vec<StreamVariant>>, JoinHandle<()>)>,
+    reciever: &mut Option<(mpsc::Receiver<ToolUpdate>, JoinHandle<()>)>,
+    code_verbosity: CodeVerbosity,
+    params: RequestParams,
 ) -> Vec<StreamVariant> {
+    // What a finish reason means can differ between providers, so the action comes from a table that can be configured per model.
+    let action = model_stop_action(&chatbot, reason);
+    if action == StopAction::Continue {
+        // Some providers send more content after the finish reason; the stream just goes on and the tool state is kept.
+        debug!(
+            "Ignoring finish reason {:?} for chatbot {:?}, continuing the stream.",
+            reason, chatbot
+        );
+        return vec![StreamVariant::Assistant(String::new())];
+    }
+
+    // Every other stop event is a turn boundary, so the tool state is consumed here, whatever happens with it afterwards.
+    // That way, no stale arguments can leak into the next stream if something fails along the way.
+    let (tool_name, tool_arguments, tool_id) = take_tool_state(tool_name, tool_arguments, tool_id);
+    // The same goes for the earlier tool calls of a response with parallel tool calls.
+    let earlier_calls = std::mem::take(parallel_calls);
+    // If another finish reason is configured to run tools, but there is no tool call, it's just a normal end.
+    if action == StopAction::End
+        || (tool_name.is_none() && reason != async_openai::types::FinishReason::ToolCalls)
+    {
+        return stream_end_for(reason);
+    }
+
+    // Otherwise, the tool call that was accumulated is run.
+    // We expect there to now be a tool call in the response.
+    if let Some(choice) = choice {
+        if let Some(content) = choice.delta.tool_calls.clone() {
+            // Handle the tool call
+            trace!("Tool call: {:?}", content);
+        }
+    }
+
+    let mut all_generated_variants = vec![];
+
+    // In order to allow for a heartbeat, we need to create a mspc channel for the tool call to communicate with the main thread.
+    // It also carries the partial output of the code interpreter, so it has room for a few lines.
+    let (tx, rx) = mpsc::channel::<ToolUpdate>(64);
+
+    // Every tool call makes the LLM go another round, so it counts against the budget of the turn.
+    // If the LLM is stuck in a loop, we end the turn here instead of running the tool again.
+    if tool_name.is_some() && !count_operation(thread_id, *MAX_OPERATIONS_PER_TURN) {
+        warn!(
+            "Thread {} exceeded the maximum number of operations per turn, ending the turn.",
+            thread_id
+        );
+        return vec![StreamVariant::StreamEnd(format!(
+            "Reached the maximum of {} operations (like tool calls) in a single turn",
+            *MAX_OPERATIONS_PER_TURN
+        ))];
+    }
+
+    // There is NOT a tool call there, because that was accumulated in the previous iterations.
+    // The stream ending is just OpenAI's way of telling us that the tool call is done and can now be executed.
+    if let Some(name) = tool_name {
+        let handle = if earlier_calls.is_empty() {
+            tokio::spawn(route_call(
+                name,
+                Some(tool_arguments),
+                tool_id,
+                thread_id.to_string(),
+                user_id.to_string(),
+                tx,
+                database,
+                code_verbosity,
+            ))
+        } else {
+            // All tool calls of the response run at the same time; their outputs arrive together, so the stream is restarted only once.
+            let mut calls = earlier_calls;
+            calls.push(PendingToolCall {
+                name: Some(name),
+                arguments: tool_arguments,
+                id: tool_id,
+            });
+            tokio::spawn(route_calls_concurrently(
+                calls,
+                thread_id.to_string(),
+                user_id.to_string(),
+                tx,
+                database,
+                code_verbosity,
+            ))
+        };
+
+        // The stop requests need to be able to abort it, not just the stream.
+        set_tool_task(thread_id, handle.abort_handle());
+
+        // At this point, we need to inform the main thread that that the tool call is running.
+        // Specifically, we need to return the info that a tool call was started and the reciever of the mpsc channel.
+        reciever.replace((rx, handle));
+        vec![heartbeat_content(thread_id).await]
+    } else {
+        warn!(
+            "Tool call expected, but not found in response: {:?}",
+            response
+        );
+        all_generated_variants.push(StreamVariant::CodeError(
+            "Tool call expected, but not found in response.".to_string(),
+        ));
+
+        restart_stream(
+            thread_id,
+            all_generated_variants,
+            chatbot,
+            open_ai_stream,
+            params,
+        )
+        .await
+    }
+}
+
+/// The StreamEnd for a finish reason that ends the stream.
+fn stream_end_for(reason: async_openai::types::FinishReason) -> Vec<StreamVariant> {
     match reason {
         async_openai::types::FinishReason::Stop => {
             debug!("Stopping stream due to successfull end of generation.");
@@ -1209,61 +2340,43 @@ async fn handle_stop_event(
             )]
         }
         async_openai::types::FinishReason::ToolCalls => {
-            // We expect there to now be a tool call in the response.
-            if let Some(choice) = choice {
-                if let Some(content) = choice.delta.tool_calls.clone() {
-                    // Handle the tool call
-                    trace!("Tool call: {:?}", content);
-                }
-            }
-
-            let mut all_generated_variants = vec![];
-
-            // In order to allow for a heartbeat, we need to create a mspc channel for the tool call to communicate with the main thread.
-            let (tx, rx) = mpsc::channel::<Vec<StreamVariant>>(1);
-
-            // There is NOT a tool call there, because that was accumulated in the previous iterations.
-            // The stream ending is just OpenAI's way of telling us that the tool call is done and can now be executed.
-            if let Some(name) = tool_name {
-                let handle = tokio::spawn(route_call(
-                    (*name).to_string(),
-                    Some((*tool_arguments).to_string()),
-                    (*tool_id).to_string(),
-                    thread_id.to_string(),
-                    user_id.to_string(),
-                    tx,
-                    database,
-                ));
-                // Reset the tool_name and tool_arguments
-                *tool_name = None;
-                *tool_arguments = String::new();
-                *tool_id = String::new();
-
-                // At this point, we need to inform the main thread that that the tool call is running.
-                // Specifically, we need to return the info that a tool call was started and the reciever of the mpsc channel.
-                reciever.replace((rx, handle));
-                vec![heartbeat_content().await]
-            } else {
-                warn!(
-                    "Tool call expected, but not found in response: {:?}",
-                    response
-                );
-                all_generated_variants.push(StreamVariant::CodeError(
-                    "Tool call expected, but not found in response.".to_string(),
-                ));
-
-                restart_stream(thread_id, all_generated_variants, chatbot, open_ai_stream).await
-            }
+            info!("Stopping stream due to tool call, tool calls are configured to end the stream for this chatbot.");
+            vec![StreamVariant::StreamEnd(
+                "Tool call ended the stream".to_string(),
+            )]
         }
     }
 }
 
+/// Resets the state of the tool call that is accumulated over the deltas of a stream and returns what it was.
+/// Has to be called on every turn boundary, so that no tool call carries over into the next stream.
+fn take_tool_state(
+    tool_name: &mut Option<String>,
+    tool_arguments: &mut String,
+    tool_id: &mut String,
+) -> (Option<String>, String, String) {
+    if tool_name.is_some() || !tool_arguments.is_empty() {
+        trace!(
+            "Resetting tool state: {:?} with arguments {:?} and id {:?}",
+            tool_name,
+            tool_arguments,
+            tool_id
+        );
+    }
+    (
+        tool_name.take(),
+        std::mem::take(tool_arguments),
+        std::mem::take(tool_id),
+    )
+}
+
 /// Helper function to restart the stream.
 async fn restart_stream(
     thread_id: &String,
     all_generated_variants: Vec<StreamVariant>,
     chatbot: AvailableChatbots,
     open_ai_stream: &mut Fuse<ChatCompletionResponseStream>,
+    params: RequestParams,
 ) -> Vec<StreamVariant> {
     // Before we can return the generated variants, we need to start a new steam because the old one is done.
     // We need a list of all messages, which we can get from the active conversation global variable.
@@ -1283,13 +2396,21 @@ async fn restart_stream(
             );
 
             // The stream wants a vector of ChatCompletionRequestMessage, so we need to convert the StreamVariants to that.
-            let all_oai_messages =
-                help_convert_sv_ccrm(all_messages, model_supports_images(chatbot.clone()));
+            let all_oai_messages = fit_tool_call_only_messages(
+                fit_message_order(
+                    help_convert_sv_ccrm(all_messages, model_supports_images(chatbot.clone())),
+                    model_message_ordering(&chatbot),
+                ),
+                model_tool_call_content(&chatbot),
+            );
 
             trace!("All messages: {:?}", all_oai_messages);
+            if let Err(error) = validate_messages(&all_oai_messages) {
+                return vec![error];
+            }
 
             // Now we construct a new stream and substitute the old one with it.
-            match build_request(all_oai_messages, chatbot) {
+            match build_request(all_oai_messages, chatbot, params) {
                 Err(e) => {
                     // If we can't build the request, we'll return a generic error.
                     warn!("Error building request: {:?}", e);
@@ -1299,12 +2420,17 @@ async fn restart_stream(
                 }
                 Ok(request) => {
                     trace!("Request built successfully: {:?}", request);
-                    match LITE_LLM_CLIENT.chat().create_stream(request).await {
+                    match create_stream_with_context_retry(
+                        request,
+                        create_litellm_stream_with_retries,
+                    )
+                    .await
+                    {
                         Err(e) => {
-                            // If we can't create the stream, we'll return a generic error.
+                            // The temporary errors were already retried, so this is passed on as an error of the LLM.
                             warn!("Error creating stream: {:?}", e);
-                            vec![StreamVariant::ServerError(format!(
-                                "Error creating stream: {e:?}"
+                            vec![StreamVariant::OpenAIError(format!(
+                                "Error creating stream: {e}"
                             ))]
                         }
                         Ok(stream) => {
@@ -1385,9 +2511,53 @@ fn try_extract_tool_call(content: &str) -> Option<(String, String)> {
     }
 }
 
-/// Helper function to convert a StreamVariant to bytes.
+/// Returns what a reconnected client missed of its conversation, ending with a StreamEnd, or None if it can't be resumed.
+/// A resume is replay-only: the generation stopped when the connection was lost, so the turn ends with what was produced until then.
+/// To go on, the client sends a new request to the thread.
+fn replay_missed_variants(
+    thread_id: &str,
+    user_id: &str,
+    cursor: usize,
+) -> Option<Vec<StreamVariant>> {
+    let mut missed_variants = resume_conversation(thread_id, user_id, cursor)?;
+    let stream_end = StreamVariant::StreamEnd("Stream resumed after a disconnect".to_string());
+    add_to_conversation(
+        thread_id,
+        vec![stream_end.clone()],
+        String::new(),
+        user_id.to_string(),
+    );
+    missed_variants.push(stream_end);
+    Some(missed_variants)
+}
+
+/// Prepares a heartbeat for the client while a tool call runs.
+/// It counts as activity of the conversation, but is only stored in it if PERSIST_HEARTBEATS is set.
+fn emit_heartbeat(
+    thread_id: &str,
+    heartbeat: &StreamVariant,
+    freva_config_path: String,
+    user_id: String,
+    framing: StreamFraming,
+) -> Bytes {
+    if *PERSIST_HEARTBEATS {
+        add_to_conversation(
+            thread_id,
+            vec![heartbeat.clone()],
+            freva_config_path,
+            user_id,
+        );
+    } else {
+        // It still counts as activity and a resumed client counted it, so it's kept until the conversation is removed.
+        add_to_conversation(thread_id, Vec::new(), freva_config_path, user_id);
+        add_transient_to_conversation(thread_id, vec![heartbeat.clone()]);
+    }
+    variant_to_bytes(heartbeat, framing)
+}
+
+/// Helper function to convert a StreamVariant to bytes, framed as the client requested.
 /// Doesn't panic, always returns a valid byte array.
-fn variant_to_bytes(variant: &StreamVariant) -> Bytes {
+pub fn variant_to_bytes(variant: &StreamVariant, framing: StreamFraming) -> Bytes {
     let string_rep = match serde_json::to_string(variant) {
         Ok(string) => string,
         Err(e) => {
@@ -1401,5 +2571,582 @@ fn variant_to_bytes(variant: &StreamVariant) -> Bytes {
         }
     };
 
-    actix_web::web::Bytes::copy_from_slice(string_rep.as_bytes())
+    framing.frame(actix_web::web::Bytes::copy_from_slice(
+        string_rep.as_bytes(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_llm_backend_down_is_a_json_error_instead_of_a_stream() {
+        let response = lite_llm_down_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // It's a plain JSON error, not the start of a stream the client would have to parse.
+        assert_eq!(
+            response
+                .headers()
+                .get(actix_web::http::header::CONTENT_TYPE)
+                .expect("The response has a content type"),
+            "application/json"
+        );
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .expect("The body can be read");
+        let body: serde_json::Value = serde_json::from_slice(&body).expect("The body is JSON");
+        assert_eq!(body["error"]["code"], "llm_unavailable");
+        assert_eq!(body["error"]["status"], 503);
+    }
+
+    #[test]
+    fn test_reasoning_effort_is_only_sent_to_gpt_5() {
+        let params =
+            request_params_from_request(&QString::from("reasoning_effort=high"), &HeaderMap::new())
+                .expect("The reasoning effort is valid");
+        let request = |model: &str, params: RequestParams| {
+            build_request(vec![], AvailableChatbots(model.to_string()), params)
+                .expect("The request can be built")
+        };
+
+        assert_eq!(
+            request("gpt-5", params).reasoning_effort,
+            Some(async_openai::types::ReasoningEffort::High)
+        );
+        assert_eq!(
+            request("gpt-5-mini", RequestParams::default()).reasoning_effort,
+            Some(async_openai::types::ReasoningEffort::Medium)
+        );
+        for model in ["gpt-4.1", "o4-mini", "claude-sonnet-4-5"] {
+            assert_eq!(request(model, params).reasoning_effort, None, "{model}");
+        }
+
+        let response = request_params_from_request(
+            &QString::from("reasoning_effort=maximal"),
+            &HeaderMap::new(),
+        )
+        .expect_err("The reasoning effort is invalid");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Returns the model of every turn of a thread, in order; None for the turns that didn't record it.
+    fn turn_models(conversation: &[StreamVariant]) -> Vec<Option<String>> {
+        let mut models = vec![];
+        for variant in conversation {
+            match variant {
+                StreamVariant::User(_) => models.push(None),
+                StreamVariant::ServerHint(content) => {
+                    let model = serde_json::from_str::<serde_json::Value>(content)
+                        .ok()
+                        .and_then(|value| value.get("model")?.as_str().map(str::to_string));
+                    if let (Some(model), Some(turn)) = (model, models.last_mut()) {
+                        *turn = Some(model);
+                    }
+                }
+                _ => {}
+            }
+        }
+        models
+    }
+
+    #[test]
+    fn test_turns_record_their_model() {
+        let conversation = vec![
+            thread_id_hint("abc"),
+            StreamVariant::User("hi".to_string()),
+            model_hint(&AvailableChatbots("gpt-4.1".to_string())),
+            StreamVariant::Assistant("Hello!".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+            // A turn from before the model was recorded.
+            StreamVariant::User("and now?".to_string()),
+            StreamVariant::Assistant("Still here.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+            StreamVariant::User("plot it".to_string()),
+            model_hint(&AvailableChatbots("qwen3:32b".to_string())),
+            StreamVariant::ServerHint("{\"heartbeat\": true}".to_string()),
+            StreamVariant::Assistant("Done.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        assert_eq!(
+            turn_models(&conversation),
+            vec![
+                Some("gpt-4.1".to_string()),
+                None,
+                Some("qwen3:32b".to_string())
+            ]
+        );
+        // The LLM never sees which model answered.
+        let messages = help_convert_sv_ccrm(conversation, false);
+        assert!(!serde_json::to_string(&messages)
+            .expect("The messages should serialize")
+            .contains("qwen3"));
+    }
+
+    #[test]
+    fn test_empty_message_list_gives_diagnostic_error() {
+        // Only variants that are hidden from the LLM, so nothing is left after the conversion.
+        let conversation = vec![
+            StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string()),
+            StreamVariant::CodeError("NameError".to_string()),
+        ];
+        let messages = help_convert_sv_ccrm(conversation, false);
+        assert!(messages.is_empty());
+        match validate_messages(&messages) {
+            Err(StreamVariant::ServerError(diagnostics)) => {
+                assert!(diagnostics.contains("the list of messages is empty"));
+            }
+            other => panic!("Expected a diagnostic ServerError, got {other:?}"),
+        }
+
+        // A conversation with a prompt but without the user's message is caught as well.
+        let mut messages = crate::chatbot::prompting::get_entire_prompt("user", "thread");
+        assert!(matches!(
+            validate_messages(&messages),
+            Err(StreamVariant::ServerError(diagnostics)) if diagnostics.contains("no message of the user")
+        ));
+        messages.push(ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessage {
+                name: None,
+                content: "hi".to_string().into(),
+            },
+        ));
+        assert_eq!(validate_messages(&messages), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn test_error_shaped_chunk_ends_stream() {
+        // This is what LiteLLM sends when the provider fails after the stream already started.
+        let raw_chunk = r#"{"error": {"message": "litellm.RateLimitError: Rate limit reached for gpt-4.1", "type": "rate_limit_error", "param": null, "code": "429"}}"#;
+        let chunk: LiteLLMStreamChunk =
+            serde_json::from_str(raw_chunk).expect("The error chunk should deserialize");
+        let response: Result<CreateChatCompletionStreamResponse, async_openai::error::OpenAIError> =
+            chunk.into();
+
+        // The database and stream are not touched when handling the error, they just need to exist.
+        let database = mongodb::Client::with_options(
+            mongodb::options::ClientOptions::builder()
+                .hosts(vec![mongodb::options::ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Creating a client doesn't connect yet")
+        .database("test");
+        let empty_stream: ChatCompletionResponseStream = Box::pin(stream::empty());
+
+        let variants = oai_stream_to_variants(
+            Some(response),
+            &mut None,
+            &mut String::new(),
+            &mut String::new(),
+            &mut Vec::new(),
+            &"testthread".to_string(),
+            &"testuser".to_string(),
+            database,
+            &mut empty_stream.fuse(),
+            DEFAULTCHATBOT.clone(),
+            &mut Cell::new(None),
+            &mut None,
+            CodeVerbosity::Concise,
+            RequestParams::default(),
+        )
+        .await;
+
+        assert_eq!(variants.len(), 2);
+        assert!(
+            matches!(&variants[0], StreamVariant::OpenAIError(message) if message.contains("Rate limit reached for gpt-4.1"))
+        );
+        assert!(matches!(variants[1], StreamVariant::StreamEnd(_)));
+    }
+
+    #[actix_web::test]
+    async fn test_dropped_body_aborts_tool_call_and_removes_conversation() {
+        let database = mongodb::Client::with_options(
+            mongodb::options::ClientOptions::builder()
+                .hosts(vec![mongodb::options::ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Creating a client doesn't connect yet")
+        .database("test");
+
+        for grace_period in [None, Some(std::time::Duration::from_millis(50))] {
+            let thread_id = generate_id();
+            add_to_conversation(
+                &thread_id,
+                vec![StreamVariant::User("compute for an hour".to_string())],
+                String::new(),
+                "testuser".to_string(),
+            );
+            let tool_call = tokio::spawn(std::future::pending::<()>());
+            set_tool_task(&thread_id, tool_call.abort_handle());
+
+            // The body owns the guard like the stream of create_and_stream does; the client goes away before the stream ends.
+            let guard = DisconnectGuard {
+                thread_id: thread_id.clone(),
+                database: database.clone(),
+                grace_period,
+            };
+            let body =
+                stream::pending::<Result<Bytes, std::convert::Infallible>>().map(move |item| {
+                    let _guard = &guard;
+                    item
+                });
+            drop(HttpResponse::Ok().streaming(body));
+
+            assert!(tool_call.await.is_err_and(|e| e.is_cancelled()));
+            if grace_period.is_some() {
+                // The conversation can still be resumed during the grace period.
+                assert!(get_conversation(&thread_id).is_some());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            assert_eq!(get_conversation(&thread_id), None, "{grace_period:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_abrupt_ollama_end_flushes_buffer() {
+        let database = mongodb::Client::with_options(
+            mongodb::options::ClientOptions::builder()
+                .hosts(vec![mongodb::options::ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Creating a client doesn't connect yet")
+        .database("test");
+
+        // The model started a tool call, but the stream ended before the JSON was complete.
+        let buffered = r#"{"name": "code_interpreter", "arguments": {"code": "import xarray"#;
+        let empty_stream: ChatCompletionResponseStream = Box::pin(stream::empty());
+        let variants = oai_stream_to_variants(
+            None,
+            &mut None,
+            &mut String::new(),
+            &mut String::new(),
+            &mut Vec::new(),
+            &"testthread".to_string(),
+            &"testuser".to_string(),
+            database.clone(),
+            &mut empty_stream.fuse(),
+            DEFAULTCHATBOT.clone(),
+            &mut Cell::new(Some(Cell::new(buffered.to_string()))),
+            &mut None,
+            CodeVerbosity::Concise,
+            RequestParams::default(),
+        )
+        .await;
+        assert_eq!(variants.len(), 2);
+        assert!(matches!(&variants[0], StreamVariant::Code(code, _) if code == buffered));
+        assert!(matches!(variants[1], StreamVariant::StreamEnd(_)));
+
+        // Plain text in the buffer is flushed as assistant text.
+        let empty_stream: ChatCompletionResponseStream = Box::pin(stream::empty());
+        let variants = oai_stream_to_variants(
+            None,
+            &mut None,
+            &mut String::new(),
+            &mut String::new(),
+            &mut Vec::new(),
+            &"testthread".to_string(),
+            &"testuser".to_string(),
+            database,
+            &mut empty_stream.fuse(),
+            DEFAULTCHATBOT.clone(),
+            &mut Cell::new(Some(Cell::new("Let me plot that".to_string()))),
+            &mut None,
+            CodeVerbosity::Concise,
+            RequestParams::default(),
+        )
+        .await;
+        assert_eq!(
+            variants,
+            vec![
+                StreamVariant::Assistant("Let me plot that".to_string()),
+                StreamVariant::StreamEnd("Stream ended abruptly.".to_string()),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_failed_tool_call_leaves_clean_tool_state() {
+        let database = mongodb::Client::with_options(
+            mongodb::options::ClientOptions::builder()
+                .hosts(vec![mongodb::options::ServerAddress::Tcp {
+                    host: "localhost".to_string(),
+                    port: None,
+                }])
+                .build(),
+        )
+        .expect("Creating a client doesn't connect yet")
+        .database("test");
+        let response: CreateChatCompletionStreamResponse = serde_json::from_str(
+            r#"{"id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 0, "model": "gpt-4.1", "choices": []}"#,
+        )
+        .expect("The chunk should deserialize");
+        let empty_stream: ChatCompletionResponseStream = Box::pin(stream::empty());
+
+        // The LLM streamed arguments, but never the name of the tool, so the tool call fails.
+        let mut tool_name = None;
+        let mut tool_arguments = r#"{"code": "print(1)"}"#.to_string();
+        let mut tool_id = "call_1".to_string();
+        let variants = handle_stop_event(
+            FinishReason::ToolCalls,
+            None,
+            &mut tool_arguments,
+            &mut tool_name,
+            &mut tool_id,
+            &mut Vec::new(),
+            &generate_id(),
+            &"testuser".to_string(),
+            database,
+            &mut empty_stream.fuse(),
+            &response,
+            DEFAULTCHATBOT.clone(),
+            &mut None,
+            CodeVerbosity::Concise,
+            RequestParams::default(),
+        )
+        .await;
+
+        // There is no active conversation, so the stream can't be restarted; the next turn still starts clean.
+        assert!(matches!(variants[0], StreamVariant::ServerError(_)));
+        assert_eq!(tool_name, None);
+        assert!(tool_arguments.is_empty());
+        assert!(tool_id.is_empty());
+    }
+
+    #[test]
+    fn test_thread_id_hint_is_sent_and_stored_once() {
+        // The past variants of an edited thread contain the hint of the old thread.
+        let past_variants = vec![
+            thread_id_hint("old_thread"),
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::ServerHint("{\"memory\": 1024}".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+        ];
+        let (stored, starting_variants) = edit_variants(past_variants, "new_thread");
+
+        // The stored part has no hint, so stream_response stores exactly one, for the new thread.
+        assert!(!stored.iter().any(is_thread_id_hint));
+        // The heartbeat is kept.
+        assert_eq!(stored.len(), 3);
+
+        let sent_hints = starting_variants
+            .iter()
+            .filter(|variant| is_thread_id_hint(variant))
+            .collect::<Vec<_>>();
+        assert_eq!(sent_hints, vec![&thread_id_hint("new_thread")]);
+        assert_eq!(
+            starting_variants.last(),
+            Some(&thread_id_hint("new_thread"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_sse_stream_separates_every_event() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::ACCEPT,
+            actix_web::http::header::HeaderValue::from_static("text/event-stream"),
+        );
+        let framing = StreamFraming::from_request(&QString::default(), &headers);
+        assert_eq!(framing, StreamFraming::Sse);
+
+        let variants = vec![
+            thread_id_hint("abc"),
+            StreamVariant::Assistant("Hello\n".to_string()),
+            StreamVariant::Assistant("world".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        // All variants arrive in the same chunk, which is where the raw stream can't be split without parsing it.
+        let chunk: Bytes = variants
+            .iter()
+            .flat_map(|variant| variant_to_bytes(variant, framing))
+            .collect::<Vec<u8>>()
+            .into();
+        let response = framing
+            .response()
+            .streaming(stream::iter([Ok::<Bytes, std::convert::Infallible>(chunk)]));
+        assert_eq!(
+            response
+                .headers()
+                .get(actix_web::http::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+            Some("text/event-stream")
+        );
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .expect("Infallible");
+        let body = String::from_utf8(body.to_vec()).expect("The stream is UTF-8");
+        let events = body
+            .strip_suffix("\n\n")
+            .expect("The last event is terminated")
+            .split("\n\n")
+            .map(|event| {
+                let data = event
+                    .strip_prefix("data: ")
+                    .expect("Every event is a data line");
+                assert!(!data.contains('\n'));
+                serde_json::from_str::<StreamVariant>(data).expect("Every event is a variant")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, variants);
+
+        // Without the header, the stream stays raw.
+        assert_eq!(
+            StreamFraming::from_request(&QString::default(), &HeaderMap::new()),
+            StreamFraming::Raw
+        );
+    }
+
+    #[test]
+    fn test_jsonl_stream_splits_on_newlines() {
+        let framing =
+            StreamFraming::from_request(&QString::from("framing=jsonl"), &HeaderMap::new());
+        assert_eq!(framing, StreamFraming::Jsonl);
+
+        let variants = vec![
+            thread_id_hint("abc"),
+            StreamVariant::Code(
+                "{\"code\": \"import xarray as xr\\nprint(1)\"}".to_string(),
+                "call_1".to_string(),
+            ),
+            StreamVariant::CodeOutput("1\n".to_string(), "call_1".to_string()),
+            StreamVariant::Assistant("Multi\nline\r\nanswer".to_string()),
+        ];
+        let mut wire = variants
+            .iter()
+            .flat_map(|variant| variant_to_bytes(variant, framing))
+            .collect::<Vec<u8>>();
+        wire.extend_from_slice(&framing.frame(STREAM_STOP_CONTENT.clone()));
+        let wire = String::from_utf8(wire).expect("The stream is UTF-8");
+
+        let lines = wire
+            .strip_suffix('\n')
+            .expect("The last variant is terminated")
+            .split('\n')
+            .map(|line| {
+                serde_json::from_str::<StreamVariant>(line).expect("Every line is a variant")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                variants,
+                vec![StreamVariant::StreamEnd("Conversation aborted".to_string())]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_heartbeats_are_sent_but_not_stored() {
+        let thread_id = generate_id();
+        let user_input = StreamVariant::User("run the long computation".to_string());
+        add_to_conversation(
+            &thread_id,
+            vec![user_input.clone()],
+            String::new(),
+            "testuser".to_string(),
+        );
+
+        let heartbeat = StreamVariant::ServerHint("{\"heartbeat\":true}".to_string());
+        let sent = emit_heartbeat(
+            &thread_id,
+            &heartbeat,
+            String::new(),
+            "testuser".to_string(),
+            StreamFraming::Raw,
+        );
+        assert_eq!(sent, variant_to_bytes(&heartbeat, StreamFraming::Raw));
+
+        // The first heartbeat of a tool call is added together with the tool call, and older detailed ones don't have the heartbeat field.
+        let output = StreamVariant::CodeOutput("done".to_string(), "call_1".to_string());
+        add_to_conversation(
+            &thread_id,
+            vec![
+                heartbeat,
+                StreamVariant::ServerHint("{\"memory\":1024,\"process_memory\":512}".to_string()),
+                output.clone(),
+            ],
+            String::new(),
+            "testuser".to_string(),
+        );
+        assert_eq!(get_conversation(&thread_id), Some(vec![user_input, output]));
+        end_conversation(&thread_id);
+    }
+
+    #[test]
+    fn test_resume_replays_the_missed_variants_and_ends_the_turn() {
+        let thread_id = generate_id();
+        let user_input = StreamVariant::User("plot a circle".to_string());
+        let answer = StreamVariant::Assistant("Sure".to_string());
+        add_to_conversation(
+            &thread_id,
+            vec![user_input.clone(), answer.clone()],
+            String::new(),
+            "testuser".to_string(),
+        );
+        assert!(mark_disconnected(&thread_id));
+
+        // The client only received the user input; it gets the answer, but the turn isn't continued after it.
+        let stream_end = StreamVariant::StreamEnd("Stream resumed after a disconnect".to_string());
+        assert_eq!(
+            replay_missed_variants(&thread_id, "testuser", 1),
+            Some(vec![answer.clone(), stream_end.clone()])
+        );
+        assert_eq!(
+            get_conversation(&thread_id),
+            Some(vec![user_input, answer, stream_end])
+        );
+        end_conversation(&thread_id);
+    }
+
+    #[test]
+    fn test_completed_turn_reports_its_usage() {
+        let chatbot = AvailableChatbots("gpt-4.1".to_string());
+        let usage = TokenUsage {
+            prompt_tokens: 1200,
+            completion_tokens: 300,
+            total_tokens: 1500,
+        };
+        let turn = vec![
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+        ];
+
+        let variants = end_of_turn_variants(&turn, Some(usage), &chatbot, false);
+        let [StreamVariant::Usage(content)] = variants.as_slice() else {
+            panic!("Expected only the usage, got {variants:?}");
+        };
+        let reported: serde_json::Value =
+            serde_json::from_str(content).expect("The usage should be valid JSON");
+        assert_eq!(reported["total_tokens"], 1500);
+        assert_eq!(reported["prompt_tokens"], 1200);
+        assert_eq!(reported["completion_tokens"], 300);
+        assert_eq!(reported["model"], "gpt-4.1");
+
+        // It's stored like every other variant, but the LLM doesn't see it.
+        let stored = serde_json::to_string(&variants[0]).expect("The usage can be serialized");
+        assert_eq!(
+            serde_json::from_str::<StreamVariant>(&stored).ok().as_ref(),
+            Some(&variants[0])
+        );
+        assert!(help_convert_sv_ccrm(variants, false).is_empty());
+
+        // The summary comes first, and without a reported usage there is nothing to send.
+        let variants = end_of_turn_variants(&turn, Some(usage), &chatbot, true);
+        assert!(matches!(
+            variants.as_slice(),
+            [StreamVariant::Summary(_), StreamVariant::Usage(_)]
+        ));
+        assert!(end_of_turn_variants(&turn, None, &chatbot, false).is_empty());
+    }
 }
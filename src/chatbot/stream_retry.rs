@@ -0,0 +1,222 @@
+// Retries opening the stream of the LLM if it fails for a reason that's likely to go away by itself, like a rate limit or a restarting provider.
+
+use std::{future::Future, time::Duration};
+
+use async_openai::{error::OpenAIError, types::ChatCompletionResponseStream};
+use futures::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tracing::{debug, info, warn};
+
+/// How often and how patiently opening a stream is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a failed attempt is retried; 0 disables retrying.
+    pub retries: u32,
+    /// The delay before the first retry; it doubles with every further retry.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry (starting at 0): exponential backoff with up to 50% random jitter,
+    /// so the streams that were rate limited together don't all come back at the same moment.
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2_u32.saturating_pow(retry));
+        let jitter = rand::rng().random_range(0.0..=0.5);
+        backoff.mul_f64(1.0 + jitter)
+    }
+}
+
+/// The retry policy for the streams of LiteLLM.
+/// Configured via the environment variables `LLM_STREAM_RETRIES` (default 3) and `LLM_STREAM_RETRY_BASE_MS` (default 500).
+pub static LLM_STREAM_RETRY_POLICY: Lazy<RetryPolicy> = Lazy::new(|| RetryPolicy {
+    retries: std::env::var("LLM_STREAM_RETRIES")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(3),
+    base_delay: Duration::from_millis(
+        std::env::var("LLM_STREAM_RETRY_BASE_MS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(500),
+    ),
+});
+
+/// Whether the error is likely temporary: a rate limit (429), an error of the server (5xx) or a failed connection.
+/// Other client errors, like a bad request, fail the same way every time, so they aren't retried.
+pub fn is_retryable_error(error: &OpenAIError) -> bool {
+    let is_retryable_status = |status: u16| status == 429 || (500..600).contains(&status);
+    match error {
+        OpenAIError::Reqwest(_) => true,
+        // The stream reports the status of the response as "Invalid status code: 429 Too Many Requests".
+        OpenAIError::StreamError(message) => match message.strip_prefix("Invalid status code: ") {
+            Some(status) => status
+                .split_whitespace()
+                .next()
+                .and_then(|status| status.parse().ok())
+                .is_some_and(is_retryable_status),
+            None => message.contains("error sending request"),
+        },
+        // LiteLLM passes on the status of the provider as the code of its error objects.
+        OpenAIError::ApiError(api_error) => api_error
+            .code
+            .as_deref()
+            .and_then(|code| code.parse().ok())
+            .is_some_and(is_retryable_status),
+        _ => false,
+    }
+}
+
+/// Opens the stream for the request, retrying with backoff as long as it fails with a retryable error.
+/// The error usually only arrives as the first item of the stream, when the request is actually sent,
+/// so the stream is given on lazily and the retries happen when it's first polled.
+/// Once the retries are used up, the last error is passed on like it came from the LLM.
+/// An error that isn't retryable on the first attempt, like the open circuit breaker, is returned right away.
+pub async fn create_stream_with_retries<F, Fut>(
+    request: async_openai::types::CreateChatCompletionRequest,
+    policy: RetryPolicy,
+    create_stream: F,
+) -> Result<ChatCompletionResponseStream, OpenAIError>
+where
+    F: Fn(async_openai::types::CreateChatCompletionRequest) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<ChatCompletionResponseStream, OpenAIError>> + Send,
+{
+    if policy.retries == 0 {
+        return create_stream(request).await;
+    }
+    let first_attempt = create_stream(request.clone()).await;
+    if first_attempt
+        .as_ref()
+        .is_err_and(|e| !is_retryable_error(e))
+    {
+        return first_attempt;
+    }
+    let retried = stream::once(async move {
+        let mut attempt = first_attempt;
+        let mut retry = 0;
+        loop {
+            let error = match attempt {
+                Ok(mut stream) => match stream.next().await {
+                    Some(Err(e)) if is_retryable_error(&e) => e,
+                    first => {
+                        return Box::pin(stream::iter(first).chain(stream))
+                            as ChatCompletionResponseStream
+                    }
+                },
+                Err(e) if is_retryable_error(&e) => e,
+                Err(e) => return Box::pin(stream::iter([Err(e)])),
+            };
+            if retry >= policy.retries {
+                warn!(
+                    "Opening the stream failed {} times, giving up: {:?}",
+                    retry + 1,
+                    error
+                );
+                return Box::pin(stream::iter([Err(error)]));
+            }
+            let delay = policy.delay(retry);
+            info!(
+                "Opening the stream failed with a retryable error, retrying in {:?}.",
+                delay
+            );
+            debug!("The error was: {:?}", error);
+            tokio::time::sleep(delay).await;
+            retry += 1;
+            attempt = create_stream(request.clone()).await;
+        }
+    })
+    .flatten();
+    Ok(Box::pin(retried))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionStreamResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_stream_is_retried_until_it_succeeds() {
+        let policy = RetryPolicy {
+            retries: 3,
+            base_delay: Duration::from_millis(1),
+        };
+        // The mock client is rate limited twice, first when connecting and then in the first item, like LiteLLM reports it.
+        let mock_client = |attempts: Arc<AtomicU32>, failure: &'static str| {
+            move |_: CreateChatCompletionRequest| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let chunk = serde_json::from_value::<CreateChatCompletionStreamResponse>(
+                        serde_json::json!({"id": "1", "choices": [], "created": 0, "model": "test", "object": "chat.completion.chunk"}),
+                    )
+                    .expect("The chunk is valid");
+                    match attempt {
+                        0 => Err(OpenAIError::StreamError(failure.to_string())),
+                        1 => Ok(Box::pin(stream::iter([Err(OpenAIError::StreamError(
+                            failure.to_string(),
+                        ))]))
+                            as ChatCompletionResponseStream),
+                        _ => {
+                            Ok(Box::pin(stream::iter([Ok(chunk)])) as ChatCompletionResponseStream)
+                        }
+                    }
+                }
+            }
+        };
+
+        let attempts = Arc::new(AtomicU32::new(0));
+        let items = create_stream_with_retries(
+            CreateChatCompletionRequest::default(),
+            policy,
+            mock_client(
+                Arc::clone(&attempts),
+                "Invalid status code: 429 Too Many Requests",
+            ),
+        )
+        .await
+        .expect("The stream is created lazily")
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(matches!(&items[..], [Ok(_)]));
+
+        // A bad request fails the same way every time, so it's returned right away.
+        let attempts = Arc::new(AtomicU32::new(0));
+        let result = create_stream_with_retries(
+            CreateChatCompletionRequest::default(),
+            policy,
+            mock_client(
+                Arc::clone(&attempts),
+                "Invalid status code: 400 Bad Request",
+            ),
+        )
+        .await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert!(matches!(result, Err(e) if !is_retryable_error(&e)));
+
+        // Once the retries are used up, the error is passed on.
+        let attempts = Arc::new(AtomicU32::new(0));
+        let items = create_stream_with_retries(
+            CreateChatCompletionRequest::default(),
+            RetryPolicy {
+                retries: 1,
+                ..policy
+            },
+            mock_client(
+                Arc::clone(&attempts),
+                "Invalid status code: 503 Service Unavailable",
+            ),
+        )
+        .await
+        .expect("The stream is created lazily")
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(matches!(&items[..], [Err(e)] if is_retryable_error(e)));
+    }
+}
@@ -0,0 +1,109 @@
+// A machine-readable summary of a turn, which clients can ask for at the end of the stream.
+// Screen readers can announce it instead of walking the whole answer, and clients can log it without parsing the variants themselves.
+
+use actix_web::http::header::HeaderMap;
+use qstring::QString;
+
+use crate::auth::get_first_matching_field;
+
+use super::types::{StreamVariant, TokenUsage};
+
+/// Whether the client asked for a Summary variant before the StreamEnd, with the parameter `summary=true`.
+pub fn summary_requested(qstring: &QString, headers: &HeaderMap) -> bool {
+    get_first_matching_field(qstring, headers, &["summary", "x-summary"], false)
+        .is_some_and(|value| value.trim() == "true")
+}
+
+/// Summarizes the turn that ends with the given variants: everything after the last input of the user.
+/// Consecutive Assistant variants are the deltas of a single message, so they count once.
+pub fn turn_summary(conversation: &[StreamVariant], usage: Option<TokenUsage>) -> StreamVariant {
+    let turn_start = conversation
+        .iter()
+        .rposition(|variant| matches!(variant, StreamVariant::User(_)))
+        .map_or(0, |index| index + 1);
+
+    let (mut assistant_messages, mut code_executions, mut images, mut errors) = (0, 0, 0, 0);
+    let mut in_assistant_message = false;
+    for variant in &conversation[turn_start..] {
+        match variant {
+            // Empty deltas neither start nor interrupt a message.
+            StreamVariant::Assistant(content) if content.is_empty() => continue,
+            StreamVariant::Assistant(_) => {
+                if !in_assistant_message {
+                    assistant_messages += 1;
+                }
+                in_assistant_message = true;
+                continue;
+            }
+            StreamVariant::CodeOutput(_, _) => code_executions += 1,
+            StreamVariant::Image(_) => images += 1,
+            StreamVariant::ServerError(_)
+            | StreamVariant::OpenAIError(_)
+            | StreamVariant::CodeError(_) => errors += 1,
+            _ => {}
+        }
+        in_assistant_message = false;
+    }
+
+    StreamVariant::Summary(
+        serde_json::json!({
+            "assistant_messages": assistant_messages,
+            "code_executions": code_executions,
+            "images": images,
+            "errors": errors,
+            "total_tokens": usage.map(|usage| usage.total_tokens),
+        })
+        .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_counts_the_variants_of_the_turn() {
+        let conversation = vec![
+            // The earlier turn isn't part of the summary.
+            StreamVariant::User("hi".to_string()),
+            StreamVariant::Assistant("Hello!".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+            StreamVariant::User("plot the temperature".to_string()),
+            StreamVariant::Assistant("Sure".to_string()),
+            StreamVariant::Assistant(", let me plot it.".to_string()),
+            StreamVariant::Code("plt.plot(t)".to_string(), "call_1".to_string()),
+            StreamVariant::ServerHint("{\"heartbeat\": true}".to_string()),
+            StreamVariant::CodeOutput(String::new(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::Assistant(String::new()),
+            StreamVariant::Assistant("Here is the plot.".to_string()),
+        ];
+        let usage = TokenUsage {
+            prompt_tokens: 4000,
+            completion_tokens: 120,
+            total_tokens: 4120,
+        };
+
+        let StreamVariant::Summary(summary) = turn_summary(&conversation, Some(usage)) else {
+            panic!("The summary is a Summary variant");
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&summary).expect("The summary is JSON"),
+            serde_json::json!({
+                "assistant_messages": 2,
+                "code_executions": 1,
+                "images": 1,
+                "errors": 0,
+                "total_tokens": 4120,
+            })
+        );
+
+        // The LLM never sees the summary.
+        let converted: Result<Vec<async_openai::types::ChatCompletionRequestMessage>, _> =
+            StreamVariant::Summary(summary).try_into();
+        assert!(matches!(
+            converted,
+            Err(crate::chatbot::types::ConversionError::VariantHide(_))
+        ));
+    }
+}
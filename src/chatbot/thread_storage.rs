@@ -2,29 +2,159 @@
 // In the OpenAI V2, they're called threads, so that's what we'll call them here too.
 // Due to us using V1, OpenAI doesn't store the conversations (for us), so we need to do that ourselves.
 // They will all be stored at `./threads/THEADID.txt`, where the ThreadID is the ID of the conversation.
+// New files are gzip-compressed and stored at `./threads/THEADID.txt.gz` instead, unless COMPRESS_THREADS is false.
+// Existing files keep the format they were started in, so the uncompressed files of older threads are still read and appended to.
 // Reading and writing is just manipulating files, so we can use the `std::fs` module.
 // Note that the file of a conversation is opened at the start of the stream, so it cannot be read from while it is being written to.
 
 // The File will store the conversation in the JSON lines format, where each line is a JSON object,
 // specifying the variant, as serialized by serde_json.
+// The first line of a file is a comment with the user_id of the owner, so the ownership is known like in the MongoDB.
+// Older files don't have it; their owner is unknown.
 
 use std::{
     fs::{File, OpenOptions},
-    io::{Error, Read, Write},
+    io::{Error, ErrorKind, Read, Write},
+    path::Path,
 };
 
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use once_cell::sync::Lazy;
+use strum::VariantNames;
 use tracing::{debug, error, info, trace, warn};
 
 use crate::chatbot::types::unescape_string;
 
 use super::types::{Conversation, StreamVariant};
 
+/// The start of the comment line that records the owner of a thread.
+const USER_ID_PREFIX: &str = "// user_id: ";
+
+/// The version of the format threads are stored in, on disk and in the MongoDB.
+/// Threads that were stored before the version was recorded are version 0; they are migrated when they are read.
+/// - 0: Unversioned. On disk, a line is either a JSON-encoded variant or in the old `Variant:content` encoding.
+/// - 1: Every line is a JSON-encoded variant.
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+/// The start of the comment line that records the schema version of a thread file.
+const SCHEMA_VERSION_PREFIX: &str = "// schema_version: ";
+
+/// Whether new thread files are written gzip-compressed.
+/// Set via the environment variable `COMPRESS_THREADS`; defaults to true, only "false" disables it.
+pub static COMPRESS_THREADS: Lazy<bool> = Lazy::new(|| {
+    !std::env::var("COMPRESS_THREADS").is_ok_and(|value| value.trim().eq_ignore_ascii_case("false"))
+});
+
+/// The first two bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The path of a thread file, where `name` is the thread_id, or `{thread_id}.raw` for the raw copy.
+/// An existing file keeps its format; a new one is compressed if `compress` is set.
+fn thread_file_path(name: &str, compress: bool) -> String {
+    let compressed = format!("./threads/{name}.txt.gz");
+    let plain = format!("./threads/{name}.txt");
+    if Path::new(&compressed).exists() {
+        compressed
+    } else if !compress || Path::new(&plain).exists() {
+        plain
+    } else {
+        compressed
+    }
+}
+
+/// Whether a file of the thread is stored on disk, compressed or not.
+pub fn thread_file_exists(thread_id: &str) -> bool {
+    Path::new(&format!("./threads/{thread_id}.txt.gz")).exists()
+        || Path::new(&format!("./threads/{thread_id}.txt")).exists()
+}
+
 /// Appends events from a stream of a conversation to the file of the conversation.
-pub fn append_thread(thread_id: &str, content: Conversation) {
+/// If the file is new, the user_id is written into it first.
+pub fn append_thread(thread_id: &str, user_id: &str, content: Conversation) {
     trace!("Will append content to thread: {:?} (to clean up)", content);
     let mut content = content;
     cleanup_conversation(&mut content);
     trace!("Appending content to thread: {:?}", content);
+    append_to_file(
+        &thread_file_path(thread_id, *COMPRESS_THREADS),
+        user_id,
+        content,
+    );
+}
+
+/// Cuts the thread file to its first `keep` variants, by writing them to a new file.
+/// The variants are read the same way as for the client, so `keep` refers to the same positions.
+pub fn truncate_thread(thread_id: &str, user_id: &str, keep: usize) {
+    let path = thread_file_path(thread_id, *COMPRESS_THREADS);
+    let (mut content, owner) = match read_thread_and_owner(thread_id) {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("Error reading thread {} to truncate it: {:?}", thread_id, e);
+            return;
+        }
+    };
+    content.truncate(keep);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!(
+            "Error removing thread file {} to truncate it: {:?}",
+            path, e
+        );
+        return;
+    }
+    // The rewritten file is compressed like a new one.
+    append_to_file(
+        &thread_file_path(thread_id, *COMPRESS_THREADS),
+        owner.as_deref().unwrap_or(user_id),
+        content,
+    );
+}
+
+/// Appends the events of a conversation to a second file of the thread, exactly as they were streamed.
+/// Unlike the main file, the content isn't cleaned up, so bugs in the streaming can be analyzed later.
+pub fn append_raw_thread(thread_id: &str, user_id: &str, content: Conversation) {
+    trace!("Appending raw content to thread: {:?}", content);
+    append_to_file(
+        &thread_file_path(&format!("{thread_id}.raw"), *COMPRESS_THREADS),
+        user_id,
+        content,
+    );
+}
+
+/// Removes the files of a thread on disk: the thread, its raw copy, compressed or not, and the pickle file of the code interpreter.
+/// Returns how many of them existed.
+/// # Errors
+/// Returns an InvalidInput error if the thread_id isn't one that could have been generated, so it can't point outside of the directories,
+/// and the IO Errors that occured while removing the files.
+pub fn delete_thread_files(thread_id: &str) -> Result<usize, Error> {
+    if thread_id.is_empty() || !thread_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Thread IDs only contain letters and digits",
+        ));
+    }
+    let mut removed = 0;
+    for path in [
+        format!("./threads/{thread_id}.txt"),
+        format!("./threads/{thread_id}.txt.gz"),
+        format!("./threads/{thread_id}.raw.txt"),
+        format!("./threads/{thread_id}.raw.txt.gz"),
+        format!("python_pickles/{thread_id}.pickle"),
+    ] {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                debug!("Removed {}", path);
+                removed += 1;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Writes the variants to the end of the file, in the JSON lines format.
+/// Files ending in `.gz` are written gzip-compressed.
+fn append_to_file(path: &str, user_id: &str, content: Conversation) {
     // First we have to convert the content to a string.
     if content.is_empty() {
         // weird, but we can just return here
@@ -55,15 +185,34 @@ pub fn append_thread(thread_id: &str, content: Conversation) {
     trace!("Writing to file: {}", to_write);
 
     // Open File and write to it
-    let Some(mut file) = open_thread(thread_id) else {
+    let Some(mut file) = open_thread(path) else {
         // If we can't open the file, we'll just print the error and continue.
         // This is not a critical error, as the conversation is still running, but is bad because it means something is wrong with the filesystem.
         warn!("Error opening conversation file, not writing to file.");
         return;
     };
 
+    // A new file starts with the owner of the thread and the schema version. Comments are skipped when reading the variants.
+    if file.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+        to_write.insert_str(
+            0,
+            &format!(
+                "{USER_ID_PREFIX}{user_id}\n{SCHEMA_VERSION_PREFIX}{CURRENT_SCHEMA_VERSION}\n"
+            ),
+        );
+    }
+
     // Then we write it to the file.
-    match file.write_all(to_write.as_bytes()) {
+    let result = if path.ends_with(".gz") {
+        // Every append is a gzip member of its own; the members of a file are decompressed as one stream.
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder
+            .write_all(to_write.as_bytes())
+            .and_then(|()| encoder.finish().map(|_| ()))
+    } else {
+        file.write_all(to_write.as_bytes())
+    };
+    match result {
         Ok(()) => trace!("Successfully wrote to file."),
         Err(e) => {
             // If we can't write to the file, we'll just print the error and continue.
@@ -73,15 +222,14 @@ pub fn append_thread(thread_id: &str, content: Conversation) {
     }
 }
 
-/// Opens a file for a conversation and returns a file handle.
-fn open_thread(thread_id: &str) -> Option<File> {
-    trace!("Opening thread with id: {}", thread_id);
+/// Opens the file of a conversation for appending and returns a file handle.
+fn open_thread(path: &str) -> Option<File> {
+    trace!("Opening thread file: {}", path);
     // We'll try to open the file for the conversation.
     match OpenOptions::new()
-        .write(true) // Write, don't only read
         .append(true) // Append, don't overwrite
         .create(true) // Create if it doesn't exist
-        .open(format!("./threads/{thread_id}.txt"))
+        .open(path)
     {
         Ok(file) => {
             trace!("Successfully opened file for conversation.");
@@ -100,35 +248,27 @@ fn open_thread(thread_id: &str) -> Option<File> {
 /// Returns the Read content as a Vec of `StreamVariants` or the IO Error that occured.
 /// # Errors
 /// Returns the IO Errors that occured while reading the file.
+#[cfg(test)] // The storage router always reads the owner as well, only the tests need just the content.
 pub fn read_thread(thread_id: &str) -> Result<Conversation, Error> {
+    read_thread_and_owner(thread_id).map(|(content, _)| content)
+}
+
+/// Reads a file for a conversation and returns the content as well as the user_id of its owner.
+/// The owner is None for files that were written before the owner was recorded.
+/// # Errors
+/// Returns the IO Errors that occured while reading the file.
+pub fn read_thread_and_owner(thread_id: &str) -> Result<(Conversation, Option<String>), Error> {
     trace!("Reading thread with id: {}", thread_id);
 
-    let content = match OpenOptions::new()
-        .read(true)
-        .open(format!("./threads/{thread_id}.txt"))
-    {
-        Ok(mut file) => {
-            // we can open the file
-            let mut content = String::new();
-            match file.read_to_string(&mut content) {
-                Ok(_) => {
-                    trace!("Successfully read file for conversation.");
-                }
-                Err(e) => {
-                    // If we can't read the file, we'll have to error out.
-                    error!(
-                        "Error reading conversation file, sending error to client: {:?}",
-                        e
-                    );
-                    return Err(e);
-                }
-            }
+    let content = match read_thread_file(&thread_file_path(thread_id, *COMPRESS_THREADS)) {
+        Ok(content) => {
+            trace!("Successfully read file for conversation.");
             content
         }
         Err(e) => {
-            // If we can't open the file, we'll have to error out again, as the client expects the conversation to be there.
+            // If we can't read the file, we'll have to error out, as the client expects the conversation to be there.
             error!(
-                "Error opening conversation file, sending error to client: {:?}",
+                "Error reading conversation file, sending error to client: {:?}",
                 e
             );
             return Err(e);
@@ -137,18 +277,84 @@ pub fn read_thread(thread_id: &str) -> Result<Conversation, Error> {
 
     trace!("Successfully read from File, content: {}", content);
 
-    // We now need to "split" at the first colon, so we can get the variant and the content.
-    let res = extract_variants_from_string(&content);
+    let res = parse_thread_file(&content);
 
     trace!("Returning number of lines: {}", res.len());
 
-    Ok(res)
+    let owner = content
+        .lines()
+        .find_map(|line| line.strip_prefix(USER_ID_PREFIX))
+        .map(|user_id| user_id.trim().to_string());
+    if owner.is_none() {
+        debug!("Thread {} doesn't record its owner.", thread_id);
+    }
+
+    Ok((res, owner))
+}
+
+/// Reads a thread file, decompressing it if it's gzip-compressed.
+/// The compression is detected from the content, not the name of the file.
+/// # Errors
+/// Returns the IO Errors that occured while reading the file, and an InvalidData error if it isn't valid UTF-8.
+fn read_thread_file(path: &str) -> Result<String, Error> {
+    let bytes = std::fs::read(path)?;
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e));
+    }
+    let mut content = Vec::new();
+    if let Err(e) = MultiGzDecoder::new(bytes.as_slice()).read_to_end(&mut content) {
+        // An append that was cut off, like by a crash, only loses its own member; the earlier ones were read already.
+        warn!(
+            "The compressed thread file {} is damaged, reading what could be decompressed: {:?}",
+            path, e
+        );
+    }
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// Parses the content of a thread file according to the schema version it records, migrating it to the current one.
+/// Files without a version are version 0.
+pub fn parse_thread_file(content: &str) -> Conversation {
+    let version = content
+        .lines()
+        .find_map(|line| line.strip_prefix(SCHEMA_VERSION_PREFIX))
+        .and_then(|version| version.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    trace!("Thread file has schema version {}", version);
+    match version {
+        // Version 0 has to guess the encoding of every line.
+        0 => extract_variants_from_string(content),
+        1 => parse_json_lines(content),
+        newer => {
+            warn!(
+                "Thread file has schema version {}, which is newer than {}; reading it as the current version.",
+                newer, CURRENT_SCHEMA_VERSION
+            );
+            parse_json_lines(content)
+        }
+    }
+}
+
+/// Parses a thread file where every line is a JSON-encoded variant, skipping comments and lines that can't be parsed.
+fn parse_json_lines(content: &str) -> Conversation {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with("//"))
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(variant) => Some(variant),
+            Err(e) => {
+                warn!("Error deserializing line, skipping it: {:?}", e);
+                None
+            }
+        })
+        .collect()
 }
 
+/// Parses a thread file of version 0, where each line is either JSON or in the old `Variant:content` encoding.
 pub fn extract_variants_from_string(content: &str) -> Vec<StreamVariant> {
     let lines = content.lines();
     let mut res = Vec::new();
-    for line in lines {
+    for (index, line) in lines.enumerate() {
         // For organisational purposes, some lines might be comments (or empty), so we need to skip those.
         if line.trim().is_empty() || line.starts_with("//") {
             trace!("Skipping empty or comment line: {}", line);
@@ -168,8 +374,8 @@ pub fn extract_variants_from_string(content: &str) -> Vec<StreamVariant> {
             }
         }
 
-        let line = line.trim_matches('\"'); // Remove any quotes that might be there.
-        let parts = line.split_once(':');
+        // Only lines that clearly use the old encoding are split; anything else would be mis-parsed.
+        let parts = split_legacy_line(line);
         trace!("Parts: {:?}", parts);
         if let Some(parts) = parts {
             let to_append = match parts {
@@ -198,7 +404,7 @@ pub fn extract_variants_from_string(content: &str) -> Vec<StreamVariant> {
                 ("CodeError", s) => StreamVariant::CodeError(unescape_string(s)),
                 ("StreamEnd", s) => StreamVariant::StreamEnd(unescape_string(s)),
                 ("ServerHint", s) => StreamVariant::ServerHint(unescape_string(s)),
-                // If we do find a line that doesn't match any of the above, we can skip it.
+                // split_legacy_line only lets known variants through, but a new variant might be missing above.
                 (variant, s) => {
                     warn!(
                         "Unknown variant in conversation file: {}, skipping.",
@@ -210,12 +416,31 @@ pub fn extract_variants_from_string(content: &str) -> Vec<StreamVariant> {
             };
             res.push(to_append);
         } else {
-            warn!("Error splitting line during parsing, is there no colon? Skipping.");
+            // Likely a broken JSON line or a partially migrated file; guessing would silently corrupt the thread.
+            warn!(
+                "Line {} is neither JSON nor in the old encoding, skipping it.",
+                index + 1
+            );
+            debug!("The content of the ambiguous line was: {}", line);
         }
     }
     res
 }
 
+/// Splits a line of the old `Variant:content` encoding into the name of the variant and its content.
+/// Returns None unless the line starts with a known variant name followed by a colon; the content may contain further colons.
+fn split_legacy_line(line: &str) -> Option<(&str, &str)> {
+    // The old encoding wrote the lines with Debug formatting, so they are surrounded by exactly one pair of quotes.
+    let line = line
+        .strip_prefix('"')
+        .and_then(|line| line.strip_suffix('"'))
+        .unwrap_or(line);
+    let (variant, content) = line.split_once(':')?;
+    StreamVariant::VARIANTS
+        .contains(&variant)
+        .then_some((variant, content))
+}
+
 /// Some variants like Code and CodeOutput have more than one field, so this function splits the content at the last colon.
 fn split_colon_at_end(s: &str) -> Option<(&str, &str)> {
     let (first, last) = s.rsplit_once(':')?;
@@ -265,3 +490,137 @@ pub fn cleanup_conversation(content: &mut Conversation) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_id_survives_round_trip() {
+        let thread_id = crate::chatbot::handle_active_conversations::generate_id();
+        let content = vec![
+            StreamVariant::User("plot a circle".to_string()),
+            StreamVariant::Assistant("Here is your circle.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        append_thread(&thread_id, "testuser", content.clone());
+        // A second turn doesn't write the owner again.
+        append_thread(&thread_id, "testuser", content.clone());
+
+        let result = read_thread_and_owner(&thread_id);
+        assert_eq!(
+            delete_thread_files(&thread_id).expect("The thread file can be removed"),
+            1
+        );
+        let (read_content, owner) = result.expect("The thread should be readable");
+        assert_eq!(owner.as_deref(), Some("testuser"));
+        assert_eq!(read_content, [content.clone(), content].concat());
+
+        // Older files without the owner are still read.
+        assert_eq!(
+            extract_variants_from_string("{\"variant\":\"User\",\"content\":\"hi\"}"),
+            vec![StreamVariant::User("hi".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_raw_copy_is_stored_next_to_cleaned_thread() {
+        let thread_id = crate::chatbot::handle_active_conversations::generate_id();
+        // A conversation that was cut off during the code execution; the cleanup closes the stream.
+        let raw = vec![
+            StreamVariant::User("hi".to_string()),
+            StreamVariant::Code("print(1)".to_string(), "call_1".to_string()),
+        ];
+        append_thread(&thread_id, "testuser", raw.clone());
+        append_raw_thread(&thread_id, "testuser", raw.clone());
+
+        let cleaned = read_thread(&thread_id);
+        let raw_file = read_thread_file(&thread_file_path(&format!("{thread_id}.raw"), false));
+        assert_eq!(
+            delete_thread_files(&thread_id).expect("The thread files can be removed"),
+            2
+        );
+
+        let cleaned = cleaned.expect("The thread should be readable");
+        assert_eq!(cleaned.len(), raw.len() + 1);
+        assert!(matches!(cleaned.last(), Some(StreamVariant::StreamEnd(_))));
+        assert_eq!(
+            extract_variants_from_string(&raw_file.expect("The raw file should be readable")),
+            raw
+        );
+    }
+
+    #[test]
+    fn test_compressed_thread_survives_round_trip() {
+        let thread_id = crate::chatbot::handle_active_conversations::generate_id();
+        let path = format!("./threads/{thread_id}.txt.gz");
+        let content = vec![
+            StreamVariant::User("plot the temperature".to_string()),
+            StreamVariant::Code("plt.plot(t)".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput(String::new(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".repeat(1000)),
+            StreamVariant::Assistant("Here is the plot.".to_string()),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+        // The second turn is appended to the existing compressed file.
+        append_to_file(&path, "testuser", content.clone());
+        append_to_file(&path, "testuser", content.clone());
+
+        let bytes = std::fs::read(&path);
+        let result = read_thread_and_owner(&thread_id);
+        delete_thread_files(&thread_id).expect("The thread file can be removed");
+
+        let bytes = bytes.expect("The compressed file should have been written");
+        assert!(bytes.starts_with(&GZIP_MAGIC));
+        // Both turns together are much smaller than a single one uncompressed.
+        assert!(bytes.len() < serde_json::to_string(&content).unwrap_or_default().len() / 10);
+        let (read_content, owner) = result.expect("The compressed thread should be readable");
+        assert_eq!(owner.as_deref(), Some("testuser"));
+        assert_eq!(read_content, [content.clone(), content.clone()].concat());
+
+        // Uncompressed files of older threads are still read and stay uncompressed.
+        let legacy_id = crate::chatbot::handle_active_conversations::generate_id();
+        append_to_file(
+            &format!("./threads/{legacy_id}.txt"),
+            "testuser",
+            content.clone(),
+        );
+        assert_eq!(
+            thread_file_path(&legacy_id, true),
+            format!("./threads/{legacy_id}.txt")
+        );
+        let legacy = read_thread(&legacy_id);
+        delete_thread_files(&legacy_id).expect("The thread file can be removed");
+        assert_eq!(
+            legacy.expect("The legacy thread should be readable"),
+            content
+        );
+    }
+
+    #[test]
+    fn test_mixed_encodings_only_split_clear_legacy_lines() {
+        let content = [
+            "// schema_version: 0",
+            "{\"variant\":\"User\",\"content\":\"When is noon?\"}",
+            "\"Assistant:Note: noon is at 12:00 UTC, see \\\"docs\\\"\"",
+            "\"Code:print(1):call_1\"",
+            "{\"variant\":\"CodeOutput\",\"content\":[\"1\",\"call_1\"]}",
+            // A JSON line that was cut off, and a line that isn't in either encoding.
+            "{\"variant\":\"Assistant\",\"content\":\"Time: 12",
+            "Note: this line has no known variant",
+            "\"StreamEnd:Generation complete\"",
+        ]
+        .join("\n");
+
+        assert_eq!(
+            extract_variants_from_string(&content),
+            vec![
+                StreamVariant::User("When is noon?".to_string()),
+                StreamVariant::Assistant("Note: noon is at 12:00 UTC, see \"docs\"".to_string()),
+                StreamVariant::Code("print(1)".to_string(), "call_1".to_string()),
+                StreamVariant::CodeOutput("1".to_string(), "call_1".to_string()),
+                StreamVariant::StreamEnd("Generation complete".to_string()),
+            ]
+        );
+    }
+}
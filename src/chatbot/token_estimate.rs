@@ -0,0 +1,304 @@
+// Estimates how many tokens the next request of a thread would have, so clients can warn before the context window is exceeded.
+
+use std::{iter::Peekable, str::Chars};
+
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent,
+};
+use documented::docs_const;
+use qstring::QString;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::{database_from_request, get_first_matching_field},
+    chatbot::{
+        available_chatbots::{
+            model_context_window, model_is_claude, model_supports_images, AvailableChatbots,
+        },
+        mongodb::mongodb_storage::read_user_settings,
+        prompting::prompt_ccrm_for_chatbot,
+        storage_router::read_thread_and_owner,
+        stream_response::chatbot_from_request,
+        types::{help_convert_sv_ccrm, Conversation},
+    },
+    tool_calls::ALL_TOOLS,
+};
+
+/// What an image in the request costs, roughly what OpenAI counts for a 1024x1024 image in high detail.
+const IMAGE_TOKENS: u64 = 765;
+
+/// What every message costs besides its content, for the role and the separators around it.
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+
+/// The tokens that start the answer of the LLM.
+const REPLY_PRIMING_TOKENS: u64 = 3;
+
+/// How the tokenizer of a model family splits text, as far as it matters for an estimate.
+/// The actual vocabularies aren't available to the backend, so whole words are counted in chunks of typical token length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tokenizer {
+    /// The o200k tokenizer of the newer OpenAI models.
+    OpenAi,
+    /// Anthropic's tokenizer, which splits words into somewhat more tokens.
+    Claude,
+    /// The tokenizers of the open models like Llama, Qwen and gpt-oss.
+    Open,
+}
+
+impl Tokenizer {
+    fn for_model(model: &AvailableChatbots) -> Self {
+        if model_is_claude(model) {
+            Tokenizer::Claude
+        } else if ["gpt-", "o3", "o4"]
+            .iter()
+            .any(|prefix| model.0.starts_with(prefix))
+            && !model.0.starts_with("gpt-oss")
+        {
+            Tokenizer::OpenAi
+        } else {
+            Tokenizer::Open
+        }
+    }
+
+    /// How many letters of a word usually end up in one token.
+    fn letters_per_token(self) -> usize {
+        match self {
+            Tokenizer::OpenAi => 6,
+            Tokenizer::Claude => 4,
+            Tokenizer::Open => 5,
+        }
+    }
+
+    /// Estimates the tokens of a text.
+    /// Words are counted in chunks of letters and numbers in groups of three digits; punctuation, symbols and
+    /// characters of non-Latin scripts are a token each. A single space belongs to the following word, longer runs of whitespace are a token of their own.
+    fn count(self, text: &str) -> u64 {
+        let mut tokens = 0;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            tokens += if is_latin_letter(c) {
+                run_length(&mut chars, is_latin_letter).div_ceil(self.letters_per_token())
+            } else if c.is_ascii_digit() {
+                run_length(&mut chars, |c| c.is_ascii_digit()).div_ceil(3)
+            } else if c.is_whitespace() {
+                let length = run_length(&mut chars, char::is_whitespace);
+                usize::from(length > 1 || c == '\n')
+            } else {
+                1
+            };
+        }
+        tokens as u64
+    }
+}
+
+/// Letters of the Latin scripts, which are merged into words by all tokenizers.
+fn is_latin_letter(c: char) -> bool {
+    c.is_alphabetic() && u32::from(c) < 0x250
+}
+
+/// Consumes the characters that continue a run and returns the length of the run, including the character that started it.
+fn run_length(chars: &mut Peekable<Chars>, continues: impl Fn(char) -> bool) -> usize {
+    let mut length = 1;
+    while chars.next_if(|c| continues(*c)).is_some() {
+        length += 1;
+    }
+    length
+}
+
+/// Estimates the tokens of a request with these messages and the tools, as the model would count them.
+fn estimate_tokens(messages: &[ChatCompletionRequestMessage], model: &AvailableChatbots) -> u64 {
+    let tokenizer = Tokenizer::for_model(model);
+    // The provider puts the definitions of the tools into the prompt as well.
+    let tools = serde_json::to_string(&*ALL_TOOLS).unwrap_or_default();
+    let messages_tokens = messages
+        .iter()
+        .map(|message| {
+            let message = serde_json::to_value(message).unwrap_or_default();
+            MESSAGE_OVERHEAD_TOKENS + count_value(&message, tokenizer)
+        })
+        .sum::<u64>();
+    tokenizer.count(&tools) + messages_tokens + REPLY_PRIMING_TOKENS
+}
+
+/// Counts the tokens of all strings of a message; images, which are sent as data URLs, have a fixed cost.
+fn count_value(value: &serde_json::Value, tokenizer: Tokenizer) -> u64 {
+    match value {
+        serde_json::Value::String(text) if text.starts_with("data:image/") => IMAGE_TOKENS,
+        serde_json::Value::String(text) => tokenizer.count(text),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| count_value(value, tokenizer))
+            .sum(),
+        serde_json::Value::Object(map) => map
+            .values()
+            .map(|value| count_value(value, tokenizer))
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// The messages the streamresponse endpoint would send for the input: the stored thread, or the prompt for a new thread, followed by the input.
+fn would_be_messages(
+    thread: Option<Conversation>,
+    input: &str,
+    model: &AvailableChatbots,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut messages = match thread {
+        Some(content) => help_convert_sv_ccrm(content, model_supports_images(model.clone())),
+        None => prompt_ccrm_for_chatbot(model),
+    };
+    messages.push(ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage {
+            name: Some("user".to_string()),
+            content: ChatCompletionRequestUserMessageContent::Text(input.to_string()),
+        },
+    ));
+    messages
+}
+
+/// # Token Estimate
+/// Estimates how many tokens the next request to the LLM would have, so clients can warn the user before the input doesn't fit into the context window. Requires Authentication.
+///
+/// Takes in the `input` and optionally the `thread_id` of the thread it would continue and the `chatbot`, like the streamresponse endpoint.
+/// Without a thread_id, the input is counted as the start of a new thread, together with the prompt. The vault URL is only needed with a thread_id.
+/// If no chatbot is given, the one of the user's settings profile or the default one is used.
+///
+/// Returns a JSON object with the `model`, the `estimated_tokens` of the request and the `context_window` of the model, both in tokens.
+/// The estimate follows how the tokenizer of the model family splits text, but it isn't exact; clients should leave some room.
+///
+/// If authentication fails an Unauthorized response is returned.
+///
+/// If the input is not given, the chatbot is not available, or a thread_id is given without a vault URL, an UnprocessableEntity response is returned.
+///
+/// If the thread doesn't exist, a NotFound response is returned.
+///
+/// If the thread belongs to another user, a Forbidden response is returned.
+#[docs_const] // writes the docstring into a variable called TOKEN_ESTIMATE_DOCS
+pub async fn token_estimate(req: HttpRequest) -> impl Responder {
+    let qstring = QString::from(req.query_string());
+    let headers = req.headers();
+
+    // First try to authorize the user.
+    let user_id = crate::auth::authorize_or_fail!(qstring, headers);
+
+    let Some(input) = get_first_matching_field(&qstring, headers, &["input", "x-input"], false)
+    else {
+        warn!("The User requested a token estimate without an input.");
+        return HttpResponse::UnprocessableEntity()
+            .body("Input not found. Please provide an input in the query parameters.");
+    };
+
+    let thread_id = get_first_matching_field(
+        &qstring,
+        headers,
+        &["thread_id", "x-thread-id", "thread-id"],
+        false,
+    )
+    .filter(|thread_id| !thread_id.is_empty());
+
+    // The database is only needed for the thread, and with it the user's settings can be read too.
+    let (thread, profile_chatbot) = match thread_id {
+        None => (None, None),
+        Some(thread_id) => {
+            let database = match database_from_request(&qstring, headers).await {
+                Ok(database) => database,
+                Err(e) => return e,
+            };
+            let (content, owner) = match read_thread_and_owner(thread_id, database.clone()).await {
+                Ok(result) => result,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    info!(
+                        "The User requested a token estimate for thread {} that does not exist.",
+                        thread_id
+                    );
+                    return HttpResponse::NotFound()
+                        .body("Thread not found. Maybe it exists on another freva instance?");
+                }
+                Err(e) => {
+                    error!("Error reading thread: {:?}", e);
+                    return HttpResponse::InternalServerError().body("Error reading thread.");
+                }
+            };
+            if owner.is_some_and(|owner| owner != user_id) {
+                warn!(
+                    "User {} requested a token estimate for thread {}, which belongs to another user.",
+                    user_id, thread_id
+                );
+                return HttpResponse::Forbidden().body("This thread belongs to another user.");
+            }
+            let profile = read_user_settings(&user_id, database).await;
+            (
+                Some(content),
+                profile.and_then(|profile| profile.default_chatbot()),
+            )
+        }
+    };
+
+    let chatbot = match chatbot_from_request(&qstring, headers, profile_chatbot) {
+        Ok(chatbot) => chatbot,
+        Err(response) => return *response,
+    };
+
+    let estimated_tokens = estimate_tokens(&would_be_messages(thread, input, &chatbot), &chatbot);
+    let context_window = model_context_window(&chatbot);
+    debug!(
+        "Estimated {} of {} tokens for a request of user {} to {}.",
+        estimated_tokens, context_window, user_id, chatbot.0
+    );
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "model": chatbot.0,
+        "estimated_tokens": estimated_tokens,
+        "context_window": context_window,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::types::StreamVariant;
+
+    #[test]
+    fn test_estimate_of_known_input_is_in_range() {
+        let gpt = AvailableChatbots("gpt-4.1".to_string());
+        // The o200k tokenizer splits this sentence into 10 tokens.
+        let sentence = "The quick brown fox jumps over the lazy dog.";
+        let tokens = Tokenizer::for_model(&gpt).count(sentence);
+        assert!((8..=13).contains(&tokens), "Estimated {tokens} tokens");
+        // Claude needs more tokens for the same text.
+        let claude = Tokenizer::Claude.count(sentence);
+        assert!(claude >= tokens && claude <= 2 * tokens);
+
+        // English text and code usually have about four characters per token.
+        let code = "import xarray as xr\nds = xr.open_dataset('/work/bm1159/tas_day_2020.nc')\nprint(ds.tas.mean().values)\n";
+        let code_tokens = Tokenizer::OpenAi.count(code) as usize;
+        let chars = code.chars().count();
+        assert!(
+            (chars / 6..=chars / 2).contains(&code_tokens),
+            "Estimated {code_tokens} tokens for {chars} characters"
+        );
+
+        // Continuing a thread counts its history, and its images at a fixed cost.
+        let thread = vec![
+            StreamVariant::User("plot the temperature".to_string()),
+            StreamVariant::Code(code.to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput("290.5".to_string(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::Assistant("Here is the plot.".to_string()),
+        ];
+        let input_only = estimate_tokens(&would_be_messages(Some(vec![]), sentence, &gpt), &gpt);
+        let continued = estimate_tokens(
+            &would_be_messages(Some(thread.clone()), sentence, &gpt),
+            &gpt,
+        );
+        assert!(continued > input_only + IMAGE_TOKENS);
+        assert!(continued < input_only + IMAGE_TOKENS + 200);
+
+        // A new thread starts with the prompt, which is much longer than the input.
+        let new_thread = estimate_tokens(&would_be_messages(None, sentence, &gpt), &gpt);
+        assert!(new_thread > input_only + 500);
+        assert!(u64::from(model_context_window(&gpt)) > new_thread);
+    }
+}
@@ -0,0 +1,72 @@
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use documented::docs_const;
+use once_cell::sync::Lazy;
+use tracing::trace;
+
+use crate::tool_calls::ALL_TOOLS;
+
+/// The tools as they are described to the clients.
+/// The tools are fixed when the server starts, so the description only needs to be built once.
+static TOOL_DESCRIPTIONS: Lazy<serde_json::Value> = Lazy::new(tool_descriptions);
+
+/// Describes all tools the LLM can call by their name, description and the JSON schema of their parameters.
+fn tool_descriptions() -> serde_json::Value {
+    serde_json::Value::Array(
+        ALL_TOOLS
+            .iter()
+            .map(|tool| {
+                serde_json::json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description,
+                    "parameters": tool.function.parameters,
+                })
+            })
+            .collect(),
+    )
+}
+
+/// # Tools
+///
+/// Returns the list of tools the LLM can call as JSON. Requires Authentication.
+///
+/// Each tool has a `name`, a `description` and the JSON schema of its `parameters`, exactly as they are given to the LLM.
+/// The name is the one that appears in the tool calls of a thread, so clients can render tool-specific elements for them.
+#[docs_const]
+pub async fn tools_endpoint(req: HttpRequest) -> impl Responder {
+    let qstring = qstring::QString::from(req.query_string());
+    let headers = req.headers();
+
+    trace!("Query string: {:?}", qstring);
+
+    // First try to authorize the user.
+    let _maybe_username = crate::auth::authorize_or_fail!(qstring, headers);
+
+    HttpResponse::Ok().json(&*TOOL_DESCRIPTIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_interpreter_is_listed_with_schema() {
+        let tools = tool_descriptions();
+        let code_interpreter = tools
+            .as_array()
+            .expect("The tools are a list")
+            .iter()
+            .find(|tool| tool["name"] == "code_interpreter")
+            .expect("The code interpreter is a tool");
+        assert_eq!(
+            code_interpreter["parameters"]["properties"]["code"]["type"],
+            "string"
+        );
+        assert_eq!(
+            code_interpreter["parameters"]["required"],
+            serde_json::json!(["code"])
+        );
+        assert!(code_interpreter["description"]
+            .as_str()
+            .is_some_and(|description| description.contains("python")));
+    }
+}
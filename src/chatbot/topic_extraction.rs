@@ -2,9 +2,99 @@ use async_openai::types::{
     ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
     ChatCompletionRequestUserMessage, CreateChatCompletionRequest,
 };
-use tracing::warn;
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
 
-use crate::chatbot::LITE_LLM_CLIENT;
+use crate::chatbot::{
+    truncation::truncate_chars,
+    types::{Conversation, StreamVariant},
+    LITE_LLM_CLIENT,
+};
+
+/// How the text that gets summarized into the topic of a thread is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicStrategy {
+    /// Always use the first message of the user.
+    FirstUserMessage,
+    /// Always use the first message of the user together with the first response of the assistant.
+    FirstExchange,
+    /// Use the first message of the user, unless it's mostly code; then use the first exchange instead.
+    Adaptive,
+}
+
+/// The strategy to use for the topics of new threads.
+/// Set via the environment variable `TOPIC_STRATEGY` to "user", "exchange" or "adaptive"; defaults to adaptive.
+pub static TOPIC_STRATEGY: Lazy<TopicStrategy> =
+    Lazy::new(
+        || match std::env::var("TOPIC_STRATEGY").as_deref().map(str::trim) {
+            Ok("user") => TopicStrategy::FirstUserMessage,
+            Ok("exchange") => TopicStrategy::FirstExchange,
+            Ok("adaptive") | Err(_) => TopicStrategy::Adaptive,
+            Ok(other) => {
+                warn!("Unknown TOPIC_STRATEGY {other}, falling back to adaptive.");
+                TopicStrategy::Adaptive
+            }
+        },
+    );
+
+/// Heuristic for whether a line of a message is code rather than prose.
+fn looks_like_code(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("```")
+        || line.starts_with("import ")
+        || line.starts_with("from ")
+        || line.starts_with("def ")
+        || line.starts_with("for ")
+        || line.starts_with("if ")
+        || line.starts_with('#')
+        || line.ends_with(':')
+        || line.ends_with(')')
+        || line.ends_with(';')
+        || line.contains(" = ")
+}
+
+/// Whether more than half of the non-empty lines of the message are code.
+/// Messages like that ("run this: ...") make for bad topics, because the summary just describes the code.
+pub fn is_mostly_code(message: &str) -> bool {
+    let lines = message
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    let code_lines = lines.iter().filter(|line| looks_like_code(line)).count();
+    !lines.is_empty() && code_lines * 2 > lines.len()
+}
+
+/// Chooses the text that should be summarized into the topic of a thread, according to the strategy.
+/// Returns None if the thread doesn't contain a user message.
+pub fn topic_source(content: &Conversation, strategy: TopicStrategy) -> Option<String> {
+    let first_user_message = content.iter().find_map(|variant| match variant {
+        StreamVariant::User(input) => Some(input.clone()),
+        _ => None,
+    })?;
+
+    let use_exchange = match strategy {
+        TopicStrategy::FirstUserMessage => false,
+        TopicStrategy::FirstExchange => true,
+        TopicStrategy::Adaptive => is_mostly_code(&first_user_message),
+    };
+    if !use_exchange {
+        return Some(first_user_message);
+    }
+
+    // The assistant messages are already concatenated when the thread is stored, so the first one is the entire first response.
+    let first_assistant_message = content.iter().find_map(|variant| match variant {
+        StreamVariant::Assistant(output) => Some(output.clone()),
+        _ => None,
+    });
+    debug!(
+        "Using the first exchange for the topic, assistant response found: {}",
+        first_assistant_message.is_some()
+    );
+    Some(match first_assistant_message {
+        Some(output) => format!("User: {first_user_message}\n\nAssistant: {output}"),
+        None => first_user_message,
+    })
+}
 
 /// Given a "topic", that is, the users' first actual request of the conversation, sum it up.
 /// This will then be used as a summary for the history view on the frontend.
@@ -12,10 +102,9 @@ pub async fn summarize_topic(topic: &str) -> String {
     // We will use the GPT-4.1-mini chatbot for now.
 
     // Cut the topic short if it is too long
-    let topic = if topic.len() > 5000 {
-        format!("{}...", &topic[..5000])
-    } else {
-        topic.to_string()
+    let topic = match truncate_chars(topic, 5000) {
+        truncated if truncated.len() < topic.len() => format!("{truncated}..."),
+        _ => topic.to_string(),
     };
 
     if topic.is_empty() {
@@ -64,3 +153,38 @@ pub async fn summarize_topic(topic: &str) -> String {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_heavy_first_message_uses_exchange() {
+        let code = "run this:\nimport xarray as xr\nds = xr.open_dataset('tas.nc')\nds['tas'].mean(dim='time').plot()";
+        assert!(is_mostly_code(code));
+        assert!(!is_mostly_code(
+            "Plot the annual mean temperature of ERA5 for 2023"
+        ));
+
+        let content = vec![
+            StreamVariant::ServerHint("{\"thread_id\": \"abc\"}".to_string()),
+            StreamVariant::User(code.to_string()),
+            StreamVariant::Assistant(
+                "I'll plot the time mean of the near-surface air temperature.".to_string(),
+            ),
+            StreamVariant::StreamEnd("Generation complete".to_string()),
+        ];
+
+        // The adaptive strategy notices the code and gives the summarizer the assistant's explanation as well.
+        let source = topic_source(&content, TopicStrategy::Adaptive)
+            .expect("The thread contains a user message");
+        assert!(source.contains("time mean of the near-surface air temperature"));
+        assert!(source.starts_with("User: run this:"));
+
+        // The plain strategy just uses the raw first message.
+        assert_eq!(
+            topic_source(&content, TopicStrategy::FirstUserMessage),
+            Some(code.to_string())
+        );
+    }
+}
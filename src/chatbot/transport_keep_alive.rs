@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures::{stream, Stream, StreamExt};
+use once_cell::sync::Lazy;
+use tracing::trace;
+
+/// The keep-alive time of the server in seconds.
+/// Set via the environment variable `KEEP_ALIVE_SECS`; defaults to 120.
+pub static KEEP_ALIVE_SECS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("KEEP_ALIVE_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(120)
+});
+
+/// How long a stream may be silent before a keep-alive byte is sent.
+/// It's half the keep-alive time, so there is always a byte within the keep-alive window, even if the stream was silent right before it started.
+pub static TRANSPORT_KEEP_ALIVE_INTERVAL: Lazy<Duration> =
+    Lazy::new(|| Duration::from_secs(*KEEP_ALIVE_SECS) / 2);
+
+/// What is sent to keep the connection alive.
+/// The variants are JSON objects without a separator, so whitespace in between doesn't change how they are parsed.
+/// For Server-Sent-Events, it's an empty line without data, which clients ignore; for JSON-lines, it's an empty line.
+pub const KEEP_ALIVE_BYTES: Bytes = Bytes::from_static(b"\n");
+
+/// Wraps a response stream so that a keep-alive byte is sent whenever it was silent for the interval.
+/// This is different from the heartbeat variants: those are only sent while a tool is running and are meant for the client,
+/// while this makes sure that the connection itself isn't dropped while the LLM takes long for the next token (like reasoning models do).
+pub fn with_transport_keep_alive<S, E>(
+    stream: S,
+    interval: Duration,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+{
+    stream::unfold(Box::pin(stream), move |mut stream| async move {
+        // The state of the inner stream is kept in the stream itself, so it's fine to drop the next() future on a timeout.
+        match tokio::time::timeout(interval, stream.next()).await {
+            Ok(Some(item)) => Some((item, stream)),
+            Ok(None) => None,
+            Err(_) => {
+                trace!(
+                    "The stream was silent for {:?}, sending a keep-alive byte.",
+                    interval
+                );
+                Some((Ok(KEEP_ALIVE_BYTES), stream))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_silent_stream_gets_keep_alive_bytes() {
+        // The "LLM" takes 300ms for its only answer.
+        let silent = stream::once(async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<Bytes, std::convert::Infallible>(Bytes::from_static(
+                b"{\"variant\":\"Assistant\",\"content\":\"Hi\"}",
+            ))
+        });
+        let items = with_transport_keep_alive(silent, Duration::from_millis(50))
+            .map(|item| item.expect("Infallible"))
+            .collect::<Vec<_>>()
+            .await;
+
+        let (answer, keep_alives) = items.split_last().expect("The answer is always sent");
+        assert!(keep_alives.len() >= 3);
+        assert!(keep_alives.iter().all(|bytes| *bytes == KEEP_ALIVE_BYTES));
+        assert!(answer.starts_with(b"{\"variant\":\"Assistant\""));
+    }
+}
@@ -0,0 +1,37 @@
+// Shortens text that is too long for the LLM, the client or the database.
+// Slicing a string at a byte index panics (or, with unchecked conversions, produces invalid UTF-8) if the index is inside a multibyte character,
+// so everything that truncates output goes through this helper, which only ever cuts between characters.
+
+/// Returns the first max_chars characters of the text, or the whole text if it isn't longer.
+/// The result always ends on a character boundary, no matter how many bytes the characters take.
+pub fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncation_never_splits_a_character() {
+        // "Temperatur in °C: 25 ≈ 🌡️" mixes characters of one to four bytes.
+        let text = "Temperatur in °C: 25 ≈ 🌡️";
+        // Byte 15 is inside the two bytes of "°", so naive byte slicing would panic here.
+        assert!(!text.is_char_boundary(15));
+        assert_eq!(truncate_chars(text, 15), "Temperatur in °");
+        assert_eq!(truncate_chars(text, 23), "Temperatur in °C: 25 ≈ ");
+        assert_eq!(truncate_chars(text, 24), "Temperatur in °C: 25 ≈ 🌡");
+
+        // Every possible length cuts between characters and counts characters, not bytes.
+        for max_chars in 0..=text.chars().count() {
+            let truncated = truncate_chars(text, max_chars);
+            assert_eq!(truncated.chars().count(), max_chars);
+            assert!(text.starts_with(truncated));
+        }
+        assert_eq!(truncate_chars(text, 1000), text);
+        assert_eq!(truncate_chars("", 3), "");
+    }
+}
@@ -1,12 +1,15 @@
 use core::fmt;
+use std::collections::{HashMap, HashSet};
 
 use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestUserMessage, ChatCompletionToolType, FunctionCall, ImageDetail, ImageUrl
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage, ChatCompletionToolType, CompletionUsage, FunctionCall, ImageDetail, ImageUrl
 };
 use documented::Documented;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, trace, warn};
 
+use crate::chatbot::available_chatbots::{MessageOrdering, ToolCallContent};
+
 #[derive(Debug, Clone)]
 pub enum ConversationState {
     Streaming(String), // The String is the Path to the file of the freva config.
@@ -28,6 +31,66 @@ pub struct ActiveConversation {
     pub last_activity: std::time::Instant, // The last time the conversation was active. If the conversation is inactive for too long, it will be ended.
 
     pub user_id: String, // The ID of the user, as sent from the frontend/client.
+
+    pub usage: Option<TokenUsage>, // The tokens used by this conversation so far, summed over all responses of the LLM. None if the provider didn't report any usage.
+
+    pub disconnected_at: Option<std::time::Instant>, // When the client lost the connection while still streaming, if it did. The conversation can be resumed for a grace period.
+
+    pub operations: u32, // How many operations (like tool calls) the LLM started in this turn. Limited by MAX_OPERATIONS_PER_TURN.
+
+    pub warnings: u32, // How many warning ServerHints this stream sent. Limited by MAX_WARNINGS_PER_STREAM.
+
+    pub replaces_from: Option<usize>, // For a regenerated turn, how many variants of the stored thread are kept; the rest is replaced by this conversation when it's saved.
+
+    pub tool_task: Option<tokio::task::AbortHandle>, // The task of the last tool call the LLM started, so it can be aborted right away when the conversation is stopped. While it runs, the conversation gets more time before it counts as stale.
+
+    pub database: Option<mongodb::Database>, // The database the conversation is stored in, so it can still be saved when the server shuts down mid-stream.
+
+    pub transient: Vec<(usize, StreamVariant)>, // The variants that were only sent to the client and aren't stored (like heartbeats and status messages), each with the number of variants of the conversation before it. A resumed client counted them too.
+}
+
+/// The number of tokens used by a thread, summed over all turns.
+/// The LLM reports the usage for every response it streams, so we add them up here and store the running total with the thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Adds the usage of another response (or thread) to this one.
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+
+    /// Whether no tokens at all are recorded. Some providers send such a usage instead of none, which doesn't mean that no tokens were used.
+    pub fn is_empty(&self) -> bool {
+        self.prompt_tokens == 0 && self.completion_tokens == 0 && self.total_tokens == 0
+    }
+
+    /// Adds up two usages that might be unknown; the sum is only unknown if both are.
+    pub fn combine(first: Option<Self>, second: Option<Self>) -> Option<Self> {
+        match (first, second) {
+            (Some(mut first), Some(second)) => {
+                first.add(&second);
+                Some(first)
+            }
+            (first, second) => first.or(second),
+        }
+    }
+}
+
+impl From<&CompletionUsage> for TokenUsage {
+    fn from(usage: &CompletionUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: u64::from(usage.prompt_tokens),
+            completion_tokens: u64::from(usage.completion_tokens),
+            total_tokens: u64::from(usage.total_tokens),
+        }
+    }
 }
 
 ///
@@ -48,9 +111,13 @@ pub struct ActiveConversation {
 ///
 /// CodeOutput: The output of the code that was executed, as a String. Also not formatted.
 /// Contains tracebacks if the code itself threw an exception and also hints to the line where the exception occured.
+/// If the server streams the output of running code (`STREAM_CODE_OUTPUT`), single lines arrive as CodeOutputs with the same id while the code runs;
+/// they are followed by the complete output, which replaces them and is the only one that's stored.
 ///
 /// Image: An image that was generated during the conversation, as a String. The image is Base64 encoded.
 /// An example of this would be a matplotlib plot. The image format should always be PNG.
+/// Only if the client asked for `image_format=webp`, the stream sends WebPs instead, as data URLs (`data:image/webp;base64,...`).
+/// If the client asked for `images=ref`, the stream sends the path of the image instead (`/api/chatbot/image/{thread_id}/{image_index}`), where it can be fetched as raw PNG.
 /// LLMs that support vision will be given the image to look at.
 ///
 /// ServerError: An error that occured on the server(backend) side, as a String. Contains the error message.
@@ -66,10 +133,23 @@ pub struct ActiveConversation {
 /// If the last message is not a StreamEnd but the stream ended, it's an error from the server side and needs to be fixed.
 ///
 /// ServerHint: The Server hints something to the client. This is primarily used for giving the thread_id, but also for warnings.
-/// The Content is in JSON format, with the key being the hint and the value being the content. Mainly, the keys "thread_id" and "warning" are used,
-/// but the heartbeat during code execution may also contain "memory", "total_memory", "cpu_usage" and "cpu_last_minute", as well as "process_cpu" and "process_memory".
+/// The Content is in JSON format, with the key being the hint and the value being the content. Mainly, the keys "thread_id" and "warning" are used.
+/// Stored threads may also record the model that answered a turn as "model", directly after the User variant of the turn.
+/// Status messages of the operators (like an upcoming restart) are sent to all active streams as "status"; they are not stored.
+/// The heartbeat during code execution is `{"heartbeat": true}`. If the server is configured for debugging, the heartbeat also contains
+/// "memory", "total_memory", "cpu_usage" and "cpu_last_minute", as well as "process_cpu" and "process_memory".
+/// If the running code reports its progress (lines starting with `PROGRESS:`), the heartbeat also contains the latest report as "progress" and the seconds the code has been running as "elapsed_secs".
+/// Heartbeats are not stored, unless the server is configured to (PERSIST_HEARTBEATS).
 /// An example for a ServerHint packet would be `{"variant": "ServerHint", "content": "{\"thread_id\":\"1234\"}"}`.
 /// That means that the content needs to be parsed as JSON to get the actual content.
+///
+/// Summary: A summary of the turn for logging and accessibility, as JSON. It's only sent if the client set `summary=true`, before the Usage and the StreamEnd.
+/// It contains the number of "assistant_messages", "code_executions", "images" and "errors" of the turn, as well as its "total_tokens" (null if the provider didn't report any).
+/// An example would be `{"variant": "Summary", "content": "{\"assistant_messages\":2,\"code_executions\":1,\"images\":1,\"errors\":0,\"total_tokens\":5120}"}`.
+///
+/// Usage: The tokens the turn used, as JSON, so clients can show the usage and cost. It's sent directly before the StreamEnd and stored with the thread,
+/// but only if the provider reported the usage. It contains the "prompt_tokens", "completion_tokens" and "total_tokens" of all requests of the turn, as well as the "model" that answered.
+/// An example would be `{"variant": "Usage", "content": "{\"prompt_tokens\":4800,\"completion_tokens\":320,\"total_tokens\":5120,\"model\":\"gpt-4.1\"}"}`.
 #[derive(Debug, Serialize, Deserialize, Clone, Documented, PartialEq, Eq, strum::VariantNames)]
 #[serde(tag = "variant", content = "content")] // Makes it so that the variant names are inside the object and the content is held in the content field.
 pub enum StreamVariant {
@@ -96,6 +176,10 @@ pub enum StreamVariant {
     /// The Server hints something to the client. Primarily used for giving the thread_id or warning the frontend. May later be used for other things.
     /// The content itself is in JSON format, with the key being the hint and the value being the content.
     ServerHint(String),
+    /// A machine-readable summary of the turn, as JSON. Only sent if the client asked for it, before the StreamEnd.
+    Summary(String),
+    /// The tokens the turn used and the model that answered, as JSON. Sent directly before the StreamEnd, if the provider reported them.
+    Usage(String),
 }
 
 impl fmt::Display for StreamVariant {
@@ -113,6 +197,8 @@ impl fmt::Display for StreamVariant {
             Self::CodeError(s) => format!("CodeError:{s}"),
             Self::StreamEnd(s) => format!("StreamEnd:{s}"),
             Self::ServerHint(s) => format!("ServerHint:{s}"), // It's a JSON string, we can just write it as is.
+            Self::Summary(s) => format!("Summary:{s}"),
+            Self::Usage(s) => format!("Usage:{s}"),
         };
         write!(f, "{result:?}")
     }
@@ -203,6 +289,8 @@ impl TryInto<Vec<ChatCompletionRequestMessage>> for StreamVariant {
             ,
             Self::CodeError(_) | Self::OpenAIError(_) | Self::ServerError(_) => Err(ConversionError::VariantHide("Error variants should not be passed to the LLM, it doesn't need to know about them.")),
             Self::StreamEnd(_) => Err(ConversionError::VariantHide("StreamEnd variants are only for use on the server side, not for the LLM.")),
+            Self::Summary(_) => Err(ConversionError::VariantHide("Summary variants are only for the client, the LLM doesn't need statistics about its own answer.")),
+            Self::Usage(_) => Err(ConversionError::VariantHide("Usage variants are only for the client, the LLM doesn't need to know the tokens it used.")),
             Self::ServerHint(s) => {
                 // The content is JSON, we check whether it's valid and that its key is either "thread_id" or "warning".
                 let hint: serde_json::Value = match serde_json::from_str(&s) {
@@ -450,10 +538,15 @@ pub fn help_convert_sv_ccrm(input: Vec<StreamVariant>, send_images: bool) -> Vec
                     },
                 };
                 if let Some(buffer) = assistant_message_buffer.clone() {
+                    // With parallel tool calls, one assistant message can contain several tool calls, so the call is added to the others.
+                    // Only a call with the same id is replaced.
+                    let mut tool_calls = buffer.tool_calls.clone().unwrap_or_default();
+                    tool_calls.retain(|call| call.id != tool_call.id);
+                    tool_calls.push(tool_call);
                     assistant_message_buffer = Some(
-                        // Set the tool call in the buffer.
+                        // Set the tool calls in the buffer.
                         ChatCompletionRequestAssistantMessage {
-                            tool_calls: Some(vec![tool_call]),
+                            tool_calls: Some(tool_calls),
                             ..buffer
                         },
                     );
@@ -533,6 +626,126 @@ pub fn help_convert_sv_ccrm(input: Vec<StreamVariant>, send_images: bool) -> Vec
     all_oai_messages
 }
 
+/// Makes the assistant messages that only consist of tool calls valid for the provider.
+/// This happens if the model calls a tool before writing any text, for example as the very first action of a turn.
+/// Depending on the provider, the content is then either omitted or an empty string; an empty text is never sent on its own.
+pub fn fit_tool_call_only_messages(
+    messages: Vec<ChatCompletionRequestMessage>,
+    style: ToolCallContent,
+) -> Vec<ChatCompletionRequestMessage> {
+    messages
+        .into_iter()
+        .map(|message| match message {
+            ChatCompletionRequestMessage::Assistant(assistant)
+                if assistant.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty())
+                    && assistant.content.as_ref().is_none_or(|content| {
+                        matches!(content, async_openai::types::ChatCompletionRequestAssistantMessageContent::Text(text) if text.is_empty())
+                    }) =>
+            {
+                let content = match style {
+                    ToolCallContent::Omit => None,
+                    ToolCallContent::EmptyString => {
+                        Some(async_openai::types::ChatCompletionRequestAssistantMessageContent::Text(String::new()))
+                    }
+                };
+                ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                    content,
+                    ..assistant
+                })
+            }
+            other => other,
+        })
+        .collect()
+}
+
+/// Reorders the tool calls and their results so the messages follow the rules of the provider.
+/// With parallel tool calls, or text that was streamed between a call and its result, other messages like the image of a plot can end up between a tool call and its result.
+/// For a strict provider, the results are moved directly after the assistant message with their calls and the messages that were in between follow them; calls without a result get an empty one.
+/// If the provider limits the tool calls per message, the assistant message is split into several, each followed by its results.
+/// A second result for the same call is dropped.
+pub fn fit_message_order(
+    messages: Vec<ChatCompletionRequestMessage>,
+    ordering: MessageOrdering,
+) -> Vec<ChatCompletionRequestMessage> {
+    if !ordering.strict && ordering.max_tool_calls.is_none() {
+        return messages;
+    }
+
+    let called: HashSet<String> = messages
+        .iter()
+        .filter_map(|message| match message {
+            ChatCompletionRequestMessage::Assistant(assistant) => assistant.tool_calls.as_ref(),
+            _ => None,
+        })
+        .flatten()
+        .map(|call| call.id.clone())
+        .collect();
+    let mut results: HashMap<String, ChatCompletionRequestToolMessage> = HashMap::new();
+    for message in &messages {
+        if let ChatCompletionRequestMessage::Tool(result) = message {
+            if called.contains(&result.tool_call_id) {
+                results
+                    .entry(result.tool_call_id.clone())
+                    .or_insert_with(|| result.clone());
+            }
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message {
+            ChatCompletionRequestMessage::Assistant(assistant)
+                if assistant
+                    .tool_calls
+                    .as_ref()
+                    .is_some_and(|calls| !calls.is_empty()) =>
+            {
+                let calls = assistant.tool_calls.clone().unwrap_or_default();
+                let per_message = ordering.max_tool_calls.unwrap_or(calls.len()).max(1);
+                for (index, chunk) in calls.chunks(per_message).enumerate() {
+                    ordered.push(ChatCompletionRequestMessage::Assistant(
+                        ChatCompletionRequestAssistantMessage {
+                            // The text only belongs to the first of the split messages.
+                            content: if index == 0 {
+                                assistant.content.clone()
+                            } else {
+                                None
+                            },
+                            tool_calls: Some(chunk.to_vec()),
+                            ..assistant.clone()
+                        },
+                    ));
+                    for call in chunk {
+                        match results.remove(&call.id) {
+                            Some(result) => {
+                                ordered.push(ChatCompletionRequestMessage::Tool(result))
+                            }
+                            None if ordering.strict => {
+                                debug!(
+                                    "Tool call {} has no result, answering it with an empty one.",
+                                    call.id
+                                );
+                                ordered.push(ChatCompletionRequestMessage::Tool(
+                                    ChatCompletionRequestToolMessage {
+                                        tool_call_id: call.id.clone(),
+                                        content: async_openai::types::ChatCompletionRequestToolMessageContent::Text(String::new()),
+                                    },
+                                ));
+                            }
+                            None => {}
+                        }
+                    }
+                }
+            }
+            ChatCompletionRequestMessage::Tool(result) if called.contains(&result.tool_call_id) => {
+                // The result was already moved behind its call.
+            }
+            other => ordered.push(other),
+        }
+    }
+    ordered
+}
+
 /// A simple helper function to "unescape" a string.
 /// This is needed because the prompt is escaped when it is sent to the frontend.
 pub fn unescape_string(s: &str) -> String {
@@ -630,4 +843,148 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_token_usage_accumulates() {
+        // Two turns of the same thread; the stored total has to be the sum of both.
+        let first_turn = CompletionUsage {
+            prompt_tokens: 1200,
+            completion_tokens: 300,
+            total_tokens: 1500,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+        let second_turn = CompletionUsage {
+            prompt_tokens: 1800,
+            completion_tokens: 200,
+            total_tokens: 2000,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+
+        let mut total = TokenUsage::default();
+        total.add(&TokenUsage::from(&first_turn));
+        total.add(&TokenUsage::from(&second_turn));
+
+        assert_eq!(
+            total,
+            TokenUsage {
+                prompt_tokens: 3000,
+                completion_tokens: 500,
+                total_tokens: 3500,
+            }
+        );
+    }
+
+    #[test]
+    fn test_code_call_as_first_action_is_valid_for_provider() {
+        let input = vec![
+            StreamVariant::User("What's 2+2?".to_string()),
+            StreamVariant::Code("{\"code\": \"2+2\"}".to_string(), "call_1".to_string()),
+            StreamVariant::CodeOutput("4".to_string(), "call_1".to_string()),
+            StreamVariant::Assistant("It's 4.".to_string()),
+        ];
+        let output = help_convert_sv_ccrm(input, false);
+        // The tool call comes directly after the user message and before its result.
+        assert_eq!(output.len(), 4);
+        assert!(matches!(output[0], ChatCompletionRequestMessage::User(_)));
+        assert!(matches!(output[2], ChatCompletionRequestMessage::Tool(_)));
+
+        let tool_call_message = |messages: &[ChatCompletionRequestMessage]| match &messages[1] {
+            ChatCompletionRequestMessage::Assistant(assistant) => {
+                assert_eq!(assistant.tool_calls.as_ref().map(Vec::len), Some(1));
+                assistant.content.clone()
+            }
+            other => panic!("Expected the tool call, got {other:?}"),
+        };
+
+        // OpenAI wants no content at all, self-hosted servers an empty string.
+        let omitted = fit_tool_call_only_messages(output.clone(), ToolCallContent::Omit);
+        assert_eq!(tool_call_message(&omitted), None);
+        let empty = fit_tool_call_only_messages(output, ToolCallContent::EmptyString);
+        assert_eq!(
+            tool_call_message(&empty),
+            Some(async_openai::types::ChatCompletionRequestAssistantMessageContent::Text(
+                String::new()
+            ))
+        );
+        // The messages with text are left alone.
+        assert_eq!(empty[3], omitted[3]);
+    }
+
+    #[test]
+    fn test_strict_provider_gets_results_directly_after_calls() {
+        // Two parallel calls; the plot of the first one and a text were streamed before the second result.
+        let input = vec![
+            StreamVariant::User("plot both".to_string()),
+            StreamVariant::Code("{\"code\": \"plot(a)\"}".to_string(), "call_1".to_string()),
+            StreamVariant::Code("{\"code\": \"plot(b)\"}".to_string(), "call_2".to_string()),
+            StreamVariant::CodeOutput("a".to_string(), "call_1".to_string()),
+            StreamVariant::Image("aW1hZ2U=".to_string()),
+            StreamVariant::CodeOutput("b".to_string(), "call_2".to_string()),
+            StreamVariant::Assistant("Here are both.".to_string()),
+            StreamVariant::Code("{\"code\": \"plot(c)\"}".to_string(), "call_3".to_string()),
+        ];
+        let messages = help_convert_sv_ccrm(input, true);
+
+        // Every call has to be answered by the messages directly after it, in the order of the calls.
+        let is_strictly_ordered = |messages: &[ChatCompletionRequestMessage], max_calls: usize| {
+            let mut unanswered: Vec<String> = Vec::new();
+            for message in messages {
+                match message {
+                    ChatCompletionRequestMessage::Tool(result) => {
+                        if unanswered.first() != Some(&result.tool_call_id) {
+                            return false;
+                        }
+                        unanswered.remove(0);
+                    }
+                    _ if !unanswered.is_empty() => return false,
+                    ChatCompletionRequestMessage::Assistant(assistant) => {
+                        unanswered = assistant
+                            .tool_calls
+                            .iter()
+                            .flatten()
+                            .map(|call| call.id.clone())
+                            .collect();
+                        if unanswered.len() > max_calls {
+                            return false;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            unanswered.is_empty()
+        };
+        // The image sits between the first result and the second one.
+        assert!(!is_strictly_ordered(&messages, usize::MAX));
+
+        let strict = fit_message_order(
+            messages.clone(),
+            MessageOrdering {
+                strict: true,
+                max_tool_calls: None,
+            },
+        );
+        assert!(is_strictly_ordered(&strict, usize::MAX));
+        // Nothing is lost; only the last call, which has no result yet, got an empty one.
+        assert_eq!(strict.len(), messages.len() + 1);
+        assert!(matches!(strict[4], ChatCompletionRequestMessage::User(_)));
+
+        // With one call per message, the parallel calls are split up.
+        let one_call = fit_message_order(
+            messages.clone(),
+            MessageOrdering {
+                strict: true,
+                max_tool_calls: Some(1),
+            },
+        );
+        assert!(is_strictly_ordered(&one_call, 1));
+        assert_eq!(one_call.len(), strict.len() + 1);
+
+        // Providers without rules get the messages as they are.
+        assert_eq!(
+            fit_message_order(messages.clone(), MessageOrdering::default()),
+            messages
+        );
+    }
 }
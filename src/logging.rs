@@ -1,9 +1,26 @@
-use std::sync::{Mutex, OnceLock};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll},
+};
 
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web::Bytes,
+    Error,
+};
 use flexi_logger::{
+    filter::{LogLineFilter, LogLineWriter},
     style, Age, Cleanup, Criterion, FileSpec, LevelFilter, LogSpecification, Logger, LoggerHandle,
     Naming,
 };
+use once_cell::sync::Lazy;
+use rand::Rng;
 
 use crate::cla_parser; // imports the cla_parser module for the Args struct
 
@@ -25,6 +42,7 @@ pub fn setup_logger(args: &cla_parser::Args) {
                 .suffix("txt"),
         )
         .format(format_log_message)
+        .filter(Box::new(SampledRequestFilter)) // only the sampled requests log their debug and trace lines
         .set_palette("b1;3;2;4;6".to_string())
         .rotate(
             Criterion::Age(Age::Hour),
@@ -52,17 +70,194 @@ pub fn format_log_message(
     record: &flexi_logger::Record,
 ) -> std::io::Result<()> {
     let level = record.level();
+    // Lines logged while handling a request carry its correlation ID, so they can be found together.
+    let request = REQUEST_LOG
+        .try_with(|request| format!("[req {}] ", request.id))
+        .unwrap_or_default();
     write!(
         write,
-        "[{}]:{} ({}:{}) {}",
+        "[{}]:{} ({}:{}) {}{}",
         now.format("%Y-%m-%d %H:%M:%S%.6f"),
         style(level).paint(format!("{:7}", format!("[{}]", level))), // paint the level in a color
         record.module_path().unwrap_or("<unnamed>"),                 // Module from tracing
         record.line().unwrap_or(0), // line number can help with debugging
+        request,
         record.args()
     ) // the actual message
 }
 
+/// How often the requests log in full, per endpoint: 1 in N requests keep their debug and trace lines, the others only log info and above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleRates {
+    default: u64,
+    endpoints: HashMap<String, u64>,
+}
+
+impl SampleRates {
+    /// The rate for an endpoint, given by the last segment of its path.
+    fn rate_for(&self, endpoint: &str) -> u64 {
+        self.endpoints
+            .get(endpoint)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Parses the sample rates from a list like "10, streamresponse=1, getthread=100".
+/// A bare number is the rate of all endpoints without their own; invalid entries are ignored.
+fn parse_sample_rates(value: &str) -> SampleRates {
+    let mut rates = SampleRates {
+        default: 1,
+        endpoints: HashMap::new(),
+    };
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (endpoint, rate) = match entry.split_once('=') {
+            Some((endpoint, rate)) => (Some(endpoint.trim()), rate.trim()),
+            None => (None, entry),
+        };
+        match (endpoint, rate.parse::<u64>()) {
+            (_, Ok(0) | Err(_)) => {
+                eprintln!("Ignoring invalid entry {entry:?} of LOG_SAMPLE_RATE, expected a positive rate.");
+            }
+            (None, Ok(rate)) => rates.default = rate,
+            (Some(endpoint), Ok(rate)) => {
+                rates.endpoints.insert(endpoint.to_lowercase(), rate);
+            }
+        }
+    }
+    rates
+}
+
+/// How often the requests log their debug and trace lines.
+/// Set via the environment variable `LOG_SAMPLE_RATE`, either as a single N for all endpoints or with overrides per endpoint,
+/// like "10, streamresponse=1". Defaults to 1, so every request logs in full.
+pub static LOG_SAMPLE_RATES: Lazy<SampleRates> =
+    Lazy::new(|| parse_sample_rates(&std::env::var("LOG_SAMPLE_RATE").unwrap_or_default()));
+
+/// The logging context of the request that is currently being handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLog {
+    pub id: Arc<str>,  // The correlation ID, from the x-request-id header or generated.
+    pub sampled: bool, // Whether the debug and trace lines of the request are logged.
+}
+
+tokio::task_local! {
+    static REQUEST_LOG: RequestLog;
+}
+
+/// Returns the logging context of the request that is currently being handled, if any.
+pub fn current_request_log() -> Option<RequestLog> {
+    REQUEST_LOG.try_with(RequestLog::clone).ok()
+}
+
+/// Whether a request is sampled. This only depends on its correlation ID, so the decision can't change while it's handled.
+fn is_sampled(id: &str, rate: u64) -> bool {
+    if rate <= 1 {
+        return true;
+    }
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    hasher.finish().is_multiple_of(rate)
+}
+
+/// Takes the correlation ID the client sent, if it's usable in the log, or generates a new one.
+fn correlation_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            (1..=64).contains(&id.len())
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            rand::rng()
+                .sample_iter(rand::distr::Alphanumeric)
+                .take(12)
+                .map(char::from)
+                .collect()
+        })
+}
+
+/// Decides at the entry of every request whether it logs in full, see `LOG_SAMPLE_RATE`,
+/// and gives all of its log lines the same correlation ID, which is also returned in the x-request-id header.
+/// The context is kept while the body is streamed, so the lines of a streamed response carry the ID too.
+pub async fn sample_request_logs(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = correlation_id(&req);
+    let endpoint = req
+        .path()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let context = RequestLog {
+        sampled: is_sampled(&id, LOG_SAMPLE_RATES.rate_for(&endpoint)),
+        id: id.into(),
+    };
+
+    let mut response = REQUEST_LOG.scope(context.clone(), next.call(req)).await?;
+    if let Ok(value) = HeaderValue::from_str(&context.id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    Ok(response
+        .map_into_boxed_body()
+        .map_body(|_, body| ScopedBody { body, context }))
+}
+
+/// A response body that is polled within the logging context of its request.
+struct ScopedBody {
+    body: BoxBody,
+    context: RequestLog,
+}
+
+impl MessageBody for ScopedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        REQUEST_LOG.sync_scope(this.context.clone(), || {
+            Pin::new(&mut this.body).poll_next(cx)
+        })
+    }
+}
+
+/// Drops the debug and trace lines of requests that aren't sampled. Lines outside of requests are always kept.
+struct SampledRequestFilter;
+
+impl LogLineFilter for SampledRequestFilter {
+    fn write(
+        &self,
+        now: &mut flexi_logger::DeferredNow,
+        record: &flexi_logger::Record,
+        log_line_writer: &dyn LogLineWriter,
+    ) -> std::io::Result<()> {
+        let sampled = current_request_log().is_none_or(|request| request.sampled);
+        if sampled || record.level() <= flexi_logger::Level::Info {
+            log_line_writer.write(now, record)?;
+        }
+        Ok(())
+    }
+}
+
 /// Temporarily sets the log level to error.
 /// Useful for temporarily silencing the logger if a function is too verbose.
 pub fn silence_logger() {
@@ -103,3 +298,79 @@ pub fn undo_silence_logger() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::to_bytes, middleware::from_fn, test as actix_test, web, App, HttpResponse,
+    };
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn test_sample_rates_are_parsed_per_endpoint() {
+        let rates = parse_sample_rates("10, streamresponse=1, GetThread=100, ping=0, bad");
+        assert_eq!(rates.rate_for("streamresponse"), 1);
+        assert_eq!(rates.rate_for("getthread"), 100);
+        // Invalid entries are ignored, so the endpoint uses the default.
+        assert_eq!(rates.rate_for("ping"), 10);
+        assert_eq!(parse_sample_rates("").rate_for("ping"), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_sampling_decision_is_stable_within_a_request() {
+        let app = actix_test::init_service(App::new().wrap(from_fn(sample_request_logs)).route(
+            "/api/chatbot/streamresponse",
+            web::get().to(|| async {
+                let before = current_request_log();
+                tokio::task::yield_now().await;
+                let after = current_request_log();
+                assert_eq!(before, after);
+                // The body is polled after the handler returned, but still within the request.
+                let body = futures::stream::iter(0..3).map(move |_| {
+                    assert_eq!(current_request_log(), before);
+                    Ok::<_, actix_web::Error>(Bytes::from(format!("{}\n", before.is_some())))
+                });
+                HttpResponse::Ok().streaming(body)
+            }),
+        ))
+        .await;
+
+        for id in ["abc", "request-1", "request-2"] {
+            let request = actix_test::TestRequest::get()
+                .uri("/api/chatbot/streamresponse")
+                .insert_header(("x-request-id", id))
+                .to_request();
+            let response = actix_test::call_service(&app, request).await;
+            assert_eq!(
+                response
+                    .headers()
+                    .get("x-request-id")
+                    .expect("The response has a request ID"),
+                id
+            );
+            let body = to_bytes(response.into_body())
+                .await
+                .unwrap_or_else(|_| panic!("The body can be read"));
+            assert_eq!(body, "true\ntrue\ntrue\n");
+
+            // The same ID always gets the same decision.
+            assert_eq!(is_sampled(id, 7), is_sampled(id, 7));
+        }
+
+        // Requests without an ID get a new one.
+        let request = actix_test::TestRequest::get()
+            .uri("/api/chatbot/streamresponse")
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(
+            response
+                .headers()
+                .get("x-request-id")
+                .expect("The response has a request ID")
+                .len(),
+            12
+        );
+    }
+}
@@ -4,12 +4,13 @@
 
 use std::time::Duration;
 
-use actix_web::{services, web, App, HttpServer};
+use actix_web::{middleware, services, web, App, HttpServer};
 use clap::Parser;
 use dotenvy::dotenv;
 use tool_calls::code_interpreter::prepare_execution::run_code_interpeter;
 use tracing::{debug, error, info};
 
+mod api_error; // for structured error responses
 mod auth; // for basic authentication
 mod chatbot; // for the actual chatbot
 mod cla_parser; // for parsing the command line arguments
@@ -62,28 +63,58 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server at {host}:{port}");
 
     // Start the server
-    HttpServer::new(|| {
+    let server = HttpServer::new(|| {
         let services = services![
             web::scope("/api/chatbot")
                 .route("/ping", web::get().to(static_serve::ping)) // Ping, return a short description of the API.
                 .route("/help", web::get().to(static_serve::ping)) // Ping, return a short description of the API.
                 .route("/stop", web::get().to(chatbot::stop::stop)) // Stop, stop a specific conversation by thread ID.
                 .route("/stop", web::post().to(chatbot::stop::stop)) // Stop, stop a specific conversation by thread ID. Both post and get are allowed.
+                .route("/stopall", web::get().to(chatbot::stop::stop_all)) // StopAll, stop all running conversations of the user.
+                .route("/stopall", web::post().to(chatbot::stop::stop_all)) // StopAll, same as above, both post and get are allowed.
+                .route("/broadcast", web::post().to(chatbot::broadcast::broadcast)) // Broadcast, send a status message of the operators to all active streams.
                 .route("/docs", web::get().to(static_serve::docs)) // Docs, return the documentation of the API.
                 .route("/getthread", web::get().to(chatbot::get_thread::get_thread)) // GetThread, get the thread of a specific conversation by thread ID.
+                .route(
+                    "/deletethread",
+                    web::delete().to(chatbot::delete_thread::delete_thread)
+                ) // DeleteThread, delete a thread of the user with everything stored for it.
+                .route("/message", web::get().to(chatbot::get_message::get_message)) // Message, get a single message of a thread by thread ID and index.
+                .route(
+                    "/image/{thread_id}/{image_index}",
+                    web::get().to(chatbot::get_image::get_image)
+                ) // Image, get a single image of a thread as raw PNG by thread ID and image index.
                 .route(
                     "/streamresponse",
                     web::get().to(chatbot::stream_response::stream_response)
                 ) // StreamResponse, stream the response of a specific conversation by thread ID.
+                .route("/complete", web::get().to(chatbot::complete::complete)) // Complete, answer an input in a single JSON response once the turn is done.
+                .route(
+                    "/regenerate",
+                    web::get().to(chatbot::regenerate::regenerate)
+                ) // Regenerate, stream a new answer to the last input of a thread, replacing the old one.
+                .route("/replay", web::get().to(chatbot::replay::replay)) // Replay, stream a stored thread as if it was live. Disabled by default.
                 .route(
                     "/availablechatbots",
                     web::get()
                         .to(chatbot::available_chatbots_endpoint::available_chatbots_endpoint)
                 ) // AvailableChatbots, get the available chatbots.
+                .route(
+                    "/tools",
+                    web::get().to(chatbot::tools_endpoint::tools_endpoint)
+                ) // Tools, get the tools the LLM can call and their parameter schemas.
+                .route(
+                    "/tokenestimate",
+                    web::get().to(chatbot::token_estimate::token_estimate)
+                ) // TokenEstimate, estimate the tokens of the next request and the context window of the model.
+                .route(
+                    "/threadmeta",
+                    web::get().to(chatbot::mongodb::thread_meta::thread_meta)
+                ) // ThreadMeta, get the topic, date and message count of a thread without its content.
                 .route(
                     "/getuserthreads",
                     web::get().to(chatbot::mongodb::get_user_threads::get_user_threads)
-                ) // GetUserThreads, get the latest 10 threads of the user.
+                ) // GetUserThreads, get a page of the threads of the user, newest first.
                 .route(
                     "/setthreadtopic",
                     web::post().to(chatbot::mongodb::set_thread_topic::set_thread_topic)
@@ -95,38 +126,49 @@ async fn main() -> std::io::Result<()> {
                 .route(
                     "/searchthreads",
                     web::get().to(chatbot::mongodb::search_threads::search_threads)
-                ), // SearchThreads, search the threads of the user by a query.
-            web::scope("/ping").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ), // Also, for convenience, all old points without the /api/chatbot, give a "moved permanently" to the new location.
-            web::scope("/help").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ),
-            web::scope("/stop").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ),
-            web::scope("/stop").route(
-                "",
-                actix_web::web::post().to(static_serve::moved_permanently)
-            ),
-            web::scope("/docs").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ),
-            web::scope("/getthread").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ),
-            web::scope("/streamresponse").route(
-                "",
-                actix_web::web::get().to(static_serve::moved_permanently)
-            ),
+                ) // SearchThreads, search the threads of the user by a query.
+                .route(
+                    "/fulltextsearch",
+                    web::get().to(chatbot::mongodb::text_search::full_text_search)
+                ) // FullTextSearch, search the topics and inputs of the threads of the user, sorted by relevance.
+                .route(
+                    "/usersettings",
+                    web::get().to(chatbot::mongodb::user_settings::user_settings)
+                ) // UserSettings, get or change the settings profile that supplies the default parameters of the user's streams.
+                .route(
+                    "/usersettings",
+                    web::post().to(chatbot::mongodb::user_settings::user_settings)
+                ) // Also allow the post method
+                .route(
+                    "/sharethread",
+                    web::get().to(chatbot::mongodb::share_thread::share_thread)
+                ) // ShareThread, create a read-only link to a thread of the user.
+                .route(
+                    "/revokeshare",
+                    web::get().to(chatbot::mongodb::share_thread::revoke_share_endpoint)
+                ) // RevokeShare, revoke a read-only link before it expires.
+                .route(
+                    "/listshares",
+                    web::get().to(chatbot::mongodb::share_thread::list_shares_endpoint)
+                ) // ListShares, list the read-only links to a thread that can still be used.
+                .route(
+                    "/revokeshares",
+                    web::get().to(chatbot::mongodb::share_thread::revoke_thread_shares)
+                ) // RevokeShares, revoke all read-only links to a thread at once.
+                .route(
+                    "/shared",
+                    web::get().to(chatbot::mongodb::share_thread::shared_thread)
+                ), // Shared, get a shared thread by the token of its link, without authentication.
         ];
         App::new()
+            .wrap(middleware::from_fn(logging::sample_request_logs)) // Gives every request a correlation ID and decides whether it logs in full, see LOG_SAMPLE_RATE.
             .service(services)
+            .configure(|cfg| {
+                static_serve::configure_legacy_redirects(
+                    cfg,
+                    *static_serve::ENABLE_LEGACY_REDIRECTS,
+                );
+            }) // Also, for convenience, all old points without the /api/chatbot, give a "moved permanently" to the new location.
             .default_service(web::route().to(static_serve::not_found))
     })
     .bind((host, port))
@@ -135,12 +177,16 @@ async fn main() -> std::io::Result<()> {
         eprintln!("Error binding to the address. Exiting...");
         std::process::exit(1);
     })
-    .keep_alive(Duration::from_secs(120)) // Long keep-alive time to prevent the server from closing the connection too early.
-    // But as far as I can see, we will always have the problem that the stream length is capped at the keep-alive time...
+    .keep_alive(Duration::from_secs(*chatbot::transport_keep_alive::KEEP_ALIVE_SECS)) // Long keep-alive time to prevent the server from closing the connection too early.
+    // The stream length used to be capped at the keep-alive time; now silent streams get a keep-alive byte every half keep-alive time (see transport_keep_alive).
     // If the keep-alive time is too short, we risk the connection being closed before the stream is finished.
     // If it's too long, there might be a lot of open connections that are not being used.
     // There is a floor to how long it needs to be, since Ollama does not send parts of tool calls, it needs to be at least around 20 seconds, else the frontend loses connection for long code snippets.
     .workers(8) // It uses 128 by default - far too much background usage
-    .run()
-    .await
+    .disable_signals() // The signals are handled below, so the active conversations are saved before the server stops.
+    .run();
+
+    // On ctrl-c or SIGTERM, the conversations that are still streaming are saved first, instead of being lost.
+    tokio::spawn(chatbot::shutdown::shutdown_on_signal(server.handle()));
+    server.await
 }
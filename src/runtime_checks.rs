@@ -9,7 +9,13 @@ use crate::{
         LITE_LLM_ADDRESS,
     },
     static_serve,
-    tool_calls::route_call::print_and_clear_tool_logs,
+    tool_calls::{
+        code_interpreter::{
+            prepare_execution::CodeVerbosity,
+            resource_limits::{LimitExceeded, CI_MEM_LIMIT_MB},
+        },
+        route_call::print_and_clear_tool_logs,
+    },
 };
 
 /// Helper function to flush stdout and stderr.
@@ -39,6 +45,13 @@ pub async fn run_runtime_checks() {
         "Starting messages JSON for GPT-5: {:?}",
         entire_prompt_json_gpt_5
     );
+    // Malformed prompts in the PROMPT_DIR stop the server here instead of at the first new thread.
+    debug!(
+        "Custom prompts loaded for: {:?}",
+        chatbot::prompting::PROMPT_OVERRIDES
+            .keys()
+            .collect::<Vec<_>>()
+    );
 
     trace!("Ping Response: {:?}", static_serve::RESPONSE_STRING);
 
@@ -119,6 +132,7 @@ pub async fn run_runtime_checks() {
     info!("Checking whether the code interpreter can handle crashes.");
     check_hard_crash().await;
     check_soft_crash().await;
+    check_memory_limit().await;
     println!("Success!");
     flush_stdout_stderr();
     info!("The code interpreter can handle crashes.");
@@ -152,6 +166,7 @@ pub async fn run_runtime_checks() {
     check_plot_extraction_false_negative().await;
     check_plot_extraction_false_positive().await;
     check_plot_extraction_close().await;
+    check_plot_extraction_distinct().await;
     check_indentation().await;
     println!("Success!");
     info!(
@@ -182,6 +197,8 @@ async fn check_two_plus_two() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -201,6 +218,8 @@ async fn check_print() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -220,6 +239,8 @@ async fn check_print_noflush() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -240,6 +261,8 @@ async fn check_print_two() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -259,6 +282,8 @@ async fn check_assignments() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     // The output should be empty, as we're not printing anything.
@@ -309,6 +334,8 @@ async fn check_single_import(library: &str) {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert!(output.len() == 1);
@@ -325,6 +352,8 @@ pub async fn check_hard_crash() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     // If we reach this point, the code interpreter did not crash.
@@ -337,6 +366,8 @@ pub async fn check_soft_crash() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -349,6 +380,40 @@ pub async fn check_soft_crash() {
     );
 }
 
+#[cfg(target_os = "linux")]
+/// Checks that the code interpreter can't allocate more memory than its limit, but is stopped with an error.
+pub async fn check_memory_limit() {
+    let limit = *CI_MEM_LIMIT_MB;
+    if limit == 0 {
+        info!("The memory of the code interpreter is not limited, skipping the check.");
+        return;
+    }
+    // A plain bytearray, so the check doesn't depend on numpy.
+    let code = format!(
+        r#"{{"code": "a = bytearray({} * 1024 * 1024)"}}"#,
+        limit * 2
+    );
+    let output = crate::tool_calls::code_interpreter::prepare_execution::start_code_interpeter(
+        Some(code),
+        "test".to_string(),
+        None,
+        "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
+    )
+    .await;
+    assert_eq!(
+        output.first(),
+        Some(&StreamVariant::CodeError(LimitExceeded::Memory.message()))
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Checks that the code interpreter can't allocate more memory than its limit, but is stopped with an error.
+pub async fn check_memory_limit() {
+    println!("Resource limits are only implemented for Linux (Docker), skipping.");
+}
+
 #[cfg(target_os = "linux")]
 /// Simple helper function that checks whether the given string is a path to a directory we can read from.
 pub fn check_directory(path: &str) -> bool {
@@ -370,6 +435,8 @@ async fn check_syntax_error() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -390,6 +457,8 @@ async fn check_syntax_error_surround() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -412,6 +481,8 @@ async fn check_traceback_error_surround() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -432,6 +503,8 @@ async fn check_eval_exec() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -447,6 +520,8 @@ async fn check_eval_exec() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -462,6 +537,8 @@ async fn check_eval_exec() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -477,6 +554,8 @@ async fn check_eval_exec() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -492,6 +571,8 @@ async fn check_eval_exec() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -527,6 +608,8 @@ async fn check_plot_extraction() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 2);
@@ -543,6 +626,8 @@ async fn check_plot_extraction_no_import() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 2);
@@ -558,6 +643,8 @@ async fn check_plot_extraction_second_to_last_line() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 2);
@@ -573,6 +660,8 @@ async fn check_plot_extraction_false_negative() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -587,6 +676,8 @@ async fn check_plot_extraction_false_positive() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -603,6 +694,8 @@ async fn check_plot_extraction_close() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 2);
@@ -612,6 +705,31 @@ async fn check_plot_extraction_close() {
     assert!(matches!(output[1], StreamVariant::Image(_)));
 }
 
+/// Tests whether two plots that are generated back-to-back are returned as different images.
+/// Each plot has to come from its own figure, not from a file or figure that was left over from the one before.
+async fn check_plot_extraction_distinct() {
+    let mut images = vec![];
+    for code in [
+        r#"{"code": "import matplotlib.pyplot as plt\nplt.plot([1, 2, 3], [4, 5, 6])"}"#,
+        r#"{"code": "import matplotlib.pyplot as plt\nplt.bar([1, 2, 3], [6, 5, 4])"}"#,
+    ] {
+        let output = crate::tool_calls::code_interpreter::prepare_execution::start_code_interpeter(
+            Some(code.to_string()),
+            "test".to_string(),
+            None,
+            "testing".to_string(),
+            CodeVerbosity::Concise,
+            None,
+        )
+        .await;
+        match output.as_slice() {
+            [_, StreamVariant::Image(image)] => images.push(image.clone()),
+            _ => panic!("Expected an image from the plot, got: {output:?}"),
+        }
+    }
+    assert_ne!(images[0], images[1]);
+}
+
 /// Tests whether or not the code interpreter can handle indentation on the last line.
 async fn check_indentation() {
     let output = crate::tool_calls::code_interpreter::prepare_execution::start_code_interpeter(
@@ -622,6 +740,8 @@ async fn check_indentation() {
         "test".to_string(),
         None,
         "testing".to_string(),
+        CodeVerbosity::Concise,
+        None,
     )
     .await;
     assert_eq!(output.len(), 1);
@@ -2,7 +2,7 @@
 
 use std::env;
 
-use actix_web::{HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
 use const_format::concatcp;
 use documented::{docs_const, Documented};
 use once_cell::sync::Lazy;
@@ -12,9 +12,31 @@ use tracing::{debug, trace};
 use crate::{
     auth::AUTHORIZE_OR_FAIL_FN_DOCS,
     chatbot::{
-        available_chatbots_endpoint::AVAILABLE_CHATBOTS_ENDPOINT_DOCS, get_thread::GET_THREAD_DOCS,
-        mongodb::get_user_threads::GET_USER_THREADS_DOCS, stop::STOP_DOCS,
-        stream_response::STREAM_RESPONSE_DOCS, types::StreamVariant,
+        available_chatbots_endpoint::AVAILABLE_CHATBOTS_ENDPOINT_DOCS,
+        broadcast::BROADCAST_DOCS,
+        circuit_breaker::with_llm_breaker,
+        complete::COMPLETE_DOCS,
+        delete_thread::DELETE_THREAD_DOCS,
+        get_image::GET_IMAGE_DOCS,
+        get_message::GET_MESSAGE_DOCS,
+        get_thread::GET_THREAD_DOCS,
+        mongodb::{
+            get_user_threads::GET_USER_THREADS_DOCS,
+            share_thread::{
+                LIST_SHARES_ENDPOINT_DOCS, REVOKE_SHARE_ENDPOINT_DOCS, REVOKE_THREAD_SHARES_DOCS,
+                SHARED_THREAD_DOCS, SHARE_THREAD_DOCS,
+            },
+            text_search::FULL_TEXT_SEARCH_DOCS,
+            thread_meta::THREAD_META_DOCS,
+            user_settings::USER_SETTINGS_DOCS,
+        },
+        regenerate::REGENERATE_DOCS,
+        replay::REPLAY_DOCS,
+        stop::{STOP_ALL_DOCS, STOP_DOCS},
+        stream_response::STREAM_RESPONSE_DOCS,
+        token_estimate::TOKEN_ESTIMATE_DOCS,
+        tools_endpoint::TOOLS_ENDPOINT_DOCS,
+        types::StreamVariant,
     },
 };
 
@@ -24,6 +46,7 @@ use crate::{
 enum EndpointMethods {
     Get,
     Post,
+    Delete,
 }
 
 /// The specification for an endpoint, for the ping endpoint.
@@ -38,7 +61,7 @@ struct EndpointSpec {
 static PING_SPEC: Lazy<EndpointSpec> = Lazy::new(|| {
     EndpointSpec {
     name: "ping",
-    return_type: serde_json::Value::String("json{version:string,streamvariants:list{string},endpoints:list{name:string,methods:string,params:list{json},returntype:json}}".to_string()),
+    return_type: serde_json::Value::String("json{version:string,streamvariants:list{string},endpoints:list{name:string,methods:string,params:list{json},returntype:json},llm_circuit_breaker:string}".to_string()),
     params: serde_json::Map::new(),                             // no params
     methods: &[EndpointMethods::Get],
 }
@@ -61,6 +84,36 @@ static GETTHREAD_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
             "thread_id".to_string(),
             serde_json::Value::String("string".to_string()),
         ),
+        (
+            "include_images".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "format".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static MESSAGE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "message",
+    return_type: serde_json::Value::String(
+        "json{variant:streamvariant=string,content:string}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "index".to_string(),
+            serde_json::Value::String("integer".to_string()),
+        ),
         (
             "auth_key".to_string(),
             serde_json::Value::String("string".to_string()),
@@ -69,6 +122,16 @@ static GETTHREAD_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
     methods: &[EndpointMethods::Get],
 });
 
+static IMAGE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "image/{thread_id}/{image_index}",
+    return_type: serde_json::Value::String("bytes{image/png}".to_string()),
+    params: serde_json::Map::from_iter(vec![(
+        "auth_key".to_string(),
+        serde_json::Value::String("string".to_string()),
+    )]),
+    methods: &[EndpointMethods::Get],
+});
+
 static STREAMRESPONSE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
     name: "streamresponse",
     return_type: serde_json::Value::String(
@@ -83,6 +146,202 @@ static STREAMRESPONSE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
             "input".to_string(),
             serde_json::Value::String("string".to_string()),
         ),
+        (
+            "freva_config_content".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "code_verbosity".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "framing".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "temperature".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "max_tokens".to_string(),
+            serde_json::Value::String("optional{integer}".to_string()),
+        ),
+        (
+            "frequency_penalty".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "parallel_tool_calls".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "reasoning_effort".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "image_format".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "images".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "summary".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "delta_offsets".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static COMPLETE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| {
+    EndpointSpec {
+    name: "complete",
+    return_type: serde_json::Value::String(
+        "json{thread_id:string,message:string,code_outputs:list{json{id:string,code:string,output:optional{string}}},images:list{string},errors:list{string},usage:optional{json},end_reason:string}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "input".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "freva_config_content".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "code_verbosity".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "temperature".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "max_tokens".to_string(),
+            serde_json::Value::String("optional{integer}".to_string()),
+        ),
+        (
+            "frequency_penalty".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "parallel_tool_calls".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "reasoning_effort".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "image_format".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "images".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "summary".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+}
+});
+
+static REGENERATE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "regenerate",
+    return_type: serde_json::Value::String(
+        "stream{json{variant:streamvariant=string,content:string}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "code_verbosity".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "framing".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "temperature".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "max_tokens".to_string(),
+            serde_json::Value::String("optional{integer}".to_string()),
+        ),
+        (
+            "frequency_penalty".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "parallel_tool_calls".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "reasoning_effort".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "image_format".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "images".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "summary".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "delta_offsets".to_string(),
+            serde_json::Value::String("optional{bool}".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static REPLAY_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "replay",
+    return_type: serde_json::Value::String(
+        "stream{json{variant:streamvariant=string,content:string}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "speed".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
         (
             "auth_key".to_string(),
             serde_json::Value::String("string".to_string()),
@@ -107,6 +366,152 @@ static STOP_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
     methods: &[EndpointMethods::Get, EndpointMethods::Post],
 });
 
+static STOPALL_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "stopall",
+    return_type: serde_json::Value::String("json{stopped:integer}".to_string()),
+    params: serde_json::Map::from_iter(vec![(
+        "auth_key".to_string(),
+        serde_json::Value::String("string".to_string()),
+    )]),
+    methods: &[EndpointMethods::Get, EndpointMethods::Post],
+});
+
+static BROADCAST_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "broadcast",
+    return_type: serde_json::Value::String("integer".to_string()),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "message".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "admin_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Post],
+});
+
+static TOOLS_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "tools",
+    return_type: serde_json::Value::String(
+        "json{list{name:string,description:string,parameters:json}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![(
+        "auth_key".to_string(),
+        serde_json::Value::String("string".to_string()),
+    )]),
+    methods: &[EndpointMethods::Get],
+});
+
+static DELETETHREAD_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "deletethread",
+    return_type: serde_json::Value::String("json{removed:integer}".to_string()),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Delete],
+});
+
+static SHARETHREAD_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "sharethread",
+    return_type: serde_json::Value::String(
+        "json{token:string,expires_at:integer,tenant:optional{string}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "thread_id".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static REVOKESHARE_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "revokeshare",
+    return_type: serde_json::Value::String("string".to_string()),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "token".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static SHARED_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "shared",
+    return_type: serde_json::Value::String(
+        "json{list{json{variant:streamvariant=string,content:string}}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "token".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "tenant".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static FULLTEXTSEARCH_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "fulltextsearch",
+    return_type: serde_json::Value::String(
+        "json{list{json{thread_id:string,topic:string,date:string,score:float}}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "query".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get],
+});
+
+static USERSETTINGS_SPEC: Lazy<EndpointSpec> = Lazy::new(|| EndpointSpec {
+    name: "usersettings",
+    return_type: serde_json::Value::String(
+        "json{chatbot:optional{string},temperature:optional{float}}".to_string(),
+    ),
+    params: serde_json::Map::from_iter(vec![
+        (
+            "chatbot".to_string(),
+            serde_json::Value::String("optional{string}".to_string()),
+        ),
+        (
+            "temperature".to_string(),
+            serde_json::Value::String("optional{float}".to_string()),
+        ),
+        (
+            "auth_key".to_string(),
+            serde_json::Value::String("string".to_string()),
+        ),
+    ]),
+    methods: &[EndpointMethods::Get, EndpointMethods::Post],
+});
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 // Thanks to strum, there's StreamVariant::VARIANTS;
@@ -133,8 +538,22 @@ static RESPONSE: Lazy<serde_json::Value> = Lazy::new(|| {
                 serde_json::to_value(&*PING_SPEC).expect("Unable to serialize JSON"),
                 serde_json::to_value(&*DOCS_SPEC).expect("Unable to serialize JSON"),
                 serde_json::to_value(&*GETTHREAD_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*MESSAGE_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*IMAGE_SPEC).expect("Unable to serialize JSON"),
                 serde_json::to_value(&*STREAMRESPONSE_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*COMPLETE_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*REGENERATE_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*REPLAY_SPEC).expect("Unable to serialize JSON"),
                 serde_json::to_value(&*STOP_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*STOPALL_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*BROADCAST_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*TOOLS_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*DELETETHREAD_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*SHARETHREAD_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*REVOKESHARE_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*SHARED_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*FULLTEXTSEARCH_SPEC).expect("Unable to serialize JSON"),
+                serde_json::to_value(&*USERSETTINGS_SPEC).expect("Unable to serialize JSON"),
             ]),
         ),
     ]))
@@ -145,17 +564,30 @@ pub static RESPONSE_STRING: Lazy<String> =
 
 /// # Ping
 /// Simply returns a short description of the server's capabilities as well as the backend version.
-/// This is in the JSON format and contains three keys: the version, streamvariants and all the endpoint specs in a list.
+/// This is in the JSON format and contains four keys: the version, streamvariants, all the endpoint specs in a list and the state of the LLM circuit breaker.
 ///
 /// The version number is in the form of "Version: x.y.z" where x.y.z is the version of the backend. This follows the rules of SemVer.
 ///
 /// The Endpoints all have four keys: name, methods, params and return_type.
 ///
+/// Additionally, `llm_circuit_breaker` is the state of the circuit breaker around the LLM proxy: "closed" if it works normally,
+/// "open" if requests are rejected because it failed repeatedly, and "half_open" if it's being probed for recovery.
+///
 /// This endpoint can be used to check whether the server is running and whether the interal model of the client matches the server's.
 #[docs_const] // constructs the documentation for this function into PING_DOCS
 pub async fn ping() -> impl Responder {
     trace!("Ping request received.");
-    HttpResponse::Ok().body(RESPONSE_STRING.to_string())
+    // The state of the circuit breaker changes, so it's added to the static part on every request.
+    let mut response = RESPONSE.clone();
+    let breaker_state = with_llm_breaker(|breaker| breaker.state(std::time::Instant::now()));
+    if let serde_json::Value::Object(map) = &mut response {
+        map.insert(
+            "llm_circuit_breaker".to_string(),
+            serde_json::to_value(breaker_state).expect("Unable to serialize JSON"),
+        );
+    }
+    HttpResponse::Ok()
+        .body(serde_json::to_string_pretty(&response).expect("Unable to serialize JSON"))
 }
 
 /// not_found returns a 404 response
@@ -176,14 +608,50 @@ const ALL_DOCS: &str = concatcp!(
     "\n\n",
     GET_THREAD_DOCS,
     "\n\n",
+    GET_MESSAGE_DOCS,
+    "\n\n",
+    GET_IMAGE_DOCS,
+    "\n\n",
+    THREAD_META_DOCS,
+    "\n\n",
     STREAM_RESPONSE_DOCS,
     "\n\n",
+    COMPLETE_DOCS,
+    "\n\n",
+    REGENERATE_DOCS,
+    "\n\n",
+    REPLAY_DOCS,
+    "\n\n",
     GET_USER_THREADS_DOCS,
     "\n\n",
+    DELETE_THREAD_DOCS,
+    "\n\n",
+    SHARE_THREAD_DOCS,
+    "\n\n",
+    REVOKE_SHARE_ENDPOINT_DOCS,
+    "\n\n",
+    LIST_SHARES_ENDPOINT_DOCS,
+    "\n\n",
+    REVOKE_THREAD_SHARES_DOCS,
+    "\n\n",
+    SHARED_THREAD_DOCS,
+    "\n\n",
+    FULL_TEXT_SEARCH_DOCS,
+    "\n\n",
+    USER_SETTINGS_DOCS,
+    "\n\n",
     STOP_DOCS,
     "\n\n",
+    STOP_ALL_DOCS,
+    "\n\n",
+    BROADCAST_DOCS,
+    "\n\n",
     AVAILABLE_CHATBOTS_ENDPOINT_DOCS,
     "\n\n",
+    TOOLS_ENDPOINT_DOCS,
+    "\n\n",
+    TOKEN_ESTIMATE_DOCS,
+    "\n\n",
 );
 pub const DOCS: &str = concatcp!(
     "Version: ",
@@ -209,3 +677,49 @@ pub async fn moved_permanently() -> impl Responder {
     HttpResponse::MovedPermanently()
         .body("The Api Endpoints have changed. Instead of using /ping, etc. use /api/chatbot/ping.")
 }
+
+/// Whether the old endpoints without the /api/chatbot prefix answer with a "moved permanently".
+/// Deployments that never exposed the old paths can turn them off, so they fall through to the 404.
+/// Set via the environment variable `ENABLE_LEGACY_REDIRECTS`; defaults to true for compatibility.
+pub static ENABLE_LEGACY_REDIRECTS: Lazy<bool> =
+    Lazy::new(|| env::var("ENABLE_LEGACY_REDIRECTS").map_or(true, |value| value.trim() != "false"));
+
+/// Registers the old endpoints, which redirect to the new ones, if enabled.
+pub fn configure_legacy_redirects(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if !enabled {
+        debug!("Legacy redirects are disabled.");
+        return;
+    }
+    cfg.route("/ping", web::get().to(moved_permanently))
+        .route("/help", web::get().to(moved_permanently))
+        .route("/stop", web::get().to(moved_permanently))
+        .route("/stop", web::post().to(moved_permanently))
+        .route("/docs", web::get().to(moved_permanently))
+        .route("/getthread", web::get().to(moved_permanently))
+        .route("/streamresponse", web::get().to(moved_permanently));
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_legacy_redirects_can_be_disabled() {
+        for (enabled, expected) in [
+            (true, StatusCode::MOVED_PERMANENTLY),
+            (false, StatusCode::NOT_FOUND),
+        ] {
+            let app = test::init_service(
+                App::new()
+                    .configure(|cfg| configure_legacy_redirects(cfg, enabled))
+                    .default_service(web::route().to(not_found)),
+            )
+            .await;
+            let request = test::TestRequest::get().uri("/ping").to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use std::ffi::CString;
 use std::io::Write;
+use std::time::Duration;
 
 use base64::Engine;
-use pyo3::exceptions::PyBaseException;
-use pyo3::types::{PyDict, PyTuple};
+use once_cell::sync::Lazy;
+use pyo3::types::{PyBytes, PyDict, PyTuple};
 use pyo3::{prelude::*, types::PyList};
 use tracing::{debug, info, trace, warn};
 
@@ -217,16 +218,19 @@ pub fn execute_code(code: String, thread_id: Option<String>) -> Result<String, S
             };
             // We now need to encode the image into the string.
             if let Some(inner_image) = image {
-                // We'll encode the image as base64.
-                let encoded_image = base64::engine::general_purpose::STANDARD.encode(inner_image);
-                // We'll return the image as a string, in the format the other side of the LLM expects.
-                let to_append = format!("\n\nEncoded Image: {encoded_image}");
-                // This needs to be appended to the result, so we can return it.
-                if let Ok(ref mut res) = result {
-                    res.push_str(&to_append);
-                } else {
-                    // If the result is an error, we don't want to append the image to it.
-                    warn!("Error executing code, but we still got an image: {to_append}");
+                match encode_plot(&inner_image, *VALIDATE_PLOTS) {
+                    Ok(encoded_image) => {
+                        append_image(&mut result, &encoded_image, *RETURN_IMAGE_ON_ERROR)
+                    }
+                    // A broken image would only be shown as such, so the LLM and the user are told instead.
+                    Err(reason) => {
+                        warn!("The saved plot is broken, not returning it: {}", reason);
+                        append_to_result(
+                            &mut result,
+                            &format!("\n\n{PLOT_NOT_CAPTURED_NOTE}"),
+                            *RETURN_IMAGE_ON_ERROR,
+                        );
+                    }
                 }
             }
         }
@@ -361,45 +365,100 @@ fn format_pyerr(e: &PyErr, py: Python) -> String {
     }
 }
 
-// Code to save the image from the plt module in a
+/// Whether a plot that was created before the code failed is still returned together with the error.
+/// Set via the environment variable `RETURN_IMAGE_ON_ERROR`; defaults to true.
+static RETURN_IMAGE_ON_ERROR: Lazy<bool> = Lazy::new(|| {
+    std::env::var("RETURN_IMAGE_ON_ERROR").map_or(true, |value| value.trim() != "false")
+});
+
+/// Whether the saved plots are checked to be complete PNGs before they're returned.
+/// A failing backend can leave savefig with an empty or truncated image, which would otherwise be sent as a broken one.
+/// Set via the environment variable `VALIDATE_PLOTS`; defaults to true.
+static VALIDATE_PLOTS: Lazy<bool> =
+    Lazy::new(|| std::env::var("VALIDATE_PLOTS").map_or(true, |value| value.trim() != "false"));
+
+/// Returned in place of a plot that couldn't be read back correctly.
+const PLOT_NOT_CAPTURED_NOTE: &str =
+    "Note: The plot couldn't be captured, the saved figure was empty or corrupt. Try plotting it again.";
+
+/// Encodes the saved plot as base64. If `validate` is set, it has to be a PNG that can be decoded,
+/// otherwise the reason why it's broken is returned.
+fn encode_plot(image: &[u8], validate: bool) -> Result<String, String> {
+    if validate {
+        if image.is_empty() {
+            return Err("The saved figure is empty.".to_string());
+        }
+        if !image.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Err("The saved figure isn't a PNG.".to_string());
+        }
+        image::load_from_memory_with_format(image, image::ImageFormat::Png)
+            .map_err(|e| format!("The saved figure can't be decoded: {e}"))?;
+    }
+    Ok(base64::engine::general_purpose::STANDARD.encode(image))
+}
+
+/// Appends the encoded image to the result, in the format the other side of the LLM expects.
+/// If the code failed, the image is only appended if `on_error` is set, otherwise it's discarded.
+fn append_image(result: &mut Result<String, String>, encoded_image: &str, on_error: bool) {
+    append_to_result(
+        result,
+        &format!("\n\nEncoded Image: {encoded_image}"),
+        on_error,
+    );
+}
+
+/// Appends something about the plot to the output of the code; to the error only if `on_error` is set.
+fn append_to_result(result: &mut Result<String, String>, to_append: &str, on_error: bool) {
+    match result {
+        Ok(output) => output.push_str(to_append),
+        Err(error_output) if on_error => {
+            debug!("Error executing code, but we still got an image; returning it with the error.");
+            error_output.push_str(to_append);
+        }
+        Err(_) => warn!("Error executing code, but we still got an image: {to_append}"),
+    }
+}
 
 /// Helper function to try to get an image from the plt module.
 /// That means that there is probably a plot that we want to return.
+/// The figure is rendered into memory, so concurrent executions can't read each other's plots from a shared file.
+/// Afterwards it's closed, like in a Jupyter notebook, so the next plot starts on an empty figure.
 fn try_get_image(plt: &Bound<PyAny>) -> Option<Vec<u8>> {
-    // I tested this before in a sandbox.
     // First get the string representation of the plt module.
     let name = plt.to_string();
-    if name.starts_with("<module 'matplotlib.pyplot") {
-        // We most likely have a plt module.
-        // But we can't just extract the image from it, we need to save it to a file first.
-        // False, we could save it to a python object first, but would be quite difficult and I don't currently see a reason to do so. FIXME: Maybe later?
-        match plt.call_method1("savefig", ("/tmp/matplotlib_plt.png",)) {
-            Err(e) => {
-                // Something went wrong, but we don't know what.
-                println!("Tried to retrieve image from python code, but failed: {e:?}",);
-            }
-            Ok(_) => {
-                // The file was saved successfully.
-                // Now we can read it and return it.
-
-                // We'll open the file in binary mode.
-                match std::fs::read("/tmp/matplotlib_plt.png") {
-                    Ok(content) => {
-                        // We have the content of the file.
-                        // We can now return it.
-                        return Some(content);
-                    }
-                    Err(e) => {
-                        // We couldn't read the file.
-                        println!("Tried to retrieve image from python code, but failed to read the file: {e:?}");
-                        return None;
-                    }
-                }
-            }
+    if !name.starts_with("<module 'matplotlib.pyplot") {
+        // If it's not a plt module, we'll just return None.
+        return None;
+    }
+    let image = render_figure(plt);
+    if let Err(e) = plt.call_method0("close") {
+        warn!("Failed to close the figure after retrieving its image: {e:?}");
+    }
+    match image {
+        Ok(image) => Some(image),
+        Err(e) => {
+            // Something went wrong, but we don't know what.
+            warn!("Tried to retrieve image from python code, but failed: {e:?}");
+            None
         }
     }
-    // If it's not a plt module, we'll just return None.
-    None
+}
+
+/// Saves the current figure of the plt module as a PNG into an `io.BytesIO` buffer and returns its bytes.
+fn render_figure(plt: &Bound<PyAny>) -> PyResult<Vec<u8>> {
+    let py = plt.py();
+    let buffer = py.import("io")?.call_method0("BytesIO")?;
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("format", "png")?;
+    let image = plt
+        .call_method("savefig", (&buffer,), Some(&kwargs))
+        .and_then(|_| buffer.call_method0("getvalue"))
+        .and_then(|value| Ok(value.downcast_into::<PyBytes>()?.as_bytes().to_vec()));
+    // The buffer is closed however saving went, so its memory is freed right away.
+    if let Err(e) = buffer.call_method0("close") {
+        warn!("Failed to close the buffer of the image: {e:?}");
+    }
+    image
 }
 
 /// Helper function to read the locals from the pickled file.
@@ -503,6 +562,123 @@ else:
     Some(locals)
 }
 
+/// The start of the line the code interpreter prints if the session state couldn't be saved.
+pub const PICKLE_SAVE_FAILED_MARKER: &str = "Pickle Save Failed: ";
+
+/// How often saving the pickle file is tried before giving up.
+/// Set via the environment variable `PICKLE_SAVE_ATTEMPTS`; defaults to 3.
+static PICKLE_SAVE_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    std::env::var("PICKLE_SAVE_ATTEMPTS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(3)
+});
+
+/// How long to wait between two attempts to save the pickle file.
+/// Set via the environment variable `PICKLE_SAVE_RETRY_DELAY_MS`; defaults to 200 milliseconds.
+static PICKLE_SAVE_RETRY_DELAY: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_millis(
+        std::env::var("PICKLE_SAVE_RETRY_DELAY_MS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(200),
+    )
+});
+
+/// The largest the pickle file of a thread may get. Reloading a huge file slows down every execution of the thread,
+/// so the largest variables are left out until the rest fits.
+/// Set via the environment variable `MAX_PICKLE_MB`; defaults to 500 megabytes.
+static MAX_PICKLE_BYTES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_PICKLE_MB")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(500)
+        .saturating_mul(1024 * 1024)
+});
+
+/// Chooses the variables to leave out so the others fit into the cap, largest first.
+fn vars_over_cap(mut sizes: Vec<(String, usize)>, cap: usize) -> Vec<(String, usize)> {
+    let mut total = sizes.iter().map(|(_, size)| size).sum::<usize>();
+    sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    let mut dropped = Vec::new();
+    for (key, size) in sizes {
+        if total <= cap {
+            break;
+        }
+        total -= size;
+        dropped.push((key, size));
+    }
+    dropped
+}
+
+/// Writes the serialized variables to the pickle file, leaving out the largest ones if they don't fit into the cap.
+/// The dropped variables are only logged; the LLM isn't told, it just won't find them in the next execution.
+/// The file is written anew every time, to a temporary file that then replaces the old one, so it never contains stale variables.
+fn store_serialized_vars(
+    py: Python,
+    serialized_vars: &Bound<PyDict>,
+    path: &str,
+    cap: usize,
+) -> PyResult<()> {
+    let sizes = serialized_vars
+        .iter()
+        .map(|(key, value)| Ok((key.extract::<String>()?, value.len()?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    for (key, size) in vars_over_cap(sizes, cap) {
+        warn!(
+            "Not storing the variable {} ({} bytes), the pickle file would be larger than {} bytes.",
+            key, size, cap
+        );
+        serialized_vars.del_item(key)?;
+    }
+
+    let code = CString::new(
+        r"import os
+import pickle
+
+to_store = dict(serialized_vars)
+# In order to be consistent to the new standard, we need at least two variables to store, so they aren't confused with the locals.
+if len(to_store) == 0:
+    to_store['empty'] = pickle.dumps({'empty': None})
+if len(to_store) == 1:
+    to_store['empty2'] = pickle.dumps({'empty2': None})
+
+with open(path + '.tmp', 'wb') as f:
+    for serialized in to_store.values():
+        f.write(serialized)
+os.replace(path + '.tmp', path)",
+    )
+    .expect("Constant CString failed conversion");
+    let locals = PyDict::new(py);
+    locals.set_item("serialized_vars", serialized_vars)?;
+    locals.set_item("path", path)?;
+    py.run(&code, Some(&PyDict::new(py)), Some(&locals))
+}
+
+/// Runs the operation until it succeeds, but at most `attempts` times (and at least once), waiting `delay` in between.
+/// Returns the error of the last attempt if none succeeded.
+fn retry_with_delay<T, E: std::fmt::Debug>(
+    attempts: u32,
+    delay: Duration,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                debug!(
+                    "Attempt {} of {} failed, retrying: {:?}",
+                    attempt, attempts, e
+                );
+                attempt += 1;
+                std::thread::sleep(delay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 /// Helper function to save the locals to a pickle file.
 fn save_to_pickle_file(py: Python, locals: &Bound<PyDict>, thread_id: &str) {
     trace!("Saving the locals to a pickle file.");
@@ -519,15 +695,19 @@ fn save_to_pickle_file(py: Python, locals: &Bound<PyDict>, thread_id: &str) {
 
     // First we filter the locals to only include the ones that are actually serializable.
     // We'll execute some python code to do that.
-    let code = CString::new(format!(
+    let code = CString::new(
         r"import dill # like pickle, but can handle >2GB variables
 from types import ModuleType
 import freva_client
 import inspect
 
 local_items = locals().copy()
-pickleable_vars = {{}}
-unpickleable_vars = {{}}
+pickleable_vars = {}
+unpickleable_vars = {}
+# Every variable is serialized on its own, because dill can't tell which variables are pickleable and which aren't.
+# If we tried to pickle them all at once, it would fail if one of them is not pickleable.
+# The serialized variables are written to the file as they are, so they're only serialized once.
+serialized_vars = {}
 
 for key, value in local_items.items():
     try:
@@ -537,8 +717,7 @@ for key, value in local_items.items():
             continue
         if isinstance(value, freva_client.query.databrowser):
             # We cannot store it as a databrowser result, but we can store it as a list
-            pickleable_vars[key] = list(value)
-            continue # We don't want to store it twice
+            value = list(value)
 
         # Avoid pickling matplotlib objects (Axes, Figure, etc.)
         mod = inspect.getmodule(value.__class__)
@@ -547,46 +726,50 @@ for key, value in local_items.items():
             unpickleable_vars[key] = [None, value]
             continue
         
-        dill.dumps(value)
+        serialized_vars[key] = dill.dumps({key: value})
         pickleable_vars[key] = value
     except Exception as e:
         # We'd like to hint that we can't pickle this variable, but printing would show it to the LLM.
         # So instead we store it in a variable that we access later in Rust.
         unpickleable_vars[key] = [e,value]
-        pass # we'll just assume that it's something we can't handle like a module
-
-# In order to be consistent to the new standard, we need at least two variables to store, so they aren't confused with the locals.
-if len(pickleable_vars) == 0:
-    pickleable_vars['empty'] = None
-if len(pickleable_vars) == 1:
-    pickleable_vars['empty2'] = None
-
-# Save picklable variables
-with open('python_pickles/{thread_id}.pickle', 'wb') as f:
-    # Loop over all the variables and pickle them individually.
-    # This is necessary because dill can't tell which variables are pickleable and which aren't.
-    # If we try to pickle them all at once, it will fail if one of them is not pickleable.
-    for key, value in pickleable_vars.items():
-        # We use dill.dump to save the variables to the file.
-        try:
-            dill.dump({{key: value}}, f)
-        except Exception as e:
-            # If we can't pickle the variable, we'll just skip it.
-            # We'll store the exception in the unpickleable_vars dictionary.
-            unpickleable_vars[key] = [e, value]"
-    )).expect("Constant CString failed conversion");
+        pass # we'll just assume that it's something we can't handle like a module"
+    ).expect("Constant CString failed conversion");
     let locals = locals.clone();
 
-    // We'll run the code.
     match py.run(&code, Some(&PyDict::new(py)), Some(&locals)) {
         Ok(()) => {
-            // The code executed successfully.
-            trace!("Successfully saved the locals to a pickle file.");
+            let serialized_vars = locals
+                .get_item("serialized_vars")
+                .ok()
+                .flatten()
+                .and_then(|x| x.downcast_into::<PyDict>().ok());
+            match serialized_vars {
+                Some(serialized_vars) => {
+                    let path = format!("python_pickles/{thread_id}.pickle");
+                    // Saving can fail transiently (like a full disk that's cleaned up a moment later), so it's retried a few times.
+                    let result =
+                        retry_with_delay(*PICKLE_SAVE_ATTEMPTS, *PICKLE_SAVE_RETRY_DELAY, || {
+                            store_serialized_vars(py, &serialized_vars, &path, *MAX_PICKLE_BYTES)
+                        });
+                    match result {
+                        Ok(()) => {
+                            // The code executed successfully.
+                            trace!("Successfully saved the locals to a pickle file.");
+                        }
+                        Err(e) => {
+                            // The code didn't execute successfully, the variables won't be there in the next execution.
+                            // The marker tells the backend to warn the user about that; it's not part of the output for the LLM.
+                            warn!("Error saving the locals to a pickle file: {:?}", e);
+                            println!("{PICKLE_SAVE_FAILED_MARKER}{e}");
+                        }
+                    }
+                }
+                None => warn!("The serialized variables are missing, not saving the locals."),
+            }
         }
         Err(e) => {
-            // The code didn't execute successfully.
-            warn!("Error saving the locals to a pickle file: {:?}", e);
-            println!("Error saving the locals to a pickle file: {e:?}",);
+            warn!("Error serializing the locals: {:?}", e);
+            println!("{PICKLE_SAVE_FAILED_MARKER}{e}");
         }
     }
 
@@ -622,3 +805,143 @@ with open('python_pickles/{thread_id}.pickle', 'wb') as f:
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transient_save_failure_is_retried() {
+        let path = std::env::temp_dir().join(format!("retry_test_{}.pickle", std::process::id()));
+        let mut attempts = 0;
+        let result = retry_with_delay(3, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts == 1 {
+                // Like a disk that's full for a moment.
+                return Err("No space left on device");
+            }
+            std::fs::write(&path, "state").map_err(|_| "Write failed")
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts, 2);
+        let stored = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(stored.expect("The state should have been stored"), "state");
+
+        // A persistent failure gives up after the last attempt.
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_with_delay(3, Duration::from_millis(1), || {
+            attempts += 1;
+            Err("No space left on device")
+        });
+        assert_eq!(result, Err("No space left on device"));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_plot_before_an_error_is_returned() {
+        // The code plotted something and failed in a later line.
+        let error_output = "Traceback (most recent call last):\n  File \"<string>\", line 3, in <module>\nZeroDivisionError: division by zero";
+        let encoded_image = "aW1hZ2U=";
+
+        let mut result = Err(error_output.to_string());
+        append_image(&mut result, encoded_image, true);
+        let output = result.expect_err("The code still failed");
+        // The code interpreter prints the error like any other output, where the image is then split off.
+        assert!(output.starts_with(error_output));
+        assert!(output
+            .lines()
+            .any(|line| line == format!("Encoded Image: {encoded_image}")));
+
+        // Disabled, the error is returned without the image.
+        let mut result = Err(error_output.to_string());
+        append_image(&mut result, encoded_image, false);
+        assert_eq!(result, Err(error_output.to_string()));
+
+        // Successful code always gets its image.
+        let mut result = Ok("42".to_string());
+        append_image(&mut result, encoded_image, false);
+        assert_eq!(result, Ok(format!("42\n\nEncoded Image: {encoded_image}")));
+    }
+
+    #[test]
+    fn test_pickle_file_stays_under_the_cap() {
+        assert_eq!(
+            vars_over_cap(
+                vec![
+                    ("small".to_string(), 10),
+                    ("huge".to_string(), 300),
+                    ("large".to_string(), 200),
+                ],
+                250
+            ),
+            vec![("huge".to_string(), 300)]
+        );
+
+        let path = std::env::temp_dir().join(format!("cap_test_{}.pickle", std::process::id()));
+        let path = path.to_str().expect("The path is UTF-8");
+        let cap = 1024 * 1024;
+        Python::initialize();
+        let loaded = Python::attach(|py| {
+            // A variable of 2 MB, like a list made from a large dataset, and a small one.
+            let code = CString::new(
+                "import pickle\nserialized_vars = {'large': pickle.dumps({'large': b'x' * (2 * 1024 * 1024)}), 'small': pickle.dumps({'small': 42})}",
+            )
+            .expect("Constant CString failed conversion");
+            let locals = PyDict::new(py);
+            py.run(&code, None, Some(&locals))
+                .expect("The variables can be serialized");
+            let serialized_vars = locals
+                .get_item("serialized_vars")
+                .ok()
+                .flatten()
+                .and_then(|x| x.downcast_into::<PyDict>().ok())
+                .expect("The variables were serialized");
+            // Storing twice rewrites the file instead of appending to it.
+            for _ in 0..2 {
+                store_serialized_vars(py, &serialized_vars, path, cap)
+                    .expect("The variables can be stored");
+            }
+
+            let code = CString::new(
+                "import pickle\nloaded = {}\nwith open(path, 'rb') as f:\n    while True:\n        try:\n            loaded.update(pickle.load(f))\n        except EOFError:\n            break\nloaded = sorted(loaded)",
+            )
+            .expect("Constant CString failed conversion");
+            let locals = PyDict::new(py);
+            locals.set_item("path", path).expect("The path can be set");
+            py.run(&code, None, Some(&locals))
+                .expect("The file can be loaded");
+            locals
+                .get_item("loaded")
+                .ok()
+                .flatten()
+                .and_then(|x| x.extract::<Vec<String>>().ok())
+                .expect("The loaded variables are a list of names")
+        });
+        let size = std::fs::metadata(path).map(|metadata| metadata.len());
+        let _ = std::fs::remove_file(path);
+        assert!(size.expect("The file was stored") <= cap as u64);
+        // The large variable was left out, and there's never only one variable in the file.
+        assert_eq!(loaded, vec!["empty2".to_string(), "small".to_string()]);
+    }
+
+    #[test]
+    fn test_broken_plot_is_replaced_by_a_note() {
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([255, 255, 255]))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .expect("The plot can be encoded as PNG");
+        assert_eq!(
+            encode_plot(&png, true),
+            Ok(base64::engine::general_purpose::STANDARD.encode(&png))
+        );
+
+        // Like savefig on a full disk: an empty file, or one that stops in the middle.
+        for broken in [&[][..], &png[..png.len() / 2], b"not a png"] {
+            let reason = encode_plot(broken, true).expect_err("The plot is broken");
+            assert!(reason.starts_with("The saved figure"), "{reason}");
+        }
+        // Without the validation, the bytes are passed on as they are.
+        assert_eq!(encode_plot(&[], false), Ok(String::new()));
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
+use tracing::{debug, trace};
+
+/// The maximum number of code interpreters that run at the same time, over all threads.
+/// Set via the environment variable `MAX_CONCURRENT_CODE_EXECUTIONS`; defaults to 8.
+pub static MAX_CONCURRENT_CODE_EXECUTIONS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_CONCURRENT_CODE_EXECUTIONS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(8)
+});
+
+static INTERPRETER_SEMAPHORE: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(*MAX_CONCURRENT_CODE_EXECUTIONS));
+
+/// One lock per thread, so that executions of the same thread can't read and write its pickle file at the same time.
+static THREAD_EXECUTION_LOCKS: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the lock for the thread, creating it if needed.
+fn thread_lock(thread_id: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut locks = THREAD_EXECUTION_LOCKS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    // Locks that nobody holds or waits for anymore are only referenced by the map, so they can go.
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    locks.entry(thread_id.to_string()).or_default().clone()
+}
+
+/// Runs the execution once no other execution of the same thread is running and there is a free slot in the global limit.
+/// Executions of different threads still run in parallel.
+pub async fn run_serialized<F: Future>(thread_id: &str, execution: F) -> F::Output {
+    let lock = thread_lock(thread_id);
+    // The thread lock is acquired first, so waiting for the own thread doesn't take a slot from other threads.
+    let _thread_guard = lock.lock().await;
+    trace!("Acquired the execution lock for thread {}.", thread_id);
+    let _permit = INTERPRETER_SEMAPHORE
+        .acquire()
+        .await
+        .expect("The interpreter semaphore is never closed");
+    debug!("Running the code interpreter for thread {}.", thread_id);
+    execution.await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_executions_of_one_thread_are_serialized() {
+        // Each "execution" reads the state, takes a while and then writes it back, like the pickle file.
+        let state = Arc::new(Mutex::new(0));
+        let execution = |state: Arc<Mutex<i32>>| async move {
+            let read = *state.lock().expect("Not poisoned");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            *state.lock().expect("Not poisoned") = read + 1;
+        };
+
+        futures::join!(
+            run_serialized("thread_a", execution(state.clone())),
+            run_serialized("thread_a", execution(state.clone())),
+        );
+        // If they had run at the same time, both would have read 0 and the result would be 1.
+        assert_eq!(*state.lock().expect("Not poisoned"), 2);
+
+        // Different threads don't wait for each other.
+        let start = std::time::Instant::now();
+        futures::join!(
+            run_serialized("thread_b", tokio::time::sleep(Duration::from_millis(100))),
+            run_serialized("thread_c", tokio::time::sleep(Duration::from_millis(100))),
+        );
+        assert!(start.elapsed() < Duration::from_millis(190));
+    }
+}
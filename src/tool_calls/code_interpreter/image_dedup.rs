@@ -0,0 +1,183 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+};
+
+use base64::Engine;
+use once_cell::sync::Lazy;
+use tracing::{debug, trace, warn};
+
+/// Which images are compared when deciding whether a generated image was already returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageDedupScope {
+    /// Only images of the same thread are suppressed.
+    Thread,
+    /// Images already returned in any thread of the same user are suppressed as well, for example after a fork.
+    User,
+    /// Every image is returned.
+    Off,
+}
+
+/// The scope of the image deduplication.
+/// Set via the environment variable `IMAGE_DEDUP_SCOPE` to "thread", "user" or "off"; defaults to thread.
+pub static IMAGE_DEDUP_SCOPE: Lazy<ImageDedupScope> =
+    Lazy::new(
+        || match std::env::var("IMAGE_DEDUP_SCOPE").as_deref().map(str::trim) {
+            Ok("thread") | Err(_) => ImageDedupScope::Thread,
+            Ok("user") => ImageDedupScope::User,
+            Ok("off") => ImageDedupScope::Off,
+            Ok(other) => {
+                warn!("Unknown IMAGE_DEDUP_SCOPE {other}, falling back to thread.");
+                ImageDedupScope::Thread
+            }
+        },
+    );
+
+/// The content hashes of the images that were returned to each user, for the user scope.
+/// This only lives as long as the backend; after a restart, only the thread scope is checked.
+static RETURNED_IMAGES_PER_USER: Lazy<Mutex<HashMap<String, HashSet<u64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The PNG chunks that only contain metadata, like the creation time or the software used.
+/// They differ between two renders of the same plot, so they are ignored for the hash.
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 4] = [b"tIME", b"tEXt", b"zTXt", b"iTXt"];
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Hashes the content of a base64 encoded image.
+/// For PNGs, the metadata chunks (and the CRCs) are skipped, so that visually identical images get the same hash.
+/// Other formats are hashed as they are. Returns None if the image isn't valid base64.
+pub fn image_content_hash(encoded_image: &str) -> Option<u64> {
+    let bytes = match base64::engine::general_purpose::STANDARD.decode(encoded_image.trim()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Could not decode image for deduplication: {:?}", e);
+            return None;
+        }
+    };
+
+    let mut hasher = DefaultHasher::new();
+    let Some(mut rest) = bytes.strip_prefix(PNG_SIGNATURE) else {
+        trace!("Image is not a PNG, hashing all of it.");
+        bytes.hash(&mut hasher);
+        return Some(hasher.finish());
+    };
+
+    // Each chunk is a 4 byte length, a 4 byte type, the data and a 4 byte CRC.
+    while rest.len() >= 12 {
+        let (length, after_length) = rest.split_at(4);
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let (chunk_type, after_type) = after_length.split_at(4);
+        if after_type.len() < length + 4 {
+            debug!("PNG chunk is truncated, hashing the remaining bytes.");
+            break;
+        }
+        let (data, after_data) = after_type.split_at(length);
+        if !PNG_METADATA_CHUNKS
+            .iter()
+            .any(|c| c.as_slice() == chunk_type)
+        {
+            chunk_type.hash(&mut hasher);
+            data.hash(&mut hasher);
+        }
+        rest = &after_data[4..]; // Skip the CRC.
+    }
+    rest.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Filters the newly generated images, removing those that were already returned within the dedup scope.
+/// Takes the images already present in the thread and returns the ones that should be sent to the user.
+pub fn deduplicate_images(
+    new_images: Vec<String>,
+    previous_images: &[String],
+    user_id: &str,
+    scope: ImageDedupScope,
+) -> Vec<String> {
+    if scope == ImageDedupScope::Off {
+        return new_images;
+    }
+
+    let mut seen = previous_images
+        .iter()
+        .filter_map(|image| image_content_hash(image))
+        .collect::<HashSet<_>>();
+
+    // For the user scope, the images of the other threads of the user are also considered.
+    let mut per_user = RETURNED_IMAGES_PER_USER
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    let user_images = per_user.entry(user_id.to_string()).or_default();
+    if scope == ImageDedupScope::User {
+        seen.extend(user_images.iter());
+    }
+
+    let mut images = vec![];
+    for image in new_images {
+        match image_content_hash(&image) {
+            Some(hash) if !seen.insert(hash) => {
+                debug!("Found an image that has already been returned; skipping.");
+                trace!("Skipping image that has already been returned: {}", image);
+            }
+            hash => {
+                // Images that couldn't be hashed are always returned, we can't tell whether they are duplicates.
+                if let Some(hash) = hash {
+                    user_images.insert(hash);
+                }
+                images.push(image);
+            }
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a PNG chunk; the CRC isn't checked, so it's left empty.
+    fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png(time: &[u8]) -> String {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0]));
+        bytes.extend(chunk(b"tIME", time));
+        bytes.extend(chunk(b"IDAT", b"the same pixels"));
+        bytes.extend(chunk(b"IEND", &[]));
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn test_identical_pngs_with_different_timestamps_are_deduplicated() {
+        let first = png(&[7, 233, 10, 16, 12, 0, 0]);
+        let second = png(&[7, 233, 10, 16, 12, 0, 1]);
+        assert_ne!(first, second);
+        assert_eq!(image_content_hash(&first), image_content_hash(&second));
+
+        let images = deduplicate_images(
+            vec![second.clone()],
+            &[first],
+            "testuser",
+            ImageDedupScope::Thread,
+        );
+        assert!(images.is_empty());
+
+        // A new thread of the same user only suppresses it with the user scope.
+        let images = deduplicate_images(
+            vec![second.clone()],
+            &[],
+            "testuser",
+            ImageDedupScope::Thread,
+        );
+        assert_eq!(images.len(), 1);
+        let images = deduplicate_images(vec![second], &[], "testuser", ImageDedupScope::User);
+        assert!(images.is_empty());
+    }
+}
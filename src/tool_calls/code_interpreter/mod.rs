@@ -10,11 +10,27 @@ pub mod safety_check;
 /// For executing the code.
 pub mod execute;
 
+/// For suppressing images that were already returned to the user.
+pub mod image_dedup;
+
+/// For making sure that only one execution per thread runs at a time.
+pub mod execution_lock;
+
+/// For limiting the memory and CPU time of the code interpreter.
+pub mod resource_limits;
+
+/// For reporting the progress of a running code interpreter in the heartbeats.
+pub mod progress;
+
+use std::path::{Component, Path, PathBuf};
+
 use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
 use once_cell::sync::Lazy;
 use serde_json::json;
 use tracing::{debug, warn};
 
+use crate::chatbot::inline_freva_config::INLINE_FREVA_CONFIG_DIR;
+
 /// The code interpreter as a tool.
 /// Needed for the LLM to understand how to call the code interpreter.
 pub static CODE_INTERPRETER_TOOL_TYPE: Lazy<ChatCompletionTool> =
@@ -30,6 +46,7 @@ static CODE_INTERPRETER_FUNCTION: Lazy<FunctionObject> = Lazy::new(|| {
             "Recieves python code, executes it in a jupyter kernel, and returns the result.
 If Matplotlib generates a plot, the plot will be shown to the user.
 Stores the variables from previous executions, so you can use them in later executions.
+For long computations, print lines starting with `PROGRESS:` (like `PROGRESS: 3/10 files`) to show the user how far it is.
 DOES NOT AUTO-IMPORT ANYTHING. You need to import the libraries you need yourself."
                 .to_string(),
         ),
@@ -54,18 +71,194 @@ static CODE_INTERPRETER_PARAMETER: Lazy<serde_json::Value> = Lazy::new(|| {
     })
 });
 
+/// The directory the freva config files of the requests have to be in, so a request can't make the backend read any other file.
+/// Set via the environment variable `FREVA_CONFIG_BASE`; defaults to `/work`, where the project directories with the configs are.
+pub static FREVA_CONFIG_BASE: Lazy<PathBuf> = Lazy::new(|| {
+    std::env::var("FREVA_CONFIG_BASE")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map_or_else(
+            || PathBuf::from("/work"),
+            |value| PathBuf::from(value.trim()),
+        )
+});
+
+/// Why the freva config file can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigAccessError {
+    /// The path points outside of the allowed directories, after resolving `..` and symlinks.
+    NotAllowed,
+    /// There is no file at the path.
+    NotFound,
+    /// The file exists, but can't be read.
+    Unreadable(String),
+}
+
 /// One of the things that the code interpreter needs is the path to the freva config file.
-/// This function gets the path and makes sure we have access to it.
-pub fn verify_can_access(freva_config_path: &str) -> bool {
-    // We'll try to read the file to see if we can access it.
-    match std::fs::read_to_string(freva_config_path) {
-        Ok(content) => {
-            debug!("Successfully read the freva config file: {:?}", content);
-            true
+/// This function checks that the path is inside of `FREVA_CONFIG_BASE` and that we can read the file.
+/// The path comes from the client, so the directory of the inline configs (`INLINE_FREVA_CONFIG_DIR`) is never allowed, even inside of the base:
+/// the backend sets the path of the inline config of a thread itself, and a client must not be able to use the one of another thread.
+/// Returns the resolved path, which is the one that should be given to the code interpreter.
+pub fn verify_can_access(freva_config_path: &str) -> Result<String, ConfigAccessError> {
+    verify_can_access_in(
+        freva_config_path,
+        &[FREVA_CONFIG_BASE.as_path()],
+        &[INLINE_FREVA_CONFIG_DIR.as_path()],
+    )
+}
+
+/// Checks that the path is inside of one of the base directories, but not in one of the excluded ones, and that the file can be read.
+fn verify_can_access_in(
+    freva_config_path: &str,
+    base_dirs: &[&Path],
+    excluded_dirs: &[&Path],
+) -> Result<String, ConfigAccessError> {
+    // The path is first checked as written, so a traversal is rejected without looking at the filesystem.
+    let is_allowed = |path: &Path, resolve: fn(&Path) -> Option<PathBuf>| {
+        let is_inside = |dirs: &[&Path]| {
+            dirs.iter()
+                .filter_map(|dir| resolve(dir))
+                .any(|dir| path.starts_with(dir))
+        };
+        is_inside(base_dirs) && !is_inside(excluded_dirs)
+    };
+    let normalized = normalize_path(Path::new(freva_config_path));
+    if !is_allowed(&normalized, |dir| Some(normalize_path(dir))) {
+        warn!(
+            "The freva config path {:?} is outside of the allowed directories.",
+            freva_config_path
+        );
+        return Err(ConfigAccessError::NotAllowed);
+    }
+
+    // A symlink inside of the directory could still point outside of it.
+    let resolved = match std::fs::canonicalize(&normalized) {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "The freva config file {:?} doesn't exist.",
+                freva_config_path
+            );
+            return Err(ConfigAccessError::NotFound);
+        }
+        Err(e) => return Err(ConfigAccessError::Unreadable(e.to_string())),
+    };
+    if !is_allowed(&resolved, |dir| std::fs::canonicalize(dir).ok()) {
+        warn!(
+            "The freva config path {:?} resolves to {:?}, which is outside of the allowed directories.",
+            freva_config_path, resolved
+        );
+        return Err(ConfigAccessError::NotAllowed);
+    }
+
+    match std::fs::read_to_string(&resolved) {
+        // The config may contain credentials, so its content isn't logged.
+        Ok(_) => {
+            debug!("Successfully read the freva config file {:?}", resolved);
+            Ok(resolved.to_string_lossy().into_owned())
         }
         Err(e) => {
             warn!("Error reading the freva config file: {:?}", e);
-            false
+            Err(ConfigAccessError::Unreadable(e.to_string()))
         }
     }
 }
+
+/// Makes the path absolute and removes its `.` and `..` components without touching the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatbot::handle_active_conversations::generate_id;
+
+    #[test]
+    fn test_config_path_has_to_stay_inside_base() {
+        let base_dir = std::env::temp_dir().join(format!("freva_config_base_{}", generate_id()));
+        let project_dir = base_dir.join("project");
+        std::fs::create_dir_all(&project_dir).expect("The directory can be created");
+        let config = project_dir.join("evaluation_system.conf");
+        std::fs::write(&config, "[evaluation_system]\n").expect("The config can be written");
+        let base_dirs = [base_dir.as_path()];
+
+        // A config inside of the base directory can be used, also when the path takes a detour.
+        let resolved = std::fs::canonicalize(&config)
+            .expect("The config exists")
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(
+            verify_can_access_in(&config.to_string_lossy(), &base_dirs, &[]),
+            Ok(resolved.clone())
+        );
+        let detour = project_dir.join("../project/./evaluation_system.conf");
+        assert_eq!(
+            verify_can_access_in(&detour.to_string_lossy(), &base_dirs, &[]),
+            Ok(resolved)
+        );
+
+        // Escaping the base directory isn't allowed, whether the file exists or not.
+        for traversal in [
+            project_dir.join("../../../../../../etc/shadow"),
+            project_dir.join("../../no_such_file.conf"),
+            PathBuf::from("/etc/passwd"),
+        ] {
+            assert_eq!(
+                verify_can_access_in(&traversal.to_string_lossy(), &base_dirs, &[]),
+                Err(ConfigAccessError::NotAllowed),
+                "{traversal:?}"
+            );
+        }
+        #[cfg(unix)]
+        {
+            let link = project_dir.join("link.conf");
+            std::os::unix::fs::symlink("/etc/passwd", &link).expect("The link can be created");
+            assert_eq!(
+                verify_can_access_in(&link.to_string_lossy(), &base_dirs, &[]),
+                Err(ConfigAccessError::NotAllowed)
+            );
+        }
+
+        // A missing file inside of the base directory is reported as such.
+        assert_eq!(
+            verify_can_access_in(
+                &project_dir.join("missing.conf").to_string_lossy(),
+                &base_dirs,
+                &[]
+            ),
+            Err(ConfigAccessError::NotFound)
+        );
+
+        // The excluded directory isn't allowed, even though it's inside of the base directory.
+        let excluded_dir = base_dir.join("inline");
+        std::fs::create_dir_all(&excluded_dir).expect("The directory can be created");
+        let inline_config = excluded_dir.join("thread.conf");
+        std::fs::write(&inline_config, "[evaluation_system]\n").expect("The config can be written");
+        assert_eq!(
+            verify_can_access_in(
+                &inline_config.to_string_lossy(),
+                &base_dirs,
+                &[excluded_dir.as_path()]
+            ),
+            Err(ConfigAccessError::NotAllowed)
+        );
+
+        std::fs::remove_dir_all(&base_dir).expect("The directory can be removed");
+    }
+}
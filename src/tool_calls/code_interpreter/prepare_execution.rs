@@ -1,20 +1,45 @@
+use std::{
+    future::Future,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
 use async_process::Command;
 
+use futures::{future::Either, AsyncBufReadExt, AsyncReadExt};
 use itertools::Itertools;
 use mongodb::Database;
+use once_cell::sync::Lazy;
+use tokio::sync::mpsc;
 use tracing::{debug, info, trace, warn};
 
 use crate::{
     chatbot::{
         handle_active_conversations::{conversation_state, get_conversation},
+        heartbeat::HEARTBEAT_INTERVAL,
         storage_router::read_thread,
+        truncation::truncate_chars,
         types::{ConversationState, StreamVariant},
     },
     logging::{silence_logger, undo_silence_logger},
     tool_calls::code_interpreter::{
-        execute::execute_code,
-        safety_check::{code_is_likely_safe, sanitize_code},
+        execute::{execute_code, PICKLE_SAVE_FAILED_MARKER},
+        execution_lock::run_serialized,
+        image_dedup::{deduplicate_images, IMAGE_DEDUP_SCOPE},
+        progress::{ProgressTracker, PROGRESS_MARKER},
+        resource_limits::{
+            apply_resource_limits, exceeded_limit, CI_CPU_LIMIT_SECS, CI_MEM_LIMIT_MB,
+        },
+        safety_check::{
+            adjust_freva_pitfalls, check_restricted_patterns, code_is_likely_safe, sanitize_code,
+            CODE_ENV_ALLOWLIST, CODE_SHELL_DENYLIST, FREVA_PITFALL_CHECK,
+        },
     },
+    tool_calls::route_call::ToolUpdate,
 };
 
 #[cfg(debug_assertions)]
@@ -23,16 +48,71 @@ const BIN_PATH: &str = "./target/debug/freva-gpt2-backend";
 #[cfg(not(debug_assertions))]
 const BIN_PATH: &str = "./target/release/freva-gpt2-backend";
 
+/// How much of the output of the code interpreter is given back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeVerbosity {
+    /// The output is truncated and tracebacks only show the frames of the user's code (and where the error was raised).
+    #[default]
+    Concise,
+    /// The output is only limited by the general cap on tool results and tracebacks are complete, for power users.
+    Full,
+}
+
+impl CodeVerbosity {
+    /// Parses the `code_verbosity` parameter; unknown values are None.
+    pub fn from_param(value: &str) -> Option<Self> {
+        match value.trim() {
+            "concise" => Some(CodeVerbosity::Concise),
+            "full" => Some(CodeVerbosity::Full),
+            _ => None,
+        }
+    }
+
+    /// The maximum number of characters of stdout and stderr each, if any.
+    const fn max_output_chars(self) -> Option<usize> {
+        match self {
+            // 1000 was not enough
+            CodeVerbosity::Concise => Some(3500),
+            CodeVerbosity::Full => None,
+        }
+    }
+}
+
+/// The number of times the code interpreter was called without any code since the start of the server.
+pub static EMPTY_CODE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// What the model gets back if it called the code interpreter without code.
+const EMPTY_CODE_CALL_MESSAGE: &str = "The code_interpreter was called without any code, so nothing was executed. To run code, call the code_interpreter again and put the Python code in the `code` field of the arguments, like this: {\"code\": \"print('Hello')\"}";
+
+/// Whether the arguments of a code interpreter call contain no code at all.
+/// That's the case for empty arguments, an empty JSON object or a `code` field with only whitespace.
+/// Malformed JSON is not counted, because that's a different mistake with its own error message.
+fn is_empty_code_call(arguments: &str) -> bool {
+    if arguments.trim().is_empty() {
+        return true;
+    }
+    match serde_json::from_str::<serde_json::Value>(arguments) {
+        Ok(serde_json::Value::Object(map)) => map
+            .get("code")
+            .is_none_or(|code| code.as_str().is_some_and(|code| code.trim().is_empty())),
+        _ => false,
+    }
+}
+
 /// The main function to execute the code interpreter.
 /// Takes in the arguments that were passed to the tool call as well as the id of the tool call (for the output).
 /// Returns the output of the code interpreter as a Vector of StreamVariants.
 /// Requires the thread_id to be set when used by the frontend. It is used to get the freva_config_path.
 /// Also requires the user_id to be set, so that the rw_dir is correctly pointed to.
+/// The verbosity decides how much of the output is returned.
+/// If a sender for the partial output is given, the output printed so far is also sent at every heartbeat while the code is running.
 pub async fn start_code_interpeter(
     arguments: Option<String>,
     id: String,
     thread_id_and_database: Option<(String, Database)>,
     user_id: String,
+    verbosity: CodeVerbosity,
+    partial_output: Option<mpsc::Sender<ToolUpdate>>,
 ) -> Vec<StreamVariant> {
     trace!(
         "Running the code interpreter with the following arguments: {:?}",
@@ -45,8 +125,7 @@ pub async fn start_code_interpeter(
             info!("Thread_id not set, assuming in testing mode. Not setting freva_config_path.");
             (String::new(), "testing".to_string())
         }
-        Some((thread_id, database)) => match conversation_state(&thread_id, database.clone()).await
-        {
+        Some((thread_id, _)) => match conversation_state(&thread_id).await {
             None => {
                 warn!("No conversation state found while trying to run the code interpreter. Not setting freva_config_path, this WILL break any calls to the code interpreter that require it.");
                 (String::new(), thread_id)
@@ -81,13 +160,17 @@ pub async fn start_code_interpeter(
     // Now, we have to convert the arguments from JSON to a struct.
 
     // First check whether the arguments are actually present, maybe the LLM forgot to include them.
-    let Some(code) = arguments else {
-        warn!("No code was found while trying to run the code_interpreter.");
-        return vec![StreamVariant::CodeOutput(
-            "No code was found while trying to run the code_interpreter. Please try again."
-                .to_string(),
-            id,
-        )];
+    // A generic error is often ignored by the model, so it's told exactly what's missing.
+    let code = match arguments {
+        Some(code) if !is_empty_code_call(&code) => code,
+        _ => {
+            let count = EMPTY_CODE_CALLS.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("No code was found while trying to run the code_interpreter. Empty code calls so far: {count}");
+            return vec![StreamVariant::CodeOutput(
+                EMPTY_CODE_CALL_MESSAGE.to_string(),
+                id,
+            )];
+        }
     };
 
     // In order to not import twice, which can appearently cause issues, we'll check the code and remove any imports that are already present.
@@ -103,8 +186,23 @@ pub async fn start_code_interpeter(
         }
     };
 
+    // Reading secrets from the environment or running shell commands is not allowed; the LLM is told why, so it can try another way.
+    if let Err(reason) =
+        check_restricted_patterns(&code.code, &CODE_ENV_ALLOWLIST, &CODE_SHELL_DENYLIST)
+    {
+        return vec![
+            StreamVariant::CodeError(reason.clone()),
+            StreamVariant::CodeOutput(reason, id),
+        ];
+    }
+
     let sanitized_code = sanitize_code(imports + &code.code);
-    let post_processed_code = post_process(sanitized_code, user_id, thread_id);
+    let sanitized_code = if *FREVA_PITFALL_CHECK {
+        adjust_freva_pitfalls(sanitized_code)
+    } else {
+        sanitized_code
+    };
+    let post_processed_code = post_process(sanitized_code, user_id.clone(), thread_id);
     code.code = post_processed_code;
 
     trace!(
@@ -121,18 +219,65 @@ pub async fn start_code_interpeter(
     // Secondly, the python module likes to crash hard sometimes, so if the code interpreter crashes, it won't take the whole chatbot down with it.
     // The code we use will be the same as in the execute_code function.
 
-    let output = Command::new(BIN_PATH)
-        .arg("--code-interpreter")
-        .arg(code.code.clone())
-        .env("EVALUATION_SYSTEM_CONFIG_FILE", freva_config_path)
-        .env(
-            "THREAD_ID",
-            thread_id_and_database
-                .map(|t_a_d| t_a_d.0)
-                .unwrap_or_default(),
-        ) // Extracts the thread_id from the tuple, or uses an empty string if it is None.
-        .output()
-        .await; // It's a future now, so we have to await it.
+    // The executions of one thread share the pickle file, so they have to run one after the other.
+    // Extracts the thread_id from the tuple, or uses an empty string if it is None.
+    let thread_id = thread_id_and_database
+        .map(|t_a_d| t_a_d.0)
+        .unwrap_or_default();
+    // The process is spawned inside the serialized block, so executions of the same thread don't overlap.
+    let timeout = *CODE_INTERPRETER_TIMEOUT;
+    let partial_output =
+        partial_output.map(|sender| Mutex::new(PartialOutput::new(sender, id.clone(), verbosity)));
+    let output = run_serialized(&thread_id, async {
+        // The progress the code reports is shown in the heartbeats until it's done.
+        let progress = ProgressTracker::start(&thread_id);
+        let process = run_interpreter_process(
+            &code.code,
+            &freva_config_path,
+            &thread_id,
+            timeout,
+            |line| {
+                progress.record(line);
+                if let Some(partial_output) = partial_output.as_ref() {
+                    partial_output
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push_line(line);
+                }
+            },
+        );
+        match partial_output.as_ref() {
+            Some(partial_output) => {
+                flush_at_every_tick(process, partial_output, *HEARTBEAT_INTERVAL).await
+            }
+            None => process.await,
+        }
+    })
+    .await;
+
+    // A runaway execution (like an endless loop) was killed; the LLM is told so it doesn't just try again.
+    let output = match output {
+        Ok(Some(output)) => Ok(output),
+        Ok(None) => {
+            let message = format!("Execution timed out after {} seconds", timeout.as_secs());
+            warn!("The code interpreter of thread {}: {}", thread_id, message);
+            return vec![
+                StreamVariant::CodeError(message.clone()),
+                StreamVariant::CodeOutput(message, id),
+            ];
+        }
+        Err(e) => Err(e),
+    };
+
+    // Same if it was stopped by its memory or CPU limit.
+    if let Some(limit) = output.as_ref().ok().and_then(exceeded_limit) {
+        let message = limit.message();
+        warn!("The code interpreter of thread {}: {}", thread_id, message);
+        return vec![
+            StreamVariant::CodeError(message.clone()),
+            StreamVariant::CodeOutput(message, id),
+        ];
+    }
 
     // for now, we'll just return the output as a string. The code interpreter will later be able to return more complex data.
     match output {
@@ -161,48 +306,30 @@ pub async fn start_code_interpeter(
             // In that case, we need to extract the image and return it as a separate stream variant.
             let mut images = vec![];
             let mut stdout_without_images = String::new();
+            let mut pickle_save_failed = false;
             for line in stdout.lines() {
                 if line.starts_with("Encoded Image: ") {
-                    let encoded_image = line.trim_start_matches("Encoded Image: ");
-                    // However, we don't want to return any images that have previously been returned.
-                    // So we need to check the past conversation state for images.
-
-                    if previous_images.contains(&encoded_image.to_string()) {
-                        debug!("Found an image that has already been returned; skipping.");
-                        trace!(
-                            "Skipping image that has already been returned: {}",
-                            encoded_image
-                        );
-                        continue; // Skip this image, it has already been returned.
-                    }
-
-                    images.push(StreamVariant::Image(encoded_image.to_string()));
+                    images.push(line.trim_start_matches("Encoded Image: ").to_string());
+                } else if line.starts_with(PICKLE_SAVE_FAILED_MARKER) {
+                    // Not for the LLM; the user is warned below.
+                    pickle_save_failed = true;
+                } else if line.starts_with(PROGRESS_MARKER) {
+                    // The progress was already shown in the heartbeats.
                 } else {
                     stdout_without_images.push_str(line);
                     stdout_without_images.push('\n');
                 }
             }
 
-            // We might get a problem with the output being too long, so we'll limit it to 3500 characters. (1000 was not enough)
-            // This is a temporary solution, and we'll have to find a better one later. FIXME
-            let stdout_short = if stdout_without_images.len() > 3500 {
-                warn!("The code interpreter output was too long. Truncating to 3500 characters.");
-                stdout_without_images.chars().take(3500).collect()
-            } else {
-                stdout_without_images.to_string()
-            };
-
-            let stderr_short = if stderr.len() > 3500 {
-                warn!("The code interpreter error output was too long. Truncating to 3500 characters.");
-                stderr.chars().take(3500).collect()
-            } else {
-                stderr.to_string()
-            };
-
-            // The LLM probably needs both the stdout and stderr, so we'll return both.
-            let stdout_stderr = format!("{stdout_short}\n{stderr_short}").trim().to_string(); // Because if the stderr is empty, this would add an unnecessary newline.
+            // However, we don't want to return any images that have previously been returned.
+            // They are compared by their content, because re-rendering the same plot gives different bytes.
+            let images = deduplicate_images(images, &previous_images, &user_id, *IMAGE_DEDUP_SCOPE)
+                .into_iter()
+                .map(StreamVariant::Image)
+                .collect::<Vec<_>>();
 
-            let stdout_stderr = post_process_output(&stdout_stderr, &code.code.clone());
+            let stdout_stderr =
+                format_output(&stdout_without_images, &stderr, &code.code, verbosity);
             if stdout_stderr.split_whitespace().next().is_none() {
                 // This will check whether it contains only whitespace.
                 info!("The code interpreter returned an empty output.");
@@ -210,6 +337,18 @@ pub async fn start_code_interpeter(
 
             let mut ouput_vec = vec![StreamVariant::CodeOutput(stdout_stderr, id)];
             ouput_vec.extend(images); // All the images (most of the time, there will be none and almost all other times it should only be one).
+            if pickle_save_failed {
+                warn!(
+                    "The session state of thread {} couldn't be saved.",
+                    thread_id
+                );
+                ouput_vec.push(StreamVariant::ServerHint(
+                    serde_json::json!({
+                        "warning": "The session state couldn't be saved, so the variables of this code execution won't be available in the next one."
+                    })
+                    .to_string(),
+                ));
+            }
             ouput_vec
         }
         Err(output) => {
@@ -219,6 +358,238 @@ pub async fn start_code_interpeter(
     }
 }
 
+/// How long the code interpreter may run before it's killed.
+/// Set via the environment variable `CODE_INTERPRETER_TIMEOUT_SECS`; defaults to 120 seconds.
+pub static CODE_INTERPRETER_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("CODE_INTERPRETER_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(120),
+    )
+});
+
+/// Whether ANSI escape codes (colors, cursor movements of progress bars) are removed from the output of the code interpreter.
+/// The frontend shows them as garbage, and they would be stored and sent to the LLM as well.
+/// Set via the environment variable `STRIP_ANSI_CODES`; defaults to true. Set it to false for clients that render ANSI.
+pub static STRIP_ANSI_CODES: Lazy<bool> =
+    Lazy::new(|| !std::env::var("STRIP_ANSI_CODES").is_ok_and(|value| value.trim() == "false"));
+
+/// Removes the ANSI escape sequences from the text, keeping everything that is visible.
+/// Handles CSI sequences (colors, cursor movements), OSC sequences (titles, links) and the short two-character ones.
+fn strip_ansi_codes(text: &str) -> String {
+    // A CSI sequence ends with a final byte between @ and ~, after its parameters.
+    let skip_csi = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        for c in chars.by_ref() {
+            if ('@'..='~').contains(&c) {
+                break;
+            }
+        }
+    };
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' => match chars.next() {
+                Some('[') => skip_csi(&mut chars),
+                // An OSC sequence ends with BEL or the string terminator ESC \.
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\u{7}' || (c == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // Everything else is a two-character sequence, like ESC 7 to save the cursor.
+                _ => {}
+            },
+            // The single-character CSI that some programs use instead of ESC [.
+            '\u{9b}' => skip_csi(&mut chars),
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+/// Collects the output of a running code interpreter and sends it to the stream at every tick.
+struct PartialOutput {
+    sender: mpsc::Sender<ToolUpdate>,
+    id: String,
+    /// The output that was printed since it was last sent.
+    pending: String,
+    /// How many characters may still be sent. Like for the complete output, the limit depends on the verbosity,
+    /// but it applies to all lines together, so a loop that prints a lot can't flood the stream.
+    remaining_chars: Option<usize>,
+}
+
+impl PartialOutput {
+    fn new(sender: mpsc::Sender<ToolUpdate>, id: String, verbosity: CodeVerbosity) -> Self {
+        Self {
+            sender,
+            id,
+            pending: String::new(),
+            remaining_chars: verbosity.max_output_chars(),
+        }
+    }
+
+    /// Adds a line of the output to the next CodeOutput; images and internal markers are only part of the complete output,
+    /// progress markers are shown in the heartbeats.
+    fn push_line(&mut self, line: &str) {
+        if line.starts_with("Encoded Image: ")
+            || line.starts_with(PICKLE_SAVE_FAILED_MARKER)
+            || line.starts_with(PROGRESS_MARKER)
+        {
+            return;
+        }
+        let mut chunk = if *STRIP_ANSI_CODES {
+            format!("{}\n", strip_ansi_codes(line))
+        } else {
+            format!("{line}\n")
+        };
+        if let Some(remaining_chars) = self.remaining_chars.as_mut() {
+            if *remaining_chars == 0 {
+                return;
+            }
+            let chars = chunk.chars().count();
+            if chars > *remaining_chars {
+                debug!("The partial output of the code interpreter reached its limit, not sending any more.");
+                chunk = truncate_chars(&chunk, *remaining_chars).to_string();
+            }
+            *remaining_chars = remaining_chars.saturating_sub(chars);
+        }
+        self.pending.push_str(&chunk);
+    }
+
+    /// Sends the output that was printed since the last tick as a single CodeOutput.
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let variant = StreamVariant::CodeOutput(std::mem::take(&mut self.pending), self.id.clone());
+        // The stream may be busy; then the output waits for the next tick instead of holding up the code interpreter.
+        match self.sender.try_send(ToolUpdate::Partial(vec![variant])) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(ToolUpdate::Partial(mut variants))) => {
+                if let Some(StreamVariant::CodeOutput(content, _)) = variants.pop() {
+                    self.pending = content;
+                }
+            }
+            Err(e) => trace!("Dropped the partial output: {:?}", e),
+        }
+    }
+}
+
+/// Waits for the code interpreter and sends the output it printed in the meantime at every tick,
+/// so the user sees the latest output with each heartbeat instead of only when the code is done.
+async fn flush_at_every_tick<T>(
+    process: impl Future<Output = T>,
+    partial_output: &Mutex<PartialOutput>,
+    interval: Duration,
+) -> T {
+    let mut process = std::pin::pin!(process);
+    loop {
+        let tick = std::pin::pin!(tokio::time::sleep(interval));
+        match futures::future::select(process.as_mut(), tick).await {
+            Either::Left((output, _)) => return output,
+            Either::Right(((), _)) => partial_output
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush(),
+        }
+    }
+}
+
+/// Runs the code in a new code interpreter process and waits for its output.
+/// Every line of the stdout is passed to `on_line` as soon as the process prints it.
+/// Returns None if it didn't finish within the timeout; the process is then killed together with everything it started.
+async fn run_interpreter_process(
+    code: &str,
+    freva_config_path: &str,
+    thread_id: &str,
+    timeout: Duration,
+    mut on_line: impl FnMut(&str),
+) -> std::io::Result<Option<std::process::Output>> {
+    let mut command = std::process::Command::new(BIN_PATH);
+    command
+        .arg("--code-interpreter")
+        .arg(code)
+        .env("EVALUATION_SYSTEM_CONFIG_FILE", freva_config_path)
+        .env("THREAD_ID", thread_id)
+        // The limits are applied by the code interpreter itself, before it runs any code.
+        .env("CI_MEM_LIMIT_MB", CI_MEM_LIMIT_MB.to_string())
+        .env("CI_CPU_LIMIT_SECS", CI_CPU_LIMIT_SECS.to_string());
+    // The process gets its own process group, so subprocesses started by the Python code can be killed with it.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut command = Command::from(command);
+    command.kill_on_drop(true); // At least the process itself dies if the output isn't awaited anymore.
+                                // The pipes have to be set on the async command, it doesn't take them over from the std one.
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    let (Some(stdout), Some(mut stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(std::io::Error::other(
+            "The output of the code interpreter isn't piped.",
+        ));
+    };
+
+    // The stdout is read line by line, the stderr only at the end. Both have to be read at the same time,
+    // else the process could block on a full pipe.
+    let read_output = async {
+        let read_stdout = async {
+            let mut stdout = futures::io::BufReader::new(stdout);
+            let mut all = Vec::new();
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if stdout.read_until(b'\n', &mut line).await? == 0 {
+                    break;
+                }
+                on_line(String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']));
+                all.extend_from_slice(&line);
+            }
+            Ok::<_, std::io::Error>(all)
+        };
+        let read_stderr = async {
+            let mut all = Vec::new();
+            stderr.read_to_end(&mut all).await?;
+            Ok::<_, std::io::Error>(all)
+        };
+        let (stdout, stderr) = futures::join!(read_stdout, read_stderr);
+        Ok::<_, std::io::Error>(std::process::Output {
+            status: child.status().await?,
+            stdout: stdout?,
+            stderr: stderr?,
+        })
+    };
+    match tokio::time::timeout(timeout, read_output).await {
+        Ok(output) => output.map(Some),
+        Err(_) => {
+            kill_process_group(pid);
+            Ok(None)
+        }
+    }
+}
+
+/// Kills all processes of the process group, whose id is the pid of the code interpreter that started it.
+fn kill_process_group(pid: u32) {
+    // Sending the signal directly would need unsafe code, so the kill command is used.
+    match std::process::Command::new("kill")
+        .arg("-KILL")
+        .arg("--")
+        .arg(format!("-{pid}"))
+        .status()
+    {
+        Ok(status) if status.success() => debug!("Killed the process group {}.", pid),
+        Ok(status) => warn!("Killing the process group {} failed: {}", pid, status),
+        Err(e) => warn!("Couldn't run kill for the process group {}: {:?}", pid, e),
+    }
+}
+
 /// Simple struct to ease the conversion from JSON to a struct.
 #[derive(serde::Deserialize, Debug)]
 struct CodeInterpreterArguments {
@@ -245,6 +616,9 @@ pub fn run_code_interpeter(arguments: String) -> ! {
         );
     }
 
+    // The code is untrusted, so the limits have to be in place before Python starts.
+    apply_resource_limits(*CI_MEM_LIMIT_MB, *CI_CPU_LIMIT_SECS);
+
     let mut thread_id = match std::env::var("THREAD_ID") {
         Err(e) => {
             warn!("Error reading the thread_id environment variable: {:?}", e);
@@ -281,12 +655,13 @@ async fn retrieve_previous_code_interpreter_imports_and_images(
     database: Database,
 ) -> (Vec<String>, Vec<String>) {
     // The running conversation is in the global variable.
-    let mut this_conversation = get_conversation(thread_id).unwrap_or_default();
+    let running_conversation = get_conversation(thread_id).unwrap_or_default();
     // The past conversation is stored on disk.
     silence_logger();
-    let past_conversation = read_thread(thread_id, database).await.unwrap_or_default(); // We don't want to log an error if the file doesn't exist.
+    let mut this_conversation = read_thread(thread_id, database).await.unwrap_or_default(); // We don't want to log an error if the file doesn't exist.
     undo_silence_logger();
-    this_conversation.extend(past_conversation);
+    // In chronological order, so that later imports can take precedence (see sanitize_imports).
+    this_conversation.extend(running_conversation);
 
     let mut imports = Vec::<String>::new();
     for variant in this_conversation.clone() {
@@ -319,8 +694,78 @@ async fn retrieve_previous_code_interpreter_imports_and_images(
     (imports, images)
 }
 
+/// Returns the names an import line binds, together with what they're bound to.
+/// `import numpy as np` binds np to numpy, `import os.path` binds os to os and `from a import b as c` binds c to a.b.
+/// Star imports don't bind anything we know of.
+fn import_bindings(line: &str) -> Vec<(String, String)> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let (module, names) = if let Some(rest) = line.strip_prefix("from ") {
+        let Some((module, names)) = rest.split_once(" import ") else {
+            return vec![];
+        };
+        (Some(module.trim()), names)
+    } else if let Some(names) = line.strip_prefix("import ") {
+        (None, names)
+    } else {
+        return vec![];
+    };
+
+    names
+        .trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+        .split(',')
+        .filter_map(|name| {
+            let mut parts = name.split_whitespace();
+            let target = parts.next().filter(|target| *target != "*")?;
+            let alias = match (parts.next(), parts.next()) {
+                (Some("as"), Some(alias)) => alias.to_string(),
+                // Without an alias, `import a.b` binds a.
+                _ if module.is_none() => target.split('.').next().unwrap_or(target).to_string(),
+                _ => target.to_string(),
+            };
+            let source = match module {
+                Some(module) => format!("{module}.{target}"),
+                None if alias == target.split('.').next().unwrap_or(target) => alias.clone(),
+                None => target.to_string(),
+            };
+            Some((alias, source))
+        })
+        .collect()
+}
+
+/// Removes imports that bind a name which a later import binds to something else.
+/// If the LLM redefines an alias in a later turn (like `import polars as pd`), re-injecting the older import
+/// would silently shadow it, so the most recent one wins. The imports have to be in chronological order.
+fn drop_conflicting_imports(imports: Vec<String>) -> Vec<String> {
+    let mut bound = std::collections::HashMap::<String, String>::new();
+    let mut kept = vec![];
+    // Go from the most recent to the oldest, so the first binding of a name we see is the one that's kept.
+    for import in imports.into_iter().rev() {
+        let bindings = import_bindings(&import);
+        let conflict = bindings.iter().find(|(alias, source)| {
+            bound
+                .get(alias)
+                .is_some_and(|bound_source| bound_source != source)
+        });
+        if let Some((alias, _)) = conflict {
+            trace!(
+                "Dropping import {:?}, because {} is imported differently later on.",
+                import,
+                alias
+            );
+            continue;
+        }
+        for (alias, source) in bindings {
+            bound.entry(alias).or_insert(source);
+        }
+        kept.push(import);
+    }
+    kept.reverse();
+    kept
+}
+
 /// Takes in a list of possible imports and the code that should be run.
 /// Returns a sanitized list of the imports to add to the code.
+/// Imports that conflict with later ones are dropped, see drop_conflicting_imports.
 fn sanitize_imports(prev_imports: Vec<String>, code: &str) -> Vec<String> {
     let mut imports = vec![];
 
@@ -339,6 +784,8 @@ fn sanitize_imports(prev_imports: Vec<String>, code: &str) -> Vec<String> {
         }
     }
 
+    let mut imports = drop_conflicting_imports(imports);
+
     // This newline prevents the imports from accidentally being on the same line
     if !imports.is_empty() {
         imports.push("\n".to_string());
@@ -386,10 +833,95 @@ fn post_process(code: String, user_id: String, thread_id: String) -> String {
     code
 }
 
+/// Combines stdout and stderr into the output for the LLM, shortened according to the verbosity.
+fn format_output(stdout: &str, stderr: &str, code: &str, verbosity: CodeVerbosity) -> String {
+    // We might get a problem with the output being too long, so we'll limit it.
+    // This is a temporary solution, and we'll have to find a better one later. FIXME
+    let shorten = |output: &str, name: &str| match verbosity.max_output_chars() {
+        Some(max_chars) => {
+            let truncated = truncate_chars(output, max_chars);
+            if truncated.len() < output.len() {
+                warn!(
+                    "The code interpreter {name} was too long. Truncating to {max_chars} characters."
+                );
+            }
+            truncated.to_string()
+        }
+        None => output.to_string(),
+    };
+    let stdout_short = shorten(stdout, "output");
+    let stderr_short = shorten(stderr, "error output");
+
+    // The LLM probably needs both the stdout and stderr, so we'll return both.
+    let stdout_stderr = format!("{stdout_short}\n{stderr_short}").trim().to_string(); // Because if the stderr is empty, this would add an unnecessary newline.
+
+    let stdout_stderr = match verbosity {
+        CodeVerbosity::Concise => condense_traceback(&stdout_stderr),
+        CodeVerbosity::Full => stdout_stderr,
+    };
+    post_process_output(&stdout_stderr, code)
+}
+
+/// Removes the frames of a traceback that are neither in the executed code nor the one where the error was raised.
+/// Errors deep inside libraries like xarray produce long tracebacks that mostly consist of the library's internals.
+fn condense_traceback(output: &str) -> String {
+    // A traceback looks like this, the frames being a "File" line followed by the indented source line:
+    // Traceback (most recent call last):
+    //   File "<string>", line 3, in <module>
+    //     ds = xr.open_dataset(path)
+    //   File "/opt/conda/lib/python3.12/site-packages/xarray/backends/api.py", line 566, in open_dataset
+    //     ...
+    let lines = output.lines().collect::<Vec<_>>();
+    let is_frame_start = |line: &str| line.starts_with("  File \"");
+    let Some(traceback_start) = lines.iter().position(|line| line.starts_with("Traceback")) else {
+        return output.to_string();
+    };
+
+    // Group the lines after the traceback header into frames.
+    let mut frames: Vec<Vec<&str>> = vec![];
+    let mut rest_start = lines.len();
+    for (index, line) in lines.iter().enumerate().skip(traceback_start + 1) {
+        if is_frame_start(line) {
+            frames.push(vec![line]);
+        } else if line.starts_with("    ") && !frames.is_empty() {
+            if let Some(frame) = frames.last_mut() {
+                frame.push(line);
+            }
+        } else {
+            rest_start = index;
+            break;
+        }
+    }
+
+    let last_frame = frames.len().saturating_sub(1);
+    let mut condensed = lines[..=traceback_start]
+        .iter()
+        .map(|line| (*line).to_string())
+        .collect::<Vec<_>>();
+    let mut omitted = 0;
+    for (index, frame) in frames.iter().enumerate() {
+        if index == last_frame || frame[0].starts_with("  File \"<string>\"") {
+            if omitted > 0 {
+                condensed.push(format!("  ... ({omitted} library frames omitted)"));
+                omitted = 0;
+            }
+            condensed.extend(frame.iter().map(|line| (*line).to_string()));
+        } else {
+            omitted += 1;
+        }
+    }
+    condensed.extend(lines[rest_start..].iter().map(|line| (*line).to_string()));
+    condensed.join("\n")
+}
+
 /// Post-processes the output before returning it.
-/// Gives hints for SyntaxErrors and Tracebacks.
+/// Strips ANSI escape codes unless `STRIP_ANSI_CODES` is disabled, and gives hints for SyntaxErrors and Tracebacks.
 fn post_process_output(output: &str, code: &str) -> String {
-    let mut output = output.to_string();
+    let mut output = if *STRIP_ANSI_CODES {
+        strip_ansi_codes(output)
+    } else {
+        output.to_string()
+    };
 
     // The line we are looking for is formatted like this: "SyntaxError: invalid syntax # or other error #  (<string>, line 1)"
     // If we find it, we want to insert the line that caused the error.
@@ -560,3 +1092,223 @@ fn setup_logging() -> Option<flexi_logger::LoggerHandle> {
     // Since we have nothing to print if this fails, we'll just ignore the error.
     result.ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_later_alias_wins_over_reinjected_import() {
+        // The first turn used pandas, the second one switched to polars under the same alias.
+        let prev_imports = vec![
+            "import pandas as pd".to_string(),
+            "import numpy as np".to_string(),
+            "import polars as pd".to_string(),
+        ];
+        let imports = sanitize_imports(prev_imports.clone(), "print(pd.DataFrame(np.ones(3)))");
+        assert_eq!(
+            imports,
+            vec!["import numpy as np", "import polars as pd", "\n"]
+        );
+
+        // An import of the code itself is the most recent one of all.
+        let imports = sanitize_imports(prev_imports, "from modin import pandas as pd\nprint(pd)");
+        assert_eq!(
+            imports,
+            vec!["import numpy as np", "from modin import pandas as pd", "\n"]
+        );
+
+        // From-imports bind their names, dotted imports the top-level package.
+        assert_eq!(
+            import_bindings("from os import path, sep as separator"),
+            vec![
+                ("path".to_string(), "os.path".to_string()),
+                ("separator".to_string(), "os.sep".to_string())
+            ]
+        );
+        assert_eq!(
+            import_bindings("import matplotlib.pyplot"),
+            vec![("matplotlib".to_string(), "matplotlib".to_string())]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_runaway_code_is_killed_after_timeout() {
+        let timeout = Duration::from_secs(3);
+        let start = std::time::Instant::now();
+        let output = run_interpreter_process(
+            "import time\ntime.sleep(999)",
+            "",
+            "testing",
+            timeout,
+            |_| {},
+        )
+        .await
+        .expect("The code interpreter should start");
+        assert!(output.is_none(), "The sleep should have timed out");
+        assert!(start.elapsed() < timeout + Duration::from_secs(5));
+    }
+
+    #[actix_web::test]
+    async fn test_output_grows_at_every_tick_while_running() {
+        let (sender, mut receiver) = mpsc::channel(64);
+        let partial_output = Mutex::new(PartialOutput::new(
+            sender,
+            "call_1".to_string(),
+            CodeVerbosity::Concise,
+        ));
+        let process = run_interpreter_process(
+            "import time\nfor i in range(6):\n    print(i, flush=True)\n    time.sleep(0.4)",
+            "",
+            "testing",
+            Duration::from_secs(30),
+            |line| {
+                partial_output
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push_line(line);
+            },
+        );
+        let output = flush_at_every_tick(process, &partial_output, Duration::from_millis(700))
+            .await
+            .expect("The code interpreter should start")
+            .expect("The code doesn't time out");
+        assert!(output.status.success());
+
+        // Every tick sends what was printed since the one before, so the output the user sees keeps growing.
+        let mut seen = String::new();
+        let mut ticks = 0;
+        while let Ok(update) = receiver.try_recv() {
+            let ToolUpdate::Partial(variants) = update else {
+                panic!("Only partial output is sent while the code runs");
+            };
+            let [StreamVariant::CodeOutput(chunk, id)] = &variants[..] else {
+                panic!("Every tick sends a single CodeOutput");
+            };
+            assert_eq!(id, "call_1");
+            assert!(!chunk.is_empty());
+            seen.push_str(chunk);
+            ticks += 1;
+        }
+        assert!(ticks >= 2, "Only {ticks} ticks sent output");
+        assert!(
+            "0\n1\n2\n3\n4\n5\n".starts_with(&seen),
+            "Unexpected output: {seen:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_heartbeat_reports_the_progress_of_the_code() {
+        let thread_id = "progress_test_thread";
+        let progress = ProgressTracker::start(thread_id);
+        let progress_of_heartbeat = move || async move {
+            let StreamVariant::ServerHint(content) =
+                crate::chatbot::heartbeat::heartbeat_content(thread_id).await
+            else {
+                panic!("The heartbeat should be a ServerHint");
+            };
+            let json: serde_json::Value =
+                serde_json::from_str(&content).expect("The heartbeat should be valid JSON");
+            json.get("progress")
+                .and_then(|progress| progress.as_str())
+                .map(|progress| (progress.to_string(), json["elapsed_secs"].clone()))
+        };
+        // Without a report, the heartbeat is the usual one.
+        assert_eq!(progress_of_heartbeat().await, None);
+
+        let process = run_interpreter_process(
+            "import time\nprint('PROGRESS: loading data', flush=True)\ntime.sleep(1.5)\nprint('PROGRESS: plotting', flush=True)\ntime.sleep(1.5)",
+            "",
+            "testing",
+            Duration::from_secs(30),
+            |line| progress.record(line),
+        );
+        let watch_heartbeats = async {
+            let mut reported = vec![];
+            for _ in 0..50 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if let Some((message, elapsed_secs)) = progress_of_heartbeat().await {
+                    assert!(elapsed_secs.is_u64());
+                    if reported.last() != Some(&message) {
+                        reported.push(message);
+                    }
+                }
+            }
+            reported
+        };
+        let (output, reported) = futures::join!(process, watch_heartbeats);
+        let output = output
+            .expect("The code interpreter should start")
+            .expect("The code doesn't time out");
+        assert!(output.status.success());
+        assert_eq!(reported, vec!["loading data", "plotting"]);
+
+        // Once the code is done, the heartbeat doesn't report its progress anymore.
+        drop(progress);
+        assert_eq!(progress_of_heartbeat().await, None);
+    }
+
+    #[test]
+    fn test_ansi_codes_are_stripped_from_output() {
+        let colored = "\u{1b}[1;31mValueError\u{1b}[0m: bad value\n\u{1b}]0;title\u{7}\
+                       50%|\u{1b}[32m#####\u{1b}[0m| 5/10 \u{1b}[K\u{1b}7done\u{9b}2J";
+        assert_eq!(
+            strip_ansi_codes(colored),
+            "ValueError: bad value\n50%|#####| 5/10 done"
+        );
+        // Text without escape codes, including non-ASCII characters, is kept as is.
+        assert_eq!(strip_ansi_codes("Temperatur: 15 °C"), "Temperatur: 15 °C");
+    }
+
+    #[actix_web::test]
+    async fn test_empty_code_call_gets_corrective_message() {
+        for arguments in [
+            None,
+            Some(""),
+            Some("  \n"),
+            Some("{}"),
+            Some(r#"{"code": " "}"#),
+        ] {
+            let output = start_code_interpeter(
+                arguments.map(str::to_string),
+                "call_1".to_string(),
+                None,
+                "testing".to_string(),
+                CodeVerbosity::Concise,
+                None,
+            )
+            .await;
+            assert_eq!(
+                output,
+                vec![StreamVariant::CodeOutput(
+                    EMPTY_CODE_CALL_MESSAGE.to_string(),
+                    "call_1".to_string()
+                )]
+            );
+        }
+        assert!(EMPTY_CODE_CALLS.load(Ordering::Relaxed) >= 5);
+        assert!(!is_empty_code_call(r#"{"code": "print(1)"}"#));
+    }
+
+    #[test]
+    fn test_full_verbosity_returns_more_output() {
+        // A large result, like printing an entire dataset, followed by an error deep inside a library.
+        let stdout = "0.123456789\n".repeat(1000);
+        let stderr = "KeyError: 'tas'\nTraceback (most recent call last):\n  File \"<string>\", line 1, in <module>\n    ds['tas']\n  File \"/opt/conda/lib/python3.12/site-packages/xarray/core/dataset.py\", line 1473, in __getitem__\n    return self._construct_dataarray(key)\n  File \"/opt/conda/lib/python3.12/site-packages/xarray/core/dataset.py\", line 1384, in _construct_dataarray\n    _, name, variable = _get_virtual_variable(self._variables, name, self.sizes)\n  File \"/opt/conda/lib/python3.12/site-packages/xarray/core/dataset_utils.py\", line 79, in _get_virtual_variable\n    raise KeyError(key)";
+        let code = "ds['tas']";
+
+        let concise = format_output(&stdout, stderr, code, CodeVerbosity::Concise);
+        let full = format_output(&stdout, stderr, code, CodeVerbosity::Full);
+        assert!(concise.len() < full.len());
+        assert!(full.contains(&stdout));
+
+        // The concise traceback keeps the user's code and where the error was raised, but not the frames in between.
+        let concise_traceback = format_output("", stderr, code, CodeVerbosity::Concise);
+        assert!(concise_traceback.contains("File \"<string>\", line 1"));
+        assert!(concise_traceback.contains("raise KeyError(key)"));
+        assert!(concise_traceback.contains("(2 library frames omitted)"));
+        assert!(!concise_traceback.contains("_construct_dataarray(key)"));
+        assert!(format_output("", stderr, code, CodeVerbosity::Full)
+            .contains("_construct_dataarray(key)"));
+    }
+}
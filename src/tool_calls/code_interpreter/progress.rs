@@ -0,0 +1,91 @@
+// Keeps the latest progress that a running code interpreter reported, so the heartbeats can tell the user how far it is.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tracing::trace;
+
+/// Lines of the output that start with this are progress reports for the user, not output for the LLM.
+pub const PROGRESS_MARKER: &str = "PROGRESS:";
+
+/// The progress of the code interpreter of a single thread.
+#[derive(Debug)]
+struct Progress {
+    started: Instant,
+    latest: Option<String>,
+}
+
+/// The progress of all running code interpreters, by thread_id.
+/// The executions of a thread are serialized, so there is at most one per thread.
+static CODE_PROGRESS: Lazy<Mutex<HashMap<String, Progress>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Marks the code interpreter of a thread as running and records its progress, until it's dropped.
+#[derive(Debug)]
+pub struct ProgressTracker {
+    thread_id: String,
+}
+
+impl ProgressTracker {
+    /// Starts tracking the execution of the thread; the elapsed time is counted from now.
+    pub fn start(thread_id: &str) -> Self {
+        CODE_PROGRESS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                thread_id.to_string(),
+                Progress {
+                    started: Instant::now(),
+                    latest: None,
+                },
+            );
+        Self {
+            thread_id: thread_id.to_string(),
+        }
+    }
+
+    /// Records the line as the latest progress if it's a progress marker.
+    pub fn record(&self, line: &str) {
+        let Some(message) = progress_message(line) else {
+            return;
+        };
+        trace!(
+            "Code interpreter of thread {} reported progress: {}",
+            self.thread_id,
+            message
+        );
+        if let Some(progress) = CODE_PROGRESS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&self.thread_id)
+        {
+            progress.latest = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        CODE_PROGRESS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.thread_id);
+    }
+}
+
+/// The message of a progress marker line, or None if the line isn't one.
+pub fn progress_message(line: &str) -> Option<&str> {
+    line.strip_prefix(PROGRESS_MARKER).map(str::trim)
+}
+
+/// The latest progress the code interpreter of the thread reported and how long it has been running.
+/// Returns None if no code is running for the thread or it didn't report any progress yet.
+pub fn current_progress(thread_id: &str) -> Option<(String, Duration)> {
+    let progress = CODE_PROGRESS.lock().unwrap_or_else(PoisonError::into_inner);
+    let progress = progress.get(thread_id)?;
+    Some((progress.latest.clone()?, progress.started.elapsed()))
+}
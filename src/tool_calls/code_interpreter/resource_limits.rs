@@ -0,0 +1,128 @@
+// Limits the memory and CPU time of the code interpreter, so a single execution can't take the whole server down.
+
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+
+/// The maximum address space of the code interpreter, in megabytes. 0 disables the limit.
+/// Python, numpy and matplotlib already reserve a few hundred megabytes, so it shouldn't be set too low.
+/// Set via the environment variable `CI_MEM_LIMIT_MB`; defaults to 4096.
+pub static CI_MEM_LIMIT_MB: Lazy<u64> = Lazy::new(|| {
+    std::env::var("CI_MEM_LIMIT_MB")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(4096)
+});
+
+/// The maximum CPU time of the code interpreter, in seconds. 0 disables the limit.
+/// Unlike the timeout, this counts the time of all threads, so parallel numpy code reaches it sooner.
+/// Set via the environment variable `CI_CPU_LIMIT_SECS`; defaults to 120.
+pub static CI_CPU_LIMIT_SECS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("CI_CPU_LIMIT_SECS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(120)
+});
+
+/// The signal the kernel sends once the CPU limit is reached.
+#[cfg(target_os = "linux")]
+const SIGXCPU: i32 = 24;
+
+/// Which of the limits the code interpreter ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Memory,
+    Cpu,
+}
+
+impl LimitExceeded {
+    /// The message for the user and the LLM.
+    pub fn message(self) -> String {
+        match self {
+            Self::Memory => format!(
+                "Execution ran out of memory (limit: {} MB). Try working on a smaller subset of the data.",
+                *CI_MEM_LIMIT_MB
+            ),
+            Self::Cpu => format!(
+                "Execution was stopped after using {} seconds of CPU time.",
+                *CI_CPU_LIMIT_SECS
+            ),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Limits the process it's called in; the code interpreter calls it before running any code.
+/// A limit of 0 is not applied.
+pub fn apply_resource_limits(mem_limit_mb: u64, cpu_limit_secs: u64) {
+    if mem_limit_mb > 0 {
+        let bytes = mem_limit_mb.saturating_mul(1024 * 1024);
+        if let Err(e) = rlimit::Resource::AS.set(bytes, bytes) {
+            warn!("Couldn't limit the memory of the code interpreter: {:?}", e);
+        }
+    }
+    if cpu_limit_secs > 0 {
+        // The soft limit sends SIGXCPU, the hard limit one second later makes sure the process dies even if that's ignored.
+        if let Err(e) = rlimit::Resource::CPU.set(cpu_limit_secs, cpu_limit_secs + 1) {
+            warn!(
+                "Couldn't limit the CPU time of the code interpreter: {:?}",
+                e
+            );
+        }
+    }
+    debug!(
+        "Limited the code interpreter to {} MB and {} seconds of CPU time (0 is unlimited).",
+        mem_limit_mb, cpu_limit_secs
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Limits the process it's called in; the code interpreter calls it before running any code.
+pub fn apply_resource_limits(_mem_limit_mb: u64, _cpu_limit_secs: u64) {
+    debug!("Resource limits are only implemented for Linux (Docker), skipping.");
+}
+
+/// Checks whether the code interpreter ran into one of its limits.
+/// Reaching the memory limit makes Python raise a MemoryError (numpy raises a subclass of it), the CPU limit kills the process.
+pub fn exceeded_limit(output: &std::process::Output) -> Option<LimitExceeded> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if *CI_CPU_LIMIT_SECS > 0 && output.status.signal() == Some(SIGXCPU) {
+            return Some(LimitExceeded::Cpu);
+        }
+    }
+    let ran_out_of_memory = [&output.stdout, &output.stderr]
+        .iter()
+        .any(|stream| String::from_utf8_lossy(stream).contains("MemoryError"));
+    (*CI_MEM_LIMIT_MB > 0 && ran_out_of_memory).then_some(LimitExceeded::Memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_exceeded_limit_tells_memory_and_cpu_apart() {
+        use std::os::unix::process::ExitStatusExt;
+        let output = |status: i32, stdout: &str| std::process::Output {
+            status: std::process::ExitStatus::from_raw(status),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        };
+
+        assert_eq!(exceeded_limit(&output(0, "4")), None);
+        assert_eq!(
+            exceeded_limit(&output(
+                0,
+                "numpy.core._exceptions._ArrayMemoryError: Unable to allocate 16.0 GiB"
+            )),
+            Some(LimitExceeded::Memory)
+        );
+        // A raw status that is just a signal number means the process was killed by it.
+        assert_eq!(
+            exceeded_limit(&output(SIGXCPU, "")),
+            Some(LimitExceeded::Cpu)
+        );
+    }
+}
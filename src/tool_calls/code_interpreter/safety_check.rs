@@ -1,7 +1,159 @@
-use tracing::{debug, warn};
+use std::ffi::CString;
+
+use once_cell::sync::Lazy;
+use pyo3::{prelude::*, types::PyDict};
+use tracing::{debug, error, warn};
+
+/// Parses a comma-separated list from an environment variable, falling back to the defaults if it's not set.
+fn list_from_env(name: &str, defaults: &[&str]) -> Vec<String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect(),
+        Err(_) => defaults.iter().map(|entry| (*entry).to_string()).collect(),
+    }
+}
+
+/// The environment variables that generated code is allowed to read. Reading any other one is rejected, because it could leak secrets like the AUTH_KEY.
+/// Generated code can't import `os` (see CODE_IMPORT_BLOCKLIST), so this only matters for the ways to the environment the import checks miss;
+/// the freva library reads its config file from the environment itself, generated code never needs to.
+/// Set via the environment variable `CODE_ENV_ALLOWLIST` as a comma-separated list; defaults to the freva config file.
+pub static CODE_ENV_ALLOWLIST: Lazy<Vec<String>> =
+    Lazy::new(|| list_from_env("CODE_ENV_ALLOWLIST", &["EVALUATION_SYSTEM_CONFIG_FILE"]));
+
+/// Line prefixes that are rejected, because they are notebook syntax for running shell commands.
+/// Set via the environment variable `CODE_SHELL_DENYLIST` as a comma-separated list.
+pub static CODE_SHELL_DENYLIST: Lazy<Vec<String>> = Lazy::new(|| {
+    list_from_env(
+        "CODE_SHELL_DENYLIST",
+        &[
+            "!", "%%bash", "%%sh", "%%script", "%%system", "%system", "%sx",
+        ],
+    )
+});
+
+/// The modules that generated code must not import, not even a submodule of them (like `os.path`).
+/// Set via the environment variable `CODE_IMPORT_BLOCKLIST_FILE`, the path to a file with one module per line; lines starting with `#` are comments.
+/// Defaults to the modules that allow running commands or reaching the network and file system outside of Python,
+/// and the ones that hand out other modules by name (like `importlib.import_module("os")` or `sys.modules["os"]`).
+pub static CODE_IMPORT_BLOCKLIST: Lazy<Vec<String>> = Lazy::new(|| {
+    const DEFAULT_BLOCKLIST: [&str; 10] = [
+        "os",
+        "posix",
+        "nt",
+        "subprocess",
+        "socket",
+        "shutil",
+        "ctypes",
+        "sys",
+        "importlib",
+        "builtins",
+    ];
+    match std::env::var("CODE_IMPORT_BLOCKLIST_FILE") {
+        Err(_) => DEFAULT_BLOCKLIST
+            .iter()
+            .map(|module| (*module).to_string())
+            .collect(),
+        Ok(path) => match std::fs::read_to_string(path.trim()) {
+            Ok(content) => parse_blocklist(&content),
+            Err(e) => {
+                // Running without a blocklist would be worse than the defaults.
+                error!(
+                    "Could not read the import blocklist from {}, using the default one: {:?}",
+                    path, e
+                );
+                DEFAULT_BLOCKLIST
+                    .iter()
+                    .map(|module| (*module).to_string())
+                    .collect()
+            }
+        },
+    }
+});
+
+/// Names that generated code must not use at all, neither directly nor as an attribute (like `builtins.eval`),
+/// because they run code or import modules that the checks can't see, like `getattr(__builtins__, "ev" + "al")`.
+const BLOCKED_NAMES: [&str; 6] = [
+    "__import__",
+    "eval",
+    "exec",
+    "import_module",
+    "__builtins__",
+    "globals",
+];
+
+/// Parses the content of an import blocklist file: one module per line, empty lines and lines starting with `#` are ignored.
+fn parse_blocklist(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Checks the code with Python's ast module, which also finds imports that the string patterns miss (like `from  os import system`).
+/// Returns the reason why the code is rejected, if it is. Code that can't be parsed isn't rejected here, running it fails anyway.
+fn ast_check(code: &str, blocklist: &[String]) -> Option<String> {
+    Python::initialize();
+    let script = CString::new(
+        r#"import ast
+violation = None
+try:
+    tree = ast.parse(code)
+except Exception:
+    tree = None
+for node in (ast.walk(tree) if tree is not None else []):
+    if isinstance(node, ast.Import):
+        modules = [alias.name for alias in node.names]
+    elif isinstance(node, ast.ImportFrom) and node.module and node.level == 0:
+        modules = [node.module]
+    else:
+        modules = []
+    blocked = [module for module in modules if module.split(".")[0] in blocklist]
+    if blocked:
+        violation = "import of " + blocked[0]
+    elif isinstance(node, ast.Name) and node.id in blocked_names:
+        violation = "use of " + node.id
+    elif isinstance(node, ast.Attribute) and node.attr in blocked_names:
+        violation = "attribute access of " + node.attr
+    if violation is not None:
+        break
+"#,
+    )
+    .expect("Constant CString failed conversion");
+
+    Python::attach(|py| {
+        // The comprehensions in the script only see the globals, so everything is put there.
+        let globals = PyDict::new(py);
+        let result = globals
+            .set_item("code", code)
+            .and_then(|()| globals.set_item("blocklist", blocklist.to_vec()))
+            .and_then(|()| globals.set_item("blocked_names", BLOCKED_NAMES.to_vec()))
+            .and_then(|()| py.run(&script, Some(&globals), None))
+            .and_then(|()| {
+                globals
+                    .get_item("violation")
+                    .map(|violation| violation.and_then(|violation| violation.extract().ok()))
+            });
+        match result {
+            Ok(violation) => violation,
+            Err(e) => {
+                // The check itself failing is suspicious, so the code isn't run.
+                error!("The AST safety check failed: {:?}", e);
+                Some("the AST check failed".to_string())
+            }
+        }
+    })
+}
 
 /// Checks whether the given code passes the basic safety checks.
 /// The code should actually be in JSON format, but our checks should be able to handle that.
+/// The checks catch the obvious ways of running commands or escaping to the system, they aren't a security boundary:
+/// Python is dynamic enough that determined code can get around any static check.
+/// What the code can do in the end is limited by the resource limits and the permissions of the process it runs in.
 pub fn code_is_likely_safe(code: &String) -> bool {
     // For now, we'll implement a simple check: test whether a "dangerous pattern" is present.
 
@@ -29,10 +181,87 @@ pub fn code_is_likely_safe(code: &String) -> bool {
         }
     }
 
-    // Later, we'll expand this to include more sophisticated checks.
+    // The string patterns are easy to get around, so the code itself is also checked with Python's parser.
+    // The reason is only logged, a potential attacker shouldn't learn what was detected.
+    let parsed_code = serde_json::from_str::<serde_json::Value>(code).ok();
+    if let Some(code) = parsed_code
+        .as_ref()
+        .and_then(|arguments| arguments.get("code"))
+        .and_then(serde_json::Value::as_str)
+    {
+        if let Some(reason) = ast_check(code, &CODE_IMPORT_BLOCKLIST) {
+            warn!("The code was rejected by the AST check: {}", reason);
+            debug!("The code is: {}", code);
+            return false;
+        }
+    }
+
     true
 }
 
+/// Checks the code for reads of the environment and for shell escapes.
+/// Returns an explanation of the restriction for the first violation found.
+/// Unlike code_is_likely_safe, this gives the reason back, because the code is likely not malicious, just not allowed here.
+pub fn check_restricted_patterns(
+    code: &str,
+    env_allowlist: &[String],
+    shell_denylist: &[String],
+) -> Result<(), String> {
+    for line in code.lines() {
+        let line = line.trim_start();
+        if let Some(prefix) = shell_denylist
+            .iter()
+            .find(|prefix| line.starts_with(prefix.as_str()))
+        {
+            warn!("The code contains a shell escape: {}", line);
+            return Err(format!("Shell commands (lines starting with \"{prefix}\") are not allowed in the code interpreter. Please use Python instead."));
+        }
+    }
+
+    // Every access of the environment has to name an allowed variable as a string literal.
+    // Like this: os.environ['X'], os.environ.get('X'), os.getenv('X') or getenv("X").
+    for (index, pattern) in code
+        .match_indices("environ")
+        .chain(code.match_indices("getenv"))
+    {
+        let rest = &code[index + pattern.len()..];
+        // Only whole words count, so something like print("environment") is fine.
+        let is_identifier_char = |c: char| c.is_alphanumeric() || c == '_';
+        if rest.starts_with(is_identifier_char) || code[..index].ends_with(is_identifier_char) {
+            continue;
+        }
+        let key = if pattern == "getenv" {
+            rest.strip_prefix('(').and_then(string_literal_at_start)
+        } else if let Some(rest) = rest.strip_prefix('[') {
+            string_literal_at_start(rest)
+        } else {
+            rest.strip_prefix(".get(").and_then(string_literal_at_start)
+        };
+        match key {
+            Some(key) if env_allowlist.iter().any(|allowed| allowed == key) => {
+                debug!("The code reads the allowed environment variable {}", key);
+            }
+            Some(key) => {
+                warn!("The code reads the environment variable {}", key);
+                return Err(format!("Reading the environment variable {key} is not allowed in the code interpreter."));
+            }
+            None => {
+                warn!("The code accesses the environment in a way that can't be checked.");
+                return Err("Accessing the environment is not allowed in the code interpreter, except for reading specific, allowed variables by name.".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the content of the string literal at the start of the text, if there is one.
+fn string_literal_at_start(text: &str) -> Option<&str> {
+    let text = text.trim_start();
+    let quote = text.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let content = &text[1..];
+    content.find(quote).map(|end| &content[..end])
+}
+
 /// Sanitizes the code for problems that we want to avoid.
 /// This isn't something like rm rf, but instead things like using the wrong matplotlib backend.
 pub fn sanitize_code(code: String) -> String {
@@ -55,3 +284,149 @@ pub fn sanitize_code(code: String) -> String {
 
     code
 }
+
+/// Whether generated code that uses the freva databrowser is adjusted for common pitfalls, see adjust_freva_pitfalls.
+/// Set via the environment variable `FREVA_PITFALL_CHECK`; defaults to false.
+pub static FREVA_PITFALL_CHECK: Lazy<bool> =
+    Lazy::new(|| std::env::var("FREVA_PITFALL_CHECK").is_ok_and(|value| value.trim() == "true"));
+
+/// Adjusts patterns in code that uses the freva databrowser that often lead to errors.
+/// Only transformations that can't change what correct code does are made:
+/// - A databrowser result that is passed directly to open_mfdataset is wrapped in list(), like save_to_pickle_file does when storing it.
+/// - A databrowser search without a time selection gets a reminder comment above it.
+pub fn adjust_freva_pitfalls(code: String) -> String {
+    // The names that the result of a databrowser search is assigned to, like "files = freva_client.databrowser(...)".
+    let databrowser_results = code
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.trim().split_once('=')?;
+            let name = name.trim();
+            let is_search = value.trim().starts_with("freva_client.databrowser(")
+                || value.trim().starts_with("databrowser(");
+            (is_search && name.chars().all(|c| c.is_alphanumeric() || c == '_') && !name.is_empty())
+                .then(|| name.to_string())
+        })
+        .collect::<Vec<_>>();
+    if databrowser_results.is_empty() {
+        return code;
+    }
+
+    let mut adjusted = Vec::new();
+    for line in code.lines() {
+        let mut line = line.to_string();
+        for name in &databrowser_results {
+            for ending in [")", ","] {
+                line = line.replace(
+                    &format!("open_mfdataset({name}{ending}"),
+                    &format!("open_mfdataset(list({name}){ending}"),
+                );
+            }
+        }
+        if line.contains("databrowser(") && !line.contains("time=") {
+            let indentation = &line[..line.len() - line.trim_start().len()];
+            adjusted.push(format!("{indentation}# Reminder: select the time in the search with time=\"YYYY-MM-DDtoYYYY-MM-DD\", time_select=\"flexible\" instead of filtering the files."));
+        }
+        adjusted.push(line);
+    }
+    debug!("Adjusted the code for freva pitfalls.");
+    adjusted.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_reads_need_to_be_allowed() {
+        let allowlist = vec!["EVALUATION_SYSTEM_CONFIG_FILE".to_string()];
+        let denylist = vec!["!".to_string(), "%%bash".to_string()];
+
+        let blocked = check_restricted_patterns(
+            "import os\nprint(os.environ['AUTH_KEY'])",
+            &allowlist,
+            &denylist,
+        );
+        assert!(blocked.is_err_and(|reason| reason.contains("AUTH_KEY")));
+        assert!(
+            check_restricted_patterns("print(dict(os.environ))", &allowlist, &denylist).is_err()
+        );
+
+        assert_eq!(
+            check_restricted_patterns(
+                "config = os.environ.get(\"EVALUATION_SYSTEM_CONFIG_FILE\")\nprint(config)",
+                &allowlist,
+                &denylist,
+            ),
+            Ok(())
+        );
+
+        // Notebook shell escapes are rejected, even if indented.
+        assert!(check_restricted_patterns("  !cat /etc/passwd", &allowlist, &denylist).is_err());
+        assert!(check_restricted_patterns("%%bash\nenv", &allowlist, &denylist).is_err());
+        assert_eq!(
+            check_restricted_patterns("a = 1\nprint(a != 2)", &allowlist, &denylist),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_ast_check_blocks_dangerous_imports() {
+        let blocklist =
+            parse_blocklist("# dangerous modules\nos\n\nsubprocess\n  socket\nshutil\nctypes\n");
+        assert_eq!(
+            blocklist,
+            ["os", "subprocess", "socket", "shutil", "ctypes"]
+        );
+        let blocklist = &*CODE_IMPORT_BLOCKLIST;
+
+        for code in [
+            "import os",
+            "from  os import system\nsystem('ls')",
+            "import os.path",
+            "import subprocess as sp",
+            "from socket import socket",
+            "import json, shutil",
+            "if True:\n    import ctypes",
+            "x = __import__('o' + 's')",
+            "import builtins\nbuiltins.eval('1 + 1')",
+            "f = exec",
+            // The ways around the import checks, by name.
+            "import importlib\nimportlib.import_module('os')",
+            "from importlib import import_module\nimport_module('os')",
+            "import sys\nsys.modules['os'].system('ls')",
+            "from sys import modules\nmodules['os']",
+            "getattr(__builtins__, 'ev' + 'al')('1 + 1')",
+            "globals()['__builtins__']",
+            "import posix\nposix.system('ls')",
+        ] {
+            assert!(ast_check(code, blocklist).is_some(), "{code}");
+        }
+
+        for code in [
+            "import numpy as np\nprint(np.mean([1, 2]))",
+            "import osgeo",
+            "from matplotlib import pyplot as plt",
+            "evaluation = 1\nprint(evaluation)",
+            "import xarray as xr\nds = xr.Dataset()\nds",
+            "this is not valid python",
+        ] {
+            assert_eq!(ast_check(code, blocklist), None, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_freva_pitfalls_are_adjusted() {
+        let code = "import freva_client\nimport xarray as xr\nfiles = freva_client.databrowser(project='reanalysis', variable='tas')\ndset = xr.open_mfdataset(files, combine='by_coords')\nother = xr.open_mfdataset(paths)\ndset";
+        let adjusted = adjust_freva_pitfalls(code.to_string());
+        assert!(adjusted.contains("xr.open_mfdataset(list(files), combine='by_coords')"));
+        assert!(adjusted.contains("xr.open_mfdataset(paths)"));
+        assert!(adjusted.contains("# Reminder: select the time"));
+        assert!(adjusted.ends_with("\ndset"));
+
+        // Code without a databrowser search, or with a time selection, stays as it is.
+        let unrelated = "import xarray as xr\nds = xr.open_mfdataset(files)\nds";
+        assert_eq!(adjust_freva_pitfalls(unrelated.to_string()), unrelated);
+        let with_time = "files = freva_client.databrowser(variable='tas', time='2023-01-01to2023-12-31')\nfiles";
+        assert_eq!(adjust_freva_pitfalls(with_time.to_string()), with_time);
+    }
+}
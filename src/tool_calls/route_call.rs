@@ -5,16 +5,42 @@ use std::{fs::OpenOptions, io::Read, time::UNIX_EPOCH};
 use fs2::FileExt;
 use itertools::Itertools;
 use mongodb::Database;
+use once_cell::sync::Lazy;
 use std::io::Write;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 
-use crate::chatbot::types::StreamVariant;
+use crate::chatbot::{truncation::truncate_chars, types::StreamVariant};
 
-use super::code_interpreter::prepare_execution::start_code_interpeter;
+use super::code_interpreter::prepare_execution::{start_code_interpeter, CodeVerbosity};
 
 pub static SUPPORTED_TOOLS: &[&str] = &["code_interpreter"];
 
+/// The maximum number of characters of a tool result that is put into the conversation.
+/// Every character of it is sent to the LLM again in each following request, so a verbose tool can use up the context.
+/// Set via the environment variable `MAX_TOOL_RESULT_CHARS`; defaults to 10000.
+pub static MAX_TOOL_RESULT_CHARS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("MAX_TOOL_RESULT_CHARS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(10000)
+});
+
+/// Whether the output of the code interpreter is streamed to the client while the code is still running.
+/// At every heartbeat, the output printed since the last one is sent as a CodeOutput variant; the complete output follows once the code finished and replaces them.
+/// Set via the environment variable `STREAM_CODE_OUTPUT`; defaults to false, because the frontend has to replace the partial output.
+pub static STREAM_CODE_OUTPUT: Lazy<bool> =
+    Lazy::new(|| std::env::var("STREAM_CODE_OUTPUT").is_ok_and(|value| value.trim() == "true"));
+
+/// What a running tool call sends back to the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolUpdate {
+    /// Output of the tool while it's still running. It's only for the client, it isn't stored and the LLM doesn't see it.
+    Partial(Vec<StreamVariant>),
+    /// The complete result of the tool call, which is stored and given to the LLM.
+    Done(Vec<StreamVariant>),
+}
+
 /// Routes a tool call to the appropriate function.
 pub async fn route_call(
     func_name: String,
@@ -22,8 +48,9 @@ pub async fn route_call(
     id: String,
     thread_id: String,
     user_id: String,
-    sender: mpsc::Sender<Vec<StreamVariant>>,
+    sender: mpsc::Sender<ToolUpdate>,
     database: Database,
+    code_verbosity: CodeVerbosity,
 ) {
     // // Placeholder to disable the code interpreter
     // let variant = StreamVariant::CodeOutput("The code interpreter was successfully called, but is currently disabled. Please wait for the next major version for it to be stabilized. ".to_string(), id);
@@ -37,8 +64,20 @@ pub async fn route_call(
         // The code interpreter has a severe overhead that is quite inconsistent. In order to track it down, several points of interest will record when they are reached.
         let routing_pit = std::time::SystemTime::now(); // The point in time when the routing function is reached.
 
+        let output = start_code_interpeter(
+            arguments,
+            id,
+            Some((thread_id, database)),
+            user_id,
+            code_verbosity,
+            STREAM_CODE_OUTPUT.then(|| sender.clone()),
+        )
+        .await;
         let result = sender
-            .send(start_code_interpeter(arguments, id, Some((thread_id, database)), user_id).await)
+            .send(ToolUpdate::Done(cap_tool_result(
+                output,
+                *MAX_TOOL_RESULT_CHARS,
+            )))
             .await;
 
         let return_pit = std::time::SystemTime::now(); // The point in time when the code interpreter returns.
@@ -54,7 +93,7 @@ pub async fn route_call(
             func_name, supported_tools
         );
         let answer = vec![StreamVariant::CodeOutput(format!("The function '{func_name}' is not recognized. Supported tools are: {supported_tools}"), id)];
-        sender.send(answer).await
+        sender.send(ToolUpdate::Done(answer)).await
     };
 
     if let Err(e) = senderror {
@@ -62,6 +101,29 @@ pub async fn route_call(
     }
 }
 
+/// Truncates the text output of a tool to at most max_chars characters, with a note that tells the LLM it was truncated.
+/// Images are not touched, they are handled separately when the messages for the LLM are built.
+fn cap_tool_result(output: Vec<StreamVariant>, max_chars: usize) -> Vec<StreamVariant> {
+    output
+        .into_iter()
+        .map(|variant| match variant {
+            StreamVariant::CodeOutput(content, id) if content.chars().count() > max_chars => {
+                warn!(
+                    "Tool result with {} characters exceeds the maximum of {}, truncating it.",
+                    content.chars().count(),
+                    max_chars
+                );
+                let truncated = truncate_chars(&content, max_chars);
+                StreamVariant::CodeOutput(
+                    format!("{truncated}\n[Output truncated after {max_chars} characters]"),
+                    id,
+                )
+            }
+            other => other,
+        })
+        .collect()
+}
+
 // Note that I want to be able to debug this on my local machine too where docker doesn't work.
 #[cfg(target_os = "macos")]
 const DEBUG_OVERHEAD_FILE_PATH: &str = "./testdata/debug_overhead.log";
@@ -163,3 +225,26 @@ pub fn print_and_clear_tool_logs(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oversized_tool_result_is_truncated() {
+        let nested = format!("{}{}", "{\"a\": ".repeat(200), "}".repeat(200));
+        let output = vec![
+            StreamVariant::CodeOutput(nested, "call_1".to_string()),
+            StreamVariant::Image("iVBORw0KGgo=".to_string()),
+        ];
+        let capped = cap_tool_result(output, 100);
+
+        let StreamVariant::CodeOutput(content, id) = &capped[0] else {
+            panic!("The first variant should still be the code output");
+        };
+        assert_eq!(id, "call_1");
+        assert!(content.ends_with("[Output truncated after 100 characters]"));
+        assert!(content.len() < 200);
+        assert_eq!(capped[1], StreamVariant::Image("iVBORw0KGgo=".to_string()));
+    }
+}